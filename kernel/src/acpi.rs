@@ -0,0 +1,710 @@
+use crate::{
+    memlayout::{Address, MSize, PhysAddr, VirtAddr, phys_to_virt},
+    paging,
+    spin::{Once, SpinLock},
+    warn, x86,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::mem::size_of;
+
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+const RSDT_SIGNATURE: [u8; 4] = *b"RSDT";
+const XSDT_SIGNATURE: [u8; 4] = *b"XSDT";
+const MADT_SIGNATURE: [u8; 4] = *b"APIC";
+const FADT_SIGNATURE: [u8; 4] = *b"FACP";
+const HPET_SIGNATURE: [u8; 4] = *b"HPET";
+const MCFG_SIGNATURE: [u8; 4] = *b"MCFG";
+
+// ACPI 1.0のRSDPの長さ (この範囲のchecksumはrevisionによらず検証する)
+const RSDP_V1_LENGTH: usize = 20;
+
+const MADT_TYPE_LOCAL_APIC: u8 = 0;
+const MADT_TYPE_IO_APIC: u8 = 1;
+const MADT_TYPE_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_TYPE_LOCAL_APIC_NMI: u8 = 4;
+const MADT_TYPE_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_TYPE_LOCAL_X2APIC: u8 = 9;
+const MADT_TYPE_LOCAL_X2APIC_NMI: u8 = 10;
+const MADT_LAPIC_ENABLED: u32 = 1 << 0;
+const MADT_LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+const FADT_FLAG_RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // 以下はrevision 2以降
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+const SDT_HEADER_SIZE: usize = size_of::<SdtHeader>();
+
+// ACPIのテーブルはアラインされていないことがあるため、すべてread_unalignedで読む
+unsafe fn read_phys<T: Copy>(phys: PhysAddr) -> T {
+    unsafe { core::ptr::read_unaligned(phys_to_virt(phys).to_ptr() as *const T) }
+}
+
+// テーブル中のoffsetの値. テーブルが短ければNone
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    let field = bytes.get(offset..offset + size_of::<T>())?;
+    Some(unsafe { core::ptr::read_unaligned(field.as_ptr() as *const T) })
+}
+
+// 全バイトの和が0になっていれば正しい
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+// 物理アドレスのSDTをヘッダのlength分のスライスとして返す. checksumが合わなければNone
+fn sdt_bytes(phys: PhysAddr) -> Option<&'static [u8]> {
+    let header: SdtHeader = unsafe { read_phys(phys) };
+    let length = header.length as usize;
+    if length < SDT_HEADER_SIZE {
+        return None;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(phys).to_ptr(), length) };
+    if !checksum_ok(bytes) {
+        let signature = header.signature;
+        warn!(
+            "ACPI: checksum mismatch in {} table",
+            core::str::from_utf8(&signature).unwrap_or("????")
+        );
+        return None;
+    }
+    Some(bytes)
+}
+
+// Generic Address Structure. レジスタの場所を表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    const SIZE: usize = 12;
+
+    fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+        let space = match read::<u8>(bytes, offset)? {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        let address = GenericAddress {
+            space,
+            bit_width: read(bytes, offset + 1)?,
+            bit_offset: read(bytes, offset + 2)?,
+            access_size: read(bytes, offset + 3)?,
+            address: read(bytes, offset + 4)?,
+        };
+        // アドレス0は未実装のレジスタを表す
+        (address.address != 0).then_some(address)
+    }
+
+    // ACPI 1.0のFADTにあるI/Oポート番号のフィールドから作る
+    fn io(port: u32, bit_width: u8) -> Option<Self> {
+        (port != 0).then_some(GenericAddress {
+            space: AddressSpace::Io,
+            bit_width,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        })
+    }
+
+    // アクセス幅 (bit). access_sizeが未指定 (0) ならbit_widthから決める
+    fn access_bits(&self) -> u8 {
+        match self.access_size {
+            1 => 8,
+            2 => 16,
+            3 => 32,
+            4 => 64,
+            _ => match self.bit_width {
+                0..=8 => 8,
+                9..=16 => 16,
+                17..=32 => 32,
+                _ => 64,
+            },
+        }
+    }
+
+    pub fn read(&self) -> Result<u64, &'static str> {
+        match self.space {
+            AddressSpace::Io => {
+                let port = self.address as u16;
+                match self.access_bits() {
+                    8 => Ok(x86::read_io(port) as u64),
+                    16 => Ok(x86::read_io_u16(port) as u64),
+                    32 => Ok(x86::read_io_u32(port) as u64),
+                    _ => Err("Unsupported I/O access width"),
+                }
+            }
+            AddressSpace::Memory => {
+                let virt = self.map_memory()?.to_usize();
+                unsafe {
+                    Ok(match self.access_bits() {
+                        8 => core::ptr::read_volatile(virt as *const u8) as u64,
+                        16 => core::ptr::read_volatile(virt as *const u16) as u64,
+                        32 => core::ptr::read_volatile(virt as *const u32) as u64,
+                        _ => core::ptr::read_volatile(virt as *const u64),
+                    })
+                }
+            }
+            _ => Err("Unsupported address space"),
+        }
+    }
+
+    pub fn write(&self, value: u64) -> Result<(), &'static str> {
+        match self.space {
+            AddressSpace::Io => {
+                let port = self.address as u16;
+                match self.access_bits() {
+                    8 => x86::write_io(port, value as u8),
+                    16 => x86::write_io_u16(port, value as u16),
+                    32 => x86::write_io_u32(port, value as u32),
+                    _ => return Err("Unsupported I/O access width"),
+                }
+                Ok(())
+            }
+            AddressSpace::Memory => {
+                let virt = self.map_memory()?.to_usize();
+                unsafe {
+                    match self.access_bits() {
+                        8 => core::ptr::write_volatile(virt as *mut u8, value as u8),
+                        16 => core::ptr::write_volatile(virt as *mut u16, value as u16),
+                        32 => core::ptr::write_volatile(virt as *mut u32, value as u32),
+                        _ => core::ptr::write_volatile(virt as *mut u64, value),
+                    }
+                }
+                Ok(())
+            }
+            _ => Err("Unsupported address space"),
+        }
+    }
+
+    // 最初のアクセスでマップし、以降は同じ仮想アドレスを使う
+    fn map_memory(&self) -> Result<VirtAddr, &'static str> {
+        let mut mapped = MAPPED_REGISTERS.lock();
+        if let Some(&virt) = mapped.get(&self.address) {
+            return Ok(virt);
+        }
+        let virt = paging::map_io(PhysAddr::new(self.address as usize), MSize::new(8))?;
+        mapped.insert(self.address, virt);
+        Ok(virt)
+    }
+}
+
+// メモリ空間のレジスタの物理アドレスと、マップした仮想アドレス
+static MAPPED_REGISTERS: SpinLock<BTreeMap<u64, VirtAddr>> = SpinLock::new(BTreeMap::new());
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct MadtLocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub flags: u32,
+}
+
+impl MadtLocalApic {
+    // 起動済み、または後から起動できるCPU
+    pub fn usable(&self) -> bool {
+        self.flags & (MADT_LAPIC_ENABLED | MADT_LAPIC_ONLINE_CAPABLE) != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MadtIoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+// ISAのIRQがGSIにどう接続されているか
+#[derive(Debug, Clone, Copy)]
+pub struct MadtInterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct MadtLocalApicNmi {
+    // u32::MAXは全てのプロセッサを表す
+    pub processor_uid: u32,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub flags: u32,
+    pub local_apics: Vec<MadtLocalApic>,
+    pub io_apics: Vec<MadtIoApic>,
+    pub interrupt_overrides: Vec<MadtInterruptOverride>,
+    pub local_apic_nmis: Vec<MadtLocalApicNmi>,
+}
+
+impl Madt {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut madt = Madt {
+            local_apic_address: read::<u32>(bytes, SDT_HEADER_SIZE)? as u64,
+            flags: read(bytes, SDT_HEADER_SIZE + 4)?,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            interrupt_overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+        // ヘッダの後にLocal APICのアドレス(u32)とフラグ(u32)が続き、その後が可変長のエントリ
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset + 2 <= bytes.len() {
+            let typ: u8 = read(bytes, offset)?;
+            let len = read::<u8>(bytes, offset + 1)? as usize;
+            if len < 2 || offset + len > bytes.len() {
+                break;
+            }
+            let entry = &bytes[offset..offset + len];
+            match typ {
+                // type, length, processor uid(u8), apic id(u8), flags(u32)
+                MADT_TYPE_LOCAL_APIC => madt.local_apics.push(MadtLocalApic {
+                    processor_uid: read::<u8>(entry, 2)? as u32,
+                    apic_id: read::<u8>(entry, 3)? as u32,
+                    flags: read(entry, 4)?,
+                }),
+                // type, length, io apic id(u8), reserved(u8), address(u32), gsi base(u32)
+                MADT_TYPE_IO_APIC => madt.io_apics.push(MadtIoApic {
+                    id: read(entry, 2)?,
+                    address: read(entry, 4)?,
+                    gsi_base: read(entry, 8)?,
+                }),
+                // type, length, bus(u8), source(u8), gsi(u32), flags(u16)
+                MADT_TYPE_INTERRUPT_OVERRIDE => {
+                    madt.interrupt_overrides.push(MadtInterruptOverride {
+                        bus: read(entry, 2)?,
+                        source: read(entry, 3)?,
+                        gsi: read(entry, 4)?,
+                        flags: read(entry, 8)?,
+                    })
+                }
+                // type, length, processor uid(u8), flags(u16), lint(u8)
+                MADT_TYPE_LOCAL_APIC_NMI => {
+                    let uid: u8 = read(entry, 2)?;
+                    madt.local_apic_nmis.push(MadtLocalApicNmi {
+                        processor_uid: if uid == 0xFF { u32::MAX } else { uid as u32 },
+                        flags: read(entry, 3)?,
+                        lint: read(entry, 5)?,
+                    })
+                }
+                // type, length, reserved(u16), address(u64)
+                MADT_TYPE_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    madt.local_apic_address = read(entry, 4)?;
+                }
+                // type, length, reserved(u16), x2apic id(u32), flags(u32), processor uid(u32)
+                MADT_TYPE_LOCAL_X2APIC => madt.local_apics.push(MadtLocalApic {
+                    processor_uid: read(entry, 12)?,
+                    apic_id: read(entry, 4)?,
+                    flags: read(entry, 8)?,
+                }),
+                // type, length, flags(u16), processor uid(u32), lint(u8), reserved(u8 * 3)
+                MADT_TYPE_LOCAL_X2APIC_NMI => madt.local_apic_nmis.push(MadtLocalApicNmi {
+                    processor_uid: read(entry, 4)?,
+                    flags: read(entry, 2)?,
+                    lint: read(entry, 8)?,
+                }),
+                _ => {}
+            }
+            offset += len;
+        }
+        Some(madt)
+    }
+
+    // 起動可能なCPUのLocal APIC ID (x2APICのエントリと重複するものは除く)
+    pub fn usable_apic_ids(&self) -> Vec<u32> {
+        let mut ids = Vec::new();
+        for lapic in self.local_apics.iter().filter(|lapic| lapic.usable()) {
+            if !ids.contains(&lapic.apic_id) {
+                ids.push(lapic.apic_id);
+            }
+        }
+        ids
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    pub dsdt: u64,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: Option<GenericAddress>,
+    pub pm1b_event_block: Option<GenericAddress>,
+    pub pm1a_control_block: Option<GenericAddress>,
+    pub pm1b_control_block: Option<GenericAddress>,
+    pub pm_timer_block: Option<GenericAddress>,
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    // ACPI 2.0以降の64bitのフィールド (X_*) があればそちらを優先する
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        // 古い形式では、ブロックの長さ (バイト) が別のフィールドにある
+        let block = |legacy: usize, extended: usize, length: usize| {
+            GenericAddress::parse(bytes, extended).or_else(|| {
+                let bit_width = read::<u8>(bytes, length).unwrap_or(0).saturating_mul(8);
+                GenericAddress::io(read(bytes, legacy).unwrap_or(0), bit_width)
+            })
+        };
+        let flags: u32 = read(bytes, 112).unwrap_or(0);
+        let reset_register = if flags & FADT_FLAG_RESET_REG_SUPPORTED != 0 {
+            GenericAddress::parse(bytes, 116)
+        } else {
+            None
+        };
+        let x_dsdt: u64 = read(bytes, 140).unwrap_or(0);
+        Some(Fadt {
+            dsdt: if x_dsdt != 0 {
+                x_dsdt
+            } else {
+                read::<u32>(bytes, 40)? as u64
+            },
+            sci_interrupt: read(bytes, 46)?,
+            smi_command_port: read(bytes, 48)?,
+            acpi_enable: read(bytes, 52)?,
+            acpi_disable: read(bytes, 53)?,
+            pm1a_event_block: block(56, 148, 88),
+            pm1b_event_block: block(60, 160, 88),
+            pm1a_control_block: block(64, 172, 89),
+            pm1b_control_block: block(68, 184, 89),
+            pm_timer_block: block(76, 208, 91),
+            century: read(bytes, 108).unwrap_or(0),
+            boot_architecture_flags: read(bytes, 109).unwrap_or(0),
+            flags,
+            reset_register,
+            reset_value: read(bytes, 128).unwrap_or(0),
+        })
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct HpetTable {
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+}
+
+impl HpetTable {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        Some(HpetTable {
+            event_timer_block_id: read(bytes, SDT_HEADER_SIZE)?,
+            base_address: GenericAddress::parse(bytes, SDT_HEADER_SIZE + 4)?,
+            hpet_number: read(bytes, SDT_HEADER_SIZE + 4 + GenericAddress::SIZE)?,
+            minimum_tick: read(bytes, SDT_HEADER_SIZE + 5 + GenericAddress::SIZE)?,
+        })
+    }
+}
+
+// PCIセグメントごとのECAM領域
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        // ヘッダの後に予約領域(u64)があり、その後が16バイトずつのエントリ
+        const ENTRY_SIZE: usize = 16;
+        let start = SDT_HEADER_SIZE + 8;
+        let count = bytes.len().saturating_sub(start) / ENTRY_SIZE;
+        let entries = (0..count)
+            .map(|i| {
+                let offset = start + i * ENTRY_SIZE;
+                Some(McfgEntry {
+                    base_address: read(bytes, offset)?,
+                    segment: read(bytes, offset + 8)?,
+                    start_bus: read(bytes, offset + 10)?,
+                    end_bus: read(bytes, offset + 11)?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Mcfg { entries })
+    }
+}
+
+// 起動時に解析したACPIのテーブル. 見つからないか壊れているテーブルはNone
+pub struct AcpiTables {
+    pub revision: u8,
+    // RSDT/XSDTから辿れた全てのテーブルのシグネチャ
+    pub signatures: Vec<[u8; 4]>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<HpetTable>,
+    pub mcfg: Option<Mcfg>,
+}
+
+static TABLES: Once<AcpiTables> = Once::new();
+
+// RSDPを検証し、RSDTかXSDTに並んでいるテーブルの物理アドレスを返す
+fn root_table_entries(rsdp_phys: PhysAddr) -> Result<(u8, Vec<PhysAddr>), &'static str> {
+    let rsdp: Rsdp = unsafe { read_phys(rsdp_phys) };
+    if rsdp.signature != RSDP_SIGNATURE {
+        return Err("Invalid RSDP signature");
+    }
+    let rsdp_bytes = |length: usize| unsafe {
+        core::slice::from_raw_parts(phys_to_virt(rsdp_phys).to_ptr(), length)
+    };
+    if !checksum_ok(rsdp_bytes(RSDP_V1_LENGTH)) {
+        return Err("RSDP checksum mismatch");
+    }
+    let use_xsdt = rsdp.revision >= 2 && rsdp.xsdt_address != 0;
+    if use_xsdt && !checksum_ok(rsdp_bytes(rsdp.length as usize)) {
+        return Err("RSDP extended checksum mismatch");
+    }
+
+    let (root, signature, entry_size) = if use_xsdt {
+        (rsdp.xsdt_address as usize, XSDT_SIGNATURE, size_of::<u64>())
+    } else {
+        (rsdp.rsdt_address as usize, RSDT_SIGNATURE, size_of::<u32>())
+    };
+    let bytes = sdt_bytes(PhysAddr::new(root)).ok_or("Invalid RSDT/XSDT")?;
+    if bytes[..4] != signature {
+        return Err("Invalid RSDT/XSDT signature");
+    }
+    let count = (bytes.len() - SDT_HEADER_SIZE) / entry_size;
+    let entries = (0..count)
+        .filter_map(|i| {
+            let offset = SDT_HEADER_SIZE + i * entry_size;
+            let addr = if use_xsdt {
+                read::<u64>(bytes, offset)? as usize
+            } else {
+                read::<u32>(bytes, offset)? as usize
+            };
+            (addr != 0).then(|| PhysAddr::new(addr))
+        })
+        .collect();
+    Ok((rsdp.revision, entries))
+}
+
+// ローダから渡されたRSDPからテーブルを解析して保持する
+pub fn init(rsdp: PhysAddr) -> Result<&'static AcpiTables, &'static str> {
+    if rsdp.to_usize() == 0 {
+        return Err("RSDP is not available");
+    }
+    let (revision, entries) = root_table_entries(rsdp)?;
+    let mut tables = AcpiTables {
+        revision,
+        signatures: Vec::new(),
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+    };
+    for bytes in entries.into_iter().filter_map(sdt_bytes) {
+        let signature: [u8; 4] = bytes[..4].try_into().unwrap();
+        tables.signatures.push(signature);
+        match signature {
+            MADT_SIGNATURE => tables.madt = Madt::parse(bytes),
+            FADT_SIGNATURE => tables.fadt = Fadt::parse(bytes),
+            HPET_SIGNATURE => tables.hpet = HpetTable::parse(bytes),
+            MCFG_SIGNATURE => tables.mcfg = Mcfg::parse(bytes),
+            _ => {}
+        }
+    }
+    Ok(TABLES.call_once(|| tables))
+}
+
+// DSDTのAML中の\_S5オブジェクト (Name(_S5, Package() {SLP_TYPa, SLP_TYPb, ...})) から
+// S5 (soft off) に入るときにPM1a/PM1bに書き込むSLP_TYPの値を取り出す
+// AMLのインタプリタは持たないため、QEMUや一般的なファームウェアが出力する形だけを解釈する
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let mut start = 0;
+    while let Some(found) = aml[start..].windows(4).position(|name| name == b"_S5_") {
+        let pos = start + found;
+        start = pos + 4;
+        // 定義 (NameOp _S5_ または NameOp \_S5_) 以外での参照は無視する
+        let is_definition = matches!(pos.checked_sub(1).map(|i| aml[i]), Some(NAME_OP))
+            || (pos >= 2 && aml[pos - 2] == NAME_OP && aml[pos - 1] == b'\\');
+        if !is_definition || aml.get(pos + 4) != Some(&PACKAGE_OP) {
+            continue;
+        }
+        // PkgLengthの先頭バイトの上位2bitは後続のバイト数
+        let pkg_length_bytes = (*aml.get(pos + 5)? >> 6) as usize;
+        // PkgLength, NumElementsの後に要素が並ぶ
+        let mut index = pos + 5 + 1 + pkg_length_bytes + 1;
+        let mut element = || -> Option<u8> {
+            let value = match *aml.get(index)? {
+                BYTE_PREFIX => {
+                    index += 1;
+                    *aml.get(index)?
+                }
+                ZERO_OP => 0,
+                ONE_OP => 1,
+                _ => return None,
+            };
+            index += 1;
+            Some(value)
+        };
+        let slp_typ_a = element()?;
+        let slp_typ_b = element()?;
+        return Some((slp_typ_a, slp_typ_b));
+    }
+    None
+}
+
+// S5のSLP_TYPa, SLP_TYPb
+pub fn s5_sleep_type() -> Option<(u8, u8)> {
+    let dsdt = sdt_bytes(PhysAddr::new(fadt()?.dsdt as usize))?;
+    parse_s5(&dsdt[SDT_HEADER_SIZE..])
+}
+
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.get()
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    tables()?.madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    tables()?.fadt.as_ref()
+}
+
+pub fn hpet() -> Option<&'static HpetTable> {
+    tables()?.hpet.as_ref()
+}
+
+pub fn mcfg() -> Option<&'static Mcfg> {
+    tables()?.mcfg.as_ref()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    // ヘッダとbodyからchecksumを合わせたテーブルを作る
+    fn make_table(signature: [u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0u8; SDT_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&signature);
+        bytes.extend_from_slice(body);
+        let length = bytes.len() as u32;
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        bytes[9] = 0u8.wrapping_sub(sum);
+        bytes
+    }
+
+    #[test_case]
+    fn parse_madt_entries() {
+        let mut body = vec![];
+        body.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        // Local APIC (uid 0, id 0, enabled) と (uid 1, id 1, disabled)
+        body.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+        body.extend_from_slice(&[0, 8, 1, 1, 0, 0, 0, 0]);
+        // I/O APIC (id 2, address 0xFEC00000, gsi base 0)
+        body.extend_from_slice(&[1, 12, 2, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+        // IRQ0 -> GSI2
+        body.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        let bytes = make_table(MADT_SIGNATURE, &body);
+        assert!(checksum_ok(&bytes));
+
+        let madt = Madt::parse(&bytes).unwrap();
+        assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+        assert_eq!(madt.local_apics.len(), 2);
+        assert_eq!(madt.usable_apic_ids(), vec![0]);
+        assert_eq!(madt.io_apics[0].address, 0xFEC0_0000);
+        assert_eq!(madt.interrupt_overrides[0].gsi, 2);
+    }
+
+    #[test_case]
+    fn parse_mcfg_entries() {
+        let mut body = vec![0u8; 8];
+        body.extend_from_slice(&0xB000_0000u64.to_le_bytes());
+        body.extend_from_slice(&[0, 0, 0, 0xFF, 0, 0, 0, 0]);
+        let bytes = make_table(MCFG_SIGNATURE, &body);
+        let mcfg = Mcfg::parse(&bytes).unwrap();
+        assert_eq!(mcfg.entries.len(), 1);
+        assert_eq!(mcfg.entries[0].base_address, 0xB000_0000);
+        assert_eq!(mcfg.entries[0].end_bus, 0xFF);
+    }
+
+    #[test_case]
+    fn parse_s5_package() {
+        // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+        let aml = [
+            0x10, 0x00, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x00,
+            0x00, 0x00,
+        ];
+        assert_eq!(parse_s5(&aml), Some((5, 0)));
+        // 参照のみで定義がない
+        assert_eq!(parse_s5(b"\x70_S5_\x60"), None);
+    }
+
+    #[test_case]
+    fn tables_are_parsed_at_boot() {
+        let Some(tables) = tables() else {
+            return;
+        };
+        assert!(tables.madt.is_some());
+        assert!(tables.fadt.is_some());
+    }
+}
@@ -63,6 +63,15 @@ pub fn init_allocator(start: usize, size: usize) {
     }
 }
 
+// (割り当て済みのバイト数, ヒープの大きさ). 解放しないので割り当て済みは増える一方
+pub fn usage() -> (usize, usize) {
+    let allocator = ALLOCATOR.lock();
+    (
+        allocator.next - allocator.start,
+        allocator.end - allocator.start,
+    )
+}
+
 #[cfg(test)]
 mod test {
     use alloc::alloc::{alloc, dealloc};
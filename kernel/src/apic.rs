@@ -0,0 +1,249 @@
+use crate::{
+    info,
+    memlayout::{Address, MSize, PhysAddr, VirtAddr},
+    paging,
+    spin::Once,
+    x86,
+};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+#[allow(dead_code)]
+const APIC_BASE_BSP: u64 = 1 << 8;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+const CPUID_FEAT_ECX_X2APIC: u32 = 1 << 21;
+
+const APIC_MMIO_SIZE: MSize = MSize::new(0x1000);
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+pub const ERROR_VECTOR: u8 = 0xFE;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+#[allow(dead_code)]
+const LVT_MASKED: u32 = 1 << 16;
+#[allow(dead_code)]
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+#[allow(dead_code)]
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+#[allow(dead_code)]
+const ICR_TRIGGER_LEVEL: u32 = 1 << 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    XApic,
+    X2Apic,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiKind {
+    Fixed(u8),
+    Nmi,
+    Init,
+    InitDeassert,
+    Startup(u8),
+}
+
+#[allow(dead_code)]
+impl IpiKind {
+    fn icr_low(&self) -> u32 {
+        match *self {
+            IpiKind::Fixed(vector) => ICR_LEVEL_ASSERT | vector as u32,
+            IpiKind::Nmi => ICR_LEVEL_ASSERT | (0b100 << 8),
+            IpiKind::Init => ICR_LEVEL_ASSERT | ICR_TRIGGER_LEVEL | (0b101 << 8),
+            IpiKind::InitDeassert => ICR_TRIGGER_LEVEL | (0b101 << 8),
+            // 起動ページ番号 (物理アドレス >> 12) をベクタとして渡す
+            IpiKind::Startup(page) => ICR_LEVEL_ASSERT | (0b110 << 8) | page as u32,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDest {
+    Apic(u32),
+    SelfOnly,
+    AllIncludingSelf,
+    AllExcludingSelf,
+}
+
+#[allow(dead_code)]
+impl IpiDest {
+    fn shorthand(&self) -> u32 {
+        match self {
+            IpiDest::Apic(_) => 0b00 << 18,
+            IpiDest::SelfOnly => 0b01 << 18,
+            IpiDest::AllIncludingSelf => 0b10 << 18,
+            IpiDest::AllExcludingSelf => 0b11 << 18,
+        }
+    }
+}
+
+pub struct LocalApic {
+    base: PhysAddr,
+    mmio: VirtAddr,
+    mode: ApicMode,
+}
+
+#[allow(dead_code)]
+impl LocalApic {
+    pub const ID: usize = 0x20;
+    pub const VERSION: usize = 0x30;
+    pub const TASK_PRIORITY: usize = 0x80;
+    pub const END_OF_INTERRUPT: usize = 0xB0;
+    pub const SPURIOUS_INTERRUPT: usize = 0xF0;
+    pub const ERROR_STATUS: usize = 0x280;
+    pub const INTERRUPT_COMMAND_LOW: usize = 0x300;
+    pub const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+    pub const LVT_TIMER: usize = 0x320;
+    pub const LVT_LINT0: usize = 0x350;
+    pub const LVT_LINT1: usize = 0x360;
+    pub const LVT_ERROR: usize = 0x370;
+    pub const TIMER_INIT_COUNT: usize = 0x380;
+    pub const TIMER_CURRENT_COUNT: usize = 0x390;
+    pub const TIMER_DIV: usize = 0x3E0;
+
+    fn detect() -> Self {
+        let base = PhysAddr::new((x86::read_msr(IA32_APIC_BASE) & APIC_BASE_ADDR_MASK) as usize);
+        let mode = if x86::cpuid(1, 0).ecx & CPUID_FEAT_ECX_X2APIC != 0 {
+            ApicMode::X2Apic
+        } else {
+            ApicMode::XApic
+        };
+        // x2APICモードではMMIOを使わないが、xAPICへのフォールバックに備えてマップしておく
+        let mmio = paging::map_io(base, APIC_MMIO_SIZE).expect("Failed to map Local APIC");
+        LocalApic { base, mmio, mode }
+    }
+
+    pub fn base(&self) -> PhysAddr {
+        self.base
+    }
+
+    pub fn mode(&self) -> ApicMode {
+        self.mode
+    }
+
+    pub fn read(&self, offset: usize) -> u32 {
+        match self.mode {
+            ApicMode::XApic => unsafe {
+                core::ptr::read_volatile((self.mmio.to_usize() + offset) as *const u32)
+            },
+            ApicMode::X2Apic => x86::read_msr(X2APIC_MSR_BASE + (offset >> 4) as u32) as u32,
+        }
+    }
+
+    pub fn write(&self, offset: usize, value: u32) {
+        match self.mode {
+            ApicMode::XApic => unsafe {
+                core::ptr::write_volatile((self.mmio.to_usize() + offset) as *mut u32, value)
+            },
+            ApicMode::X2Apic => {
+                x86::write_msr(X2APIC_MSR_BASE + (offset >> 4) as u32, value as u64)
+            }
+        }
+    }
+
+    // 各CPUで呼び出し、APICを有効化する
+    pub fn enable(&self) {
+        let mut msr = x86::read_msr(IA32_APIC_BASE) | APIC_BASE_GLOBAL_ENABLE;
+        if self.mode == ApicMode::X2Apic {
+            msr |= APIC_BASE_X2APIC_ENABLE;
+        }
+        x86::write_msr(IA32_APIC_BASE, msr);
+
+        self.write(Self::TASK_PRIORITY, 0);
+        self.write(Self::LVT_ERROR, ERROR_VECTOR as u32);
+        self.clear_error_status();
+        self.write(
+            Self::SPURIOUS_INTERRUPT,
+            SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        x86::read_msr(IA32_APIC_BASE) & APIC_BASE_BSP != 0
+    }
+
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            ApicMode::XApic => self.read(Self::ID) >> 24,
+            ApicMode::X2Apic => self.read(Self::ID),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        (self.read(Self::VERSION) & 0xFF) as u8
+    }
+
+    pub fn max_lvt_entry(&self) -> u8 {
+        ((self.read(Self::VERSION) >> 16) & 0xFF) as u8
+    }
+
+    // ESRは読み出す前に書き込みを行い、最新の状態をラッチする必要がある
+    pub fn error_status(&self) -> u32 {
+        self.write(Self::ERROR_STATUS, 0);
+        self.read(Self::ERROR_STATUS)
+    }
+
+    fn clear_error_status(&self) {
+        self.write(Self::ERROR_STATUS, 0);
+        self.write(Self::ERROR_STATUS, 0);
+    }
+
+    pub fn mask_lvt(&self, offset: usize) {
+        let value = self.read(offset);
+        self.write(offset, value | LVT_MASKED);
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(Self::END_OF_INTERRUPT, 0);
+    }
+
+    pub fn send_ipi(&self, dest: IpiDest, kind: IpiKind) {
+        let low = kind.icr_low() | dest.shorthand();
+        let target = match dest {
+            IpiDest::Apic(id) => id,
+            _ => 0,
+        };
+        match self.mode {
+            ApicMode::XApic => {
+                self.write(Self::INTERRUPT_COMMAND_HIGH, target << 24);
+                self.write(Self::INTERRUPT_COMMAND_LOW, low);
+                while self.read(Self::INTERRUPT_COMMAND_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            }
+            // x2APICではICRは64bitのMSR1つで、書き込みは即座に完了する
+            ApicMode::X2Apic => x86::write_msr(
+                X2APIC_MSR_BASE + (Self::INTERRUPT_COMMAND_LOW >> 4) as u32,
+                ((target as u64) << 32) | low as u64,
+            ),
+        }
+    }
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+pub fn local() -> &'static LocalApic {
+    LOCAL_APIC.get().expect("Local APIC is not initialized")
+}
+
+pub fn init() {
+    let apic = LOCAL_APIC.call_once(LocalApic::detect);
+    apic.enable();
+    info!(
+        "Local APIC: base {:#x}, mode {:?}, id {}, version {:#x}, max LVT {}",
+        apic.base().to_usize(),
+        apic.mode(),
+        apic.id(),
+        apic.version(),
+        apic.max_lvt_entry()
+    );
+}
+
+pub fn end_of_interrupt() {
+    local().end_of_interrupt();
+}
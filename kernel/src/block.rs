@@ -0,0 +1,296 @@
+use crate::spin::{Once, SpinLock};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    Read,
+    Write,
+    Flush,
+}
+
+// 非同期に発行したリクエストの完了を待つためのハンドル
+// 読み込みの場合は、完了時に読んだデータを受け取る
+#[derive(Clone)]
+pub struct Completion {
+    inner: Arc<CompletionInner>,
+}
+
+struct CompletionInner {
+    done: AtomicBool,
+    result: Once<Result<Vec<u8>, &'static str>>,
+}
+
+impl Completion {
+    pub fn new() -> Self {
+        Completion {
+            inner: Arc::new(CompletionInner {
+                done: AtomicBool::new(false),
+                result: Once::new(),
+            }),
+        }
+    }
+
+    // ドライバが割り込みハンドラなどから呼び出す
+    pub fn complete(&self, result: Result<Vec<u8>, &'static str>) {
+        self.inner.result.call_once(|| result);
+        self.inner.done.store(true, Ordering::Release);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.inner.done.load(Ordering::Acquire)
+    }
+
+    fn take(&self) -> Result<Vec<u8>, &'static str> {
+        self.inner
+            .result
+            .get()
+            .expect("Request is not completed")
+            .clone()
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    // セクタ数
+    fn capacity(&self) -> u64;
+
+    fn read_only(&self) -> bool;
+
+    // sectorから始まるリクエストを発行する
+    // 読み込みではdataの長さだけ読み、書き込みではdataを書く (セクタサイズの倍数)
+    fn submit(&self, op: BlockOp, sector: u64, data: Vec<u8>) -> Result<Completion, &'static str>;
+
+    // 割り込みを使えないデバイスで、完了したリクエストを回収する
+    fn poll(&self) {}
+
+    fn wait(&self, completion: &Completion) -> Result<Vec<u8>, &'static str> {
+        while !completion.is_done() {
+            self.poll();
+            core::hint::spin_loop();
+        }
+        completion.take()
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, sector, buf.len())?;
+        let completion = self.submit(BlockOp::Read, sector, vec![0; buf.len()])?;
+        buf.copy_from_slice(&self.wait(&completion)?);
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, sector, buf.len())?;
+        if self.read_only() {
+            return Err("Block device is read-only");
+        }
+        let completion = self.submit(BlockOp::Write, sector, buf.to_vec())?;
+        self.wait(&completion).map(|_| ())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        let completion = self.submit(BlockOp::Flush, 0, Vec::new())?;
+        self.wait(&completion).map(|_| ())
+    }
+}
+
+fn check_range<D: BlockDevice + ?Sized>(
+    device: &D,
+    sector: u64,
+    len: usize,
+) -> Result<(), &'static str> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err("Buffer size is not a multiple of the sector size");
+    }
+    match sector.checked_add((len / SECTOR_SIZE) as u64) {
+        Some(end) if end <= device.capacity() => Ok(()),
+        _ => Err("Access beyond the end of the block device"),
+    }
+}
+
+static DEVICES: SpinLock<Vec<(String, Arc<dyn BlockDevice>)>> = SpinLock::new(Vec::new());
+
+// ドライバが見つけたデバイスを登録する
+pub fn register(name: String, device: Arc<dyn BlockDevice>) {
+    DEVICES.lock().push((name, device));
+}
+
+#[allow(dead_code)]
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, device)| device.clone())
+}
+
+#[allow(dead_code)]
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES.lock().clone()
+}
+
+struct CacheEntry {
+    data: Vec<u8>,
+    dirty: bool,
+    // 最後に使った時刻 (LRUでの追い出しに使う)
+    last_used: u64,
+}
+
+// セクタ単位のライトバックキャッシュ
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    entries: SpinLock<(BTreeMap<u64, CacheEntry>, u64)>,
+}
+
+#[allow(dead_code)]
+impl BufferCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        BufferCache {
+            device,
+            capacity: capacity.max(1),
+            entries: SpinLock::new((BTreeMap::new(), 0)),
+        }
+    }
+
+    // sectorのキャッシュを用意してfを呼び出す. 必要なら最も古いエントリを追い出す
+    fn with_entry<T>(
+        &self,
+        sector: u64,
+        f: impl FnOnce(&mut CacheEntry) -> T,
+    ) -> Result<T, &'static str> {
+        let mut guard = self.entries.lock();
+        let (entries, clock) = &mut *guard;
+        *clock += 1;
+        if !entries.contains_key(&sector) {
+            if entries.len() >= self.capacity {
+                let (&victim, _) = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .expect("Cache is empty");
+                let entry = entries.remove(&victim).unwrap();
+                if entry.dirty {
+                    self.device.write(victim, &entry.data)?;
+                }
+            }
+            let mut data = vec![0; SECTOR_SIZE];
+            self.device.read(sector, &mut data)?;
+            entries.insert(
+                sector,
+                CacheEntry {
+                    data,
+                    dirty: false,
+                    last_used: 0,
+                },
+            );
+        }
+        let entry = entries.get_mut(&sector).unwrap();
+        entry.last_used = *clock;
+        Ok(f(entry))
+    }
+
+    pub fn read(&self, sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        self.with_entry(sector, |entry| buf.copy_from_slice(&entry.data))
+    }
+
+    pub fn write(&self, sector: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        if self.device.read_only() {
+            return Err("Block device is read-only");
+        }
+        self.with_entry(sector, |entry| {
+            entry.data.copy_from_slice(buf);
+            entry.dirty = true;
+        })
+    }
+
+    // 変更されたセクタを書き戻し、デバイスのキャッシュもフラッシュする
+    pub fn sync(&self) -> Result<(), &'static str> {
+        let mut guard = self.entries.lock();
+        for (&sector, entry) in guard.0.iter_mut().filter(|(_, entry)| entry.dirty) {
+            self.device.write(sector, &entry.data)?;
+            entry.dirty = false;
+        }
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    // 書き込み回数を数えるメモリ上のディスク
+    struct RamDisk {
+        data: SpinLock<Vec<u8>>,
+        writes: AtomicUsize,
+    }
+
+    impl BlockDevice for RamDisk {
+        fn capacity(&self) -> u64 {
+            (self.data.lock().len() / SECTOR_SIZE) as u64
+        }
+
+        fn read_only(&self) -> bool {
+            false
+        }
+
+        fn submit(
+            &self,
+            op: BlockOp,
+            sector: u64,
+            data: Vec<u8>,
+        ) -> Result<Completion, &'static str> {
+            let start = sector as usize * SECTOR_SIZE;
+            let mut disk = self.data.lock();
+            let completion = Completion::new();
+            match op {
+                BlockOp::Read => {
+                    completion.complete(Ok(disk[start..start + data.len()].to_vec()));
+                }
+                BlockOp::Write => {
+                    disk[start..start + data.len()].copy_from_slice(&data);
+                    self.writes.fetch_add(1, Ordering::Relaxed);
+                    completion.complete(Ok(Vec::new()));
+                }
+                BlockOp::Flush => completion.complete(Ok(Vec::new())),
+            }
+            Ok(completion)
+        }
+    }
+
+    #[test_case]
+    fn buffer_cache_writes_back_on_eviction_and_sync() {
+        let disk = Arc::new(RamDisk {
+            data: SpinLock::new(vec![0; SECTOR_SIZE * 8]),
+            writes: AtomicUsize::new(0),
+        });
+        let cache = BufferCache::new(disk.clone(), 2);
+        cache.write(0, &[1; SECTOR_SIZE]).unwrap();
+        cache.write(1, &[2; SECTOR_SIZE]).unwrap();
+        assert_eq!(disk.writes.load(Ordering::Relaxed), 0);
+
+        // セクタ0が最も古いので追い出されて書き戻される
+        let mut buf = [0; SECTOR_SIZE];
+        cache.read(2, &mut buf).unwrap();
+        assert_eq!(disk.writes.load(Ordering::Relaxed), 1);
+        assert_eq!(disk.data.lock()[0], 1);
+
+        cache.sync().unwrap();
+        assert_eq!(disk.data.lock()[SECTOR_SIZE], 2);
+        cache.read(1, &mut buf).unwrap();
+        assert_eq!(buf, [2; SECTOR_SIZE]);
+    }
+
+    #[test_case]
+    fn out_of_range_access_is_rejected() {
+        let disk = RamDisk {
+            data: SpinLock::new(vec![0; SECTOR_SIZE * 2]),
+            writes: AtomicUsize::new(0),
+        };
+        let mut buf = [0; SECTOR_SIZE * 2];
+        assert!(disk.read(1, &mut buf).is_err());
+        assert!(disk.read(0, &mut buf[..100]).is_err());
+        assert!(disk.read(0, &mut buf).is_ok());
+    }
+}
@@ -0,0 +1,44 @@
+use crate::{memory::MemoryRegionArray, spin::Once};
+
+// ローダから渡される情報. レイアウトはloader/src/bootinfo.rsと一致させる
+#[repr(C)]
+#[derive(Clone)]
+pub struct BootInfo {
+    pub heap_base: u64,
+    pub heap_size: u64,
+    // ACPI RSDPの物理アドレス (見つからなければ0)
+    pub rsdp: u64,
+    // APの起動コードを置くための1MiB未満の物理ページ (確保できなければ0)
+    pub ap_trampoline: u64,
+    pub framebuffer: FramebufferInfo,
+    pub memory_regions: MemoryRegionArray,
+}
+
+// GOPのピクセル形式 (1ピクセル32ビットのもののみ)
+pub const PIXEL_FORMAT_RGB: u32 = 0;
+pub const PIXEL_FORMAT_BGR: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FramebufferInfo {
+    // フレームバッファの物理アドレス (GOPが使えなければ0)
+    pub base: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    // 1行あたりのピクセル数 (width以上)
+    pub stride: u32,
+    pub pixel_format: u32,
+}
+
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+// ローダが渡すBootInfoはローダのスタック上 (恒等マップ) にあるため、
+// ページングを切り替える前にカーネル側にコピーしておく
+pub fn init(boot_info: &BootInfo) -> &'static BootInfo {
+    BOOT_INFO.call_once(|| boot_info.clone())
+}
+
+pub fn get() -> &'static BootInfo {
+    BOOT_INFO.get().expect("Boot info is not initialized")
+}
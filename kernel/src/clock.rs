@@ -0,0 +1,217 @@
+use crate::{hpet, info, pit, spin::Once, timer, warn, x86};
+use core::{
+    ops::{Add, Sub},
+    time::Duration,
+};
+
+const CPUID_EXT_MAX_LEAF: u32 = 0x8000_0000;
+const CPUID_EXT_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_EDX_INVARIANT_TSC: u32 = 1 << 8;
+const CPUID_TSC_CRYSTAL: u32 = 0x15;
+
+const TSC_CALIBRATION_PERIOD: Duration = Duration::from_millis(20);
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    // 不変TSC (周波数が電源状態に依存しない)
+    Tsc { frequency_hz: u64 },
+    Hpet { frequency_hz: u64 },
+    // APICタイマの割り込み回数 (分解能はtimer::TICK_HZ)
+    Tick,
+}
+
+struct Clock {
+    source: ClockSource,
+    // 初期化時点のカウンタの値
+    origin: u64,
+}
+
+impl Clock {
+    fn counter(&self) -> u64 {
+        match self.source {
+            ClockSource::Tsc { .. } => x86::rdtsc(),
+            ClockSource::Hpet { .. } => hpet::get().map_or(0, |hpet| hpet.counter()),
+            ClockSource::Tick => timer::bsp_tick_count(),
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        let elapsed = self.counter().wrapping_sub(self.origin) as u128;
+        let nanos = match self.source {
+            ClockSource::Tsc { frequency_hz } | ClockSource::Hpet { frequency_hz } => {
+                elapsed * NANOS_PER_SEC / frequency_hz as u128
+            }
+            ClockSource::Tick => timer::ticks_to_duration(elapsed as u64).as_nanos(),
+        };
+        nanos as u64
+    }
+}
+
+static CLOCK: Once<Clock> = Once::new();
+
+fn has_invariant_tsc() -> bool {
+    if x86::cpuid(CPUID_EXT_MAX_LEAF, 0).eax < CPUID_EXT_POWER_MANAGEMENT {
+        return false;
+    }
+    x86::cpuid(CPUID_EXT_POWER_MANAGEMENT, 0).edx & CPUID_EDX_INVARIANT_TSC != 0
+}
+
+// CPUID 0x15 (TSC/コアクリスタル比) が完全に報告されていればそれを使い、
+// そうでなければHPETかPITで一定時間待つ間のTSCの増分から求める
+fn tsc_frequency_hz() -> u64 {
+    if x86::cpuid(0, 0).eax >= CPUID_TSC_CRYSTAL {
+        let leaf = x86::cpuid(CPUID_TSC_CRYSTAL, 0);
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64;
+        }
+    }
+    let start = x86::rdtsc();
+    calibration_wait(TSC_CALIBRATION_PERIOD);
+    let elapsed = x86::rdtsc() - start;
+    (elapsed as u128 * NANOS_PER_SEC / TSC_CALIBRATION_PERIOD.as_nanos()) as u64
+}
+
+// 周波数の測定に使う基準で待つ. HPETがあればPITより分解能が高いのでそちらを使う
+pub fn calibration_wait(duration: Duration) {
+    match hpet::get() {
+        Some(hpet) => hpet.busy_wait(duration),
+        None => pit::busy_wait(duration),
+    }
+}
+
+fn select_source() -> ClockSource {
+    if has_invariant_tsc() {
+        return ClockSource::Tsc {
+            frequency_hz: tsc_frequency_hz(),
+        };
+    }
+    warn!("TSC is not invariant, falling back to HPET");
+    if let Some(hpet) = hpet::get() {
+        return ClockSource::Hpet {
+            frequency_hz: hpet.frequency_hz(),
+        };
+    }
+    warn!("HPET is not available, falling back to the timer tick");
+    ClockSource::Tick
+}
+
+pub fn init() {
+    match hpet::init() {
+        Some(hpet) => {
+            info!(
+                "HPET: {} Hz, {} comparators",
+                hpet.frequency_hz(),
+                hpet.comparator_count()
+            );
+        }
+        None => {
+            warn!("HPET is not available");
+        }
+    }
+    let clock = CLOCK.call_once(|| {
+        let source = select_source();
+        let mut clock = Clock { source, origin: 0 };
+        clock.origin = clock.counter();
+        clock
+    });
+    info!("Clock source: {:?}", clock.source);
+}
+
+#[allow(dead_code)]
+pub fn source() -> Option<ClockSource> {
+    CLOCK.get().map(|clock| clock.source)
+}
+
+// TSC-deadlineタイマのために、単調時刻をTSCの値に変換する
+pub fn instant_to_tsc(instant: Instant) -> Option<u64> {
+    let clock = CLOCK.get()?;
+    match clock.source {
+        ClockSource::Tsc { frequency_hz } => {
+            Some(clock.origin + (instant.0 as u128 * frequency_hz as u128 / NANOS_PER_SEC) as u64)
+        }
+        _ => None,
+    }
+}
+
+// 起動時からの単調増加する時刻 (ナノ秒)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+#[allow(dead_code)]
+impl Instant {
+    pub const ZERO: Instant = Instant(0);
+
+    // クロックの初期化前は常にZEROを返す
+    pub fn now() -> Self {
+        Instant(CLOCK.get().map_or(0, Clock::nanos))
+    }
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Instant(nanos)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Self::Output {
+        Instant(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+// プロファイリング用の生のサイクル数
+#[allow(dead_code)]
+pub fn cycles() -> u64 {
+    x86::rdtsc()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn instant_is_monotonic() {
+        let mut prev = Instant::now();
+        for _ in 0..1000 {
+            let now = Instant::now();
+            assert!(now >= prev);
+            prev = now;
+        }
+    }
+
+    #[test_case]
+    fn instant_measures_sleep() {
+        let start = Instant::now();
+        timer::sleep(Duration::from_millis(10));
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(9),
+            "elapsed: {:?}",
+            elapsed
+        );
+        assert!(elapsed < Duration::from_secs(1), "elapsed: {:?}", elapsed);
+    }
+}
@@ -0,0 +1,351 @@
+use crate::{
+    clock::Instant,
+    info,
+    net::{self, IpConfig, Ipv4Addr, MacAddress, UdpSocket},
+    random,
+    spin::SpinLock,
+    timer, warn, x86,
+};
+use alloc::vec::Vec;
+use core::time::Duration;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// サーバにブロードキャストで応答してもらう (アドレスが決まるまでユニキャストを受けられない実装のため)
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: u32 = 0x6382_5363;
+// BOOTPのヘッダとマジッククッキー
+const OPTIONS_OFFSET: usize = 240;
+// BOOTPのメッセージの最小の長さ
+const MIN_MESSAGE_LEN: usize = 300;
+
+// オプション
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+// メッセージの種類
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+const RETRIES: usize = 3;
+// 更新に失敗したときに再び試みるまでの時間
+const RENEW_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// サーバから受け取った設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub config: IpConfig,
+    pub server: Ipv4Addr,
+    pub lease_time: Duration,
+    pub acquired_at: Instant,
+}
+
+static LEASE: SpinLock<Option<Lease>> = SpinLock::new(None);
+
+impl Lease {
+    // 更新を始める時刻 (T1). リース期間の半分が過ぎたとき
+    fn renewal_time(&self) -> Instant {
+        self.acquired_at + self.lease_time / 2
+    }
+
+    fn expiry(&self) -> Instant {
+        self.acquired_at + self.lease_time
+    }
+}
+
+pub fn lease() -> Option<Lease> {
+    *LEASE.lock()
+}
+
+struct Reply {
+    message_type: u8,
+    your_address: Ipv4Addr,
+    server: Option<Ipv4Addr>,
+    netmask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+    lease_time: Option<u32>,
+}
+
+// 更新時はclient_addressに使用中のアドレスを入れ、requestedは指定しない
+fn build_message(
+    xid: u32,
+    mac: MacAddress,
+    message_type: u8,
+    client_address: Ipv4Addr,
+    requested: Option<(Ipv4Addr, Ipv4Addr)>,
+) -> Vec<u8> {
+    let mut message = alloc::vec![0; OPTIONS_OFFSET];
+    message[0] = OP_REQUEST;
+    message[1] = HTYPE_ETHERNET;
+    message[2] = 6;
+    net::write_u32(&mut message, 4, xid);
+    net::write_u16(&mut message, 10, FLAG_BROADCAST);
+    message[12..16].copy_from_slice(&client_address.0);
+    message[28..34].copy_from_slice(&mac.0);
+    net::write_u32(&mut message, 236, MAGIC_COOKIE);
+
+    message.extend([OPTION_MESSAGE_TYPE, 1, message_type]);
+    if let Some((address, server)) = requested {
+        message.extend([OPTION_REQUESTED_IP, 4]);
+        message.extend(address.0);
+        message.extend([OPTION_SERVER_ID, 4]);
+        message.extend(server.0);
+    }
+    message.extend([
+        OPTION_PARAMETER_REQUEST,
+        4,
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+        OPTION_LEASE_TIME,
+    ]);
+    message.push(OPTION_END);
+    message.resize(message.len().max(MIN_MESSAGE_LEN), OPTION_PAD);
+    message
+}
+
+fn parse_reply(message: &[u8], xid: u32, mac: MacAddress) -> Option<Reply> {
+    if message.len() < OPTIONS_OFFSET
+        || message[0] != OP_REPLY
+        || net::read_u32(message, 4) != xid
+        || message[28..34] != mac.0
+        || net::read_u32(message, 236) != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let mut reply = Reply {
+        message_type: 0,
+        your_address: Ipv4Addr::new(message[16], message[17], message[18], message[19]),
+        server: None,
+        netmask: None,
+        router: None,
+        dns: None,
+        lease_time: None,
+    };
+    let address =
+        |data: &[u8]| (data.len() >= 4).then(|| Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+    let mut options = &message[OPTIONS_OFFSET..];
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let [len, rest @ ..] = rest else {
+            break;
+        };
+        let len = *len as usize;
+        if rest.len() < len {
+            break;
+        }
+        let data = &rest[..len];
+        match *code {
+            OPTION_MESSAGE_TYPE if len == 1 => reply.message_type = data[0],
+            OPTION_SUBNET_MASK => reply.netmask = address(data),
+            OPTION_ROUTER => reply.router = address(data),
+            OPTION_DNS => reply.dns = address(data),
+            OPTION_SERVER_ID => reply.server = address(data),
+            OPTION_LEASE_TIME if len == 4 => reply.lease_time = Some(net::read_u32(data, 0)),
+            _ => {}
+        }
+        options = &rest[len..];
+    }
+    Some(reply)
+}
+
+// serverに要求を送り、message_typesのいずれかの応答を待つ. 応答がなければ再送する
+fn exchange(
+    socket: &UdpSocket,
+    server: Ipv4Addr,
+    message: &[u8],
+    xid: u32,
+    mac: MacAddress,
+    message_types: &[u8],
+) -> Result<Reply, &'static str> {
+    for _ in 0..RETRIES {
+        socket.send_to(message, server, SERVER_PORT)?;
+        let reply = net::wait_until(TIMEOUT, || {
+            let datagram = socket.recv_from()?;
+            parse_reply(&datagram.data, xid, mac)
+                .filter(|reply| message_types.contains(&reply.message_type))
+        });
+        if let Some(reply) = reply {
+            return Ok(reply);
+        }
+    }
+    Err("No response from DHCP server")
+}
+
+// DISCOVER, OFFER, REQUEST, ACKの順にやり取りしてインタフェースのアドレスを設定する
+fn configure() -> Result<Lease, &'static str> {
+    let mac = net::mac_address().ok_or("No network interface")?;
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let xid = random::next_u64() as u32;
+
+    let unspecified = Ipv4Addr::UNSPECIFIED;
+    let discover = build_message(xid, mac, DHCPDISCOVER, unspecified, None);
+    let offer = exchange(
+        &socket,
+        Ipv4Addr::BROADCAST,
+        &discover,
+        xid,
+        mac,
+        &[DHCPOFFER],
+    )?;
+    let server = offer
+        .server
+        .ok_or("DHCP offer without a server identifier")?;
+
+    let request = build_message(
+        xid,
+        mac,
+        DHCPREQUEST,
+        unspecified,
+        Some((offer.your_address, server)),
+    );
+    let ack = exchange(
+        &socket,
+        Ipv4Addr::BROADCAST,
+        &request,
+        xid,
+        mac,
+        &[DHCPACK, DHCPNAK],
+    )?;
+    if ack.message_type == DHCPNAK {
+        return Err("DHCP server declined the request");
+    }
+    Ok(accept(&ack, server))
+}
+
+// 取得したリースのサーバにユニキャストでREQUESTを送り、期間を延長する
+fn renew(lease: &Lease) -> Result<Lease, &'static str> {
+    let mac = net::mac_address().ok_or("No network interface")?;
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let xid = random::next_u64() as u32;
+
+    let request = build_message(xid, mac, DHCPREQUEST, lease.config.address, None);
+    let ack = exchange(
+        &socket,
+        lease.server,
+        &request,
+        xid,
+        mac,
+        &[DHCPACK, DHCPNAK],
+    )?;
+    if ack.message_type == DHCPNAK {
+        return Err("DHCP server declined the renewal");
+    }
+    Ok(accept(&ack, lease.server))
+}
+
+// ACKの内容をインタフェースに設定する
+fn accept(ack: &Reply, server: Ipv4Addr) -> Lease {
+    let lease = Lease {
+        config: IpConfig {
+            address: ack.your_address,
+            netmask: ack.netmask.unwrap_or(Ipv4Addr::new(255, 255, 255, 0)),
+            gateway: ack.router.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            dns: ack.dns,
+        },
+        server,
+        lease_time: Duration::from_secs(ack.lease_time.unwrap_or(u32::MAX) as u64),
+        acquired_at: Instant::now(),
+    };
+    net::set_config(lease.config);
+    *LEASE.lock() = Some(lease);
+    info!(
+        "dhcp: leased {} from {} for {}s",
+        lease.config.address,
+        server,
+        lease.lease_time.as_secs()
+    );
+    lease
+}
+
+// アドレスを取得し、T1が来るたびに更新するタスク
+// 取得できなければQEMUのユーザモードネットワークの既定値を使う
+pub fn task() {
+    let mut lease = match configure() {
+        Ok(lease) => lease,
+        Err(e) => {
+            warn!("dhcp: {}, using the QEMU user-net defaults", e);
+            net::set_config(IpConfig::QEMU_USER_NET);
+            // タスクから戻ることはできない
+            loop {
+                x86::halt();
+            }
+        }
+    };
+    let mut next = lease.renewal_time();
+    loop {
+        timer::sleep(next - Instant::now());
+        // 期限が切れていれば最初から取得し直す
+        let result = if Instant::now() < lease.expiry() {
+            renew(&lease)
+        } else {
+            configure()
+        };
+        match result {
+            Ok(renewed) => {
+                lease = renewed;
+                next = lease.renewal_time();
+            }
+            Err(e) => {
+                warn!("dhcp: failed to renew the lease: {}", e);
+                next = Instant::now() + RENEW_RETRY_INTERVAL;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_reply_options() {
+        let mac = MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        let mut message =
+            build_message(0x1234_5678, mac, DHCPDISCOVER, Ipv4Addr::UNSPECIFIED, None);
+        message[0] = OP_REPLY;
+        message[16..20].copy_from_slice(&[10, 0, 2, 15]);
+        message.truncate(OPTIONS_OFFSET);
+        message.extend([OPTION_MESSAGE_TYPE, 1, DHCPOFFER, OPTION_PAD]);
+        message.extend([OPTION_SUBNET_MASK, 4, 255, 255, 255, 0]);
+        message.extend([OPTION_ROUTER, 4, 10, 0, 2, 2]);
+        message.extend([OPTION_DNS, 8, 10, 0, 2, 3, 8, 8, 8, 8]);
+        message.extend([OPTION_SERVER_ID, 4, 10, 0, 2, 2]);
+        message.extend([OPTION_LEASE_TIME, 4, 0, 1, 0x51, 0x80]);
+        message.push(OPTION_END);
+
+        assert!(parse_reply(&message, 0x1234_5679, mac).is_none());
+        let reply = parse_reply(&message, 0x1234_5678, mac).unwrap();
+        assert_eq!(reply.message_type, DHCPOFFER);
+        assert_eq!(reply.your_address, Ipv4Addr::new(10, 0, 2, 15));
+        assert_eq!(reply.netmask, Some(Ipv4Addr::new(255, 255, 255, 0)));
+        assert_eq!(reply.router, Some(Ipv4Addr::new(10, 0, 2, 2)));
+        assert_eq!(reply.dns, Some(Ipv4Addr::new(10, 0, 2, 3)));
+        assert_eq!(reply.server, Some(Ipv4Addr::new(10, 0, 2, 2)));
+        assert_eq!(reply.lease_time, Some(86400));
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{
+    clock::Instant,
+    net::{self, Ipv4Addr, UdpSocket},
+    random,
+    spin::SpinLock,
+};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::time::Duration;
+
+const DNS_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000F;
+const RCODE_NAME_ERROR: u16 = 3;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+const RETRIES: usize = 3;
+
+// ドメイン名 -> (アドレス, 有効期限)
+static CACHE: SpinLock<BTreeMap<String, (Vec<Ipv4Addr>, Instant)>> = SpinLock::new(BTreeMap::new());
+
+fn build_query(id: u16, name: &str) -> Result<Vec<u8>, &'static str> {
+    let mut query = alloc::vec![0; HEADER_LEN];
+    net::write_u16(&mut query, 0, id);
+    net::write_u16(&mut query, 2, FLAG_RECURSION_DESIRED);
+    net::write_u16(&mut query, 4, 1);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err("Invalid domain name");
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    if query.len() - HEADER_LEN > 255 {
+        return Err("Domain name is too long");
+    }
+    query.extend(TYPE_A.to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+// offsetから始まるドメイン名を読み飛ばし、その次の位置を返す
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            // 圧縮 (ポインタ) は2バイトで名前が終わる
+            0xC0.. => return (offset + 2 <= message.len()).then_some(offset + 2),
+            _ => offset += 1 + len,
+        }
+    }
+}
+
+// 応答からAレコードと最小のTTLを取り出す
+fn parse_response(message: &[u8], id: u16) -> Result<(Vec<Ipv4Addr>, u32), &'static str> {
+    if message.len() < HEADER_LEN || net::read_u16(message, 0) != id {
+        return Err("Unexpected DNS response");
+    }
+    let flags = net::read_u16(message, 2);
+    if flags & FLAG_RESPONSE == 0 {
+        return Err("Unexpected DNS response");
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Err("No such domain"),
+        _ => return Err("DNS server returned an error"),
+    }
+    let questions = net::read_u16(message, 4);
+    let answers = net::read_u16(message, 6);
+    let malformed = "Malformed DNS response";
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(message, offset).ok_or(malformed)? + 4;
+    }
+    let mut addresses = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        offset = skip_name(message, offset).ok_or(malformed)?;
+        let record = message.get(offset..offset + 10).ok_or(malformed)?;
+        let typ = net::read_u16(record, 0);
+        let class = net::read_u16(record, 2);
+        let len = net::read_u16(record, 8) as usize;
+        let data = message
+            .get(offset + 10..offset + 10 + len)
+            .ok_or(malformed)?;
+        // CNAMEの場合も、再帰問い合わせの応答には続けてAレコードが入っている
+        if typ == TYPE_A && class == CLASS_IN && len == 4 {
+            addresses.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+            ttl = ttl.min(net::read_u32(record, 4));
+        }
+        offset += 10 + len;
+    }
+    if addresses.is_empty() {
+        return Err("No address record");
+    }
+    Ok((addresses, ttl))
+}
+
+fn query(server: Ipv4Addr, name: &str) -> Result<(Vec<Ipv4Addr>, u32), &'static str> {
+    // 応答の偽装を難しくするため、IDは乱数にする
+    let id = random::next_u64() as u16;
+    let query = build_query(id, name)?;
+    let socket = UdpSocket::bind(0)?;
+    for _ in 0..RETRIES {
+        socket.send_to(&query, server, DNS_PORT)?;
+        let response = net::wait_until(TIMEOUT, || {
+            socket
+                .recv_from()
+                .filter(|datagram| datagram.src == server && datagram.src_port == DNS_PORT)
+        });
+        if let Some(response) = response {
+            return parse_response(&response.data, id);
+        }
+    }
+    Err("DNS server did not respond")
+}
+
+// nameのIPv4アドレスを全て返す. 結果はTTLの間キャッシュする
+pub fn lookup(name: &str) -> Result<Vec<Ipv4Addr>, &'static str> {
+    if let Ok(addr) = name.parse() {
+        return Ok(alloc::vec![addr]);
+    }
+    let key = name.trim_end_matches('.').to_ascii_lowercase();
+    if let Some((addresses, expires)) = CACHE.lock().get(&key)
+        && Instant::now() < *expires
+    {
+        return Ok(addresses.clone());
+    }
+    let server = net::config()
+        .and_then(|config| config.dns)
+        .ok_or("No DNS server configured")?;
+    let (addresses, ttl) = query(server, &key)?;
+    let expires = Instant::now() + Duration::from_secs(ttl as u64);
+    CACHE.lock().insert(key, (addresses.clone(), expires));
+    Ok(addresses)
+}
+
+pub fn resolve(name: &str) -> Result<Ipv4Addr, &'static str> {
+    lookup(name).map(|addresses| addresses[0])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_compressed_response() {
+        let mut message = build_query(0x4242, "www.example.com").unwrap();
+        assert_eq!(
+            &message[HEADER_LEN..HEADER_LEN + 17],
+            b"\x03www\x07example\x03com\x00"
+        );
+        net::write_u16(&mut message, 2, FLAG_RESPONSE | FLAG_RECURSION_DESIRED);
+        net::write_u16(&mut message, 6, 2);
+        // CNAME www.example.com -> example.com, A example.com
+        message.extend([0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xC0, 16]);
+        message.extend([0xC0, 16, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 93, 184, 216, 34]);
+
+        let (addresses, ttl) = parse_response(&message, 0x4242).unwrap();
+        assert_eq!(addresses, [Ipv4Addr::new(93, 184, 216, 34)]);
+        assert_eq!(ttl, 30);
+        assert!(parse_response(&message, 0x4243).is_err());
+
+        net::write_u16(&mut message, 2, FLAG_RESPONSE | RCODE_NAME_ERROR);
+        assert_eq!(parse_response(&message, 0x4242), Err("No such domain"));
+    }
+
+    #[test_case]
+    fn lookup_accepts_dotted_quad() {
+        assert_eq!(resolve("10.0.2.2"), Ok(Ipv4Addr::new(10, 0, 2, 2)));
+        assert!(build_query(1, "bad..name").is_err());
+    }
+}
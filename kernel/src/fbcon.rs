@@ -0,0 +1,430 @@
+use crate::{
+    bootinfo::{FramebufferInfo, PIXEL_FORMAT_BGR, PIXEL_FORMAT_RGB},
+    font, info,
+    memlayout::{Address, MSize, PhysAddr},
+    paging,
+    spin::{Once, SpinLock},
+    warn,
+};
+use alloc::vec::Vec;
+use core::fmt;
+
+// フォントを縦に2倍に伸ばして描く
+const SCALE_Y: usize = 2;
+const CELL_WIDTH: usize = font::GLYPH_WIDTH;
+const CELL_HEIGHT: usize = font::GLYPH_HEIGHT * SCALE_Y;
+const TAB_WIDTH: usize = 8;
+// CSIの引数の最大数 (超えた分は無視する)
+const MAX_PARAMS: usize = 8;
+
+// ANSIの16色 (0-7が通常、8-15が明るい色). xtermと同じ値
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0xCD, 0x00, 0x00),
+    (0x00, 0xCD, 0x00),
+    (0xCD, 0xCD, 0x00),
+    (0x00, 0x00, 0xEE),
+    (0xCD, 0x00, 0xCD),
+    (0x00, 0xCD, 0xCD),
+    (0xE5, 0xE5, 0xE5),
+    (0x7F, 0x7F, 0x7F),
+    (0xFF, 0x00, 0x00),
+    (0x00, 0xFF, 0x00),
+    (0xFF, 0xFF, 0x00),
+    (0x5C, 0x5C, 0xFF),
+    (0xFF, 0x00, 0xFF),
+    (0x00, 0xFF, 0xFF),
+    (0xFF, 0xFF, 0xFF),
+];
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+const BRIGHT: u8 = 8;
+
+struct Framebuffer {
+    base: *mut u32,
+    width: usize,
+    height: usize,
+    // 1行あたりのピクセル数
+    stride: usize,
+    pixel_format: u32,
+}
+
+// フレームバッファはコンソールのロックを通してだけ触る
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    fn pixel(&self, color: u8) -> u32 {
+        let (r, g, b) = PALETTE[color as usize];
+        let (r, g, b) = (r as u32, g as u32, b as u32);
+        if self.pixel_format == PIXEL_FORMAT_BGR {
+            (r << 16) | (g << 8) | b
+        } else {
+            (b << 16) | (g << 8) | r
+        }
+    }
+
+    fn draw_glyph(&self, x: usize, y: usize, c: u8, fg: u8, bg: u8) {
+        let (fg, bg) = (self.pixel(fg), self.pixel(bg));
+        for (row, bits) in font::glyph(c).iter().enumerate() {
+            for dy in 0..SCALE_Y {
+                let line = (y + row * SCALE_Y + dy) * self.stride + x;
+                for dx in 0..CELL_WIDTH {
+                    let pixel = if bits & (1 << dx) != 0 { fg } else { bg };
+                    unsafe { core::ptr::write_volatile(self.base.add(line + dx), pixel) };
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    c: u8,
+    fg: u8,
+    bg: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Normal,
+    // ESCを受け取った
+    Escape,
+    // ESC [ の後、終端の文字を待っている
+    Csi,
+}
+
+// log.rsの色付けなど、ANSIのエスケープシーケンスを解釈するテキストコンソール
+struct Console {
+    fb: Framebuffer,
+    cols: usize,
+    rows: usize,
+    // 画面に表示している内容. スクロールでは変わったセルだけを描き直す
+    cells: Vec<Cell>,
+    col: usize,
+    row: usize,
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    state: State,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+}
+
+impl Console {
+    fn new(fb: Framebuffer) -> Self {
+        let cols = fb.width / CELL_WIDTH;
+        let rows = fb.height / CELL_HEIGHT;
+        let blank = Cell {
+            c: b' ',
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+        };
+        let console = Console {
+            fb,
+            cols,
+            rows,
+            cells: alloc::vec![blank; cols * rows],
+            col: 0,
+            row: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bold: false,
+            state: State::Normal,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+        };
+        for index in 0..cols * rows {
+            console.draw_cell(index);
+        }
+        console
+    }
+
+    fn draw_cell(&self, index: usize) {
+        let cell = self.cells[index];
+        let x = index % self.cols * CELL_WIDTH;
+        let y = index / self.cols * CELL_HEIGHT;
+        self.fb.draw_glyph(x, y, cell.c, cell.fg, cell.bg);
+    }
+
+    fn set_cell(&mut self, index: usize, cell: Cell) {
+        if self.cells[index] != cell {
+            self.cells[index] = cell;
+            self.draw_cell(index);
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        Cell {
+            c: b' ',
+            fg: self.fg,
+            bg: self.bg,
+        }
+    }
+
+    fn clear(&mut self, range: core::ops::Range<usize>) {
+        let blank = self.blank();
+        for index in range {
+            self.set_cell(index, blank);
+        }
+    }
+
+    fn scroll(&mut self) {
+        for index in 0..(self.rows - 1) * self.cols {
+            self.set_cell(index, self.cells[index + self.cols]);
+        }
+        self.clear((self.rows - 1) * self.cols..self.rows * self.cols);
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    fn put(&mut self, c: u8) {
+        if self.col == self.cols {
+            self.newline();
+        }
+        let fg = if self.bold && self.fg < BRIGHT {
+            self.fg + BRIGHT
+        } else {
+            self.fg
+        };
+        let cell = Cell { c, fg, bg: self.bg };
+        self.set_cell(self.row * self.cols + self.col, cell);
+        self.col += 1;
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match self.state {
+            State::Normal => match byte {
+                0x1B => self.state = State::Escape,
+                // ログは改行にLFだけを使う
+                b'\n' => self.newline(),
+                b'\r' => self.col = 0,
+                0x08 => self.col = self.col.saturating_sub(1),
+                b'\t' => {
+                    let next = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                    while self.col < next.min(self.cols) {
+                        self.put(b' ');
+                    }
+                }
+                0x20..0x7F => self.put(byte),
+                // UTF-8は1文字を1つの代替文字で表示する
+                0xC0.. => self.put(byte),
+                _ => {}
+            },
+            State::Escape => {
+                if byte == b'[' {
+                    self.params = [0; MAX_PARAMS];
+                    self.param_count = 0;
+                    self.state = State::Csi;
+                } else {
+                    self.state = State::Normal;
+                }
+            }
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    let index = self.param_count.max(1) - 1;
+                    self.param_count = self.param_count.max(1);
+                    if index < MAX_PARAMS {
+                        self.params[index] = self.params[index]
+                            .saturating_mul(10)
+                            .saturating_add((byte - b'0') as u16);
+                    }
+                }
+                b';' => self.param_count = self.param_count.max(1) + 1,
+                0x40..=0x7E => {
+                    self.execute_csi(byte);
+                    self.state = State::Normal;
+                }
+                _ => {}
+            },
+        }
+    }
+
+    fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params.get(index) {
+            Some(&value) if index < self.param_count && value != 0 => value,
+            _ => default,
+        }
+    }
+
+    fn execute_csi(&mut self, command: u8) {
+        match command {
+            b'm' => self.select_graphic_rendition(),
+            b'A' => self.row = self.row.saturating_sub(self.param(0, 1) as usize),
+            b'B' => self.row = (self.row + self.param(0, 1) as usize).min(self.rows - 1),
+            b'C' => self.col = (self.col + self.param(0, 1) as usize).min(self.cols - 1),
+            b'D' => self.col = self.col.saturating_sub(self.param(0, 1) as usize),
+            b'H' => {
+                self.row = (self.param(0, 1) as usize - 1).min(self.rows - 1);
+                self.col = (self.param(1, 1) as usize - 1).min(self.cols - 1);
+            }
+            // 2は画面全体、0はカーソルから画面の終わりまで
+            b'J' => {
+                let cursor = self.row * self.cols + self.col;
+                match self.param(0, 0) {
+                    2 => self.clear(0..self.rows * self.cols),
+                    0 => self.clear(cursor..self.rows * self.cols),
+                    _ => {}
+                }
+            }
+            // カーソルから行の終わりまで
+            b'K' => {
+                let start = self.row * self.cols;
+                self.clear(start + self.col.min(self.cols)..start + self.cols);
+            }
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        // 引数がなければリセット
+        for index in 0..self.param_count.clamp(1, MAX_PARAMS) {
+            match self.params[index] {
+                0 => {
+                    self.fg = DEFAULT_FG;
+                    self.bg = DEFAULT_BG;
+                    self.bold = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                code @ 30..=37 => self.fg = (code - 30) as u8,
+                39 => self.fg = DEFAULT_FG,
+                code @ 40..=47 => self.bg = (code - 40) as u8,
+                49 => self.bg = DEFAULT_BG,
+                code @ 90..=97 => self.fg = (code - 90) as u8 + BRIGHT,
+                code @ 100..=107 => self.bg = (code - 100) as u8 + BRIGHT,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+static CONSOLE: Once<SpinLock<Console>> = Once::new();
+
+// initの前に呼ばれた場合は何もしない
+pub fn write(data: &[u8]) {
+    if let Some(console) = CONSOLE.get() {
+        let mut console = console.lock();
+        for &byte in data {
+            console.write_byte(byte);
+        }
+    }
+}
+
+pub fn print(args: fmt::Arguments) {
+    if let Some(console) = CONSOLE.get() {
+        let _ = fmt::Write::write_fmt(&mut *console.lock(), args);
+    }
+}
+
+// パニック時など、ロックを持ったCPUが戻ってこないかもしれない場合に使う. ロック中なら捨てる
+pub fn try_print(args: fmt::Arguments) {
+    if let Some(mut console) = CONSOLE.get().and_then(|console| console.try_lock()) {
+        let _ = fmt::Write::write_fmt(&mut *console, args);
+    }
+}
+
+// paging::init_patの後に呼び出す. ローダがフレームバッファを渡さなければ何もしない
+pub fn init(info: &FramebufferInfo) {
+    if info.base == 0 {
+        warn!("fbcon: no framebuffer");
+        return;
+    }
+    if info.pixel_format != PIXEL_FORMAT_RGB && info.pixel_format != PIXEL_FORMAT_BGR {
+        warn!("fbcon: unsupported pixel format {}", info.pixel_format);
+        return;
+    }
+    let size = info.stride as usize * info.height as usize * size_of::<u32>();
+    let base =
+        match paging::map_write_combining(PhysAddr::new(info.base as usize), MSize::new(size)) {
+            Ok(base) => base,
+            Err(e) => {
+                warn!("fbcon: {}", e);
+                return;
+            }
+        };
+    let console = CONSOLE.call_once(|| {
+        SpinLock::new(Console::new(Framebuffer {
+            base: base.to_usize() as *mut u32,
+            width: info.width as usize,
+            height: info.height as usize,
+            stride: info.stride as usize,
+            pixel_format: info.pixel_format,
+        }))
+    });
+    let (cols, rows) = {
+        let console = console.lock();
+        (console.cols, console.rows)
+    };
+    info!(
+        "fbcon: {}x{} framebuffer, {}x{} text console",
+        info.width, info.height, cols, rows
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn console(cols: usize, rows: usize, pixels: &mut Vec<u32>) -> Console {
+        let (width, height) = (cols * CELL_WIDTH, rows * CELL_HEIGHT);
+        pixels.resize(width * height, 0xDEAD_BEEF);
+        Console::new(Framebuffer {
+            base: pixels.as_mut_ptr(),
+            width,
+            height,
+            stride: width,
+            pixel_format: PIXEL_FORMAT_BGR,
+        })
+    }
+
+    fn text(console: &Console, row: usize) -> Vec<u8> {
+        console.cells[row * console.cols..(row + 1) * console.cols]
+            .iter()
+            .map(|cell| cell.c)
+            .collect()
+    }
+
+    #[test_case]
+    fn ansi_colors_and_scrolling() {
+        let mut pixels = Vec::new();
+        let mut console = console(4, 2, &mut pixels);
+        assert!(pixels.iter().all(|&pixel| pixel == 0));
+
+        fmt::Write::write_str(&mut console, "\x1b[31mab\x1b[0mc\n").unwrap();
+        assert_eq!(text(&console, 0), b"abc ");
+        assert_eq!(console.cells[0].fg, 1);
+        assert_eq!(console.cells[2].fg, DEFAULT_FG);
+        // 'a'のグリフの3行目 (縦に伸ばして4ピクセル目) には前景色のピクセルがある
+        let red = 0xCD << 16;
+        let line = 4 * console.fb.stride;
+        assert!(pixels[line..line + CELL_WIDTH].contains(&red));
+
+        // 行の終わりで折り返し、最後の行を越えるとスクロールする
+        fmt::Write::write_str(&mut console, "\x1b[1;44mdefgh").unwrap();
+        assert_eq!(text(&console, 0), b"defg");
+        assert_eq!(text(&console, 1), b"h   ");
+        assert_eq!(console.cells[0].fg, DEFAULT_FG + BRIGHT);
+        assert_eq!(console.cells[0].bg, 4);
+        assert_eq!((console.row, console.col), (1, 1));
+
+        fmt::Write::write_str(&mut console, "\x1b[H\x1b[2J").unwrap();
+        assert_eq!(text(&console, 0), b"    ");
+        assert_eq!((console.row, console.col), (0, 0));
+    }
+}
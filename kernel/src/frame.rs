@@ -0,0 +1,161 @@
+use crate::{
+    bootinfo::BootInfo,
+    memlayout::{self, Address, PhysAddr},
+    memory::MemoryRegionType,
+    spin::SpinLock,
+};
+use alloc::vec::Vec;
+
+pub const FRAME_SIZE: usize = 4096;
+
+// 1MiB未満はAPのトランポリンやBIOSの領域があるため使わない
+const LOW_MEMORY_END: usize = 0x10_0000;
+
+// ローダから渡されたUsableな領域から物理フレームを切り出すアロケータ
+// (DMAのバッファなど、物理アドレスが必要なメモリに使う)
+struct FrameAllocator {
+    // ページ境界に揃えた [start, end) の領域
+    regions: Vec<(usize, usize)>,
+    // 未使用の領域の先頭. regions[current].0 .. next は割り当て済み
+    current: usize,
+    next: usize,
+    // 解放されたフレーム
+    free: Vec<PhysAddr>,
+}
+
+impl FrameAllocator {
+    const fn new() -> Self {
+        FrameAllocator {
+            regions: Vec::new(),
+            current: 0,
+            next: 0,
+            free: Vec::new(),
+        }
+    }
+
+    fn add_region(&mut self, base: usize, len: usize) {
+        let start = base.max(LOW_MEMORY_END).next_multiple_of(FRAME_SIZE);
+        let end = (base + len) / FRAME_SIZE * FRAME_SIZE;
+        if start < end {
+            self.regions.push((start, end));
+        }
+    }
+
+    // 連続したpages個のフレームを未使用の領域から切り出す
+    fn allocate_contiguous(&mut self, pages: usize) -> Option<PhysAddr> {
+        let size = pages * FRAME_SIZE;
+        while let Some(&(start, end)) = self.regions.get(self.current) {
+            let next = self.next.max(start);
+            if next + size <= end {
+                self.next = next + size;
+                return Some(PhysAddr::new(next));
+            }
+            // 残りが足りない領域は諦めて次の領域に進む
+            self.current += 1;
+        }
+        None
+    }
+
+    fn allocate(&mut self) -> Option<PhysAddr> {
+        self.free.pop().or_else(|| self.allocate_contiguous(1))
+    }
+
+    // 未使用の領域と解放されたフレームの数. 読み飛ばした領域の残りは数えない
+    fn free_frames(&self) -> usize {
+        let untouched: usize = self
+            .regions
+            .iter()
+            .skip(self.current)
+            .map(|&(start, end)| (end - self.next.max(start)) / FRAME_SIZE)
+            .sum();
+        untouched + self.free.len()
+    }
+}
+
+static FRAME_ALLOCATOR: SpinLock<FrameAllocator> = SpinLock::new(FrameAllocator::new());
+
+pub fn init(boot_info: &BootInfo) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for region in boot_info.memory_regions.iter() {
+        if matches!(region.typ(), MemoryRegionType::Usable) {
+            allocator.add_region(region.base(), region.len());
+        }
+    }
+    allocator.regions.sort_unstable();
+}
+
+// 割り当てたフレームは線形マップ経由で0クリアしてから返す
+fn zero(phys: PhysAddr, pages: usize) {
+    let virt = memlayout::phys_to_virt(phys);
+    unsafe { core::ptr::write_bytes(virt.to_usize() as *mut u8, 0, pages * FRAME_SIZE) }
+}
+
+pub fn allocate() -> Option<PhysAddr> {
+    let phys = FRAME_ALLOCATOR.lock().allocate()?;
+    zero(phys, 1);
+    Some(phys)
+}
+
+// 物理的に連続したpages個のフレームを割り当てる
+pub fn allocate_contiguous(pages: usize) -> Option<PhysAddr> {
+    let phys = match pages {
+        0 => return None,
+        1 => FRAME_ALLOCATOR.lock().allocate()?,
+        _ => FRAME_ALLOCATOR.lock().allocate_contiguous(pages)?,
+    };
+    zero(phys, pages);
+    Some(phys)
+}
+
+// (空いているフレームの数, 全てのフレームの数)
+pub fn stats() -> (usize, usize) {
+    let allocator = FRAME_ALLOCATOR.lock();
+    let total = allocator
+        .regions
+        .iter()
+        .map(|&(start, end)| (end - start) / FRAME_SIZE)
+        .sum();
+    (allocator.free_frames(), total)
+}
+
+pub fn free(phys: PhysAddr, pages: usize) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for i in 0..pages {
+        allocator
+            .free
+            .push(PhysAddr::new(phys.to_usize() + i * FRAME_SIZE));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn contiguous_frames_skip_short_regions() {
+        let mut allocator = FrameAllocator::new();
+        allocator.add_region(0x20_0000, 0x2000);
+        allocator.add_region(0x30_0800, 0x4000);
+        assert_eq!(allocator.allocate(), Some(PhysAddr::new(0x20_0000)));
+        // 最初の領域には1ページしか残っていない
+        assert_eq!(
+            allocator.allocate_contiguous(2),
+            Some(PhysAddr::new(0x30_1000))
+        );
+        assert_eq!(allocator.allocate_contiguous(2), None);
+        allocator.free.push(PhysAddr::new(0x20_0000));
+        assert_eq!(allocator.allocate(), Some(PhysAddr::new(0x20_0000)));
+    }
+
+    #[test_case]
+    fn allocated_frames_are_zeroed_and_distinct() {
+        let a = allocate_contiguous(2).expect("Out of frames");
+        let b = allocate().expect("Out of frames");
+        assert_eq!(a.to_usize() % FRAME_SIZE, 0);
+        assert!(b.to_usize() >= a.to_usize() + 2 * FRAME_SIZE || b < a);
+        let virt = memlayout::phys_to_virt(a).to_usize() as *const u8;
+        assert!((0..2 * FRAME_SIZE).all(|i| unsafe { *virt.add(i) } == 0));
+        free(a, 2);
+        free(b, 1);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::{
+    acpi::{self, AddressSpace},
+    memlayout::{Address, MSize, PhysAddr, VirtAddr},
+    paging,
+    spin::Once,
+    warn,
+};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// ACPIのHPETテーブルがない場合に使う、QEMUやほとんどのチップセットでの既定のアドレス
+const HPET_DEFAULT_BASE: PhysAddr = PhysAddr::new(0xFED0_0000);
+const HPET_MMIO_SIZE: MSize = MSize::new(0x400);
+
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+const CAP_NUM_TIMERS_SHIFT: u64 = 8;
+const CAP_NUM_TIMERS_MASK: u64 = 0x1F;
+// メインカウンタが64ビット. なければ32ビットで、14.318MHzなら5分ほどで一周する
+const CAP_COUNT_SIZE: u64 = 1 << 13;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+// コンパレータ (タイマN) のレジスタ
+const TIMER_CONFIG_BASE: usize = 0x100;
+const TIMER_COMPARATOR_BASE: usize = 0x108;
+const TIMER_FSB_ROUTE_BASE: usize = 0x110;
+const TIMER_STRIDE: usize = 0x20;
+
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_TYPE_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_VAL_SET: u64 = 1 << 6;
+const TIMER_INT_ROUTE_SHIFT: u64 = 9;
+const TIMER_INT_ROUTE_MASK: u64 = 0x1F << TIMER_INT_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_FSB_CAP: u64 = 1 << 15;
+const TIMER_INT_ROUTE_CAP_SHIFT: u64 = 32;
+
+// FSB (MSI) で送るメッセージのアドレス
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+// 仕様上、カウンタの周期は100ns以下でなければならない
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+// コンパレータの割り込みの届け先
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventRoute {
+    // FSB (MSI) で指定したLocal APICに直接届ける
+    Fsb { apic_id: u32, vector: u8 },
+    // I/O APICの入力ピン
+    IoApic(u8),
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventMode {
+    OneShot,
+    Periodic,
+}
+
+pub struct Hpet {
+    mmio: VirtAddr,
+    period_fs: u64,
+    // コンパレータに設定できる最小の間隔 (カウンタのtick)
+    minimum_tick: u64,
+    // メインカウンタが64ビットか
+    wide: bool,
+    // 32ビットのカウンタを64ビットに伸ばすため、最後に読んだ値を覚えておく
+    last_counter: AtomicU64,
+}
+
+impl Hpet {
+    fn probe(base: PhysAddr, minimum_tick: u64) -> Option<Self> {
+        // map_ioはPageTableAttr::ReadWriteKernelIO (キャッシュ無効) でマップする
+        let mmio = paging::map_io(base, HPET_MMIO_SIZE).ok()?;
+        let mut hpet = Hpet {
+            mmio,
+            period_fs: 0,
+            minimum_tick,
+            wide: false,
+            last_counter: AtomicU64::new(0),
+        };
+        let capabilities = hpet.read(GENERAL_CAPABILITIES);
+        if capabilities == u64::MAX {
+            return None;
+        }
+        hpet.period_fs = capabilities >> 32;
+        hpet.wide = capabilities & CAP_COUNT_SIZE != 0;
+        if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+            return None;
+        }
+        Some(hpet)
+    }
+
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { core::ptr::read_volatile((self.mmio.to_usize() + offset) as *const u64) }
+    }
+
+    fn write(&self, offset: usize, value: u64) {
+        unsafe { core::ptr::write_volatile((self.mmio.to_usize() + offset) as *mut u64, value) }
+    }
+
+    fn enable(&self) {
+        let config = self.read(GENERAL_CONFIGURATION);
+        self.write(GENERAL_CONFIGURATION, config | CONFIG_ENABLE);
+    }
+
+    // 32ビットのカウンタは一周するたびに上位を繰り上げる
+    // 一周する前に一度は読まれている必要がある
+    pub fn counter(&self) -> u64 {
+        let raw = self.read(MAIN_COUNTER);
+        if self.wide {
+            return raw;
+        }
+        let mut last = self.last_counter.load(Ordering::Acquire);
+        loop {
+            let value = extend_counter(last, raw as u32);
+            match self.last_counter.compare_exchange_weak(
+                last,
+                value,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return value,
+                // 他のCPUがより新しい値を読んでいれば、それを返して単調性を保つ
+                Err(current) if current >= value => return current,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    pub fn frequency_hz(&self) -> u64 {
+        FEMTOS_PER_SEC / self.period_fs
+    }
+
+    pub fn duration_to_ticks(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * FEMTOS_PER_NANO).div_ceil(self.period_fs as u128) as u64
+    }
+
+    // メインカウンタでdurationだけ待つ
+    pub fn busy_wait(&self, duration: Duration) {
+        let ticks = self.duration_to_ticks(duration);
+        let start = self.counter();
+        while self.counter().wrapping_sub(start) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn comparator_count(&self) -> usize {
+        ((self.read(GENERAL_CAPABILITIES) >> CAP_NUM_TIMERS_SHIFT) & CAP_NUM_TIMERS_MASK) as usize
+            + 1
+    }
+
+    fn timer_config(&self, index: usize) -> u64 {
+        self.read(TIMER_CONFIG_BASE + index * TIMER_STRIDE)
+    }
+
+    fn set_timer_config(&self, index: usize, value: u64) {
+        self.write(TIMER_CONFIG_BASE + index * TIMER_STRIDE, value);
+    }
+
+    fn set_comparator(&self, index: usize, value: u64) {
+        self.write(TIMER_COMPARATOR_BASE + index * TIMER_STRIDE, value);
+    }
+
+    #[allow(dead_code)]
+    pub fn supports_periodic(&self, index: usize) -> bool {
+        self.timer_config(index) & TIMER_PERIODIC_CAP != 0
+    }
+
+    pub fn supports_fsb(&self, index: usize) -> bool {
+        self.timer_config(index) & TIMER_FSB_CAP != 0
+    }
+
+    // コンパレータが接続できるI/O APICの入力ピンのビットマップ
+    #[allow(dead_code)]
+    pub fn ioapic_routes(&self, index: usize) -> u32 {
+        (self.timer_config(index) >> TIMER_INT_ROUTE_CAP_SHIFT) as u32
+    }
+
+    // コンパレータindexを、delay後 (周期的なら以後period毎) に割り込むように設定する
+    pub fn start_event(
+        &self,
+        index: usize,
+        mode: EventMode,
+        delay: Duration,
+        route: EventRoute,
+    ) -> Result<(), &'static str> {
+        if index >= self.comparator_count() {
+            return Err("No such HPET comparator");
+        }
+        let mut config = self.timer_config(index)
+            & !(TIMER_INT_ENABLE | TIMER_TYPE_PERIODIC | TIMER_INT_ROUTE_MASK | TIMER_FSB_ENABLE);
+        self.set_timer_config(index, config);
+
+        match route {
+            EventRoute::Fsb { apic_id, vector } => {
+                if config & TIMER_FSB_CAP == 0 {
+                    return Err("HPET comparator does not support FSB delivery");
+                }
+                let address = MSI_ADDRESS_BASE | ((apic_id as u64 & 0xFF) << 12);
+                self.write(
+                    TIMER_FSB_ROUTE_BASE + index * TIMER_STRIDE,
+                    (address << 32) | vector as u64,
+                );
+                config |= TIMER_FSB_ENABLE;
+            }
+            EventRoute::IoApic(pin) => {
+                if pin >= 32 || self.ioapic_routes(index) & (1 << pin) == 0 {
+                    return Err("HPET comparator cannot be routed to the I/O APIC pin");
+                }
+                config |= (pin as u64) << TIMER_INT_ROUTE_SHIFT;
+            }
+        }
+
+        let ticks = self.duration_to_ticks(delay).max(self.minimum_tick);
+        let deadline = self.counter().wrapping_add(ticks);
+        match mode {
+            EventMode::OneShot => {
+                self.set_timer_config(index, config);
+                self.set_comparator(index, deadline);
+            }
+            EventMode::Periodic => {
+                if config & TIMER_PERIODIC_CAP == 0 {
+                    return Err("HPET comparator does not support periodic mode");
+                }
+                // VAL_SETを立てた直後の書き込みが次の期限、その次の書き込みが周期になる
+                self.set_timer_config(index, config | TIMER_TYPE_PERIODIC | TIMER_VAL_SET);
+                self.set_comparator(index, deadline);
+                self.set_comparator(index, ticks);
+                config |= TIMER_TYPE_PERIODIC;
+            }
+        }
+        self.set_timer_config(index, config | TIMER_INT_ENABLE);
+        Ok(())
+    }
+
+    pub fn stop_event(&self, index: usize) {
+        let config = self.timer_config(index);
+        self.set_timer_config(index, config & !TIMER_INT_ENABLE);
+    }
+}
+
+// lastの後に読んだ32ビットのカウンタの値rawを64ビットに伸ばす
+fn extend_counter(last: u64, raw: u32) -> u64 {
+    let high = last & !(u32::MAX as u64);
+    if raw < last as u32 {
+        high + (1 << 32) + raw as u64
+    } else {
+        high + raw as u64
+    }
+}
+
+static HPET: Once<Option<Hpet>> = Once::new();
+
+// ACPIのHPETテーブルに記載されたアドレスを使う
+fn locate() -> (PhysAddr, u64) {
+    match acpi::hpet() {
+        Some(table) if table.base_address.space == AddressSpace::Memory => (
+            PhysAddr::new(table.base_address.address as usize),
+            table.minimum_tick as u64,
+        ),
+        _ => {
+            warn!("HPET table is not found, probing the default address");
+            (HPET_DEFAULT_BASE, 0)
+        }
+    }
+}
+
+pub fn init() -> Option<&'static Hpet> {
+    HPET.call_once(|| {
+        let (base, minimum_tick) = locate();
+        let hpet = Hpet::probe(base, minimum_tick)?;
+        hpet.enable();
+        Some(hpet)
+    })
+    .as_ref()
+}
+
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get().and_then(|hpet| hpet.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn busy_wait_advances_counter() {
+        let Some(hpet) = get() else {
+            return;
+        };
+        let start = hpet.counter();
+        hpet.busy_wait(Duration::from_millis(1));
+        let elapsed = hpet.counter() - start;
+        assert!(elapsed >= hpet.frequency_hz() / 1000);
+    }
+
+    #[test_case]
+    fn narrow_counter_extends_across_wraparound() {
+        assert_eq!(extend_counter(0, 5), 5);
+        assert_eq!(extend_counter(0xFFFF_FFF0, 0x10), 0x1_0000_0010);
+        assert_eq!(extend_counter(0x1_0000_0010, 0x20), 0x1_0000_0020);
+        assert_eq!(extend_counter(0x1_FFFF_FFFF, 0), 0x2_0000_0000);
+    }
+
+    #[test_case]
+    fn comparator_rejects_out_of_range_index() {
+        let Some(hpet) = get() else {
+            return;
+        };
+        let route = EventRoute::IoApic(2);
+        let index = hpet.comparator_count();
+        assert!(
+            hpet.start_event(index, EventMode::OneShot, Duration::ZERO, route)
+                .is_err()
+        );
+    }
+}
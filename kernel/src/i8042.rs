@@ -0,0 +1,130 @@
+use crate::{clock::Instant, x86};
+use core::time::Duration;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// 出力バッファのデータが2番目のポート (マウス) から来た
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT1_CLOCK_DISABLED: u8 = 1 << 4;
+// キーボードのスキャンコードセット2をセット1に変換する
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// コントローラへのコマンドの応答を待つ時間
+const TIMEOUT: Duration = Duration::from_millis(50);
+// 出力バッファに残っているデータを捨てる上限
+const FLUSH_LIMIT: usize = 16;
+
+fn wait_until(ready: impl Fn(u8) -> bool, timeout: Duration) -> Result<(), &'static str> {
+    let start = Instant::now();
+    loop {
+        let status = x86::read_io(STATUS_PORT);
+        // コントローラがなければ全ビットが立って見える
+        if status == 0xFF {
+            return Err("No i8042 controller");
+        }
+        if ready(status) {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err("i8042 timed out");
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn write_command(command: u8) -> Result<(), &'static str> {
+    wait_until(|status| status & STATUS_INPUT_FULL == 0, TIMEOUT)?;
+    x86::write_io(COMMAND_PORT, command);
+    Ok(())
+}
+
+// 最初のポートのデバイス (キーボード) に送る
+pub fn write_data(byte: u8) -> Result<(), &'static str> {
+    wait_until(|status| status & STATUS_INPUT_FULL == 0, TIMEOUT)?;
+    x86::write_io(DATA_PORT, byte);
+    Ok(())
+}
+
+pub fn read_data(timeout: Duration) -> Result<u8, &'static str> {
+    wait_until(|status| status & STATUS_OUTPUT_FULL != 0, timeout)?;
+    Ok(x86::read_io(DATA_PORT))
+}
+
+// 最初のポートからのデータがあれば読み出す. 2番目のポートからのデータは捨てる
+pub fn try_read_data() -> Option<u8> {
+    loop {
+        let status = x86::read_io(STATUS_PORT);
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let data = x86::read_io(DATA_PORT);
+        if status & STATUS_AUX_DATA == 0 {
+            return Some(data);
+        }
+    }
+}
+
+fn command_with_response(command: u8) -> Result<u8, &'static str> {
+    write_command(command)?;
+    read_data(TIMEOUT)
+}
+
+fn flush() {
+    for _ in 0..FLUSH_LIMIT {
+        if x86::read_io(STATUS_PORT) & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        x86::read_io(DATA_PORT);
+    }
+}
+
+// コントローラを初期化して最初のポートを有効にする. 割り込みは止めたまま
+// スキャンコードがセット1に変換されるならtrueを返す
+pub fn init() -> Result<bool, &'static str> {
+    write_command(CMD_DISABLE_PORT1)?;
+    write_command(CMD_DISABLE_PORT2)?;
+    flush();
+
+    let mut config = command_with_response(CMD_READ_CONFIG)?;
+    config &= !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ | CONFIG_PORT1_CLOCK_DISABLED);
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    if command_with_response(CMD_SELF_TEST)? != SELF_TEST_PASSED {
+        return Err("i8042 self test failed");
+    }
+    // セルフテストで設定が初期化されるコントローラがある
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    if command_with_response(CMD_TEST_PORT1)? != PORT_TEST_PASSED {
+        return Err("i8042 keyboard port test failed");
+    }
+    write_command(CMD_ENABLE_PORT1)?;
+    flush();
+    Ok(config & CONFIG_TRANSLATION != 0)
+}
+
+pub fn enable_port1_interrupt() -> Result<(), &'static str> {
+    let config = command_with_response(CMD_READ_CONFIG)?;
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config | CONFIG_PORT1_IRQ)
+}
@@ -1,10 +1,15 @@
-use crate::{error, gdt, task, timer, x86};
-use alloc::boxed::Box;
+use crate::{
+    apic, error, gdt, ipi, random,
+    spin::{Once, SpinLock},
+    syscall, task, timer, warn, x86,
+};
+use alloc::{boxed::Box, sync::Arc};
 use bitfield_struct::bitfield;
 use core::arch::{asm, global_asm, naked_asm};
 use core::fmt;
 use core::mem::size_of;
 use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -99,18 +104,99 @@ macro_rules! interrupt_entry_with_ecode {
     };
 }
 
+// 動的に割り当てるベクタの入口と、そのアドレスの表 (dynamic_interrupt_entries) を作る
+macro_rules! dynamic_interrupt_entries {
+    ($($index:literal),*) => {
+        $(interrupt_entry_without_ecode!($index);)*
+        global_asm!(concat!(
+            ".pushsection .rodata\n",
+            ".balign 8\n",
+            ".global dynamic_interrupt_entries\n",
+            "dynamic_interrupt_entries:\n",
+            $(".quad interrupt_entry_", stringify!($index), "\n",)*
+            ".popsection\n"
+        ));
+    };
+}
+
+interrupt_entry_without_ecode!(2);
 interrupt_entry_without_ecode!(3);
 interrupt_entry_without_ecode!(6);
 interrupt_entry_with_ecode!(13);
 interrupt_entry_with_ecode!(14);
 interrupt_entry_without_ecode!(42);
+interrupt_entry_without_ecode!(128);
+interrupt_entry_without_ecode!(252);
+interrupt_entry_without_ecode!(253);
+interrupt_entry_without_ecode!(254);
+interrupt_entry_without_ecode!(255);
+dynamic_interrupt_entries!(
+    80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95, 96, 97, 98, 99, 100, 101, 102,
+    103, 104, 105, 106, 107, 108, 109, 110, 111
+);
 
 unsafe extern "x86-interrupt" {
+    fn interrupt_entry_2();
     fn interrupt_entry_3();
     fn interrupt_entry_6();
     fn interrupt_entry_13();
     fn interrupt_entry_14();
     fn interrupt_entry_42();
+    fn interrupt_entry_128();
+    fn interrupt_entry_252();
+    fn interrupt_entry_253();
+    fn interrupt_entry_254();
+    fn interrupt_entry_255();
+}
+
+unsafe extern "C" {
+    static dynamic_interrupt_entries: [unsafe extern "x86-interrupt" fn(); DYNAMIC_VECTOR_COUNT];
+}
+
+// MSIなどのデバイスの割り込みに割り当てるベクタの範囲
+pub const DYNAMIC_VECTOR_START: u8 = 80;
+pub const DYNAMIC_VECTOR_COUNT: usize = 32;
+
+pub type InterruptHandler = Arc<dyn Fn() + Send + Sync>;
+
+static DYNAMIC_HANDLERS: SpinLock<[Option<InterruptHandler>; DYNAMIC_VECTOR_COUNT]> =
+    SpinLock::new([const { None }; DYNAMIC_VECTOR_COUNT]);
+
+fn dynamic_index(vector: u64) -> Option<usize> {
+    let index = vector.checked_sub(DYNAMIC_VECTOR_START as u64)? as usize;
+    (index < DYNAMIC_VECTOR_COUNT).then_some(index)
+}
+
+// 空いているベクタを割り当て、割り込み時にhandlerを呼ぶようにする
+pub fn allocate_vector(handler: InterruptHandler) -> Result<u8, &'static str> {
+    let mut handlers = DYNAMIC_HANDLERS.lock();
+    let index = handlers
+        .iter()
+        .position(|h| h.is_none())
+        .ok_or("No free interrupt vector")?;
+    handlers[index] = Some(handler);
+    Ok(DYNAMIC_VECTOR_START + index as u8)
+}
+
+pub fn free_vector(vector: u8) {
+    if let Some(index) = dynamic_index(vector as u64) {
+        DYNAMIC_HANDLERS.lock()[index] = None;
+    }
+}
+
+// ハンドラの実行中に同じベクタの割り当てが変わってもよいよう、ロックの外で呼び出す
+fn handle_dynamic(index: usize) {
+    let handler = DYNAMIC_HANDLERS.lock()[index].clone();
+    match handler {
+        Some(handler) => handler(),
+        None => {
+            warn!(
+                "Interrupt on unallocated vector {}",
+                DYNAMIC_VECTOR_START as usize + index
+            );
+        }
+    }
+    apic::end_of_interrupt();
 }
 
 #[allow(unused)]
@@ -118,6 +204,12 @@ unsafe extern "x86-interrupt" {
 #[unsafe(no_mangle)]
 unsafe fn interrupt_handler_common() {
     naked_asm!(
+        // ユーザモードからの割り込みであれば、GS_BASEをカーネルのCPUごとの領域に切り替える
+        // (この時点でスタックには vector, error_code, rip, cs, ... が積まれている)
+        "test qword ptr [rsp + 0x18], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
         // Save registers
         "push r15",
         "push r14",
@@ -141,6 +233,8 @@ unsafe fn interrupt_handler_common() {
         "push [rsp]",
         "and rsp, 0xfffffffffffffff0", // Align the stack pointer to 16 bytes
         "call interrupt_handler",
+        // レジスタを復元する前に呼び出し、割り込まれたコンテキストのレジスタを壊さないようにする
+        "call check_and_schedule",
         "mov rsp, [rsp + 8]", // Restore the original stack pointer
         // Restore registers
         "pop rax",
@@ -161,23 +255,40 @@ unsafe fn interrupt_handler_common() {
         "pop r15",
         // Return from the interrupt
         "add rsp, 0x10",
-        "call check_and_schedule",
+        "test qword ptr [rsp + 0x8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
         "iretq"
     );
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn check_and_schedule() {
-    let current = task::context();
-    if current.ticks.load(core::sync::atomic::Ordering::Relaxed) >= 3 {
+    if task::preemptible() && task::ticks() >= task::quantum_ticks() {
         task::switch();
     }
 }
 
+// ベクタごとの割り込みの回数 (全CPUの合計)
+static INTERRUPT_COUNTS: [AtomicU64; 0x100] = [const { AtomicU64::new(0) }; 0x100];
+
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
 #[unsafe(no_mangle)]
-extern "C" fn interrupt_handler(stack_frame: &InterruptStackFrame) {
+extern "C" fn interrupt_handler(stack_frame: &mut InterruptStackFrame) {
     //info!("Interrupt occurred: {:?}", stack_frame);
+    INTERRUPT_COUNTS[stack_frame.vector as usize % 0x100].fetch_add(1, Ordering::Relaxed);
+    random::add_interrupt_timing(stack_frame.vector);
+    if let Some(index) = dynamic_index(stack_frame.vector) {
+        handle_dynamic(index);
+        return;
+    }
     match stack_frame.vector {
+        // NMI (停止や再起動の前に、他のCPUから送られてくる)
+        2 if ipi::stopping() => ipi::handle_stop(),
         // Breakpoint exception
         3 => {
             error!("Breakpoint exception");
@@ -203,9 +314,35 @@ extern "C" fn interrupt_handler(stack_frame: &InterruptStackFrame) {
         // Local timer interrupt
         42 => {
             //info!("Local timer interrupt");
-            timer::increment_count();
-            timer::notify_end_of_interrupt();
-            task::tick();
+            timer::handle_interrupt();
+            return;
+        }
+        // System call
+        128 => {
+            let registers = &mut stack_frame.registers;
+            let from_user = stack_frame.context.cs & 3 == 3;
+            let args = [registers.rdi, registers.rsi, registers.rdx];
+            registers.rax = syscall::dispatch(registers.rax, args, from_user) as u64;
+            return;
+        }
+        // Call function IPI
+        252 => {
+            ipi::handle_call_function();
+            return;
+        }
+        // Reschedule IPI
+        253 => {
+            ipi::handle_reschedule();
+            return;
+        }
+        // Local APIC error interrupt
+        254 => {
+            warn!("Local APIC error: ESR={:#x}", apic::local().error_status());
+            apic::end_of_interrupt();
+            return;
+        }
+        // Local APIC spurious interrupt (EOIは不要)
+        255 => {
             return;
         }
         _ => {
@@ -272,7 +409,7 @@ const IDT_DPL_0: u8 = 0b00;
 const IDT_DPL_3: u8 = 0b11;
 const IDT_GATE_TYPE_INTGATE: u8 = 0b1110;
 
-#[repr(C, packed)]
+#[repr(C)]
 pub struct Idt {
     entries: Pin<Box<[IdtDescriptor; 0x100]>>,
 }
@@ -286,6 +423,13 @@ impl Idt {
             IDT_DPL_0,
             interrupt_handler_unimplemented,
         ); 0x100];
+        entries[2] = IdtDescriptor::create(
+            segment_selector,
+            1,
+            IDT_GATE_TYPE_INTGATE,
+            IDT_DPL_0,
+            interrupt_entry_2,
+        );
         entries[3] = IdtDescriptor::create(
             segment_selector,
             1,
@@ -321,10 +465,60 @@ impl Idt {
             IDT_DPL_0,
             interrupt_entry_42,
         );
-        let entries = Box::pin(entries);
+        // ユーザモードから呼び出せるようにする
+        entries[syscall::SYSCALL_VECTOR as usize] = IdtDescriptor::create(
+            segment_selector,
+            0,
+            IDT_GATE_TYPE_INTGATE,
+            IDT_DPL_3,
+            interrupt_entry_128,
+        );
+        entries[ipi::CALL_FUNCTION_VECTOR as usize] = IdtDescriptor::create(
+            segment_selector,
+            0,
+            IDT_GATE_TYPE_INTGATE,
+            IDT_DPL_0,
+            interrupt_entry_252,
+        );
+        entries[ipi::RESCHEDULE_VECTOR as usize] = IdtDescriptor::create(
+            segment_selector,
+            0,
+            IDT_GATE_TYPE_INTGATE,
+            IDT_DPL_0,
+            interrupt_entry_253,
+        );
+        entries[apic::ERROR_VECTOR as usize] = IdtDescriptor::create(
+            segment_selector,
+            0,
+            IDT_GATE_TYPE_INTGATE,
+            IDT_DPL_0,
+            interrupt_entry_254,
+        );
+        entries[apic::SPURIOUS_VECTOR as usize] = IdtDescriptor::create(
+            segment_selector,
+            0,
+            IDT_GATE_TYPE_INTGATE,
+            IDT_DPL_0,
+            interrupt_entry_255,
+        );
+        for (index, entry) in unsafe { dynamic_interrupt_entries }.iter().enumerate() {
+            entries[DYNAMIC_VECTOR_START as usize + index] = IdtDescriptor::create(
+                segment_selector,
+                0,
+                IDT_GATE_TYPE_INTGATE,
+                IDT_DPL_0,
+                *entry,
+            );
+        }
+        Self {
+            entries: Box::pin(entries),
+        }
+    }
+
+    fn load(&self) {
         let register = IdtRegister {
-            limit: (entries.len() * size_of::<IdtDescriptor>() - 1) as u16,
-            base: entries.as_ptr(),
+            limit: (self.entries.len() * size_of::<IdtDescriptor>() - 1) as u16,
+            base: self.entries.as_ptr(),
         };
         unsafe {
             asm!(
@@ -333,8 +527,6 @@ impl Idt {
                 options(nostack),
             );
         }
-        x86::enable_interrupts();
-        Self { entries }
     }
 }
 
@@ -346,6 +538,17 @@ struct IdtRegister {
 
 const _: () = assert!(size_of::<IdtRegister>() == 10);
 
-pub fn init_idt() -> Idt {
-    Idt::new(gdt::KERNEL_CODE_SEGMENT)
+// IDTは全CPUで共有する
+static IDT: Once<Idt> = Once::new();
+
+pub fn init_idt() -> &'static Idt {
+    let idt = IDT.call_once(|| Idt::new(gdt::KERNEL_CODE_SEGMENT));
+    idt.load();
+    x86::enable_interrupts();
+    idt
+}
+
+// APで、BSPが作成したIDTを読み込む
+pub fn load() {
+    IDT.get().expect("IDT is not initialized").load();
 }
@@ -0,0 +1,192 @@
+use crate::{
+    acpi, info,
+    memlayout::{Address, MSize, PhysAddr, VirtAddr},
+    paging,
+    spin::{Once, SpinLock},
+    warn, x86,
+};
+use alloc::vec::Vec;
+
+const IOAPIC_MMIO_SIZE: MSize = MSize::new(0x20);
+
+// IOREGSELにレジスタ番号を書き込んでから、IOWINで読み書きする
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_BASE: u32 = 0x10;
+
+// リダイレクションエントリ (配送モードは固定、宛先は物理APIC ID)
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DEST_SHIFT: u64 = 56;
+
+// MADTの割り込みオーバーライドのフラグ (00はバスの既定値)
+const MPS_POLARITY_MASK: u16 = 0b11;
+const MPS_POLARITY_ACTIVE_LOW: u16 = 0b11;
+const MPS_TRIGGER_MASK: u16 = 0b11 << 2;
+const MPS_TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+// I/O APICを使うので、8259 PICの割り込みは全てマスクする
+const PIC1_DATA: u16 = 0x21;
+const PIC2_DATA: u16 = 0xA1;
+
+struct IoApic {
+    id: u8,
+    gsi_base: u32,
+    entries: u32,
+    // IOREGSELとIOWINの組を他のCPUと同時に使わないようにする
+    mmio: SpinLock<VirtAddr>,
+}
+
+impl IoApic {
+    fn new(id: u8, base: PhysAddr, gsi_base: u32) -> Result<Self, &'static str> {
+        let mmio = paging::map_io(base, IOAPIC_MMIO_SIZE)?;
+        let mut io_apic = IoApic {
+            id,
+            gsi_base,
+            entries: 0,
+            mmio: SpinLock::new(mmio),
+        };
+        io_apic.entries = ((io_apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+        Ok(io_apic)
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        let mmio = self.mmio.lock();
+        unsafe {
+            core::ptr::write_volatile((mmio.to_usize() + IOREGSEL) as *mut u32, reg);
+            core::ptr::read_volatile((mmio.to_usize() + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        let mmio = self.mmio.lock();
+        unsafe {
+            core::ptr::write_volatile((mmio.to_usize() + IOREGSEL) as *mut u32, reg);
+            core::ptr::write_volatile((mmio.to_usize() + IOWIN) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    // 上位を先に書き、マスクを外すのは宛先が決まってからにする
+    fn write_redirection(&self, index: u32, entry: u64) {
+        let reg = REG_REDIRECTION_BASE + index * 2;
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+
+    fn read_redirection(&self, index: u32) -> u64 {
+        let reg = REG_REDIRECTION_BASE + index * 2;
+        ((self.read(reg + 1) as u64) << 32) | self.read(reg) as u64
+    }
+}
+
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+
+fn io_apic_for(gsi: u32) -> Result<&'static IoApic, &'static str> {
+    IO_APICS
+        .get()
+        .ok_or("I/O APIC is not initialized")?
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or("No I/O APIC handles the GSI")
+}
+
+// ISAのIRQがつながっているGSIとエントリのフラグ. オーバーライドがなければ同じ番号でエッジ、アクティブハイ
+fn isa_irq_to_gsi(irq: u8) -> (u32, u64) {
+    let Some(over) = acpi::madt().and_then(|madt| {
+        madt.interrupt_overrides
+            .iter()
+            .find(|over| over.bus == 0 && over.source == irq)
+    }) else {
+        return (irq as u32, 0);
+    };
+    let mut flags = 0;
+    if over.flags & MPS_POLARITY_MASK == MPS_POLARITY_ACTIVE_LOW {
+        flags |= REDIRECTION_ACTIVE_LOW;
+    }
+    if over.flags & MPS_TRIGGER_MASK == MPS_TRIGGER_LEVEL {
+        flags |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    (over.gsi, flags)
+}
+
+// ISAのIRQをapic_idのCPUのvectorに届ける
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u32) -> Result<(), &'static str> {
+    let (gsi, flags) = isa_irq_to_gsi(irq);
+    let io_apic = io_apic_for(gsi)?;
+    let entry = flags | vector as u64 | ((apic_id as u64) << REDIRECTION_DEST_SHIFT);
+    io_apic.write_redirection(gsi - io_apic.gsi_base, entry);
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub fn mask_isa_irq(irq: u8) -> Result<(), &'static str> {
+    let (gsi, _) = isa_irq_to_gsi(irq);
+    let io_apic = io_apic_for(gsi)?;
+    let index = gsi - io_apic.gsi_base;
+    io_apic.write_redirection(index, io_apic.read_redirection(index) | REDIRECTION_MASKED);
+    Ok(())
+}
+
+// acpi::initの後に呼び出す. 全てのエントリをマスクした状態で始める
+pub fn init() {
+    x86::write_io(PIC1_DATA, 0xFF);
+    x86::write_io(PIC2_DATA, 0xFF);
+
+    let Some(madt) = acpi::madt() else {
+        warn!("I/O APIC: no MADT, external interrupts are disabled");
+        return;
+    };
+    let io_apics = IO_APICS.call_once(|| {
+        madt.io_apics
+            .iter()
+            .filter_map(|entry| {
+                IoApic::new(
+                    entry.id,
+                    PhysAddr::new(entry.address as usize),
+                    entry.gsi_base,
+                )
+                .inspect_err(|e| {
+                    warn!("I/O APIC {}: {}", entry.id, e);
+                })
+                .ok()
+            })
+            .collect()
+    });
+    for io_apic in io_apics {
+        for index in 0..io_apic.entries {
+            io_apic.write_redirection(index, REDIRECTION_MASKED);
+        }
+        info!(
+            "I/O APIC {}: id register {}, GSI {}-{}",
+            io_apic.id,
+            io_apic.read(REG_ID) >> 24,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.entries - 1
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // QEMUのMADTではIRQ0 (PIT) がGSI2にオーバーライドされ、COM1のIRQ4はそのまま
+    #[test_case]
+    fn isa_irqs_follow_overrides() {
+        if acpi::madt().is_none() {
+            return;
+        }
+        assert_eq!(isa_irq_to_gsi(0).0, 2);
+        assert_eq!(isa_irq_to_gsi(4), (4, 0));
+        assert!(io_apic_for(4).is_ok());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{
+    apic::{self, IpiDest, IpiKind},
+    percpu, pit, smp,
+    spin::SpinLock,
+    task, timer, x86,
+};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+pub const CALL_FUNCTION_VECTOR: u8 = 0xFC;
+pub const RESCHEDULE_VECTOR: u8 = 0xFD;
+
+// 他のCPUが止まるのを待つ時間
+const STOP_TIMEOUT_MS: usize = 100;
+
+// 他のCPUに実行を依頼した関数. 対象の全CPUが実行し終えるとpendingが0になる
+struct CallRequest {
+    func: Box<dyn Fn() + Send + Sync>,
+    pending: AtomicUsize,
+}
+
+percpu! {
+    // このCPUで実行を待っている関数
+    static CALL_QUEUE: SpinLock<VecDeque<Arc<CallRequest>>> = SpinLock::new(VecDeque::new());
+}
+
+// 指定したCPUにベクタvectorの割り込みを送る
+pub fn send(cpu: usize, vector: u8) {
+    apic::local().send_ipi(IpiDest::Apic(smp::apic_id(cpu)), IpiKind::Fixed(vector));
+}
+
+// 自分以外の起動済みの全CPUに割り込みを送る
+// (起動に失敗したAPにも届かないよう、shorthandは使わずに1つずつ送る)
+#[allow(dead_code)]
+pub fn send_to_others(vector: u8) {
+    let this = smp::cpu_id();
+    for cpu in (0..smp::cpu_count()).filter(|&cpu| cpu != this) {
+        send(cpu, vector);
+    }
+}
+
+// このCPUのキューにある関数を全て実行する
+// 実行中に別のCPUへ移らないよう、割り込みを禁止した状態で処理する
+fn run_pending_calls() {
+    CALL_QUEUE.with(|queue| {
+        loop {
+            // 実行中は他のCPUが依頼を追加できるようロックを解放しておく
+            let Some(request) = queue.lock().pop_front() else {
+                break;
+            };
+            (request.func)();
+            request.pending.fetch_sub(1, Ordering::Release);
+        }
+    });
+}
+
+// 自分以外の全CPUでfuncを割り込みコンテキストから実行する
+// waitがtrueなら、全CPUが実行し終えるまで待つ
+//
+// 待っている間も自分宛ての依頼を処理するため、割り込み禁止中に呼び出しても
+// 互いに依頼を送り合ったCPU同士でデッドロックすることはない
+pub fn smp_call_function(func: impl Fn() + Send + Sync + 'static, wait: bool) {
+    // 対象のCPUを決めてから依頼を送り終えるまで、別のCPUに移らないようにする
+    task::preempt_disable();
+    let this = smp::cpu_id();
+    let targets = smp::cpu_count() - 1;
+    if targets == 0 {
+        task::preempt_enable();
+        return;
+    }
+    let request = Arc::new(CallRequest {
+        func: Box::new(func),
+        pending: AtomicUsize::new(targets),
+    });
+    for cpu in (0..smp::cpu_count()).filter(|&cpu| cpu != this) {
+        CALL_QUEUE.get_for(cpu).lock().push_back(request.clone());
+        send(cpu, CALL_FUNCTION_VECTOR);
+    }
+    if wait {
+        while request.pending.load(Ordering::Acquire) != 0 {
+            run_pending_calls();
+            core::hint::spin_loop();
+        }
+    }
+    task::preempt_enable();
+}
+
+// 指定したCPUにスケジューラを動かすよう依頼する (idle中のCPUを起こすのに使う)
+pub fn send_reschedule(cpu: usize) {
+    if cpu == smp::cpu_id() {
+        return;
+    }
+    send(cpu, RESCHEDULE_VECTOR);
+}
+
+pub fn handle_call_function() {
+    apic::end_of_interrupt();
+    run_pending_calls();
+}
+
+// ティックが止まっていれば再開し、割り込みからの復帰時にタスクを切り替えさせる
+pub fn handle_reschedule() {
+    apic::end_of_interrupt();
+    timer::start_scheduler_tick();
+    task::request_reschedule();
+}
+
+// 仮想アドレスの範囲のTLBエントリを全CPUで無効化し、完了を待つ
+// カーネル空間のマッピングは全タスクで共有しているため、どのページテーブルかは区別しない
+pub fn tlb_shootdown(virt_start: usize, num_pages: usize) {
+    // これより多いページはinvlpgを繰り返すより全体を無効化する方が速い
+    const FULL_FLUSH_THRESHOLD: usize = 32;
+    let flush = move || {
+        if num_pages > FULL_FLUSH_THRESHOLD {
+            x86::flush_tlb();
+        } else {
+            for i in 0..num_pages {
+                x86::invlpg(virt_start + i * 4096);
+            }
+        }
+    };
+    flush();
+    smp_call_function(flush, true);
+}
+
+// 停止や再起動の前に他のCPUを止めている間はtrue
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static STOPPED_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+// 自分以外の全CPUを止め、止まるまで少し待つ
+// 割り込み禁止中やロックを待っているCPUも止められるよう、固定のベクタではなくNMIを送る
+// percpuやtaskに頼らないので、パニックハンドラからも呼び出せる
+pub fn stop_other_cpus() {
+    let others = smp::cpu_count() - 1;
+    // 既に別のCPUが止めている
+    if others == 0 || STOPPING.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let apic = apic::local();
+    let this = apic.id();
+    for cpu in 0..smp::cpu_count() {
+        let apic_id = smp::apic_id(cpu);
+        if apic_id != this {
+            apic.send_ipi(IpiDest::Apic(apic_id), IpiKind::Nmi);
+        }
+    }
+    for _ in 0..STOP_TIMEOUT_MS {
+        if STOPPED_CPUS.load(Ordering::Acquire) >= others {
+            return;
+        }
+        pit::busy_wait(Duration::from_millis(1));
+    }
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::Acquire)
+}
+
+// stop_other_cpusが送ったNMIを受けたCPUで呼び出す
+pub fn handle_stop() -> ! {
+    x86::disable_interrupts();
+    STOPPED_CPUS.fetch_add(1, Ordering::Release);
+    loop {
+        x86::halt();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn call_function_runs_on_other_cpus() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let this = smp::cpu_id();
+        let counter = count.clone();
+        smp_call_function(
+            move || {
+                assert_ne!(smp::cpu_id(), this);
+                counter.fetch_add(1, Ordering::Relaxed);
+            },
+            true,
+        );
+        assert_eq!(count.load(Ordering::Relaxed), smp::cpu_count() - 1);
+    }
+}
@@ -0,0 +1,541 @@
+use crate::{
+    apic, i8042, idt, info, ioapic,
+    spin::{Once, SpinLock},
+    tty, warn,
+};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::time::Duration;
+
+// キーボードはISAのIRQ1
+const IRQ_KEYBOARD: u8 = 1;
+
+const KBD_CMD_RESET: u8 = 0xFF;
+const KBD_CMD_ENABLE_SCANNING: u8 = 0xF4;
+const KBD_ACK: u8 = 0xFA;
+const KBD_RESEND: u8 = 0xFE;
+const KBD_SELF_TEST_PASSED: u8 = 0xAA;
+const KBD_RETRIES: usize = 3;
+
+const KBD_RESPONSE_TIMEOUT: Duration = Duration::from_millis(50);
+// リセット後のセルフテストは実機では数百msかかる
+const KBD_RESET_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// 読み出されていないキーイベントの最大数
+const MAX_EVENTS: usize = 64;
+
+const PREFIX_EXTENDED: u8 = 0xE0;
+const PREFIX_PAUSE: u8 = 0xE1;
+// セット2のブレークコードの前置
+const PREFIX_RELEASE: u8 = 0xF0;
+// Pauseキーのシーケンスのうち、前置の後に続くバイト数
+const PAUSE_LEN_SET1: usize = 5;
+const PAUSE_LEN_SET2: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    // USレイアウトでシフトなしのときの文字
+    Char(u8),
+    Escape,
+    Backspace,
+    Tab,
+    Enter,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    F(u8),
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+    // このイベントを反映した後の修飾キーの状態
+    pub modifiers: Modifiers,
+}
+
+// シフトありの文字 (USレイアウト)
+const SHIFTED: [(u8, u8); 21] = [
+    (b'`', b'~'),
+    (b'1', b'!'),
+    (b'2', b'@'),
+    (b'3', b'#'),
+    (b'4', b'$'),
+    (b'5', b'%'),
+    (b'6', b'^'),
+    (b'7', b'&'),
+    (b'8', b'*'),
+    (b'9', b'('),
+    (b'0', b')'),
+    (b'-', b'_'),
+    (b'=', b'+'),
+    (b'[', b'{'),
+    (b']', b'}'),
+    (b'\\', b'|'),
+    (b';', b':'),
+    (b'\'', b'"'),
+    (b',', b'<'),
+    (b'.', b'>'),
+    (b'/', b'?'),
+];
+
+impl KeyEvent {
+    // TTYに渡すバイト列. 押したときだけ、文字やエスケープシーケンスになる
+    pub fn input_bytes(&self, out: &mut Vec<u8>) {
+        if !self.pressed {
+            return;
+        }
+        let modifiers = self.modifiers;
+        let sequence: &[u8] = match self.key {
+            Key::Char(c) => {
+                let mut c = if c.is_ascii_lowercase() {
+                    if modifiers.shift != modifiers.caps_lock {
+                        c.to_ascii_uppercase()
+                    } else {
+                        c
+                    }
+                } else if modifiers.shift {
+                    SHIFTED
+                        .iter()
+                        .find(|&&(base, _)| base == c)
+                        .map_or(c, |&(_, shifted)| shifted)
+                } else {
+                    c
+                };
+                if modifiers.ctrl && c.is_ascii_alphabetic() {
+                    c &= 0x1F;
+                }
+                // Altはメタキーとして、ESCを前に付ける
+                if modifiers.alt {
+                    out.push(0x1B);
+                }
+                out.push(c);
+                return;
+            }
+            Key::Escape => b"\x1b",
+            Key::Backspace => b"\x7f",
+            Key::Tab => b"\t",
+            Key::Enter => b"\r",
+            Key::Up => b"\x1b[A",
+            Key::Down => b"\x1b[B",
+            Key::Right => b"\x1b[C",
+            Key::Left => b"\x1b[D",
+            Key::Home => b"\x1b[H",
+            Key::End => b"\x1b[F",
+            Key::Insert => b"\x1b[2~",
+            Key::Delete => b"\x1b[3~",
+            Key::PageUp => b"\x1b[5~",
+            Key::PageDown => b"\x1b[6~",
+            _ => b"",
+        };
+        out.extend(sequence);
+    }
+}
+
+// セット1のスキャンコード (ブレークのビットを除く) からキーへ. テンキーはNumLockが有効なものとして扱う
+fn set1_key(code: u8, extended: bool) -> Option<Key> {
+    if extended {
+        return Some(match code {
+            0x1C => Key::Enter,
+            0x1D => Key::RightCtrl,
+            0x35 => Key::Char(b'/'),
+            0x38 => Key::RightAlt,
+            0x47 => Key::Home,
+            0x48 => Key::Up,
+            0x49 => Key::PageUp,
+            0x4B => Key::Left,
+            0x4D => Key::Right,
+            0x4F => Key::End,
+            0x50 => Key::Down,
+            0x51 => Key::PageDown,
+            0x52 => Key::Insert,
+            0x53 => Key::Delete,
+            _ => return None,
+        });
+    }
+    Some(match code {
+        0x01 => Key::Escape,
+        0x02..=0x0D => Key::Char(b"1234567890-="[code as usize - 0x02]),
+        0x0E => Key::Backspace,
+        0x0F => Key::Tab,
+        0x10..=0x1B => Key::Char(b"qwertyuiop[]"[code as usize - 0x10]),
+        0x1C => Key::Enter,
+        0x1D => Key::LeftCtrl,
+        0x1E..=0x29 => Key::Char(b"asdfghjkl;'`"[code as usize - 0x1E]),
+        0x2A => Key::LeftShift,
+        0x2B..=0x35 => Key::Char(b"\\zxcvbnm,./"[code as usize - 0x2B]),
+        0x36 => Key::RightShift,
+        0x37 => Key::Char(b'*'),
+        0x38 => Key::LeftAlt,
+        0x39 => Key::Char(b' '),
+        0x3A => Key::CapsLock,
+        0x3B..=0x44 => Key::F(code - 0x3B + 1),
+        0x45 => Key::NumLock,
+        0x46 => Key::ScrollLock,
+        0x47..=0x53 => Key::Char(b"789-456+1230."[code as usize - 0x47]),
+        0x57 => Key::F(11),
+        0x58 => Key::F(12),
+        _ => return None,
+    })
+}
+
+// セット2のメイクコードからセット1へ. i8042の変換と同じ対応で、拡張キーにもそのまま使える
+const SET2_TO_SET1: [(u8, u8); 84] = [
+    (0x01, 0x43),
+    (0x03, 0x3F),
+    (0x04, 0x3D),
+    (0x05, 0x3B),
+    (0x06, 0x3C),
+    (0x07, 0x58),
+    (0x09, 0x44),
+    (0x0A, 0x42),
+    (0x0B, 0x40),
+    (0x0C, 0x3E),
+    (0x0D, 0x0F),
+    (0x0E, 0x29),
+    (0x11, 0x38),
+    (0x12, 0x2A),
+    (0x14, 0x1D),
+    (0x15, 0x10),
+    (0x16, 0x02),
+    (0x1A, 0x2C),
+    (0x1B, 0x1F),
+    (0x1C, 0x1E),
+    (0x1D, 0x11),
+    (0x1E, 0x03),
+    (0x21, 0x2E),
+    (0x22, 0x2D),
+    (0x23, 0x20),
+    (0x24, 0x12),
+    (0x25, 0x05),
+    (0x26, 0x04),
+    (0x29, 0x39),
+    (0x2A, 0x2F),
+    (0x2B, 0x21),
+    (0x2C, 0x14),
+    (0x2D, 0x13),
+    (0x2E, 0x06),
+    (0x31, 0x31),
+    (0x32, 0x30),
+    (0x33, 0x23),
+    (0x34, 0x22),
+    (0x35, 0x15),
+    (0x36, 0x07),
+    (0x3A, 0x32),
+    (0x3B, 0x24),
+    (0x3C, 0x16),
+    (0x3D, 0x08),
+    (0x3E, 0x09),
+    (0x41, 0x33),
+    (0x42, 0x25),
+    (0x43, 0x17),
+    (0x44, 0x18),
+    (0x45, 0x0B),
+    (0x46, 0x0A),
+    (0x49, 0x34),
+    (0x4A, 0x35),
+    (0x4B, 0x26),
+    (0x4C, 0x27),
+    (0x4D, 0x19),
+    (0x4E, 0x0C),
+    (0x52, 0x28),
+    (0x54, 0x1A),
+    (0x55, 0x0D),
+    (0x58, 0x3A),
+    (0x59, 0x36),
+    (0x5A, 0x1C),
+    (0x5B, 0x1B),
+    (0x5D, 0x2B),
+    (0x66, 0x0E),
+    (0x69, 0x4F),
+    (0x6B, 0x4B),
+    (0x6C, 0x47),
+    (0x70, 0x52),
+    (0x71, 0x53),
+    (0x72, 0x50),
+    (0x73, 0x4C),
+    (0x74, 0x4D),
+    (0x75, 0x48),
+    (0x76, 0x01),
+    (0x77, 0x45),
+    (0x78, 0x57),
+    (0x79, 0x4E),
+    (0x7A, 0x51),
+    (0x7B, 0x4A),
+    (0x7C, 0x37),
+    (0x7D, 0x49),
+    (0x7E, 0x46),
+];
+
+// F7だけはセット2で0x80以上になる
+const SET2_F7: u8 = 0x83;
+const SET1_F7: u8 = 0x41;
+
+fn set2_to_set1(code: u8) -> Option<u8> {
+    if code == SET2_F7 {
+        return Some(SET1_F7);
+    }
+    SET2_TO_SET1
+        .iter()
+        .find(|&&(set2, _)| set2 == code)
+        .map(|&(_, set1)| set1)
+}
+
+// スキャンコードを1バイトずつ受け取り、キーイベントにする
+struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    released: bool,
+    // Pauseキーのシーケンスの残り (キーイベントにはしない)
+    skip: usize,
+    modifiers: Modifiers,
+}
+
+impl Decoder {
+    const fn new(set: ScancodeSet) -> Self {
+        Decoder {
+            set,
+            extended: false,
+            released: false,
+            skip: 0,
+            modifiers: Modifiers {
+                shift: false,
+                ctrl: false,
+                alt: false,
+                caps_lock: false,
+            },
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        let (code, released) = match (self.set, byte) {
+            // コマンドへの応答
+            (_, 0x00 | KBD_ACK | KBD_RESEND | 0xFF) => return None,
+            (_, PREFIX_EXTENDED) => {
+                self.extended = true;
+                return None;
+            }
+            (ScancodeSet::Set1, PREFIX_PAUSE) => {
+                self.skip = PAUSE_LEN_SET1;
+                return None;
+            }
+            (ScancodeSet::Set1, _) => (byte & 0x7F, byte & 0x80 != 0),
+            (ScancodeSet::Set2, PREFIX_PAUSE) => {
+                self.skip = PAUSE_LEN_SET2;
+                return None;
+            }
+            (ScancodeSet::Set2, KBD_SELF_TEST_PASSED) => return None,
+            (ScancodeSet::Set2, PREFIX_RELEASE) => {
+                self.released = true;
+                return None;
+            }
+            (ScancodeSet::Set2, _) => {
+                let released = core::mem::take(&mut self.released);
+                (set2_to_set1(byte).unwrap_or(0), released)
+            }
+        };
+        let extended = core::mem::take(&mut self.extended);
+        let key = set1_key(code, extended)?;
+        self.update_modifiers(key, !released);
+        Some(KeyEvent {
+            key,
+            pressed: !released,
+            modifiers: self.modifiers,
+        })
+    }
+
+    fn update_modifiers(&mut self, key: Key, pressed: bool) {
+        match key {
+            Key::LeftShift | Key::RightShift => self.modifiers.shift = pressed,
+            Key::LeftCtrl | Key::RightCtrl => self.modifiers.ctrl = pressed,
+            Key::LeftAlt | Key::RightAlt => self.modifiers.alt = pressed,
+            Key::CapsLock if pressed => self.modifiers.caps_lock = !self.modifiers.caps_lock,
+            _ => {}
+        }
+    }
+}
+
+struct Keyboard {
+    decoder: SpinLock<Decoder>,
+    events: SpinLock<VecDeque<KeyEvent>>,
+}
+
+static KEYBOARD: Once<Keyboard> = Once::new();
+
+impl Keyboard {
+    fn handle_interrupt(&self) {
+        let mut input = Vec::new();
+        {
+            let mut decoder = self.decoder.lock();
+            let mut events = self.events.lock();
+            while let Some(byte) = i8042::try_read_data() {
+                let Some(event) = decoder.feed(byte) else {
+                    continue;
+                };
+                event.input_bytes(&mut input);
+                // 溢れた分は古いものから捨てる
+                if events.len() == MAX_EVENTS {
+                    events.pop_front();
+                }
+                events.push_back(event);
+            }
+        }
+        // エコーでシリアルに書き込むので、ロックの外で渡す
+        if !input.is_empty() {
+            tty::console().input(&input);
+        }
+    }
+}
+
+// 読み出されていないキーイベントを1つ取り出す
+#[allow(dead_code)]
+pub fn next_event() -> Option<KeyEvent> {
+    KEYBOARD.get()?.events.lock().pop_front()
+}
+
+// キーボードにコマンドを送り、ACKを待つ
+fn send(command: u8) -> Result<(), &'static str> {
+    for _ in 0..KBD_RETRIES {
+        i8042::write_data(command)?;
+        match i8042::read_data(KBD_RESPONSE_TIMEOUT)? {
+            KBD_ACK => return Ok(()),
+            KBD_RESEND => continue,
+            _ => return Err("Unexpected response from keyboard"),
+        }
+    }
+    Err("Keyboard did not acknowledge")
+}
+
+fn try_init() -> Result<ScancodeSet, &'static str> {
+    let translated = i8042::init()?;
+    send(KBD_CMD_RESET)?;
+    if i8042::read_data(KBD_RESET_TIMEOUT)? != KBD_SELF_TEST_PASSED {
+        return Err("Keyboard self test failed");
+    }
+    send(KBD_CMD_ENABLE_SCANNING)?;
+
+    // リセット後のキーボードはセット2を送ってくる
+    let set = if translated {
+        ScancodeSet::Set1
+    } else {
+        ScancodeSet::Set2
+    };
+    let keyboard = KEYBOARD.call_once(|| Keyboard {
+        decoder: SpinLock::new(Decoder::new(set)),
+        events: SpinLock::new(VecDeque::new()),
+    });
+    let vector = idt::allocate_vector(Arc::new(|| keyboard.handle_interrupt()))?;
+    if let Err(e) = ioapic::route_isa_irq(IRQ_KEYBOARD, vector, apic::local().id()) {
+        idt::free_vector(vector);
+        return Err(e);
+    }
+    i8042::enable_port1_interrupt()?;
+    Ok(set)
+}
+
+// tty::initとclock::initの後に呼び出す. コントローラやキーボードがなければ何もしない
+pub fn init() {
+    match try_init() {
+        Ok(set) => {
+            info!("keyboard: PS/2 keyboard enabled ({:?})", set);
+        }
+        Err(e) => {
+            warn!("keyboard: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn type_keys(decoder: &mut Decoder, scancodes: &[u8]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for &byte in scancodes {
+            if let Some(event) = decoder.feed(byte) {
+                event.input_bytes(&mut input);
+            }
+        }
+        input
+    }
+
+    #[test_case]
+    fn set1_translates_with_modifiers() {
+        let mut decoder = Decoder::new(ScancodeSet::Set1);
+        // h, Shift+i, Shift+1, Enter
+        let input = type_keys(
+            &mut decoder,
+            &[0x23, 0xA3, 0x2A, 0x17, 0x97, 0x02, 0x82, 0xAA, 0x1C, 0x9C],
+        );
+        assert_eq!(input, b"hI!\r");
+
+        // Ctrl+C, 上矢印, CapsLock+a
+        let input = type_keys(
+            &mut decoder,
+            &[
+                0x1D, 0x2E, 0xAE, 0x9D, 0xE0, 0x48, 0xE0, 0xC8, 0x3A, 0xBA, 0x1E,
+            ],
+        );
+        assert_eq!(input, b"\x03\x1b[AA");
+        assert!(decoder.modifiers.caps_lock);
+        assert!(!decoder.modifiers.ctrl);
+    }
+
+    #[test_case]
+    fn set2_translates_with_modifiers() {
+        let mut decoder = Decoder::new(ScancodeSet::Set2);
+        // Shift+a, F0でのリリース, 右Ctrl+c, Delete, F7
+        let input = type_keys(
+            &mut decoder,
+            &[
+                0x12, 0x1C, 0xF0, 0x1C, 0xF0, 0x12, 0x1C, 0xE0, 0x14, 0x21, 0xE0, 0xF0, 0x14, 0xE0,
+                0x71, 0x83,
+            ],
+        );
+        assert_eq!(input, b"Aa\x03\x1b[3~");
+        assert_eq!(decoder.feed(0xF0), None);
+        assert_eq!(
+            decoder.feed(0x83).map(|event| (event.key, event.pressed)),
+            Some((Key::F(7), false))
+        );
+        // Pauseはイベントにならない
+        let input = type_keys(
+            &mut decoder,
+            &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77, 0x1C],
+        );
+        assert_eq!(input, b"a");
+    }
+}
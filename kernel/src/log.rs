@@ -1,5 +1,13 @@
-use crate::uart::Uart;
+use crate::{
+    clock, fbcon,
+    spin::Once,
+    time::{self, SystemTime},
+    uart::{self, Uart},
+    virtio_console::{self, Port},
+};
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 
 pub const COLOR_RESET: &str = "\x1b[0m";
 pub const COLOR_CYAN: &str = "\x1b[36m";
@@ -7,12 +15,148 @@ pub const COLOR_CYAN: &str = "\x1b[36m";
 pub const COLOR_YELLOW: &str = "\x1b[33m";
 pub const COLOR_RED: &str = "\x1b[31m";
 
+// ログの時刻を壁時計で表示するか. ビルド時の環境変数KERNEL_LOG_WALL_CLOCKがあれば既定で有効
+static WALL_CLOCK: AtomicBool = AtomicBool::new(option_env!("KERNEL_LOG_WALL_CLOCK").is_some());
+
+#[allow(dead_code)]
+pub fn set_wall_clock(enabled: bool) {
+    WALL_CLOCK.store(enabled, Ordering::Relaxed);
+}
+
+pub fn wall_clock() -> bool {
+    WALL_CLOCK.load(Ordering::Relaxed)
+}
+
+// 起動からの経過時間 `[    1.234567]`
+// 壁時計を選んでいて、time::initの後であれば `[2026-10-18T12:34:56.123456Z]`
+pub enum Timestamp {
+    Uptime(Duration),
+    WallClock(SystemTime),
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        if wall_clock() && time::is_initialized() {
+            Timestamp::WallClock(SystemTime::now())
+        } else {
+            Timestamp::Uptime(clock::Instant::now().since_boot())
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timestamp::Uptime(uptime) => {
+                write!(f, "[{:>5}.{:06}]", uptime.as_secs(), uptime.subsec_micros())
+            }
+            Timestamp::WallClock(time) => {
+                let datetime = time.to_datetime();
+                write!(
+                    f,
+                    "[{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z]",
+                    datetime.year,
+                    datetime.month,
+                    datetime.day,
+                    datetime.hour,
+                    datetime.minute,
+                    datetime.second,
+                    time.since_epoch().subsec_micros()
+                )
+            }
+        }
+    }
+}
+
 // TODO: FIXME
 // ログ出力関数が呼ばれる度にserialを初期化しているのが問題ないか確認する
 #[allow(dead_code)]
 pub fn print(args: core::fmt::Arguments) {
+    print_serial(args);
+    fbcon::print(args);
+}
+
+// COM1に出力する. 割り込みが有効になった後は送信をリングバッファに任せ、UARTを待たない
+struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        uart::com1().write(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn print_serial(args: core::fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut SerialWriter, args);
+}
+
+// ログ用のポート (QEMU_OPTSのvirtserialport)
+const LOG_PORT_NAME: &str = "kernel.log";
+// ポートに送る前にまとめるバッファの大きさ
+const LOG_BUFFER_SIZE: usize = 256;
+
+static LOG_PORT: Once<&'static Port> = Once::new();
+
+// ヒープを使わずにスタック上のバッファにまとめ、一杯になったらポートに送る
+struct PortWriter {
+    port: &'static Port,
+    buffer: [u8; LOG_BUFFER_SIZE],
+    len: usize,
+}
+
+impl PortWriter {
+    fn flush(&mut self) {
+        if self.len > 0 {
+            let _ = self.port.write(&self.buffer[..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl fmt::Write for PortWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == LOG_BUFFER_SIZE {
+                self.flush();
+            }
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+// ログ用のポートがホストにつながっていればそちらに、なければシリアルに出力する
+// フレームバッファのコンソールがあれば常にそちらにも出力する
+pub fn log(args: core::fmt::Arguments) {
+    if let Some(&port) = LOG_PORT.get().filter(|port| port.is_connected()) {
+        let mut writer = PortWriter {
+            port,
+            buffer: [0; LOG_BUFFER_SIZE],
+            len: 0,
+        };
+        let _ = fmt::Write::write_fmt(&mut writer, args);
+        writer.flush();
+    } else {
+        print_serial(args);
+    }
+    fbcon::print(args);
+}
+
+// エラーとパニックはヒープやロックに頼らず、常にシリアルに出力する
+pub fn log_error(args: core::fmt::Arguments) {
+    // 先に出したログがリングバッファに残っていれば、順序を保つため先に送る
+    uart::com1().try_flush();
     let mut serial = Uart::default();
-    fmt::Write::write_fmt(&mut serial, args).unwrap();
+    let _ = fmt::Write::write_fmt(&mut serial, args);
+    fbcon::try_print(args);
+}
+
+// pci::initの後に呼び出す. ログ用のポートを探しておく
+pub fn init() {
+    if let Some(port) = virtio_console::port(LOG_PORT_NAME) {
+        LOG_PORT.call_once(|| port);
+    }
 }
 
 #[macro_export]
@@ -35,20 +179,37 @@ macro_rules! println {
 #[macro_export]
 macro_rules! warn {
     ($($arg:tt)*) => {
-        $crate::log::print(format_args!("{}[WARN]{} {}:{:<3}: {}\n", $crate::log::COLOR_YELLOW, crate::log::COLOR_RESET, file!(), line!(), format_args!($($arg)*)));
+        $crate::log::log(format_args!("{}[WARN]{} {} {}:{:<3}: {}\n", $crate::log::COLOR_YELLOW, crate::log::COLOR_RESET, $crate::log::Timestamp::now(), file!(), line!(), format_args!($($arg)*)));
     };
 }
 
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => {
-        $crate::log::print(format_args!("{}[ERROR]{} {}:{:<3}: {}\n", $crate::log::COLOR_RED, crate::log::COLOR_RESET, file!(), line!(), format_args!($($arg)*)));
+        $crate::log::log_error(format_args!("{}[ERROR]{} {} {}:{:<3}: {}\n", $crate::log::COLOR_RED, crate::log::COLOR_RESET, $crate::log::Timestamp::now(), file!(), line!(), format_args!($($arg)*)));
     };
 }
 
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => {
-        $crate::log::print(format_args!("{}[INFO]{} {}:{:<3}: {}\n", $crate::log::COLOR_CYAN, crate::log::COLOR_RESET, file!(), line!(), format_args!($($arg)*)));
+        $crate::log::log(format_args!("{}[INFO]{} {} {}:{:<3}: {}\n", $crate::log::COLOR_CYAN, crate::log::COLOR_RESET, $crate::log::Timestamp::now(), file!(), line!(), format_args!($($arg)*)));
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn timestamp_formats() {
+        let uptime = Timestamp::Uptime(Duration::from_micros(1_234_567));
+        assert_eq!(format!("{}", uptime), "[    1.234567]");
+        let time = time::UNIX_EPOCH + Duration::from_micros(1_709_208_000_000_042);
+        assert_eq!(
+            format!("{}", Timestamp::WallClock(time)),
+            "[2024-02-29T12:00:00.000042Z]"
+        );
+    }
+}
@@ -8,18 +8,53 @@
 
 extern crate alloc;
 
+mod acpi;
 mod allocator;
+mod apic;
+mod block;
+mod bootinfo;
+mod clock;
+mod dhcp;
+mod dns;
+mod fbcon;
+mod font;
+mod frame;
 mod gdt;
+mod hpet;
+mod i8042;
 mod idt;
+mod ioapic;
+mod ipi;
+mod keyboard;
 mod log;
 mod memlayout;
 mod memory;
+mod msi;
+mod net;
 mod paging;
+mod pci;
+mod pcid;
+mod percpu;
+mod pit;
+mod power;
 mod qemu;
+mod random;
+mod rtc;
+mod shell;
+mod smp;
 mod spin;
+mod syscall;
 mod task;
+mod tcp;
+mod time;
 mod timer;
+mod tty;
 mod uart;
+mod virtio;
+mod virtio_blk;
+mod virtio_console;
+mod virtio_net;
+mod virtio_rng;
 mod wasm;
 mod x86;
 
@@ -30,21 +65,16 @@ use core::arch::asm;
 #[allow(unused_imports)]
 use core::panic::PanicInfo;
 
-// FIXME: modify to pass BOOT_INFO as a unified structure to the kernel
 #[unsafe(no_mangle)]
-pub extern "C" fn kernel_entry(stack_base: u64, heap_base: u64, heap_size: u64,  memory_region: &memory::MemoryRegionArray) -> ! {
+pub extern "C" fn kernel_entry(stack_base: u64, boot_info: &bootinfo::BootInfo) -> ! {
     loop {
         unsafe {
             asm!(
                 "mov rsp, {0}",
                 "mov rdi, {1}",
-                "mov rsi, {2}",
-				"mov rdx, {3}",
                 "call kernel_main",
                 in(reg) stack_base,
-                in(reg) heap_base,
-                in(reg) heap_size,
-				in(reg) memory_region,
+                in(reg) boot_info,
             );
         }
     }
@@ -65,7 +95,8 @@ fn task_b() {
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn kernel_main(heap_base: u64, heap_size: u64, _: &memory::MemoryRegionArray) -> ! {
+extern "C" fn kernel_main(boot_info: &bootinfo::BootInfo) -> ! {
+    let boot_info = bootinfo::init(boot_info);
     uart::Uart::default().init();
 
     print!(
@@ -81,24 +112,68 @@ extern "C" fn kernel_main(heap_base: u64, heap_size: u64, _: &memory::MemoryRegi
     );
     info!("Kernel started!");
 
-    allocator::init_allocator(heap_base as usize, heap_size as usize);
+    allocator::init_allocator(boot_info.heap_base as usize, boot_info.heap_size as usize);
     info!("Allocator initialized!");
 
+    frame::init(boot_info);
+
     let pt = paging::init_paging();
+    pcid::init(true);
+    paging::init_pat();
     info!("Paging initialized!");
 
+    fbcon::init(&boot_info.framebuffer);
+
     let _gdt = gdt::init_gdt();
-    let _idt = idt::init_idt();
+    idt::init_idt();
     info!("GDT and IDT initialized!");
 
+    // セグメントレジスタの再読み込みでGS_BASEが消えるため、GDTの後に設定する
+    percpu::init(0);
+
+    match acpi::init(memlayout::PhysAddr::new(boot_info.rsdp as usize)) {
+        Ok(tables) => {
+            info!("ACPI tables parsed! (revision {})", tables.revision);
+        }
+        Err(e) => {
+            warn!("ACPI is not available: {}", e);
+        }
+    }
+
+    apic::init();
+    info!("Local APIC initialized!");
+
+    ioapic::init();
+    tty::init();
+
+    clock::init();
+    info!("Clock initialized!");
+
+    time::init();
+    random::init();
+    keyboard::init();
+
+    virtio_blk::init();
+    virtio_console::init();
+    virtio_net::init();
+    virtio_rng::init();
+    pci::init();
+    log::init();
+    info!("PCI devices enumerated! ({} found)", pci::devices().len());
+
     timer::init_timer();
     info!("Timer initialized!");
 
     x86::disable_interrupts();
     task::init(pt);
+    smp::init();
     task::spawn(task_a);
     task::spawn(task_b);
     task::spawn(wasm::wasm_entry);
+    net::init();
+    // テスト中はシェルのタスクがシリアルの入力を読まないようにする
+    #[cfg(not(test))]
+    shell::init();
     x86::enable_interrupts();
 
     #[cfg(test)]
@@ -132,6 +207,8 @@ mod symbol_offsets {
         __rodata_end,
         __data,
         __data_end,
+        __percpu,
+        __percpu_end,
         __bss,
         __bss_end
     );
@@ -143,7 +220,5 @@ fn panic(panic_info: &PanicInfo) -> ! {
     unsafe { asm!("cli") }
     error!("!!!!! Kernel panic !!!!!");
     error!("Panic info: {:?}", panic_info);
-    loop {
-        unsafe { asm!("hlt") }
-    }
+    power::on_panic()
 }
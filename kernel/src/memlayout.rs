@@ -183,5 +183,8 @@ pub fn virt_to_phys(virt: VirtAddr) -> PhysAddr {
     }
 }
 
-pub const APIC_IO_START_ADDR: usize = 0xFEE0_0000;
-pub const APIC_IO_SIZE: MSize = MSize::new(0x1000);
+// Virtual address window for memory-mapped I/O (Local APIC, HPET, PCI BARs...).
+// The whole window shares a single PML4 entry that is allocated in
+// `init_paging`, so mappings added later are visible to every task.
+pub const IO_MAPPING_BASE_VADDR: VirtAddr = VirtAddr::new(0xFFFF_C900_0000_0000);
+pub const IO_MAPPING_SIZE: MSize = MSize::new(0x80_0000_0000);
@@ -1,9 +1,11 @@
+#[repr(u8)]
 #[derive(Clone, Copy, Debug)]
 pub enum MemoryRegionType {
     Reserved,
     Usable,
 }
 
+#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct MemoryRegion {
     base: usize,
@@ -40,8 +42,16 @@ impl MemoryRegion {
 
 const MAX_MEMORY_REGION_LEN: usize = 128;
 
+#[repr(C)]
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct MemoryRegionArray {
     pub regions: [MemoryRegion; MAX_MEMORY_REGION_LEN],
     count: usize,
 }
+
+impl MemoryRegionArray {
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions[..self.count.min(MAX_MEMORY_REGION_LEN)].iter()
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{
+    idt::{self, InterruptHandler},
+    memlayout::{Address, MSize, PhysAddr, VirtAddr},
+    paging,
+    pci::{self, Bar, PciDevice},
+    smp,
+};
+
+// メッセージアドレス. bit 12-19が届け先のLocal APIC ID (物理宛先モード)
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+const MSI_ADDRESS_DEST_SHIFT: u32 = 12;
+const MSI_MAX_APIC_ID: u32 = 0xFF;
+
+// MSI capabilityのレジスタ (capabilityの先頭からのオフセット)
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS_LOW: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+const MSI_DATA_32: u16 = 0x08;
+const MSI_DATA_64: u16 = 0x0C;
+const MSI_MASK_32: u16 = 0x0C;
+const MSI_MASK_64: u16 = 0x10;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_ENABLE_MASK: u16 = 0b111 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+// MSI-X capabilityのレジスタ
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+
+const MSIX_CONTROL_TABLE_SIZE_MASK: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_BIR_MASK: u32 = 0b111;
+
+// MSI-Xテーブルのエントリ (16バイト)
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS_LOW: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_VECTOR_CONTROL: usize = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+// cpuのLocal APICにvectorを届けるメッセージのアドレスとデータ (エッジトリガ、固定配送)
+fn message(cpu: usize, vector: u8) -> Result<(u32, u32), &'static str> {
+    let apic_id = smp::apic_id(cpu);
+    // 割り込みリマッピングなしでは、8bitのAPIC IDしか指定できない
+    if apic_id > MSI_MAX_APIC_ID {
+        return Err("APIC ID is not addressable by MSI");
+    }
+    Ok((
+        MSI_ADDRESS_BASE | apic_id << MSI_ADDRESS_DEST_SHIFT,
+        vector as u32,
+    ))
+}
+
+// ベクタを割り当て、失敗したらベクタを解放する
+fn with_vector<T>(
+    handler: InterruptHandler,
+    f: impl FnOnce(u8) -> Result<T, &'static str>,
+) -> Result<T, &'static str> {
+    let vector = idt::allocate_vector(handler)?;
+    f(vector).inspect_err(|_| idt::free_vector(vector))
+}
+
+// MSIを有効にし、cpuでhandlerが呼ばれるようにする. 割り当てたベクタを返す
+// (複数メッセージは使わず、常に1つのベクタを使う)
+#[allow(dead_code)]
+pub fn enable_msi(
+    device: &PciDevice,
+    cpu: usize,
+    handler: InterruptHandler,
+) -> Result<u8, &'static str> {
+    let cap = device
+        .capability(pci::CAP_ID_MSI)
+        .ok_or("Device does not support MSI")?
+        .offset;
+    with_vector(handler, |vector| {
+        let (address, data) = message(cpu, vector)?;
+        let control = device.read_u16(cap + MSI_CONTROL)
+            & !(MSI_CONTROL_ENABLE | MSI_CONTROL_MULTIPLE_ENABLE_MASK);
+        device.write_u16(cap + MSI_CONTROL, control);
+        device.write_u32(cap + MSI_ADDRESS_LOW, address);
+        if control & MSI_CONTROL_64BIT != 0 {
+            device.write_u32(cap + MSI_ADDRESS_HIGH, 0);
+            device.write_u16(cap + MSI_DATA_64, data as u16);
+        } else {
+            device.write_u16(cap + MSI_DATA_32, data as u16);
+        }
+        if control & MSI_CONTROL_PER_VECTOR_MASK != 0 {
+            let mask = if control & MSI_CONTROL_64BIT != 0 {
+                MSI_MASK_64
+            } else {
+                MSI_MASK_32
+            };
+            device.write_u32(cap + mask, 0);
+        }
+        // MSIのメッセージはデバイスからのメモリ書き込みなのでバスマスタも有効にする
+        device.enable();
+        device.disable_intx();
+        device.write_u16(cap + MSI_CONTROL, control | MSI_CONTROL_ENABLE);
+        Ok(vector)
+    })
+}
+
+#[allow(dead_code)]
+pub fn disable_msi(device: &PciDevice) {
+    if let Some(cap) = device.capability(pci::CAP_ID_MSI) {
+        let control = device.read_u16(cap.offset + MSI_CONTROL);
+        device.write_u16(cap.offset + MSI_CONTROL, control & !MSI_CONTROL_ENABLE);
+    }
+}
+
+// MSI-Xのテーブル. エントリ毎に別のベクタ・CPUに割り込みを届けられる
+#[allow(dead_code)]
+pub struct MsiX {
+    device: &'static PciDevice,
+    cap: u16,
+    table: VirtAddr,
+    table_size: usize,
+}
+
+#[allow(dead_code)]
+impl MsiX {
+    // テーブルをマップし、全エントリをマスクした状態でMSI-Xを有効にする
+    pub fn new(device: &'static PciDevice) -> Result<Self, &'static str> {
+        let cap = device
+            .capability(pci::CAP_ID_MSIX)
+            .ok_or("Device does not support MSI-X")?
+            .offset;
+        let control = device.read_u16(cap + MSIX_CONTROL);
+        let table_size = (control & MSIX_CONTROL_TABLE_SIZE_MASK) as usize + 1;
+        let table_register = device.read_u32(cap + MSIX_TABLE);
+        let bir = (table_register & MSIX_BIR_MASK) as usize;
+        let table_offset = (table_register & !MSIX_BIR_MASK) as u64;
+        let Some(Bar::Memory { address, size, .. }) = device.bars.get(bir).copied().flatten()
+        else {
+            return Err("MSI-X table BAR is not a memory BAR");
+        };
+        let table_bytes = (table_size * MSIX_ENTRY_SIZE) as u64;
+        if address == 0 || table_offset + table_bytes > size {
+            return Err("MSI-X table is outside of its BAR");
+        }
+        // map_ioはキャッシュ無効でマップする
+        let table = paging::map_io(
+            PhysAddr::new((address + table_offset) as usize),
+            MSize::new(table_bytes as usize),
+        )?;
+        let msix = MsiX {
+            device,
+            cap,
+            table,
+            table_size,
+        };
+
+        device.enable();
+        device.disable_intx();
+        // テーブルを書き換える間は全体をマスクしておく
+        device.write_u16(
+            cap + MSIX_CONTROL,
+            control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK,
+        );
+        for entry in 0..table_size {
+            msix.mask(entry);
+        }
+        device.write_u16(
+            cap + MSIX_CONTROL,
+            (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_FUNCTION_MASK,
+        );
+        Ok(msix)
+    }
+
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+
+    fn entry_register(&self, entry: usize, offset: usize) -> *mut u32 {
+        (self.table.to_usize() + entry * MSIX_ENTRY_SIZE + offset) as *mut u32
+    }
+
+    fn read_entry(&self, entry: usize, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.entry_register(entry, offset)) }
+    }
+
+    fn write_entry(&self, entry: usize, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile(self.entry_register(entry, offset), value) }
+    }
+
+    pub fn mask(&self, entry: usize) {
+        let control = self.read_entry(entry, MSIX_ENTRY_VECTOR_CONTROL);
+        self.write_entry(
+            entry,
+            MSIX_ENTRY_VECTOR_CONTROL,
+            control | MSIX_ENTRY_MASKED,
+        );
+    }
+
+    pub fn unmask(&self, entry: usize) {
+        let control = self.read_entry(entry, MSIX_ENTRY_VECTOR_CONTROL);
+        self.write_entry(
+            entry,
+            MSIX_ENTRY_VECTOR_CONTROL,
+            control & !MSIX_ENTRY_MASKED,
+        );
+    }
+
+    // エントリentryの割り込みをcpuに届け、handlerを呼ぶようにする. 割り当てたベクタを返す
+    pub fn set_vector(
+        &self,
+        entry: usize,
+        cpu: usize,
+        handler: InterruptHandler,
+    ) -> Result<u8, &'static str> {
+        if entry >= self.table_size {
+            return Err("MSI-X table entry is out of range");
+        }
+        with_vector(handler, |vector| {
+            let (address, data) = message(cpu, vector)?;
+            self.mask(entry);
+            self.write_entry(entry, MSIX_ENTRY_ADDRESS_LOW, address);
+            self.write_entry(entry, MSIX_ENTRY_ADDRESS_HIGH, 0);
+            self.write_entry(entry, MSIX_ENTRY_DATA, data);
+            self.unmask(entry);
+            Ok(vector)
+        })
+    }
+
+    // set_vectorで設定したエントリをマスクし、ベクタを解放する
+    pub fn clear_vector(&self, entry: usize, vector: u8) {
+        if entry < self.table_size {
+            self.mask(entry);
+            self.write_entry(entry, MSIX_ENTRY_ADDRESS_LOW, 0);
+            self.write_entry(entry, MSIX_ENTRY_DATA, 0);
+        }
+        idt::free_vector(vector);
+    }
+
+    pub fn disable(&self) {
+        let control = self.device.read_u16(self.cap + MSIX_CONTROL);
+        self.device
+            .write_u16(self.cap + MSIX_CONTROL, control & !MSIX_CONTROL_ENABLE);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ipi;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test_case]
+    fn message_targets_local_apic() {
+        let (address, data) = message(0, 0x51).unwrap();
+        assert_eq!(address & 0xFFF0_0000, MSI_ADDRESS_BASE);
+        assert_eq!(address >> MSI_ADDRESS_DEST_SHIFT & 0xFF, smp::apic_id(0));
+        assert_eq!(data, 0x51);
+    }
+
+    #[test_case]
+    fn allocated_vector_runs_handler() {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let vector = idt::allocate_vector(Arc::new(|| {
+            COUNT.fetch_add(1, Ordering::Relaxed);
+        }))
+        .unwrap();
+        // 自分宛てのIPIで、デバイスからのMSIと同じベクタを起こす
+        ipi::send(smp::cpu_id(), vector);
+        while COUNT.load(Ordering::Relaxed) == 0 {
+            core::hint::spin_loop();
+        }
+        idt::free_vector(vector);
+    }
+}
@@ -0,0 +1,880 @@
+use crate::{
+    clock::Instant,
+    dhcp, info,
+    spin::{Once, SpinLock},
+    task, tcp, warn, x86,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xFF; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Ipv4Addr([a, b, c, d])
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    fn from_slice(bytes: &[u8]) -> Self {
+        Ipv4Addr([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in &mut octets {
+            *octet = parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or("Invalid IPv4 address")?;
+        }
+        match parts.next() {
+            Some(_) => Err("Invalid IPv4 address"),
+            None => Ok(Ipv4Addr(octets)),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+// ネットワークデバイスのドライバが実装する
+pub trait NetDevice: Send + Sync {
+    fn mac_address(&self) -> MacAddress;
+
+    // Ethernetフレーム (FCSを除く) を送信する
+    fn transmit(&self, frame: &[u8]) -> Result<(), &'static str>;
+
+    // 受信したフレームを1つ取り出す. なければNone
+    fn receive(&self) -> Option<Vec<u8>>;
+}
+
+// ビッグエンディアンの読み書き
+pub fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+pub fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+pub fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+// インターネットチェックサム. initialには疑似ヘッダの和などを渡す
+pub fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    let (chunks, remainder) = data.as_chunks::<2>();
+    for chunk in chunks {
+        sum += u16::from_be_bytes(*chunk) as u32;
+    }
+    if let [last] = remainder {
+        sum += (*last as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// TCP/UDPの疑似ヘッダの和
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let src = src.to_u32();
+    let dst = dst.to_u32();
+    (src >> 16) + (src & 0xFFFF) + (dst >> 16) + (dst & 0xFFFF) + protocol as u32 + len as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpConfig {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+}
+
+impl IpConfig {
+    // DHCPで設定されるまでの状態
+    pub const UNCONFIGURED: IpConfig = IpConfig {
+        address: Ipv4Addr::UNSPECIFIED,
+        netmask: Ipv4Addr::UNSPECIFIED,
+        gateway: Ipv4Addr::UNSPECIFIED,
+        dns: None,
+    };
+
+    // QEMUのユーザモードネットワークの既定値
+    pub const QEMU_USER_NET: IpConfig = IpConfig {
+        address: Ipv4Addr::new(10, 0, 2, 15),
+        netmask: Ipv4Addr::new(255, 255, 255, 0),
+        gateway: Ipv4Addr::new(10, 0, 2, 2),
+        dns: Some(Ipv4Addr::new(10, 0, 2, 3)),
+    };
+
+    fn is_local(&self, addr: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        addr.to_u32() & mask == self.address.to_u32() & mask
+    }
+}
+
+struct Interface {
+    device: Arc<dyn NetDevice>,
+    mac: MacAddress,
+    config: SpinLock<IpConfig>,
+}
+
+static INTERFACE: Once<Interface> = Once::new();
+
+// 自分宛てに送ったIPパケット. pollで受信したものとして処理する
+static LOOPBACK: SpinLock<VecDeque<Vec<u8>>> = SpinLock::new(VecDeque::new());
+
+fn interface() -> Result<&'static Interface, &'static str> {
+    INTERFACE.get().ok_or("No network interface")
+}
+
+// ドライバが見つけたデバイスを登録する. 最初のデバイスだけを使う
+pub fn register_device(device: Arc<dyn NetDevice>) {
+    let mac = device.mac_address();
+    let mut registered = false;
+    INTERFACE.call_once(|| {
+        registered = true;
+        Interface {
+            device,
+            mac,
+            config: SpinLock::new(IpConfig::UNCONFIGURED),
+        }
+    });
+    if !registered {
+        warn!(
+            "net: only the first network device is used, ignoring {}",
+            mac
+        );
+    }
+}
+
+pub fn mac_address() -> Option<MacAddress> {
+    INTERFACE.get().map(|iface| iface.mac)
+}
+
+pub fn config() -> Option<IpConfig> {
+    INTERFACE.get().map(|iface| *iface.config.lock())
+}
+
+pub fn set_config(config: IpConfig) {
+    if let Some(iface) = INTERFACE.get() {
+        *iface.config.lock() = config;
+    }
+}
+
+// DHCPのタスクがアドレスを設定するまで待つ
+#[allow(dead_code)]
+pub fn wait_configured(timeout: Duration) -> Option<IpConfig> {
+    interface().ok()?;
+    wait_until(timeout, || {
+        config().filter(|config| config.address != Ipv4Addr::UNSPECIFIED)
+    })
+}
+
+// Ethernet
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+fn send_ethernet(
+    iface: &Interface,
+    dst: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Result<(), &'static str> {
+    let mut frame = vec![0; ETHERNET_HEADER_LEN + payload.len()];
+    frame[0..6].copy_from_slice(&dst.0);
+    frame[6..12].copy_from_slice(&iface.mac.0);
+    write_u16(&mut frame, 12, ethertype);
+    frame[ETHERNET_HEADER_LEN..].copy_from_slice(payload);
+    iface.device.transmit(&frame)
+}
+
+fn handle_ethernet(iface: &Interface, frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER_LEN..];
+    match read_u16(frame, 12) {
+        ETHERTYPE_ARP => handle_arp(iface, payload),
+        ETHERTYPE_IPV4 => handle_ipv4(iface, payload),
+        _ => {}
+    }
+}
+
+// ARP
+const ARP_PACKET_LEN: usize = 28;
+const ARP_HTYPE_ETHERNET: u16 = 1;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+// 解決待ちのIPアドレスごとに保持するパケットの最大数
+const ARP_MAX_PENDING: usize = 16;
+// 覚えておくMACアドレスの最大数
+const ARP_MAX_ENTRIES: usize = 64;
+// 応答がなければ要求を送り直す間隔と回数. 使い切ったら待っていたパケットを捨てる
+const ARP_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const ARP_MAX_RETRIES: u32 = 3;
+
+// アドレス解決を待っているIPパケット
+struct ArpPending {
+    packets: Vec<Vec<u8>>,
+    // 最後に要求を送った時刻
+    requested_at: Instant,
+    retries: u32,
+}
+
+impl ArpPending {
+    fn new(now: Instant) -> Self {
+        ArpPending {
+            packets: Vec::new(),
+            requested_at: now,
+            retries: 0,
+        }
+    }
+}
+
+struct ArpTable {
+    entries: BTreeMap<Ipv4Addr, MacAddress>,
+    pending: BTreeMap<Ipv4Addr, ArpPending>,
+}
+
+impl ArpTable {
+    const fn new() -> Self {
+        ArpTable {
+            entries: BTreeMap::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    // 既に知っているか、解決を待っているか、自分宛てのパケットの送信元だけを覚える (RFC 826)
+    // 他のホスト同士のやり取りで表が埋まらないようにするため
+    // 覚えた場合は、送信元の解決を待っていたパケットを返す
+    fn learn(&mut self, ip: Ipv4Addr, mac: MacAddress, for_us: bool) -> Vec<Vec<u8>> {
+        if ip == Ipv4Addr::UNSPECIFIED {
+            return Vec::new();
+        }
+        let known = self.entries.contains_key(&ip) || self.pending.contains_key(&ip);
+        if !known && !for_us {
+            return Vec::new();
+        }
+        // 一杯なら、アドレスの最も小さいエントリを捨てる
+        if !self.entries.contains_key(&ip) && self.entries.len() >= ARP_MAX_ENTRIES {
+            let evicted = self.entries.keys().next().copied();
+            if let Some(evicted) = evicted {
+                self.entries.remove(&evicted);
+            }
+        }
+        self.entries.insert(ip, mac);
+        self.pending
+            .remove(&ip)
+            .map(|pending| pending.packets)
+            .unwrap_or_default()
+    }
+
+    // 応答のないまま間隔が過ぎた解決待ちを調べ、要求を送り直すアドレスを返す
+    // 送り直す回数を使い切ったものは、次の送信で新しく解決し直せるように捨てる
+    fn expire(&mut self, now: Instant) -> Vec<Ipv4Addr> {
+        self.pending.retain(|_, pending| {
+            now < pending.requested_at + ARP_RETRY_INTERVAL || pending.retries < ARP_MAX_RETRIES
+        });
+        let mut retry = Vec::new();
+        for (&ip, pending) in self.pending.iter_mut() {
+            if now >= pending.requested_at + ARP_RETRY_INTERVAL {
+                pending.requested_at = now;
+                pending.retries += 1;
+                retry.push(ip);
+            }
+        }
+        retry
+    }
+}
+
+static ARP_TABLE: SpinLock<ArpTable> = SpinLock::new(ArpTable::new());
+
+fn send_arp(
+    iface: &Interface,
+    op: u16,
+    target_mac: MacAddress,
+    target_ip: Ipv4Addr,
+) -> Result<(), &'static str> {
+    let mut packet = [0u8; ARP_PACKET_LEN];
+    write_u16(&mut packet, 0, ARP_HTYPE_ETHERNET);
+    write_u16(&mut packet, 2, ETHERTYPE_IPV4);
+    packet[4] = 6;
+    packet[5] = 4;
+    write_u16(&mut packet, 6, op);
+    packet[8..14].copy_from_slice(&iface.mac.0);
+    packet[14..18].copy_from_slice(&iface.config.lock().address.0);
+    packet[18..24].copy_from_slice(&target_mac.0);
+    packet[24..28].copy_from_slice(&target_ip.0);
+    let dst = if op == ARP_OP_REQUEST {
+        MacAddress::BROADCAST
+    } else {
+        target_mac
+    };
+    send_ethernet(iface, dst, ETHERTYPE_ARP, &packet)
+}
+
+// 応答のないARP要求を送り直す
+fn retry_arp(iface: &Interface) {
+    let retry = ARP_TABLE.lock().expire(Instant::now());
+    for ip in retry {
+        let _ = send_arp(iface, ARP_OP_REQUEST, MacAddress::default(), ip);
+    }
+}
+
+fn handle_arp(iface: &Interface, packet: &[u8]) {
+    if packet.len() < ARP_PACKET_LEN
+        || read_u16(packet, 0) != ARP_HTYPE_ETHERNET
+        || read_u16(packet, 2) != ETHERTYPE_IPV4
+    {
+        return;
+    }
+    let op = read_u16(packet, 6);
+    let sender_mac = MacAddress(packet[8..14].try_into().unwrap());
+    let sender_ip = Ipv4Addr::from_slice(&packet[14..18]);
+    let target_ip = Ipv4Addr::from_slice(&packet[24..28]);
+
+    let address = iface.config.lock().address;
+    let for_us = target_ip == address && address != Ipv4Addr::UNSPECIFIED;
+    let waiting = ARP_TABLE.lock().learn(sender_ip, sender_mac, for_us);
+    for packet in waiting {
+        let _ = send_ethernet(iface, sender_mac, ETHERTYPE_IPV4, &packet);
+    }
+
+    if op == ARP_OP_REQUEST && for_us {
+        let _ = send_arp(iface, ARP_OP_REPLY, sender_mac, sender_ip);
+    }
+}
+
+// IPv4
+const IPV4_HEADER_LEN: usize = 20;
+const IPV4_DEFAULT_TTL: u8 = 64;
+const IPV4_FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+static IPV4_ID: AtomicU16 = AtomicU16::new(0);
+
+// 受信したIPパケットのヘッダの情報
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+}
+
+// IPパケットを組み立てて送る. 宛先のMACアドレスが未解決ならARPの応答を待ってから送る
+pub fn send_ipv4(dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), &'static str> {
+    let iface = interface()?;
+    let config = *iface.config.lock();
+    let total_len = IPV4_HEADER_LEN + payload.len();
+    if total_len > u16::MAX as usize {
+        return Err("IPv4 packet is too large");
+    }
+    let mut packet = vec![0; total_len];
+    packet[0] = 0x45;
+    write_u16(&mut packet, 2, total_len as u16);
+    write_u16(&mut packet, 4, IPV4_ID.fetch_add(1, Ordering::Relaxed));
+    packet[8] = IPV4_DEFAULT_TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&config.address.0);
+    packet[16..20].copy_from_slice(&dst.0);
+    let sum = checksum(&packet[..IPV4_HEADER_LEN], 0);
+    write_u16(&mut packet, 10, sum);
+    packet[IPV4_HEADER_LEN..].copy_from_slice(payload);
+
+    if dst == config.address && dst != Ipv4Addr::UNSPECIFIED {
+        LOOPBACK.lock().push_back(packet);
+        return Ok(());
+    }
+    if dst == Ipv4Addr::BROADCAST {
+        return send_ethernet(iface, MacAddress::BROADCAST, ETHERTYPE_IPV4, &packet);
+    }
+    let next_hop = if config.is_local(dst) {
+        dst
+    } else {
+        config.gateway
+    };
+    let mut table = ARP_TABLE.lock();
+    if let Some(&mac) = table.entries.get(&next_hop) {
+        drop(table);
+        return send_ethernet(iface, mac, ETHERTYPE_IPV4, &packet);
+    }
+    // 既に要求を送っていれば、応答か再送を待つ
+    let requested = table.pending.contains_key(&next_hop);
+    let pending = table
+        .pending
+        .entry(next_hop)
+        .or_insert_with(|| ArpPending::new(Instant::now()));
+    if pending.packets.len() >= ARP_MAX_PENDING {
+        return Err("Too many packets waiting for ARP resolution");
+    }
+    pending.packets.push(packet);
+    drop(table);
+    if requested {
+        return Ok(());
+    }
+    send_arp(iface, ARP_OP_REQUEST, MacAddress::default(), next_hop)
+}
+
+fn handle_ipv4(iface: &Interface, packet: &[u8]) {
+    if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (packet[0] & 0xF) as usize * 4;
+    let total_len = read_u16(packet, 2) as usize;
+    if header_len < IPV4_HEADER_LEN
+        || total_len < header_len
+        || total_len > packet.len()
+        || checksum(&packet[..header_len], 0) != 0
+    {
+        return;
+    }
+    // 断片化されたパケットは扱わない
+    let fragment = read_u16(packet, 6);
+    if fragment & (IPV4_FLAG_MORE_FRAGMENTS | IPV4_FRAGMENT_OFFSET_MASK) != 0 {
+        return;
+    }
+    let header = Ipv4Header {
+        src: Ipv4Addr::from_slice(&packet[12..16]),
+        dst: Ipv4Addr::from_slice(&packet[16..20]),
+        protocol: packet[9],
+    };
+    let address = iface.config.lock().address;
+    // アドレスが未設定の間 (DHCP) は全て受け取る
+    if header.dst != address
+        && header.dst != Ipv4Addr::BROADCAST
+        && address != Ipv4Addr::UNSPECIFIED
+    {
+        return;
+    }
+    let payload = &packet[header_len..total_len];
+    match header.protocol {
+        PROTOCOL_ICMP => handle_icmp(&header, payload),
+        PROTOCOL_TCP => tcp::handle_segment(&header, payload),
+        PROTOCOL_UDP => handle_udp(&header, payload),
+        _ => {}
+    }
+}
+
+// ICMP
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HEADER_LEN: usize = 8;
+
+static PING_ID: AtomicU16 = AtomicU16::new(1);
+// 応答を待っているエコー要求 (id, seq) と、応答を受け取った時刻
+static PING_REPLIES: SpinLock<BTreeMap<(u16, u16), Option<Instant>>> =
+    SpinLock::new(BTreeMap::new());
+
+fn send_icmp_echo(
+    dst: Ipv4Addr,
+    typ: u8,
+    id: u16,
+    seq: u16,
+    data: &[u8],
+) -> Result<(), &'static str> {
+    let mut message = vec![0; ICMP_HEADER_LEN + data.len()];
+    message[0] = typ;
+    write_u16(&mut message, 4, id);
+    write_u16(&mut message, 6, seq);
+    message[ICMP_HEADER_LEN..].copy_from_slice(data);
+    let sum = checksum(&message, 0);
+    write_u16(&mut message, 2, sum);
+    send_ipv4(dst, PROTOCOL_ICMP, &message)
+}
+
+fn handle_icmp(header: &Ipv4Header, message: &[u8]) {
+    if message.len() < ICMP_HEADER_LEN || checksum(message, 0) != 0 {
+        return;
+    }
+    let id = read_u16(message, 4);
+    let seq = read_u16(message, 6);
+    match message[0] {
+        ICMP_ECHO_REQUEST => {
+            let data = &message[ICMP_HEADER_LEN..];
+            let _ = send_icmp_echo(header.src, ICMP_ECHO_REPLY, id, seq, data);
+        }
+        ICMP_ECHO_REPLY => {
+            if let Some(reply) = PING_REPLIES.lock().get_mut(&(id, seq)) {
+                reply.get_or_insert_with(Instant::now);
+            }
+        }
+        _ => {}
+    }
+}
+
+// dstにエコー要求を送り、応答までの時間を返す
+pub fn ping(dst: Ipv4Addr, timeout: Duration) -> Result<Duration, &'static str> {
+    let key = (PING_ID.fetch_add(1, Ordering::Relaxed), 0);
+    PING_REPLIES.lock().insert(key, None);
+    let start = Instant::now();
+    let result = send_icmp_echo(dst, ICMP_ECHO_REQUEST, key.0, key.1, b"ping").and_then(|_| {
+        wait_until(timeout, || PING_REPLIES.lock().get(&key).copied().flatten())
+            .ok_or("Ping timed out")
+    });
+    PING_REPLIES.lock().remove(&key);
+    result.map(|reply| reply - start)
+}
+
+// UDP
+const UDP_HEADER_LEN: usize = 8;
+const EPHEMERAL_PORT_START: u16 = 49152;
+// ソケットごとに保持する受信データグラムの最大数
+const UDP_MAX_QUEUED: usize = 64;
+
+pub struct Datagram {
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub data: Vec<u8>,
+}
+
+static UDP_SOCKETS: SpinLock<BTreeMap<u16, VecDeque<Datagram>>> = SpinLock::new(BTreeMap::new());
+
+// 使われていないエフェメラルポートを探す
+pub fn ephemeral_port(in_use: impl Fn(u16) -> bool) -> Option<u16> {
+    static NEXT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORT_START);
+    let count = u16::MAX - EPHEMERAL_PORT_START + 1;
+    (0..count)
+        .map(|_| {
+            let port = NEXT.fetch_add(1, Ordering::Relaxed);
+            if port < EPHEMERAL_PORT_START {
+                NEXT.store(EPHEMERAL_PORT_START + 1, Ordering::Relaxed);
+                EPHEMERAL_PORT_START
+            } else {
+                port
+            }
+        })
+        .find(|&port| !in_use(port))
+}
+
+fn handle_udp(header: &Ipv4Header, segment: &[u8]) {
+    if segment.len() < UDP_HEADER_LEN {
+        return;
+    }
+    let len = read_u16(segment, 4) as usize;
+    if len < UDP_HEADER_LEN || len > segment.len() {
+        return;
+    }
+    let segment = &segment[..len];
+    // チェックサム0は省略を表す
+    if read_u16(segment, 6) != 0
+        && checksum(
+            segment,
+            pseudo_header_sum(header.src, header.dst, PROTOCOL_UDP, len),
+        ) != 0
+    {
+        return;
+    }
+    let dst_port = read_u16(segment, 2);
+    let mut sockets = UDP_SOCKETS.lock();
+    if let Some(queue) = sockets.get_mut(&dst_port)
+        && queue.len() < UDP_MAX_QUEUED
+    {
+        queue.push_back(Datagram {
+            src: header.src,
+            src_port: read_u16(segment, 0),
+            data: segment[UDP_HEADER_LEN..].to_vec(),
+        });
+    }
+}
+
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    // portで受信する. 0ならエフェメラルポートを割り当てる
+    pub fn bind(port: u16) -> Result<Self, &'static str> {
+        let mut sockets = UDP_SOCKETS.lock();
+        let port = match port {
+            0 => ephemeral_port(|port| sockets.contains_key(&port)).ok_or("No free UDP port")?,
+            port if sockets.contains_key(&port) => return Err("UDP port is already in use"),
+            port => port,
+        };
+        sockets.insert(port, VecDeque::new());
+        Ok(UdpSocket { port })
+    }
+
+    #[allow(dead_code)]
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, data: &[u8], dst: Ipv4Addr, dst_port: u16) -> Result<(), &'static str> {
+        let len = UDP_HEADER_LEN + data.len();
+        if len > u16::MAX as usize {
+            return Err("UDP datagram is too large");
+        }
+        let src = interface()?.config.lock().address;
+        let mut segment = vec![0; len];
+        write_u16(&mut segment, 0, self.port);
+        write_u16(&mut segment, 2, dst_port);
+        write_u16(&mut segment, 4, len as u16);
+        segment[UDP_HEADER_LEN..].copy_from_slice(data);
+        let sum = match checksum(&segment, pseudo_header_sum(src, dst, PROTOCOL_UDP, len)) {
+            // 計算結果が0なら0xFFFFを送る (0は省略を表すため)
+            0 => 0xFFFF,
+            sum => sum,
+        };
+        write_u16(&mut segment, 6, sum);
+        send_ipv4(dst, PROTOCOL_UDP, &segment)
+    }
+
+    // 受信済みのデータグラムを1つ取り出す. なければNone
+    pub fn recv_from(&self) -> Option<Datagram> {
+        UDP_SOCKETS.lock().get_mut(&self.port)?.pop_front()
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_from_timeout(&self, timeout: Duration) -> Option<Datagram> {
+        wait_until(timeout, || self.recv_from())
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        UDP_SOCKETS.lock().remove(&self.port);
+    }
+}
+
+// 受信したフレームと自分宛てのパケットを処理する. 何か処理したらtrueを返す
+pub fn poll() -> bool {
+    let Ok(iface) = interface() else {
+        return false;
+    };
+    let mut processed = false;
+    while let Some(frame) = iface.device.receive() {
+        handle_ethernet(iface, &frame);
+        processed = true;
+    }
+    loop {
+        let Some(packet) = LOOPBACK.lock().pop_front() else {
+            break;
+        };
+        handle_ipv4(iface, &packet);
+        processed = true;
+    }
+    retry_arp(iface);
+    if tcp::poll_timers() {
+        processed = true;
+    }
+    processed
+}
+
+// fがSomeを返すまでパケットを処理しながら待つ
+pub fn wait_until<T>(timeout: Duration, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        poll();
+        if let Some(value) = f() {
+            return Some(value);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// 時間の制限なしで待つ
+pub fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> T {
+    loop {
+        poll();
+        if let Some(value) = f() {
+            return value;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// 受信処理を行うタスク. 受信がなければ次の割り込みまで止まる
+fn net_task() {
+    loop {
+        if !poll() {
+            x86::halt();
+        }
+    }
+}
+
+// UDPのechoサーバ. ホストからはhostfwdしたポートに送って確かめる
+const UDP_ECHO_PORT: u16 = 7;
+
+fn udp_echo_task() {
+    let socket = match UdpSocket::bind(UDP_ECHO_PORT) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("net: UDP echo server is not available: {}", e);
+            // タスクから戻ることはできない
+            loop {
+                x86::halt();
+            }
+        }
+    };
+    loop {
+        match socket.recv_from() {
+            Some(datagram) => {
+                let _ = socket.send_to(&datagram.data, datagram.src, datagram.src_port);
+            }
+            None => x86::halt(),
+        }
+    }
+}
+
+// ネットワークデバイスがあれば、受信処理とDHCPのタスクを起動する
+// アドレスはDHCPのタスクが設定するため、起動を待たせない
+pub fn init() {
+    let Ok(iface) = interface() else {
+        warn!("net: no network device");
+        return;
+    };
+    info!("net: {}", iface.mac);
+    task::spawn(net_task);
+    task::spawn(dhcp::task);
+    task::spawn(udp_echo_task);
+    task::spawn(tcp::echo_task);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn checksum_matches_rfc1071_example() {
+        let data = [0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7];
+        assert_eq!(checksum(&data, 0), !0xDDF2);
+        // チェックサムを含めて計算すると0になる
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xC0, 0xA8,
+            0x00, 0x01, 0xC0, 0xA8, 0x00, 0xC7,
+        ];
+        let sum = checksum(&header, 0);
+        assert_eq!(sum, 0xB861);
+        write_u16(&mut header, 10, sum);
+        assert_eq!(checksum(&header, 0), 0);
+    }
+
+    #[test_case]
+    fn arp_learns_only_relevant_senders() {
+        let mut table = ArpTable::new();
+        let mac = MacAddress([0x52, 0x54, 0, 0, 0, 1]);
+        let other = Ipv4Addr::new(10, 0, 2, 100);
+        // 他のホスト宛ての要求からは覚えない
+        assert!(table.learn(other, mac, false).is_empty());
+        assert!(!table.entries.contains_key(&other));
+
+        // 解決を待っていた相手は覚えて、待っていたパケットを返す
+        let mut pending = ArpPending::new(Instant::now());
+        pending.packets.push(vec![1, 2, 3]);
+        table.pending.insert(other, pending);
+        assert_eq!(table.learn(other, mac, false), vec![vec![1, 2, 3]]);
+        assert_eq!(table.entries.get(&other), Some(&mac));
+
+        // 既に知っている相手は更新する
+        let new_mac = MacAddress([0x52, 0x54, 0, 0, 0, 2]);
+        table.learn(other, new_mac, false);
+        assert_eq!(table.entries.get(&other), Some(&new_mac));
+
+        // 自分宛ての送信元は覚えるが、表の大きさは制限する
+        for i in 0..ARP_MAX_ENTRIES as u8 * 2 {
+            table.learn(Ipv4Addr::new(10, 0, 3, i), mac, true);
+        }
+        assert_eq!(table.entries.len(), ARP_MAX_ENTRIES);
+    }
+
+    #[test_case]
+    fn arp_retries_then_drops_pending() {
+        let mut table = ArpTable::new();
+        let ip = Ipv4Addr::new(10, 0, 2, 100);
+        let start = Instant::now();
+        table.pending.insert(ip, ArpPending::new(start));
+        assert!(table.expire(start).is_empty());
+
+        let mut now = start;
+        for _ in 0..ARP_MAX_RETRIES {
+            now = now + ARP_RETRY_INTERVAL;
+            assert_eq!(table.expire(now), vec![ip]);
+        }
+        // 最後の要求にも応答がなければ捨てる
+        now = now + ARP_RETRY_INTERVAL;
+        assert!(table.expire(now).is_empty());
+        assert!(!table.pending.contains_key(&ip));
+    }
+
+    #[test_case]
+    fn udp_loopback_and_ping_self() {
+        if config().is_none() {
+            return;
+        }
+        let config = wait_configured(Duration::from_secs(10)).unwrap();
+        let server = UdpSocket::bind(0).unwrap();
+        let client = UdpSocket::bind(0).unwrap();
+        assert!(UdpSocket::bind(server.port()).is_err());
+        client
+            .send_to(b"hello", config.address, server.port())
+            .unwrap();
+        let datagram = server
+            .recv_from_timeout(Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(datagram.data, b"hello");
+        assert_eq!(datagram.src, config.address);
+        assert_eq!(datagram.src_port, client.port());
+
+        assert!(ping(config.address, Duration::from_millis(100)).is_ok());
+    }
+}
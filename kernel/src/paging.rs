@@ -1,13 +1,15 @@
 use crate::{
-    info,
+    info, ipi,
     memlayout::{
-        APIC_IO_SIZE, APIC_IO_START_ADDR, Address, LINER_MAPPING_BASE_VADDR, LINER_MAPPING_SIZE,
-        MSize, PhysAddr, VirtAddr, phys_to_virt, virt_to_phys,
+        Address, IO_MAPPING_BASE_VADDR, IO_MAPPING_SIZE, LINER_MAPPING_BASE_VADDR,
+        LINER_MAPPING_SIZE, MSize, PhysAddr, VirtAddr, phys_to_virt, virt_to_phys,
     },
-    println, symbol_offsets,
-    x86::write_cr3,
+    println,
+    spin::SpinLock,
+    symbol_offsets,
+    x86::{self, read_cr3, write_cr3},
 };
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use core::fmt::Debug;
 use core::{fmt, mem::MaybeUninit, pin::Pin};
 
@@ -21,24 +23,42 @@ const PTE_ATTR_USER_ACCESSIBLE: u64 = 1 << 2; // Page is accessible by user mode
 const PTE_ATTR_WRITE_THROUGH: u64 = 1 << 3; // Write-through caching
 const PTE_ATTR_CACHE_DISABLED: u64 = 1 << 4; // Cache disabled
 const PTE_ATTR_HUGE_PAGE: u64 = 1 << 7; // Huge Page
+const PTE_ATTR_GLOBAL: u64 = 1 << 8; // Not flushed on CR3 writes (CR4.PGE)
 const PTE_ATTR_NOT_EXECUTABLE: u64 = 1 << 63; // Page is **not** executable
 
+const MSR_IA32_PAT: u32 = 0x277;
+const CPUID_FEAT_EDX_PAT: u32 = 1 << 16;
+// PWTだけを立てたページが使うPATのエントリ. 既定のライトスルーはカーネルで使っていないので書き込み結合にする
+const PAT_INDEX_WRITE_COMBINING: u64 = 1;
+const PAT_TYPE_WRITE_COMBINING: u64 = 0x01;
+
 #[repr(u64)]
 #[derive(Clone, Copy, Debug)]
 #[allow(dead_code)]
 pub enum PageTableAttr {
     NotPresent = 0,
-    ReadExecuteKernel = PTE_ATTR_PRESENT,
-    ReadKernel = PTE_ATTR_PRESENT | PTE_ATTR_NOT_EXECUTABLE,
-    ReadWriteExecuteKernel = PTE_ATTR_PRESENT | PTE_ATTR_WRITABLE,
-    ReadWriteKernel = PTE_ATTR_PRESENT | PTE_ATTR_WRITABLE | PTE_ATTR_NOT_EXECUTABLE,
-    ReadWriteKernel1GiB =
-        PTE_ATTR_PRESENT | PTE_ATTR_WRITABLE | PTE_ATTR_NOT_EXECUTABLE | PTE_ATTR_HUGE_PAGE,
+    ReadExecuteKernel = PTE_ATTR_PRESENT | PTE_ATTR_GLOBAL,
+    ReadKernel = PTE_ATTR_PRESENT | PTE_ATTR_NOT_EXECUTABLE | PTE_ATTR_GLOBAL,
+    ReadWriteExecuteKernel = PTE_ATTR_PRESENT | PTE_ATTR_WRITABLE | PTE_ATTR_GLOBAL,
+    ReadWriteKernel =
+        PTE_ATTR_PRESENT | PTE_ATTR_WRITABLE | PTE_ATTR_NOT_EXECUTABLE | PTE_ATTR_GLOBAL,
+    ReadWriteKernel1GiB = PTE_ATTR_PRESENT
+        | PTE_ATTR_WRITABLE
+        | PTE_ATTR_NOT_EXECUTABLE
+        | PTE_ATTR_HUGE_PAGE
+        | PTE_ATTR_GLOBAL,
     ReadWriteKernelIO = PTE_ATTR_PRESENT
         | PTE_ATTR_WRITABLE
         | PTE_ATTR_WRITE_THROUGH
         | PTE_ATTR_CACHE_DISABLED
-        | PTE_ATTR_NOT_EXECUTABLE,
+        | PTE_ATTR_NOT_EXECUTABLE
+        | PTE_ATTR_GLOBAL,
+    // PATが使えなければライトスルーになる
+    ReadWriteKernelWriteCombining = PTE_ATTR_PRESENT
+        | PTE_ATTR_WRITABLE
+        | PTE_ATTR_WRITE_THROUGH
+        | PTE_ATTR_NOT_EXECUTABLE
+        | PTE_ATTR_GLOBAL,
 }
 
 #[repr(transparent)]
@@ -114,8 +134,8 @@ impl PageTableEntry {
             // TODO: 物理メモリを取得するアロケータを実装し、そのアロケータからメモリを確保する
             let next: Box<PageTableNode> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
             let phys_addr = virt_to_phys(VirtAddr::from_ptr(Box::into_raw(next) as *const u8));
-            self.value =
-                (phys_addr.to_usize() as u64) | PageTableAttr::ReadWriteExecuteKernel as u64;
+            // 上位のテーブルのエントリではGビットは予約されている (AMD) ため付けない
+            self.value = (phys_addr.to_usize() as u64) | PTE_ATTR_PRESENT | PTE_ATTR_WRITABLE;
             Ok(self)
         }
     }
@@ -144,6 +164,7 @@ struct PageTableNode {
     entries: [PageTableEntry; 512],
 }
 
+#[repr(transparent)]
 pub struct PageTable {
     pml4: PageTableNode,
 }
@@ -220,6 +241,76 @@ impl PageTable {
         self.map(virt_start, phys_start, num_pages, attr)
     }
 
+    // 4KiBページのマッピングのエントリ. マップされていないか、大きなページの場合はNone
+    fn leaf_entry_mut(&mut self, virt: VirtAddr) -> Option<&mut PageTableEntry> {
+        let mut node = &mut self.pml4;
+        for level in (2..=4).rev() {
+            let entry = &mut node.entries[virt.nth_level_table_index(level)];
+            if entry.is_huge() {
+                return None;
+            }
+            node = entry.next_node_mut()?;
+        }
+        let entry = &mut node.entries[virt.pt_index()];
+        entry.is_present().then_some(entry)
+    }
+
+    // 範囲内の各ページのエントリをfで書き換え、全CPUのTLBから追い出す
+    fn update_mapping(
+        &mut self,
+        virt_start: VirtAddr,
+        size: MSize,
+        f: impl Fn(&mut PageTableEntry) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        assert!(
+            virt_start.to_usize() % PAGE_SIZE.to_usize() == 0,
+            "Virtual address must be page-aligned"
+        );
+        let num_pages = size.to_usize().div_ceil(PAGE_SIZE.to_usize());
+        // 途中で失敗して一部だけ書き換わることがないよう、先に全て確認する
+        for i in 0..num_pages {
+            let virt = virt_start + MSize::new(i * PAGE_SIZE.to_usize());
+            if self.leaf_entry_mut(virt).is_none() {
+                return Err("Page is not mapped with a 4KiB page");
+            }
+        }
+        for i in 0..num_pages {
+            let virt = virt_start + MSize::new(i * PAGE_SIZE.to_usize());
+            f(self.leaf_entry_mut(virt).unwrap())?;
+        }
+        ipi::tlb_shootdown(virt_start.to_usize(), num_pages);
+        Ok(())
+    }
+
+    // マッピングを解除する. ページテーブル自体は解放しない
+    #[allow(dead_code)]
+    pub fn unmap(&mut self, virt_start: VirtAddr, size: MSize) -> Result<(), &'static str> {
+        self.update_mapping(virt_start, size, |entry| {
+            entry.value = 0;
+            Ok(())
+        })
+    }
+
+    // 物理アドレスはそのままで、属性だけを変更する
+    #[allow(dead_code)]
+    pub fn protect(
+        &mut self,
+        virt_start: VirtAddr,
+        size: MSize,
+        attr: PageTableAttr,
+    ) -> Result<(), &'static str> {
+        self.update_mapping(virt_start, size, |entry| {
+            entry.set_entry(entry.paddr(), attr)
+        })
+    }
+
+    // 指定した仮想アドレスを含むPML4エントリを事前に確保しておく
+    // duplicate_kernelはPML4エントリのみをコピーするため、後から追加したマッピングも全タスクで共有される
+    fn reserve_kernel_space(&mut self, virt: VirtAddr) -> Result<(), &'static str> {
+        self.pml4.entries[virt.pml4_index()].get_or_alloc_next_level_table()?;
+        Ok(())
+    }
+
     // 現時点ではカーネル空間のみ存在し、idleタスクのページテーブルを複製する
     // そのため再帰的な複製は行わず、単純にPML4のエントリをコピーする
     pub fn duplicate_kernel(&self) -> Pin<Box<PageTable>> {
@@ -278,15 +369,143 @@ pub fn init_paging() -> Pin<Box<PageTable>> {
         .expect("Failed to .data .bss area mapping");
     page_table
         .as_mut()
-        .create_mapping(
-            VirtAddr::new(APIC_IO_START_ADDR),
-            PhysAddr::new(APIC_IO_START_ADDR),
-            APIC_IO_SIZE,
-            PageTableAttr::ReadWriteKernelIO,
-        )
-        .expect("Failed to create kernel I/O mapping");
+        .reserve_kernel_space(IO_MAPPING_BASE_VADDR)
+        .expect("Failed to reserve kernel I/O area");
     write_cr3(virt_to_phys(VirtAddr::new(
         &mut page_table.pml4 as *mut _ as usize,
     )));
     page_table
 }
+
+static IO_MAPPING_NEXT: SpinLock<VirtAddr> = SpinLock::new(IO_MAPPING_BASE_VADDR);
+
+// CR3が指している現在のページテーブルを返す
+// I/O領域のPML4エントリは全タスクで共有されているため、どのタスクから変更しても良い
+unsafe fn active_page_table() -> &'static mut PageTable {
+    let pml4 = phys_to_virt(PhysAddr::new(read_cr3() & PTE_ATTR_MASK as usize));
+    unsafe { &mut *(pml4.to_ptr_mut() as *mut PageTable) }
+}
+
+// virtの変換で参照するエントリをPML4から順に返す. 存在しないエントリか大きいページで止まる
+pub fn walk(virt: VirtAddr) -> Vec<PageTableEntry> {
+    let mut entries = Vec::new();
+    let mut node = &unsafe { active_page_table() }.pml4;
+    for level in (1..=4).rev() {
+        let entry = node.entries[virt.nth_level_table_index(level)];
+        entries.push(entry);
+        if level == 1 || !entry.is_present() || entry.is_huge() {
+            break;
+        }
+        node = unsafe { &*(phys_to_virt(entry.paddr()).to_ptr() as *const PageTableNode) };
+    }
+    entries
+}
+
+// virtからlenバイトが全てマップされ、書き込めるか. userならユーザモードからの書き込みも許されているか
+// 上位のエントリも含めて全ての段で許可されている必要がある
+pub fn is_writable_range(virt: VirtAddr, len: usize, user: bool) -> bool {
+    let Some(end) = virt.to_usize().checked_add(len) else {
+        return false;
+    };
+    let page_size = PAGE_SIZE.to_usize();
+    let mut page = virt.to_usize() & !(page_size - 1);
+    while page < end {
+        let entries = walk(VirtAddr::new(page));
+        let leaf = entries.last().unwrap();
+        let mapped = leaf.is_present() && (leaf.is_huge() || entries.len() == 4);
+        let allowed = entries
+            .iter()
+            .all(|entry| entry.is_writable() && (!user || entry.is_user_accessible()));
+        if !mapped || !allowed {
+            return false;
+        }
+        page += page_size;
+    }
+    true
+}
+
+// 各CPUで同じPATを設定する. map_write_combiningを使う前に呼び出す
+pub fn init_pat() {
+    if x86::cpuid(1, 0).edx & CPUID_FEAT_EDX_PAT == 0 {
+        return;
+    }
+    let shift = PAT_INDEX_WRITE_COMBINING * 8;
+    let pat = x86::read_msr(MSR_IA32_PAT);
+    x86::write_msr(
+        MSR_IA32_PAT,
+        (pat & !(0xFF << shift)) | (PAT_TYPE_WRITE_COMBINING << shift),
+    );
+    x86::flush_tlb();
+}
+
+// 物理アドレスのMMIO領域をI/O用の仮想アドレス領域にキャッシュ無効でマップする
+pub fn map_io(phys: PhysAddr, size: MSize) -> Result<VirtAddr, &'static str> {
+    map_io_with(phys, size, PageTableAttr::ReadWriteKernelIO)
+}
+
+// フレームバッファなど、書き込みをまとめてよい領域をI/O用の仮想アドレス領域にマップする
+pub fn map_write_combining(phys: PhysAddr, size: MSize) -> Result<VirtAddr, &'static str> {
+    map_io_with(phys, size, PageTableAttr::ReadWriteKernelWriteCombining)
+}
+
+fn map_io_with(phys: PhysAddr, size: MSize, attr: PageTableAttr) -> Result<VirtAddr, &'static str> {
+    let offset = phys.to_usize() % PAGE_SIZE.to_usize();
+    let phys_start = PhysAddr::new(phys.to_usize() - offset);
+    let size = MSize::new(size.to_usize() + offset).page_align_up();
+
+    let mut next = IO_MAPPING_NEXT.lock();
+    let virt_start = *next;
+    if virt_start.to_usize() + size.to_usize()
+        > IO_MAPPING_BASE_VADDR.to_usize() + IO_MAPPING_SIZE.to_usize()
+    {
+        return Err("I/O mapping area exhausted");
+    }
+    unsafe { active_page_table() }.create_mapping(virt_start, phys_start, size, attr)?;
+    *next += size;
+    Ok(virt_start + MSize::new(offset))
+}
+
+// 物理アドレスと同じ仮想アドレスでマップする (APの起動コードなど、ページング有効化の前後で実行される領域用)
+pub fn identity_map(phys: PhysAddr, size: MSize) -> Result<(), &'static str> {
+    unsafe { active_page_table() }.create_mapping(
+        VirtAddr::new(phys.to_usize()),
+        phys,
+        size,
+        PageTableAttr::ReadWriteExecuteKernel,
+    )
+}
+
+// 現在のページテーブルからマッピングを解除する
+pub fn unmap(virt: VirtAddr, size: MSize) -> Result<(), &'static str> {
+    unsafe { active_page_table() }.unmap(virt, size)
+}
+
+// 現在のページテーブルでマッピングの属性を変更する
+#[allow(dead_code)]
+pub fn protect(virt: VirtAddr, size: MSize, attr: PageTableAttr) -> Result<(), &'static str> {
+    unsafe { active_page_table() }.protect(virt, size, attr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn protect_and_unmap_io_mapping() {
+        let page = Box::new(PageTableNode {
+            entries: [PageTableEntry { value: 0x42 }; 512],
+        });
+        let phys = virt_to_phys(VirtAddr::from_ptr(
+            &*page as *const PageTableNode as *const u8,
+        ));
+        let virt = map_io(phys, PAGE_SIZE).unwrap();
+        let read = || unsafe { core::ptr::read_volatile(virt.to_ptr() as *const u64) };
+        assert_eq!(read(), 0x42);
+        protect(virt, PAGE_SIZE, PageTableAttr::ReadKernel).unwrap();
+        assert_eq!(read(), 0x42);
+        unmap(virt, PAGE_SIZE).unwrap();
+        assert!(unmap(virt, PAGE_SIZE).is_err());
+        // 1GiBページでマップされている領域は変更できない
+        assert!(unmap(phys_to_virt(phys), PAGE_SIZE).is_err());
+    }
+}
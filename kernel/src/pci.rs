@@ -0,0 +1,652 @@
+use crate::{
+    acpi, info,
+    memlayout::{Address, MSize, PhysAddr, VirtAddr},
+    paging,
+    spin::{Once, SpinLock},
+    warn, x86,
+};
+use alloc::vec::Vec;
+use core::fmt;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+// ECAMでは1つのバスが1MiB (32デバイス * 8ファンクション * 4KiB)
+const ECAM_BUS_SHIFT: usize = 20;
+const ECAM_DEVICE_SHIFT: usize = 15;
+const ECAM_FUNCTION_SHIFT: usize = 12;
+
+const MAX_DEVICES: u8 = 32;
+const MAX_FUNCTIONS: u8 = 8;
+
+// コンフィギュレーション空間のヘッダ
+const REG_VENDOR_ID: u16 = 0x00;
+const REG_DEVICE_ID: u16 = 0x02;
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_REVISION: u16 = 0x08;
+const REG_PROG_IF: u16 = 0x09;
+const REG_SUBCLASS: u16 = 0x0A;
+const REG_CLASS: u16 = 0x0B;
+const REG_HEADER_TYPE: u16 = 0x0E;
+const REG_BAR0: u16 = 0x10;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT_LINE: u16 = 0x3C;
+const REG_INTERRUPT_PIN: u16 = 0x3D;
+
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+const BAR_IO_SPACE: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64BIT: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_MEMORY_ADDRESS_MASK: u32 = !0xF;
+const BAR_IO_ADDRESS_MASK: u32 = !0x3;
+
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+#[allow(dead_code)]
+pub const CAP_ID_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+// MCFGに記載された、あるセグメントのバスの範囲のECAM領域
+struct EcamRegion {
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    mmio: VirtAddr,
+}
+
+// コンフィギュレーション空間へのアクセス方法
+enum ConfigSpace {
+    Ecam(Vec<EcamRegion>),
+    // I/Oポート0xCF8/0xCFC (セグメント0、先頭256バイトのみ)
+    Port,
+}
+
+static CONFIG_SPACE: Once<ConfigSpace> = Once::new();
+// 0xCF8に書いてから0xCFCを読むまでの間に他のCPUが割り込まないようにする
+static PORT_LOCK: SpinLock<()> = SpinLock::new(());
+
+impl ConfigSpace {
+    fn detect() -> Self {
+        let regions: Vec<EcamRegion> = acpi::mcfg()
+            .map(|mcfg| mcfg.entries.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|entry| {
+                let buses = (entry.end_bus - entry.start_bus) as usize + 1;
+                let phys = PhysAddr::new(
+                    entry.base_address as usize + ((entry.start_bus as usize) << ECAM_BUS_SHIFT),
+                );
+                let mmio = paging::map_io(phys, MSize::new(buses << ECAM_BUS_SHIFT)).ok()?;
+                Some(EcamRegion {
+                    segment: entry.segment,
+                    start_bus: entry.start_bus,
+                    end_bus: entry.end_bus,
+                    mmio,
+                })
+            })
+            .collect();
+        if regions.is_empty() {
+            warn!("PCI: MCFG is not available, using I/O port configuration access");
+            ConfigSpace::Port
+        } else {
+            ConfigSpace::Ecam(regions)
+        }
+    }
+
+    // ECAMでのレジスタの仮想アドレス
+    fn ecam_address(regions: &[EcamRegion], addr: PciAddress, offset: u16) -> Option<usize> {
+        let region = regions.iter().find(|region| {
+            region.segment == addr.segment
+                && (region.start_bus..=region.end_bus).contains(&addr.bus)
+        })?;
+        Some(
+            region.mmio.to_usize()
+                + (((addr.bus - region.start_bus) as usize) << ECAM_BUS_SHIFT)
+                + ((addr.device as usize) << ECAM_DEVICE_SHIFT)
+                + ((addr.function as usize) << ECAM_FUNCTION_SHIFT)
+                + offset as usize,
+        )
+    }
+
+    fn port_address(addr: PciAddress, offset: u16) -> Option<u32> {
+        (addr.segment == 0 && offset < 0x100).then_some(
+            CONFIG_ADDRESS_ENABLE
+                | (addr.bus as u32) << 16
+                | (addr.device as u32) << 11
+                | (addr.function as u32) << 8
+                | (offset as u32 & 0xFC),
+        )
+    }
+
+    fn read_u32(&self, addr: PciAddress, offset: u16) -> u32 {
+        match self {
+            ConfigSpace::Ecam(regions) => match Self::ecam_address(regions, addr, offset) {
+                Some(virt) => unsafe { core::ptr::read_volatile(virt as *const u32) },
+                None => u32::MAX,
+            },
+            ConfigSpace::Port => match Self::port_address(addr, offset) {
+                Some(address) => {
+                    let _guard = PORT_LOCK.lock();
+                    x86::write_io_u32(CONFIG_ADDRESS_PORT, address);
+                    x86::read_io_u32(CONFIG_DATA_PORT)
+                }
+                None => u32::MAX,
+            },
+        }
+    }
+
+    fn write_u32(&self, addr: PciAddress, offset: u16, value: u32) {
+        match self {
+            ConfigSpace::Ecam(regions) => {
+                if let Some(virt) = Self::ecam_address(regions, addr, offset) {
+                    unsafe { core::ptr::write_volatile(virt as *mut u32, value) }
+                }
+            }
+            ConfigSpace::Port => {
+                if let Some(address) = Self::port_address(addr, offset) {
+                    let _guard = PORT_LOCK.lock();
+                    x86::write_io_u32(CONFIG_ADDRESS_PORT, address);
+                    x86::write_io_u32(CONFIG_DATA_PORT, value);
+                }
+            }
+        }
+    }
+
+    // ステータスレジスタのようにビットに1を書いてクリアするレジスタがあるため、
+    // 16bitのレジスタは隣のレジスタに触れないよう16bit幅で書き込む
+    fn write_u16(&self, addr: PciAddress, offset: u16, value: u16) {
+        match self {
+            ConfigSpace::Ecam(regions) => {
+                if let Some(virt) = Self::ecam_address(regions, addr, offset) {
+                    unsafe { core::ptr::write_volatile(virt as *mut u16, value) }
+                }
+            }
+            ConfigSpace::Port => {
+                if let Some(address) = Self::port_address(addr, offset) {
+                    let _guard = PORT_LOCK.lock();
+                    x86::write_io_u32(CONFIG_ADDRESS_PORT, address);
+                    x86::write_io_u16(CONFIG_DATA_PORT + (offset & 2), value);
+                }
+            }
+        }
+    }
+}
+
+fn config_space() -> &'static ConfigSpace {
+    CONFIG_SPACE.call_once(ConfigSpace::detect)
+}
+
+pub fn read_config_u32(addr: PciAddress, offset: u16) -> u32 {
+    config_space().read_u32(addr, offset & !3)
+}
+
+pub fn read_config_u16(addr: PciAddress, offset: u16) -> u16 {
+    (read_config_u32(addr, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_config_u8(addr: PciAddress, offset: u16) -> u8 {
+    (read_config_u32(addr, offset) >> ((offset & 3) * 8)) as u8
+}
+
+pub fn write_config_u32(addr: PciAddress, offset: u16, value: u32) {
+    config_space().write_u32(addr, offset & !3, value);
+}
+
+pub fn write_config_u16(addr: PciAddress, offset: u16, value: u16) {
+    config_space().write_u16(addr, offset & !1, value);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+impl Bar {
+    // BARの値と、全ビットに1を書いた後に読み出した値からBARを求める
+    // 64bitのBARの場合は上位のBARの値も渡す
+    fn decode(low: u32, high: u32, size_low: u32, size_high: u32) -> Option<Self> {
+        if low & BAR_IO_SPACE != 0 {
+            let mask = size_low & BAR_IO_ADDRESS_MASK & 0xFFFF;
+            if mask == 0 {
+                return None;
+            }
+            return Some(Bar::Io {
+                port: low & BAR_IO_ADDRESS_MASK,
+                size: (!mask & 0xFFFF) + 1,
+            });
+        }
+        let is_64bit = low & BAR_TYPE_MASK == BAR_TYPE_64BIT;
+        let (address, mask) = if is_64bit {
+            (
+                (high as u64) << 32 | (low & BAR_MEMORY_ADDRESS_MASK) as u64,
+                (size_high as u64) << 32 | (size_low & BAR_MEMORY_ADDRESS_MASK) as u64,
+            )
+        } else {
+            (
+                (low & BAR_MEMORY_ADDRESS_MASK) as u64,
+                0xFFFF_FFFF_0000_0000 | (size_low & BAR_MEMORY_ADDRESS_MASK) as u64,
+            )
+        };
+        // サイズのビットが1つも立たなければ実装されていない
+        let unimplemented = if is_64bit {
+            mask == 0
+        } else {
+            mask & 0xFFFF_FFFF == 0
+        };
+        if unimplemented {
+            return None;
+        }
+        Some(Bar::Memory {
+            address,
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & BAR_PREFETCHABLE != 0,
+            is_64bit,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    // コンフィギュレーション空間中のオフセット
+    pub offset: u16,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+}
+
+impl PciDevice {
+    fn probe(address: PciAddress) -> Option<Self> {
+        let vendor_id = read_config_u16(address, REG_VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: read_config_u16(address, REG_DEVICE_ID),
+            class: read_config_u8(address, REG_CLASS),
+            subclass: read_config_u8(address, REG_SUBCLASS),
+            prog_if: read_config_u8(address, REG_PROG_IF),
+            revision: read_config_u8(address, REG_REVISION),
+            header_type: read_config_u8(address, REG_HEADER_TYPE) & HEADER_TYPE_MASK,
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            interrupt_line: read_config_u8(address, REG_INTERRUPT_LINE),
+            interrupt_pin: read_config_u8(address, REG_INTERRUPT_PIN),
+        };
+        device.bars = device.probe_bars();
+        device.capabilities = device.walk_capabilities();
+        Some(device)
+    }
+
+    fn bar_count(&self) -> usize {
+        match self.header_type {
+            HEADER_TYPE_GENERAL => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        }
+    }
+
+    // BARに全ビット1を書き込んで、デコードされるビットからサイズを求める
+    // 書き込み中に不正なアドレスをデコードしないよう、一時的にI/O・メモリ空間を無効にする
+    fn probe_bars(&self) -> [Option<Bar>; 6] {
+        let mut bars = [None; 6];
+        let command = self.command();
+        self.set_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+        let mut index = 0;
+        while index < self.bar_count() {
+            let offset = REG_BAR0 + index as u16 * 4;
+            let low = self.read_u32(offset);
+            let is_64bit = low & BAR_IO_SPACE == 0 && low & BAR_TYPE_MASK == BAR_TYPE_64BIT;
+            let size_low = self.probe_bar_register(offset);
+            let (high, size_high) = if is_64bit && index + 1 < self.bar_count() {
+                (
+                    self.read_u32(offset + 4),
+                    self.probe_bar_register(offset + 4),
+                )
+            } else {
+                (0, 0)
+            };
+            bars[index] = Bar::decode(low, high, size_low, size_high);
+            index += if is_64bit { 2 } else { 1 };
+        }
+        self.set_command(command);
+        bars
+    }
+
+    fn probe_bar_register(&self, offset: u16) -> u32 {
+        let original = self.read_u32(offset);
+        self.write_u32(offset, u32::MAX);
+        let size = self.read_u32(offset);
+        self.write_u32(offset, original);
+        size
+    }
+
+    fn walk_capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        if self.read_u16(REG_STATUS) & STATUS_CAPABILITIES_LIST == 0 {
+            return capabilities;
+        }
+        let mut offset = (self.read_u8(REG_CAPABILITIES) & !0x3) as u16;
+        // 壊れたリストで無限ループしないよう、最大数を制限する
+        while offset != 0 && capabilities.len() < 48 {
+            capabilities.push(Capability {
+                id: self.read_u8(offset),
+                offset,
+            });
+            offset = (self.read_u8(offset + 1) & !0x3) as u16;
+        }
+        capabilities
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().copied().find(|cap| cap.id == id)
+    }
+
+    // 同じIDのcapabilityが複数ある場合 (virtioのvendor specificなど) に使う
+    pub fn capabilities_with_id(&self, id: u8) -> impl Iterator<Item = Capability> + '_ {
+        self.capabilities
+            .iter()
+            .copied()
+            .filter(move |cap| cap.id == id)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        read_config_u8(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        read_config_u16(self.address, offset)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        read_config_u32(self.address, offset)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        write_config_u16(self.address, offset, value);
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        write_config_u32(self.address, offset, value);
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(REG_COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.write_u16(REG_COMMAND, command);
+    }
+
+    // MMIO/ポートへのアクセスとDMAを有効にする
+    pub fn enable(&self) {
+        self.set_command(
+            self.command() | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+
+    // MSI/MSI-Xを使うときは、INTxの割り込みを止めておく
+    pub fn disable_intx(&self) {
+        self.set_command(self.command() | COMMAND_INTERRUPT_DISABLE);
+    }
+
+    pub fn is_multi_function(&self) -> bool {
+        read_config_u8(self.address, REG_HEADER_TYPE) & HEADER_TYPE_MULTI_FUNCTION != 0
+    }
+}
+
+// ドライバが対応するデバイスの条件. Noneのフィールドは何にでも一致する
+#[derive(Debug, Clone, Copy)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+}
+
+impl PciMatch {
+    #[allow(dead_code)]
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        PciMatch {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        PciMatch {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.is_none_or(|id| id == device.vendor_id)
+            && self.device_id.is_none_or(|id| id == device.device_id)
+            && self.class.is_none_or(|class| class == device.class)
+            && self
+                .subclass
+                .is_none_or(|subclass| subclass == device.subclass)
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [PciMatch],
+    pub probe: fn(&'static PciDevice) -> Result<(), &'static str>,
+}
+
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+static DRIVERS: SpinLock<Vec<&'static PciDriver>> = SpinLock::new(Vec::new());
+
+fn probe_driver(driver: &PciDriver, device: &'static PciDevice) {
+    if !driver.matches.iter().any(|m| m.matches(device)) {
+        return;
+    }
+    match (driver.probe)(device) {
+        Ok(()) => {
+            info!("PCI {}: bound to {}", device.address, driver.name);
+        }
+        Err(e) => {
+            warn!(
+                "PCI {}: {} probe failed: {}",
+                device.address, driver.name, e
+            );
+        }
+    }
+}
+
+// ドライバを登録する. 列挙済みのデバイスがあればすぐにprobeする
+#[allow(dead_code)]
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.lock().push(driver);
+    for device in devices() {
+        probe_driver(driver, device);
+    }
+}
+
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], |devices| devices.as_slice())
+}
+
+fn scan_bus(segment: u16, bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..MAX_DEVICES {
+        let Some(first) = PciDevice::probe(PciAddress::new(segment, bus, device, 0)) else {
+            continue;
+        };
+        let multi_function = first.is_multi_function();
+        devices.push(first);
+        if multi_function {
+            for function in 1..MAX_FUNCTIONS {
+                devices.extend(PciDevice::probe(PciAddress::new(
+                    segment, bus, device, function,
+                )));
+            }
+        }
+    }
+}
+
+// 全てのバスを走査してデバイスを列挙し、登録済みのドライバをprobeする
+pub fn init() {
+    let devices = DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        match config_space() {
+            ConfigSpace::Ecam(regions) => {
+                for region in regions {
+                    for bus in region.start_bus..=region.end_bus {
+                        scan_bus(region.segment, bus, &mut devices);
+                    }
+                }
+            }
+            ConfigSpace::Port => {
+                for bus in 0..=u8::MAX {
+                    scan_bus(0, bus, &mut devices);
+                }
+            }
+        }
+        devices
+    });
+    for device in devices {
+        info!(
+            "PCI {}: {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if
+        );
+    }
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        for device in devices {
+            probe_driver(driver, device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn decode_memory_and_io_bars() {
+        assert_eq!(
+            Bar::decode(0xFEB0_0000, 0, 0xFFFF_F000, 0),
+            Some(Bar::Memory {
+                address: 0xFEB0_0000,
+                size: 0x1000,
+                prefetchable: false,
+                is_64bit: false,
+            })
+        );
+        assert_eq!(
+            Bar::decode(0x0000_000C, 0x0000_0080, 0xFFFF_C00C, 0xFFFF_FFFF),
+            Some(Bar::Memory {
+                address: 0x80_0000_0000,
+                size: 0x4000,
+                prefetchable: true,
+                is_64bit: true,
+            })
+        );
+        assert_eq!(
+            Bar::decode(0xC041, 0, 0xFFFF_FFE1, 0),
+            Some(Bar::Io {
+                port: 0xC040,
+                size: 0x20,
+            })
+        );
+        // 実装されていないBAR
+        assert_eq!(Bar::decode(0, 0, 0, 0), None);
+        assert_eq!(Bar::decode(0x0000_0004, 0, 0x0000_0004, 0), None);
+    }
+
+    #[test_case]
+    fn host_bridge_is_enumerated() {
+        let host_bridge = devices()
+            .iter()
+            .find(|device| device.address == PciAddress::new(0, 0, 0, 0))
+            .expect("Host bridge is not found");
+        assert!(PciMatch::class(0x06, 0x00).matches(host_bridge));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{
+    info,
+    memlayout::PhysAddr,
+    percpu,
+    spin::SpinLock,
+    x86::{self, CR4_PCIDE, CR4_PGE},
+};
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const CPUID_FEAT_ECX_PCID: u32 = 1 << 17;
+
+// 切り替えのコストの測定で、切り替えごとに触れるページ数と繰り返す回数
+const MEASURE_PAGES: usize = 64;
+const MEASURE_ITERATIONS: u64 = 100;
+
+// 割り当てるPCIDの数. 0はページテーブルの初期化時に使うため割り当てない
+const PCID_COUNT: u16 = 64;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// アドレス空間に割り当てたPCID. 世代が変わると無効になる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pcid {
+    value: u16,
+    generation: u64,
+}
+
+// PCIDを順に割り当て、使い切ったら世代を進めて最初から割り当て直す
+// 古い世代のPCIDのTLBエントリは、各CPUが新しい世代で最初に切り替えるときに全て無効化する
+struct PcidPool {
+    next: u16,
+    generation: u64,
+}
+
+impl PcidPool {
+    const fn new() -> Self {
+        PcidPool {
+            next: 1,
+            generation: 1,
+        }
+    }
+
+    fn alloc(&mut self) -> Pcid {
+        if self.next >= PCID_COUNT {
+            self.next = 1;
+            self.generation += 1;
+        }
+        let pcid = Pcid {
+            value: self.next,
+            generation: self.generation,
+        };
+        self.next += 1;
+        pcid
+    }
+}
+
+static POOL: SpinLock<PcidPool> = SpinLock::new(PcidPool::new());
+
+percpu! {
+    // このCPUのTLBを最後に全て無効化したときの世代
+    static FLUSHED_GENERATION: AtomicU64 = AtomicU64::new(0);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// 各CPUで呼び出し、グローバルページとPCIDを有効にする
+// PCIDを使うかどうかはBSPで決め、APはそれに従う
+pub fn init(bsp: bool) {
+    let mut cr4 = x86::read_cr4() | CR4_PGE;
+    if bsp {
+        let supported = x86::cpuid(1, 0).ecx & CPUID_FEAT_ECX_PCID != 0;
+        ENABLED.store(supported, Ordering::Relaxed);
+        info!(
+            "PCID: {}",
+            if supported {
+                "enabled"
+            } else {
+                "not supported"
+            }
+        );
+    }
+    if enabled() {
+        // PCIDEを有効にするときは、CR3のPCIDが0である必要がある
+        assert!(x86::read_cr3() & 0xFFF == 0, "CR3 PCID must be 0");
+        cr4 |= CR4_PCIDE;
+    }
+    x86::write_cr4(cr4);
+}
+
+// アドレス空間を切り替える. pcidにはそのアドレス空間に割り当てたPCIDを保持しておく
+// 同じ世代の間はPCIDがほかのアドレス空間と重ならないため、TLBを残したまま切り替えられる
+pub fn switch_address_space(pml4: PhysAddr, pcid: &mut Option<Pcid>) {
+    if !enabled() {
+        x86::write_cr3(pml4);
+        return;
+    }
+    let generation = {
+        let mut pool = POOL.lock();
+        if pcid.is_none_or(|pcid| pcid.generation != pool.generation) {
+            *pcid = Some(pool.alloc());
+        }
+        pool.generation
+    };
+    let pcid = pcid.unwrap();
+    FLUSHED_GENERATION.with(|flushed| {
+        if flushed.load(Ordering::Relaxed) < generation {
+            // このCPUには以前の世代で同じPCIDを使っていたアドレス空間のエントリが残っている
+            x86::write_cr3_pcid(pml4, pcid.value, false);
+            x86::flush_tlb();
+            flushed.store(generation, Ordering::Relaxed);
+        } else {
+            x86::write_cr3_pcid(pml4, pcid.value, true);
+        }
+    });
+}
+
+// CR3を書き換えた後にMEASURE_PAGESページに触れるまでの平均サイクル数
+fn switch_cost(switch: impl Fn()) -> u64 {
+    let buffer = vec![0u8; MEASURE_PAGES * 4096];
+    let touch = || {
+        for page in 0..MEASURE_PAGES {
+            unsafe { core::ptr::read_volatile(&buffer[page * 4096]) };
+        }
+    };
+    touch();
+    let start = x86::rdtsc();
+    for _ in 0..MEASURE_ITERATIONS {
+        switch();
+        touch();
+    }
+    (x86::rdtsc() - start) / MEASURE_ITERATIONS
+}
+
+// 同じアドレス空間への切り替えのコストを、グローバルページもPCIDも使わない場合
+// (CR3の書き込みでTLBが全て無効化される) と比較する
+// (使わない場合, 使う場合) のサイクル数を返す. PCIDが使えなければNone
+pub fn measure_switch_cost() -> Option<(u64, u64)> {
+    if !enabled() {
+        return None;
+    }
+    let pml4 = PhysAddr::new(x86::read_cr3() & !0xFFF);
+    let current = x86::read_cr3() as u16 & 0xFFF;
+    let cr4 = x86::read_cr4();
+
+    // 測定中に別のCPUへ移ったり、PGEを戻す前に割り込まれたりしないようにする
+    let interrupts_enabled = x86::interrupts_enabled();
+    x86::disable_interrupts();
+    let after = switch_cost(|| x86::write_cr3_pcid(pml4, current, true));
+    x86::write_cr4(cr4 & !CR4_PGE);
+    let before = switch_cost(|| x86::write_cr3_pcid(pml4, current, false));
+    x86::write_cr4(cr4);
+    if interrupts_enabled {
+        x86::enable_interrupts();
+    }
+    Some((before, after))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn pool_recycles_after_exhaustion() {
+        let mut pool = PcidPool::new();
+        let first = pool.alloc();
+        for _ in 2..PCID_COUNT {
+            assert_eq!(pool.alloc().generation, first.generation);
+        }
+        let recycled = pool.alloc();
+        assert_eq!(recycled.value, first.value);
+        assert_eq!(recycled.generation, first.generation + 1);
+    }
+}
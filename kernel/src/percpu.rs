@@ -0,0 +1,158 @@
+use crate::{
+    memlayout::{Address, MSize},
+    smp::MAX_CPUS,
+    symbol_offsets, x86,
+};
+use alloc::{
+    alloc::{Layout, alloc_zeroed},
+    boxed::Box,
+};
+use core::{
+    arch::asm,
+    mem::offset_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const IA32_GS_BASE: u32 = 0xC000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+const PERCPU_ALIGN: usize = 4096;
+
+// カーネル実行中はGS_BASEがこのヘッダを指す
+// ユーザモードから入るときはswapgsでKERNEL_GS_BASEと入れ替える
+#[repr(C)]
+struct CpuHeader {
+    self_ptr: usize,
+    cpu_id: usize,
+    // .percpuセクションのこのCPU用のコピー
+    area: usize,
+}
+
+// CPU番号ごとの.percpuセクションのコピーの先頭アドレス
+static AREAS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+// percpu!で宣言する変数の型. 値そのものは.percpuセクションに置かれたテンプレートで、
+// 各CPUは起動時にコピーした自分用の領域を参照する
+#[repr(transparent)]
+pub struct PerCpu<T> {
+    template: T,
+}
+
+unsafe impl<T: Sync> Sync for PerCpu<T> {}
+
+#[allow(dead_code)]
+impl<T: Sync> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        PerCpu { template: value }
+    }
+
+    fn offset(&self) -> usize {
+        &self.template as *const T as usize - symbol_offsets::__percpu().to_usize()
+    }
+
+    // 現在のCPUの値. 参照を保持している間に別のCPUへ移らないよう、
+    // 割り込み禁止中に使うか、アトミックな操作のみを行う
+    pub fn get(&self) -> &T {
+        let area: usize;
+        unsafe {
+            asm!(
+                "mov {}, gs:[{}]",
+                out(reg) area,
+                const offset_of!(CpuHeader, area),
+                options(nostack, readonly, preserves_flags),
+            );
+            &*((area + self.offset()) as *const T)
+        }
+    }
+
+    // 指定したCPUの値
+    pub fn get_for(&self, cpu: usize) -> &T {
+        let area = AREAS[cpu].load(Ordering::Acquire);
+        assert!(area != 0, "per-CPU area of CPU {} is not initialized", cpu);
+        unsafe { &*((area + self.offset()) as *const T) }
+    }
+
+    // 割り込みを禁止した状態で現在のCPUの値にアクセスする
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let interrupts_enabled = x86::interrupts_enabled();
+        x86::disable_interrupts();
+        let result = f(self.get());
+        if interrupts_enabled {
+            x86::enable_interrupts();
+        }
+        result
+    }
+}
+
+// CPUごとの変数を宣言する
+//
+// percpu! {
+//     static COUNTER: AtomicUsize = AtomicUsize::new(0);
+// }
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[unsafe(link_section = ".percpu")]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )*
+    };
+}
+
+// 各CPUで最初に呼び出し、.percpuセクションをコピーしてGS_BASEを設定する
+pub fn init(cpu: usize) {
+    let template = symbol_offsets::__percpu();
+    let size = MSize::from_address(template, symbol_offsets::__percpu_end()).to_usize();
+    let layout = Layout::from_size_align(size.max(1), PERCPU_ALIGN).unwrap();
+    let area = unsafe { alloc_zeroed(layout) };
+    assert!(!area.is_null(), "Failed to allocate per-CPU area");
+    unsafe { core::ptr::copy_nonoverlapping(template.to_ptr(), area, size) };
+    AREAS[cpu].store(area as usize, Ordering::Release);
+
+    let header = Box::leak(Box::new(CpuHeader {
+        self_ptr: 0,
+        cpu_id: cpu,
+        area: area as usize,
+    }));
+    header.self_ptr = header as *const CpuHeader as usize;
+    x86::write_msr(IA32_GS_BASE, header.self_ptr as u64);
+    x86::write_msr(IA32_KERNEL_GS_BASE, 0);
+}
+
+// 現在のCPUの番号 (BSPが0)
+pub fn cpu_id() -> usize {
+    let cpu: usize;
+    unsafe {
+        asm!(
+            "mov {}, gs:[{}]",
+            out(reg) cpu,
+            const offset_of!(CpuHeader, cpu_id),
+            options(nostack, readonly, preserves_flags),
+        );
+    }
+    cpu
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    percpu! {
+        static TEST_COUNTER: AtomicUsize = AtomicUsize::new(7);
+    }
+
+    #[test_case]
+    fn percpu_value_is_copied_from_template() {
+        TEST_COUNTER.with(|counter| {
+            assert_eq!(counter.load(Ordering::Relaxed), 7);
+            counter.fetch_add(1, Ordering::Relaxed);
+            let cpu = cpu_id();
+            assert!(core::ptr::eq(counter, TEST_COUNTER.get_for(cpu)));
+            assert_eq!(TEST_COUNTER.get_for(cpu).load(Ordering::Relaxed), 8);
+            counter.fetch_sub(1, Ordering::Relaxed);
+        });
+        // テンプレート自体は書き換わらない
+        assert_eq!(TEST_COUNTER.template.load(Ordering::Relaxed), 7);
+    }
+}
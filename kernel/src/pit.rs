@@ -0,0 +1,43 @@
+use crate::x86::{read_io, write_io};
+use core::time::Duration;
+
+// 8254 PIT. チャンネル2はスピーカー用だが、ゲートを操作でき出力を0x61から読めるため
+// 割り込みを使わない短時間のビジーウェイトに利用する
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CONTROL_PORT_B: u16 = 0x61;
+
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+// channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const PIT_CMD_CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+
+// 16bitカウンタで計測できる最大時間 (約54ms) 以下で分割して待つ
+const MAX_WAIT_TICKS: u64 = 0xFFFF;
+
+fn wait_ticks(ticks: u16) {
+    let port_b = read_io(PIT_CONTROL_PORT_B);
+    write_io(
+        PIT_CONTROL_PORT_B,
+        (port_b & !(PORT_B_SPEAKER | PORT_B_GATE2)) | PORT_B_GATE2,
+    );
+    write_io(PIT_COMMAND, PIT_CMD_CHANNEL2_ONESHOT);
+    write_io(PIT_CHANNEL2_DATA, (ticks & 0xFF) as u8);
+    write_io(PIT_CHANNEL2_DATA, (ticks >> 8) as u8);
+    while read_io(PIT_CONTROL_PORT_B) & PORT_B_OUT2 == 0 {
+        core::hint::spin_loop();
+    }
+}
+
+pub fn busy_wait(duration: Duration) {
+    let mut remaining = (duration.as_nanos() as u64 * PIT_FREQUENCY_HZ).div_ceil(1_000_000_000);
+    while remaining > 0 {
+        let ticks = remaining.min(MAX_WAIT_TICKS);
+        wait_ticks(ticks as u16);
+        remaining -= ticks;
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{
+    acpi::{self, Fadt, GenericAddress},
+    error, info, ipi, pit,
+    qemu::{QemuExitCode, exit_qemu},
+    warn, x86,
+};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+// PM1制御レジスタ
+const PM1_SCI_EN: u64 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u64 = 10;
+const PM1_SLP_TYP_MASK: u64 = 0b111 << PM1_SLP_TYP_SHIFT;
+const PM1_SLP_EN: u64 = 1 << 13;
+
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 300;
+
+const KBC_STATUS_PORT: u16 = 0x64;
+const KBC_COMMAND_PORT: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 1 << 1;
+const KBC_CMD_PULSE_RESET: u8 = 0xFE;
+
+// パニック時の動作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    Halt,
+    Reboot,
+    ExitQemu,
+}
+
+impl PanicPolicy {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => PanicPolicy::Reboot,
+            2 => PanicPolicy::ExitQemu,
+            _ => PanicPolicy::Halt,
+        }
+    }
+
+    // ビルド時の環境変数KERNEL_PANIC_POLICY (halt, reboot, exit-qemu) で既定値を変えられる
+    const fn default() -> Self {
+        match option_env!("KERNEL_PANIC_POLICY") {
+            Some(policy) if policy.eq_ignore_ascii_case("reboot") => PanicPolicy::Reboot,
+            Some(policy) if policy.eq_ignore_ascii_case("exit-qemu") => PanicPolicy::ExitQemu,
+            _ => PanicPolicy::Halt,
+        }
+    }
+}
+
+static PANIC_POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::default() as u8);
+
+#[allow(dead_code)]
+pub fn set_panic_policy(policy: PanicPolicy) {
+    PANIC_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn panic_policy() -> PanicPolicy {
+    PanicPolicy::from_u8(PANIC_POLICY.load(Ordering::Relaxed))
+}
+
+// 他のCPUも含めて、割り込みを禁止して停止し続ける
+pub fn halt() -> ! {
+    x86::disable_interrupts();
+    ipi::stop_other_cpus();
+    loop {
+        x86::halt();
+    }
+}
+
+// パニックハンドラから呼び出す (テストのパニックハンドラは常にQEMUを終了する)
+#[allow(dead_code)]
+pub fn on_panic() -> ! {
+    x86::disable_interrupts();
+    ipi::stop_other_cpus();
+    match panic_policy() {
+        PanicPolicy::Halt => halt(),
+        PanicPolicy::Reboot => reboot(),
+        PanicPolicy::ExitQemu => exit_qemu(QemuExitCode::Fail),
+    }
+}
+
+// SMI_CMDにACPI_ENABLEを書き込み、SCI_ENが立つまで待つ (既にACPIモードなら何もしない)
+fn enable_acpi_mode(fadt: &Fadt, pm1a: &GenericAddress) -> Result<(), &'static str> {
+    if pm1a.read()? & PM1_SCI_EN != 0 {
+        return Ok(());
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return Err("ACPI mode cannot be enabled");
+    }
+    x86::write_io(fadt.smi_command_port as u16, fadt.acpi_enable);
+    for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+        if pm1a.read()? & PM1_SCI_EN != 0 {
+            return Ok(());
+        }
+        pit::busy_wait(Duration::from_millis(1));
+    }
+    Err("Timed out enabling ACPI mode")
+}
+
+fn enter_sleep_state(block: &GenericAddress, slp_typ: u8) -> Result<(), &'static str> {
+    let value = block.read()? & !(PM1_SLP_TYP_MASK | PM1_SLP_EN);
+    block.write(value | ((slp_typ as u64) << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN)
+}
+
+fn acpi_shutdown() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("FADT is not available")?;
+    let (slp_typ_a, slp_typ_b) = acpi::s5_sleep_type().ok_or("\\_S5 is not found in DSDT")?;
+    let pm1a = fadt
+        .pm1a_control_block
+        .ok_or("PM1a control block is not available")?;
+    enable_acpi_mode(fadt, &pm1a)?;
+    if let Some(pm1b) = fadt.pm1b_control_block {
+        enter_sleep_state(&pm1b, slp_typ_b)?;
+    }
+    enter_sleep_state(&pm1a, slp_typ_a)?;
+    // 書き込み後すぐに電源が切れるはず
+    pit::busy_wait(Duration::from_millis(100));
+    Err("The system did not power off")
+}
+
+// ACPIのS5で電源を切る. 失敗した場合は停止する
+pub fn shutdown() -> ! {
+    x86::disable_interrupts();
+    ipi::stop_other_cpus();
+    info!("Shutting down");
+    if let Err(e) = acpi_shutdown() {
+        error!("ACPI shutdown failed: {}", e);
+    }
+    halt()
+}
+
+fn acpi_reset() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("FADT is not available")?;
+    let register = fadt
+        .reset_register
+        .ok_or("Reset register is not supported")?;
+    register.write(fadt.reset_value as u64)?;
+    pit::busy_wait(Duration::from_millis(50));
+    Err("The system did not reset")
+}
+
+// キーボードコントローラのリセット線をパルスさせる
+fn kbc_reset() {
+    for _ in 0..0x10000 {
+        if x86::read_io(KBC_STATUS_PORT) & KBC_STATUS_INPUT_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    x86::write_io(KBC_COMMAND_PORT, KBC_CMD_PULSE_RESET);
+    pit::busy_wait(Duration::from_millis(50));
+}
+
+// 空のIDTを読み込んで例外を起こし、トリプルフォールトでリセットする
+fn triple_fault() -> ! {
+    let empty_idt = [0u8; 10];
+    unsafe {
+        asm!(
+            "lidt [{}]",
+            "int3",
+            in(reg) &empty_idt,
+            options(noreturn),
+        );
+    }
+}
+
+// ACPIのリセットレジスタ、8042、トリプルフォールトの順に試して再起動する
+pub fn reboot() -> ! {
+    x86::disable_interrupts();
+    ipi::stop_other_cpus();
+    info!("Rebooting");
+    if let Err(e) = acpi_reset() {
+        warn!("ACPI reset failed: {}", e);
+    }
+    kbc_reset();
+    warn!("Keyboard controller reset failed, forcing a triple fault");
+    triple_fault()
+}
@@ -0,0 +1,413 @@
+use crate::{
+    clock::{self, Instant},
+    info, percpu, smp,
+    spin::{Once, SpinLock},
+    time, warn, x86,
+};
+use alloc::vec::Vec;
+use core::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+// CPUIDの機能ビット
+const CPUID_FEAT_ECX_RDRAND: u32 = 1 << 30;
+const CPUID_EXT_FEAT_EBX_RDSEED: u32 = 1 << 18;
+
+// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+// プールにこれだけのエントロピーが溜まったら鍵を更新する
+const RESEED_BITS: usize = 256;
+// ハードウェアやデバイスから乱数を取り込む間隔
+const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+// この回数の割り込みごとに、タイミングをプールに混ぜる
+const JITTER_BATCH: usize = 64;
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// ChaCha20のブロック関数 (RFC 8439 2.3)
+fn chacha20_block(input: &[u32; 16]) -> [u32; 16] {
+    let mut state = *input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(*input);
+    }
+    state
+}
+
+fn chacha20_keystream(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut input = [0; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    chacha20_block(&input)
+}
+
+// 入力をChaChaのブロック関数で撹拌しながら溜めておく
+struct Pool {
+    state: [u32; 16],
+    // 前回取り出してから加えられたエントロピーの見積もり
+    bits: usize,
+}
+
+impl Pool {
+    fn absorb(&mut self, data: &[u8], bits: usize) {
+        for chunk in data.chunks(32) {
+            for (word, bytes) in self.state[4..12].iter_mut().zip(chunk.chunks(4)) {
+                let mut buf = [0; 4];
+                buf[..bytes.len()].copy_from_slice(bytes);
+                *word ^= u32::from_le_bytes(buf);
+            }
+            self.state = chacha20_block(&self.state);
+        }
+        self.bits = self.bits.saturating_add(bits);
+    }
+
+    // 鍵を取り出す. 取り出した部分はプールに残さない
+    fn extract(&mut self) -> [u32; 8] {
+        let mut output = chacha20_block(&self.state);
+        let mut key = [0; 8];
+        key.copy_from_slice(&output[..8]);
+        output[..8].fill(0);
+        self.state = chacha20_block(&output);
+        self.bits = 0;
+        key
+    }
+}
+
+struct Rng {
+    key: [u32; 8],
+    pool: Pool,
+    // RESEED_BITS以上のエントロピーから鍵を作った
+    seeded: bool,
+    last_refill: Option<Instant>,
+}
+
+impl Rng {
+    // 十分なエントロピーが溜まっていれば鍵を更新する
+    fn reseed(&mut self) {
+        if self.pool.bits < RESEED_BITS {
+            return;
+        }
+        for (key, extracted) in self.key.iter_mut().zip(self.pool.extract()) {
+            *key ^= extracted;
+        }
+        self.seeded = true;
+    }
+
+    // 初期化が済むまでは、溜まったエントロピーの見積もりを残したままプールを鍵に混ぜる
+    // 出力が毎回同じにならないようにするだけで、安全な乱数にはならない
+    fn mix_unseeded(&mut self) {
+        let block = chacha20_block(&self.pool.state);
+        for (key, word) in self.key.iter_mut().zip(block) {
+            *key ^= word;
+        }
+    }
+
+    // 呼び出しごとに鍵を作り直し、後で鍵が漏れても過去の出力は分からないようにする
+    fn generate(&mut self, buf: &mut [u8]) {
+        let next = chacha20_keystream(&self.key, 0);
+        for (counter, chunk) in buf.chunks_mut(64).enumerate() {
+            let block = chacha20_keystream(&self.key, counter as u64 + 1);
+            for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        self.key.copy_from_slice(&next[..8]);
+    }
+}
+
+static RNG: SpinLock<Rng> = SpinLock::new(Rng {
+    key: [0; 8],
+    pool: Pool {
+        state: [
+            CHACHA_CONSTANTS[0],
+            CHACHA_CONSTANTS[1],
+            CHACHA_CONSTANTS[2],
+            CHACHA_CONSTANTS[3],
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ],
+        bits: 0,
+    },
+    seeded: false,
+    last_refill: None,
+});
+
+// デバイスなど、定期的に呼び出してエントロピーを加えてもらう関数
+static SOURCES: SpinLock<Vec<fn()>> = SpinLock::new(Vec::new());
+
+// 割り込みのタイミングを溜めておくバッファ. 割り込みハンドラからはロックを取らずに書き込む
+struct Jitter {
+    samples: [AtomicU64; 4],
+    count: AtomicUsize,
+    // 前回プールに混ぜてからの割り込みの回数
+    pending: AtomicUsize,
+}
+
+impl Jitter {
+    // 溜まったタイミングと、前回取り出してからの割り込みの回数を返す
+    fn take(&self) -> ([u8; 32], usize) {
+        let pending = self.pending.swap(0, Ordering::Relaxed);
+        let mut data = [0; 32];
+        let (chunks, _) = data.as_chunks_mut::<8>();
+        for (chunk, sample) in chunks.iter_mut().zip(&self.samples) {
+            *chunk = sample.load(Ordering::Relaxed).to_le_bytes();
+        }
+        (data, pending)
+    }
+}
+
+percpu! {
+    static JITTER: Jitter = Jitter {
+        samples: [const { AtomicU64::new(0) }; 4],
+        count: AtomicUsize::new(0),
+        pending: AtomicUsize::new(0),
+    };
+}
+
+struct Features {
+    rdrand: bool,
+    rdseed: bool,
+}
+
+static FEATURES: Once<Features> = Once::new();
+
+fn features() -> &'static Features {
+    FEATURES.call_once(|| Features {
+        rdrand: x86::cpuid(1, 0).ecx & CPUID_FEAT_ECX_RDRAND != 0,
+        rdseed: x86::cpuid(0, 0).eax >= 7 && x86::cpuid(7, 0).ebx & CPUID_EXT_FEAT_EBX_RDSEED != 0,
+    })
+}
+
+// bitsはdataに含まれるエントロピーの見積もり (少なめに見積もる)
+// 初期化が済んでいなければ、見積もりがRESEED_BITSに達した時点で鍵を作る
+pub fn add_entropy(data: &[u8], bits: usize) {
+    let mut rng = RNG.lock();
+    rng.pool.absorb(data, bits);
+    if !rng.seeded {
+        rng.reseed();
+    }
+}
+
+// RESEED_BITS以上のエントロピーから鍵を作り、安全な乱数を返せるようになったか
+pub fn is_seeded() -> bool {
+    RNG.lock().seeded
+}
+
+// refillはおおよそREFILL_INTERVALごとにfill_bytesの中から呼ばれる
+pub fn register_source(refill: fn()) {
+    SOURCES.lock().push(refill);
+}
+
+// 1回の割り込みあたり1/8ビットと見積もる
+fn mix_jitter(pool: &mut Pool, jitter: &Jitter) {
+    let (data, count) = jitter.take();
+    if count > 0 {
+        pool.absorb(&data, count / 8);
+    }
+}
+
+// 割り込みの到着時刻の揺らぎを集める. 割り込みハンドラから呼ぶので軽くしておく
+// 割り込まれた側がRNGのロックを持っていることがあるため、ロックは待たない.
+// 取れなければ次の割り込みか、refillでタスクのコンテキストから混ぜる
+pub fn add_interrupt_timing(vector: u64) {
+    let jitter = JITTER.get();
+    let count = jitter.count.fetch_add(1, Ordering::Relaxed);
+    let samples = &jitter.samples;
+    let sample = clock::cycles() ^ (vector << 56);
+    samples[count % samples.len()].fetch_xor(
+        sample.rotate_left((count / samples.len() * 13 % 64) as u32),
+        Ordering::Relaxed,
+    );
+    if jitter.pending.fetch_add(1, Ordering::Relaxed) + 1 >= JITTER_BATCH
+        && let Some(mut rng) = RNG.try_lock()
+    {
+        mix_jitter(&mut rng.pool, jitter);
+    }
+}
+
+// RDSEEDが使えればそれを、なければRDRANDを使う. 取り込んだビット数を返す
+fn add_hardware_entropy() -> usize {
+    let features = features();
+    let read: fn() -> Option<u64> = if features.rdseed {
+        x86::rdseed
+    } else if features.rdrand {
+        x86::rdrand
+    } else {
+        return 0;
+    };
+    let mut data = [0; 32];
+    let (chunks, _) = data.as_chunks_mut::<8>();
+    for chunk in chunks {
+        match read() {
+            Some(value) => *chunk = value.to_le_bytes(),
+            None => return 0,
+        }
+    }
+    add_entropy(&data, RESEED_BITS);
+    RESEED_BITS
+}
+
+fn refill() {
+    // ソースの割り込みハンドラがadd_entropyを呼ぶので、RNGのロックの外で呼び出す
+    let sources = SOURCES.lock().clone();
+    for source in sources {
+        source();
+    }
+    add_hardware_entropy();
+    for cpu in 0..smp::cpu_count() {
+        mix_jitter(&mut RNG.lock().pool, JITTER.get_for(cpu));
+    }
+    let mut data = [0; 16];
+    data[..8].copy_from_slice(&clock::cycles().to_le_bytes());
+    data[8..].copy_from_slice(&Instant::now().as_nanos().to_le_bytes());
+    add_entropy(&data, 0);
+}
+
+// 暗号論的に安全な乱数でbufを埋める
+pub fn fill_bytes(buf: &mut [u8]) {
+    let refill_due = {
+        let mut rng = RNG.lock();
+        let due = rng
+            .last_refill
+            .is_none_or(|last| last.elapsed() >= REFILL_INTERVAL);
+        if due {
+            rng.last_refill = Some(Instant::now());
+        }
+        due
+    };
+    if refill_due {
+        refill();
+    }
+    let mut rng = RNG.lock();
+    rng.reseed();
+    if !rng.seeded {
+        rng.mix_unseeded();
+    }
+    rng.generate(buf);
+}
+
+pub fn next_u64() -> u64 {
+    let mut buf = [0; 8];
+    fill_bytes(&mut buf);
+    u64::from_le_bytes(buf)
+}
+
+// clock::initの後に呼び出す
+pub fn init() {
+    let features = features();
+    let bits = add_hardware_entropy();
+    let mut data = [0; 16];
+    data[..8].copy_from_slice(&clock::cycles().to_le_bytes());
+    data[8..].copy_from_slice(&time::unix_timestamp().to_le_bytes());
+    add_entropy(&data, 0);
+    if bits == 0 {
+        warn!(
+            "random: no hardware RNG, not seeded until devices and interrupt timing supply enough entropy"
+        );
+    }
+    info!(
+        "random: RDRAND {}, RDSEED {}",
+        if features.rdrand {
+            "available"
+        } else {
+            "unavailable"
+        },
+        if features.rdseed {
+            "available"
+        } else {
+            "unavailable"
+        }
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn chacha20_block_test_vector() {
+        // RFC 8439 2.3.2
+        let mut input = [0; 16];
+        input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+        for (i, word) in input[4..12].iter_mut().enumerate() {
+            let base = (i * 4) as u8;
+            *word = u32::from_le_bytes([base, base + 1, base + 2, base + 3]);
+        }
+        input[12] = 1;
+        input[13] = 0x0900_0000;
+        input[14] = 0x4a00_0000;
+        let output = chacha20_block(&input);
+        assert_eq!(output[0], 0xe4e7_f110);
+        assert_eq!(output[1], 0x1559_3bd1);
+        assert_eq!(output[15], 0x4e3c_50a2);
+    }
+
+    #[test_case]
+    fn fill_bytes_does_not_repeat() {
+        let mut a = [0; 100];
+        let mut b = [0; 100];
+        fill_bytes(&mut a);
+        fill_bytes(&mut b);
+        assert_ne!(a, b);
+        assert!(a.iter().any(|&byte| byte != 0));
+    }
+
+    #[test_case]
+    fn seeded_only_after_enough_entropy() {
+        let mut rng = Rng {
+            key: [0; 8],
+            pool: Pool {
+                state: [0; 16],
+                bits: 0,
+            },
+            seeded: false,
+            last_refill: None,
+        };
+        rng.pool.absorb(&[1; 32], RESEED_BITS - 1);
+        rng.reseed();
+        assert!(!rng.seeded);
+        assert_eq!(rng.key, [0; 8]);
+
+        // 見積もりを残したまま出力は変わる
+        rng.mix_unseeded();
+        assert_ne!(rng.key, [0; 8]);
+        assert_eq!(rng.pool.bits, RESEED_BITS - 1);
+
+        rng.pool.absorb(&[2; 32], 1);
+        rng.reseed();
+        assert!(rng.seeded);
+        assert_eq!(rng.pool.bits, 0);
+    }
+}
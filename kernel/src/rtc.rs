@@ -0,0 +1,262 @@
+use crate::{
+    acpi,
+    x86::{read_io, write_io},
+};
+use core::fmt;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// アドレスポートの最上位ビットはNMIの無効化
+const CMOS_NMI_DISABLE: u8 = 1 << 7;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+// FADTに世紀のレジスタがなければ2000年代とみなす
+const DEFAULT_CENTURY: u16 = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // ref: http://howardhinnant.github.io/date_algorithms.html (days_from_civil)
+    fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let mp = (month + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    // ref: http://howardhinnant.github.io/date_algorithms.html (civil_from_days)
+    fn civil_from_days(days: i64) -> (i64, i64, i64) {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year, month, day)
+    }
+
+    pub fn to_unix_timestamp(self) -> u64 {
+        let days =
+            Self::days_from_civil(self.year as i64, self.month as i64, self.day as i64).max(0);
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix_timestamp(secs: u64) -> Self {
+        let (year, month, day) = Self::civil_from_days((secs / 86400) as i64);
+        let secs_of_day = secs % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+// 読み出す間だけNMIを無効にし、最後に最上位ビットを落としたインデックスを書いて有効に戻す
+fn read_register(reg: u8) -> u8 {
+    write_io(CMOS_ADDRESS, CMOS_NMI_DISABLE | reg);
+    let value = read_io(CMOS_DATA);
+    write_io(CMOS_ADDRESS, reg);
+    value
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    // FADTのcenturyが示すCMOSのレジスタの値
+    century: Option<u8>,
+}
+
+fn read_raw() -> RawTime {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: acpi::fadt()
+            .map(|fadt| fadt.century)
+            .filter(|&reg| reg != 0)
+            .map(read_register),
+    }
+}
+
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12時間表記: 12AM = 0時, 12PM = 12時
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        year: raw
+            .century
+            .map_or(DEFAULT_CENTURY, |century| convert(century) as u16 * 100)
+            + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+// 更新中の値を読まないよう、同じ値が2回続けて読めるまで繰り返す
+pub fn read() -> DateTime {
+    let mut last = read_raw();
+    loop {
+        let current = read_raw();
+        if current == last {
+            break;
+        }
+        last = current;
+    }
+    decode(last, read_register(REG_STATUS_B))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn bcd_decoding() {
+        let raw = RawTime {
+            second: 0x59,
+            minute: 0x30,
+            hour: 0x12 | HOUR_PM,
+            day: 0x18,
+            month: 0x10,
+            year: 0x26,
+            century: None,
+        };
+        let time = decode(raw, 0);
+        assert_eq!(
+            time,
+            DateTime {
+                year: 2026,
+                month: 10,
+                day: 18,
+                hour: 12,
+                minute: 30,
+                second: 59,
+            }
+        );
+        let time = decode(RawTime { hour: 0x12, ..raw }, 0);
+        assert_eq!(time.hour, 0);
+    }
+
+    #[test_case]
+    fn binary_decoding() {
+        let raw = RawTime {
+            second: 5,
+            minute: 4,
+            hour: 23,
+            day: 1,
+            month: 2,
+            year: 24,
+            century: None,
+        };
+        let time = decode(raw, STATUS_B_BINARY | STATUS_B_24_HOUR);
+        assert_eq!(time.year, 2024);
+        assert_eq!(time.hour, 23);
+        assert_eq!(time.second, 5);
+    }
+
+    #[test_case]
+    fn century_register() {
+        let raw = RawTime {
+            second: 0,
+            minute: 0,
+            hour: 0,
+            day: 1,
+            month: 1,
+            year: 0x99,
+            century: Some(0x19),
+        };
+        assert_eq!(decode(raw, STATUS_B_24_HOUR).year, 1999);
+        let raw = RawTime {
+            year: 5,
+            century: Some(21),
+            ..raw
+        };
+        assert_eq!(decode(raw, STATUS_B_BINARY).year, 2105);
+    }
+
+    #[test_case]
+    fn unix_timestamp_roundtrip() {
+        let epoch = DateTime::from_unix_timestamp(0);
+        assert_eq!(epoch.year, 1970);
+        assert_eq!(epoch.to_unix_timestamp(), 0);
+
+        let leap_day = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 12,
+            minute: 0,
+            second: 0,
+        };
+        assert_eq!(leap_day.to_unix_timestamp(), 1_709_208_000);
+        assert_eq!(DateTime::from_unix_timestamp(1_709_208_000), leap_day);
+    }
+}
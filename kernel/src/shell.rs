@@ -0,0 +1,498 @@
+use crate::{
+    acpi, allocator, dhcp, dns, frame, idt, memlayout::VirtAddr, net, paging, pci, pcid, power,
+    spin::SpinLock, task, tty, virtio_console, wasm, x86,
+};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::{fmt::Write, time::Duration};
+
+const PROMPT: &str = "kernel> ";
+// ホストからソケットでつなぐvirtio-consoleのポート (Taskfile.ymlのQEMU_OPTS)
+const SHELL_PORT_NAME: &str = "kernel.shell";
+
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+const PING_DEFAULT_COUNT: usize = 4;
+
+// 出力先への書き込みは失敗しないものとして扱う
+macro_rules! outln {
+    ($out:expr) => {
+        let _ = writeln!($out);
+    };
+    ($out:expr, $($arg:tt)*) => {
+        let _ = writeln!($out, $($arg)*);
+    };
+}
+
+pub struct Command {
+    pub name: &'static str,
+    // 引数の説明 (helpで表示する)
+    pub usage: &'static str,
+    pub description: &'static str,
+    // argsにコマンド名は含まない
+    pub run: fn(args: &[&str], out: &mut dyn Write) -> Result<(), &'static str>,
+}
+
+static COMMANDS: SpinLock<BTreeMap<&'static str, &'static Command>> =
+    SpinLock::new(BTreeMap::new());
+
+// 同じ名前のコマンドがあれば置き換える
+pub fn register(command: &'static Command) {
+    COMMANDS.lock().insert(command.name, command);
+}
+
+pub fn execute(line: &str, out: &mut dyn Write) -> Result<(), &'static str> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = args.split_first() else {
+        return Ok(());
+    };
+    let command = COMMANDS
+        .lock()
+        .get(name)
+        .copied()
+        .ok_or("Unknown command (try help)")?;
+    (command.run)(args, out)
+}
+
+// 0x付きまたはなしの16進数
+fn parse_hex(s: &str) -> Result<usize, &'static str> {
+    let digits = s.strip_prefix("0x").unwrap_or(s).replace('_', "");
+    usize::from_str_radix(&digits, 16).map_err(|_| "Invalid hexadecimal number")
+}
+
+fn help(_args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    let commands: Vec<&'static Command> = COMMANDS.lock().values().copied().collect();
+    for command in commands {
+        let usage = alloc::format!("{} {}", command.name, command.usage);
+        outln!(out, "  {:<22} {}", usage.trim_end(), command.description);
+    }
+    Ok(())
+}
+
+fn ps(_args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    outln!(out, "{:>5}  STATE", "PID");
+    for task in task::tasks().iter() {
+        let task = task.lock();
+        let state = match (task.is_running(), task.is_runnable()) {
+            (true, _) => "running",
+            (false, true) => "runnable",
+            (false, false) => "stopped",
+        };
+        outln!(out, "{:>5}  {}", task.pid().to_u32(), state);
+    }
+    Ok(())
+}
+
+fn mem(_args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    let (heap_used, heap_size) = allocator::usage();
+    let (free_frames, total_frames) = frame::stats();
+    outln!(
+        out,
+        "heap:   {} KiB used / {} KiB",
+        heap_used / 1024,
+        heap_size / 1024
+    );
+    outln!(
+        out,
+        "frames: {} free / {} ({} KiB free)",
+        free_frames,
+        total_frames,
+        free_frames * frame::FRAME_SIZE / 1024
+    );
+    Ok(())
+}
+
+fn pt(args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    let [addr] = args else {
+        return Err("Usage: pt <vaddr>");
+    };
+    let virt = VirtAddr::new(parse_hex(addr)?);
+    let levels = ["PML4", "PDPT", "PD", "PT"];
+    for (level, entry) in levels.iter().zip(paging::walk(virt)) {
+        outln!(out, "{:<4} {}", level, entry);
+    }
+    Ok(())
+}
+
+fn switchcost(_args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    let (before, after) = pcid::measure_switch_cost().ok_or("PCID is not supported")?;
+    outln!(out, "without PCID/global pages: {} cycles", before);
+    outln!(out, "with PCID/global pages:    {} cycles", after);
+    Ok(())
+}
+
+fn irq(_args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    outln!(out, "{:>6} {:>10}", "VECTOR", "COUNT");
+    for vector in 0..=u8::MAX {
+        let count = idt::interrupt_count(vector);
+        if count > 0 {
+            outln!(out, "{:>6} {:>10}", vector, count);
+        }
+    }
+    Ok(())
+}
+
+fn lsacpi(_args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    let tables = acpi::tables().ok_or("ACPI is not available")?;
+    outln!(out, "revision {}", tables.revision);
+    for signature in &tables.signatures {
+        outln!(
+            out,
+            "  {}",
+            core::str::from_utf8(signature).unwrap_or("????")
+        );
+    }
+    if let Some(madt) = &tables.madt {
+        outln!(
+            out,
+            "MADT: {} local APICs ({} usable), {} I/O APICs, {} overrides",
+            madt.local_apics.len(),
+            madt.usable_apic_ids().len(),
+            madt.io_apics.len(),
+            madt.interrupt_overrides.len()
+        );
+    }
+    if let Some(hpet) = &tables.hpet {
+        outln!(out, "HPET: base {:#x}", hpet.base_address.address);
+    }
+    if let Some(mcfg) = &tables.mcfg {
+        for entry in &mcfg.entries {
+            outln!(
+                out,
+                "MCFG: segment {} buses {}-{} at {:#x}",
+                entry.segment,
+                entry.start_bus,
+                entry.end_bus,
+                entry.base_address
+            );
+        }
+    }
+    Ok(())
+}
+
+fn lspci(_args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    for device in pci::devices() {
+        outln!(
+            out,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if
+        );
+    }
+    Ok(())
+}
+
+fn wasm(args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    match args {
+        ["run", name] => {
+            let (_, program) = wasm::PROGRAMS
+                .iter()
+                .find(|(program, _)| program == name)
+                .ok_or("No such program")?;
+            program(out);
+            Ok(())
+        }
+        ["ls"] => {
+            for (name, _) in wasm::PROGRAMS {
+                outln!(out, "{}", name);
+            }
+            Ok(())
+        }
+        _ => Err("Usage: wasm run <name> | wasm ls"),
+    }
+}
+
+fn ifconfig(_args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    let mac = net::mac_address().ok_or("No network interface")?;
+    outln!(out, "ether {}", mac);
+    if let Some(config) = net::config() {
+        outln!(
+            out,
+            "inet {} netmask {} gateway {}",
+            config.address,
+            config.netmask,
+            config.gateway
+        );
+        if let Some(dns) = config.dns {
+            outln!(out, "dns {}", dns);
+        }
+    }
+    if let Some(lease) = dhcp::lease() {
+        let remaining = lease.lease_time.saturating_sub(lease.acquired_at.elapsed());
+        outln!(
+            out,
+            "dhcp server {}, lease expires in {}s",
+            lease.server,
+            remaining.as_secs()
+        );
+    }
+    Ok(())
+}
+
+fn nslookup(args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    let [name] = args else {
+        return Err("Usage: nslookup <name>");
+    };
+    for address in dns::lookup(name)? {
+        outln!(out, "{} has address {}", name, address);
+    }
+    Ok(())
+}
+
+fn ping(args: &[&str], out: &mut dyn Write) -> Result<(), &'static str> {
+    let (host, count) = match args {
+        [host] => (host, PING_DEFAULT_COUNT),
+        [host, count] => (host, count.parse().map_err(|_| "Invalid count")?),
+        _ => return Err("Usage: ping <host> [count]"),
+    };
+    let address = dns::resolve(host)?;
+    let console = tty::console();
+    for seq in 0..count {
+        if console.take_interrupt() {
+            break;
+        }
+        match net::ping(address, PING_TIMEOUT) {
+            Ok(rtt) => {
+                outln!(
+                    out,
+                    "reply from {}: seq={} time={}us",
+                    address,
+                    seq,
+                    rtt.as_micros()
+                );
+            }
+            Err(e) => {
+                outln!(out, "{}: seq={} {}", address, seq, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn reboot(_args: &[&str], _out: &mut dyn Write) -> Result<(), &'static str> {
+    power::reboot()
+}
+
+fn shutdown(_args: &[&str], _out: &mut dyn Write) -> Result<(), &'static str> {
+    power::shutdown()
+}
+
+static BUILTIN_COMMANDS: [Command; 14] = [
+    Command {
+        name: "help",
+        usage: "",
+        description: "List commands",
+        run: help,
+    },
+    Command {
+        name: "ps",
+        usage: "",
+        description: "List tasks",
+        run: ps,
+    },
+    Command {
+        name: "mem",
+        usage: "",
+        description: "Show heap and physical frame usage",
+        run: mem,
+    },
+    Command {
+        name: "pt",
+        usage: "<vaddr>",
+        description: "Walk the page table entries for an address",
+        run: pt,
+    },
+    Command {
+        name: "irq",
+        usage: "",
+        description: "Show interrupt counts per vector",
+        run: irq,
+    },
+    Command {
+        name: "switchcost",
+        usage: "",
+        description: "Measure the address space switch cost with and without PCID",
+        run: switchcost,
+    },
+    Command {
+        name: "lsacpi",
+        usage: "",
+        description: "List ACPI tables",
+        run: lsacpi,
+    },
+    Command {
+        name: "lspci",
+        usage: "",
+        description: "List PCI devices",
+        run: lspci,
+    },
+    Command {
+        name: "wasm",
+        usage: "run <name> | ls",
+        description: "Run a built-in wasm program",
+        run: wasm,
+    },
+    Command {
+        name: "ifconfig",
+        usage: "",
+        description: "Show the network configuration",
+        run: ifconfig,
+    },
+    Command {
+        name: "nslookup",
+        usage: "<name>",
+        description: "Resolve a host name",
+        run: nslookup,
+    },
+    Command {
+        name: "ping",
+        usage: "<host> [count]",
+        description: "Send ICMP echo requests",
+        run: ping,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        description: "Reboot the machine",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        usage: "",
+        description: "Power off the machine",
+        run: shutdown,
+    },
+];
+
+// シリアルのTTYへの出力
+struct TtyWriter(&'static tty::Tty);
+
+impl Write for TtyWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+// virtio-consoleのポートへの出力. ホストがつないでいなければ捨てられる
+struct PortWriter(&'static virtio_console::Port);
+
+impl Write for PortWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let _ = self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+// 1行を実行し、失敗したらコマンド名とエラーを表示する
+fn run_line(line: &str, out: &mut dyn Write) {
+    if let Err(e) = execute(line, out) {
+        outln!(
+            out,
+            "{}: {}",
+            line.split_whitespace().next().unwrap_or(""),
+            e
+        );
+    }
+}
+
+fn shell_task() {
+    let console = tty::console();
+    let mut out = TtyWriter(console);
+    loop {
+        console.write_str(PROMPT);
+        let line: String = match console.read_line() {
+            Ok(line) => line,
+            // Ctrl-Cで入力を取り消した
+            Err(_) => continue,
+        };
+        run_line(&line, &mut out);
+        // コマンドの実行中に押されたCtrl-Cは次の入力に持ち越さない
+        console.take_interrupt();
+    }
+}
+
+// ポートの受信は割り込みを使わないため、入力がなければ次の割り込みまで止まって読み直す
+// 行の編集はホスト側 (socatなど) に任せる
+fn port_shell_task() {
+    let port = virtio_console::port(SHELL_PORT_NAME).unwrap();
+    let mut out = PortWriter(port);
+    let mut line = Vec::new();
+    let _ = port.write(PROMPT.as_bytes());
+    loop {
+        let mut buf = [0; 256];
+        let len = port.read(&mut buf);
+        if len == 0 {
+            x86::halt();
+            continue;
+        }
+        for &byte in &buf[..len] {
+            match byte {
+                b'\r' => {}
+                b'\n' => {
+                    run_line(&String::from_utf8_lossy(&line), &mut out);
+                    line.clear();
+                    let _ = port.write(PROMPT.as_bytes());
+                }
+                _ => line.push(byte),
+            }
+        }
+    }
+}
+
+// tty::initの後、タスクを動かせるようになってから呼び出す
+fn register_builtin_commands() {
+    for command in &BUILTIN_COMMANDS {
+        register(command);
+    }
+}
+
+// テストではタスクを起動しない (main.rs)
+#[allow(dead_code)]
+pub fn init() {
+    register_builtin_commands();
+    task::spawn(shell_task);
+    if virtio_console::port(SHELL_PORT_NAME).is_some() {
+        task::spawn(port_shell_task);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn builtin_commands_run() {
+        register_builtin_commands();
+        let mut out = String::new();
+        execute("help", &mut out).unwrap();
+        assert!(out.contains("lspci"));
+        assert!(out.contains("pt <vaddr>"));
+
+        out.clear();
+        execute("  mem ", &mut out).unwrap();
+        assert!(out.starts_with("heap:"));
+
+        assert_eq!(execute("", &mut out), Ok(()));
+        assert_eq!(
+            execute("frobnicate", &mut out),
+            Err("Unknown command (try help)")
+        );
+    }
+
+    #[test_case]
+    fn pt_walks_kernel_mapping() {
+        register_builtin_commands();
+        let mut out = String::new();
+        let addr = alloc::format!("{:#x}", shell_task as fn() as usize);
+        execute(&alloc::format!("pt {}", addr), &mut out).unwrap();
+        // カーネルのコードは4KiBページでマップされている
+        assert_eq!(out.lines().count(), 4);
+        assert!(out.lines().all(|line| line.contains("present: true")));
+        assert_eq!(parse_hex("ffff_8000"), Ok(0xffff_8000));
+        assert!(parse_hex("xyz").is_err());
+        assert!(execute("pt", &mut out).is_err());
+    }
+}
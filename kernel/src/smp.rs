@@ -0,0 +1,316 @@
+use crate::{
+    acpi,
+    apic::{self, ApicMode, IpiDest, IpiKind},
+    bootinfo, gdt, idt, info, ipi,
+    memlayout::{Address, MSize, PhysAddr, VirtAddr, phys_to_virt},
+    paging, pcid, percpu, pit, task, timer, warn, x86,
+};
+use alloc::vec;
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+pub const MAX_CPUS: usize = 16;
+
+const AP_STACK_SIZE: usize = 4096 * 4;
+const AP_STARTUP_TIMEOUT_MS: u64 = 100;
+
+// APは実モードでトランポリンページの先頭 (CS = ページ番号 << 8, IP = 0) から実行を開始する
+// 保護モード -> ロングモードと移行し、BSPが書き込んだスタックとページテーブルでap_mainを呼ぶ
+// このコードは位置独立でなければならないため、絶対アドレスはすべてBSPがコピー後に書き込む
+global_asm!(
+    r#"
+.section .text.ap_trampoline, "ax"
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    // 以降のモードでもこのページの物理アドレスを参照できるようにebxに保持する
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+    lgdt [TRAMPOLINE_GDTR]
+    mov eax, cr0
+    or eax, 0x1
+    mov cr0, eax
+    // ljmpl 0x08:ap_trampoline_protected
+    .byte 0x66, 0xEA
+.global ap_trampoline_protected_jump
+ap_trampoline_protected_jump:
+    .long 0
+    .word 0x08
+
+.code32
+.global ap_trampoline_protected
+ap_trampoline_protected:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    // CR4.PAE
+    mov eax, cr4
+    or eax, 0x20
+    mov cr4, eax
+    mov eax, [ebx + TRAMPOLINE_CR3]
+    mov cr3, eax
+    // EFER.LME | EFER.NXE
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 0x900
+    wrmsr
+    // CR0.PG | CR0.WP
+    mov eax, cr0
+    or eax, 0x80010000
+    mov cr0, eax
+    // ljmp 0x18:ap_trampoline_long
+    .byte 0xEA
+.global ap_trampoline_long_jump
+ap_trampoline_long_jump:
+    .long 0
+    .word 0x18
+
+.code64
+.global ap_trampoline_long
+ap_trampoline_long:
+    mov ebx, ebx
+    mov rsp, [rbx + TRAMPOLINE_STACK]
+    mov rdi, [rbx + TRAMPOLINE_ARG]
+    mov rax, [rbx + TRAMPOLINE_ENTRY]
+    call rax
+    ud2
+
+.balign 8
+.global ap_trampoline_gdt
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    .quad 0x00AF9A000000FFFF
+.global ap_trampoline_gdtr
+ap_trampoline_gdtr:
+    .word 4 * 8 - 1
+    .long 0
+
+.balign 8
+.global ap_trampoline_cr3
+ap_trampoline_cr3:
+    .quad 0
+.global ap_trampoline_stack
+ap_trampoline_stack:
+    .quad 0
+.global ap_trampoline_entry
+ap_trampoline_entry:
+    .quad 0
+.global ap_trampoline_arg
+ap_trampoline_arg:
+    .quad 0
+.global ap_trampoline_end
+ap_trampoline_end:
+
+// ページ先頭からのオフセット
+.set TRAMPOLINE_GDTR, ap_trampoline_gdtr - ap_trampoline_start
+.set TRAMPOLINE_CR3, ap_trampoline_cr3 - ap_trampoline_start
+.set TRAMPOLINE_STACK, ap_trampoline_stack - ap_trampoline_start
+.set TRAMPOLINE_ENTRY, ap_trampoline_entry - ap_trampoline_start
+.set TRAMPOLINE_ARG, ap_trampoline_arg - ap_trampoline_start
+.previous
+"#
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_protected_jump: u8;
+    static ap_trampoline_protected: u8;
+    static ap_trampoline_long_jump: u8;
+    static ap_trampoline_long: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_arg: u8;
+    static ap_trampoline_end: u8;
+}
+
+// コピー先のトランポリンページ
+struct Trampoline {
+    phys: PhysAddr,
+}
+
+impl Trampoline {
+    // 起動コード内のシンボルの、ページ先頭からのオフセット
+    fn offset(symbol: *const u8) -> usize {
+        symbol as usize - (&raw const ap_trampoline_start) as usize
+    }
+
+    fn write<T>(&self, offset: usize, value: T) {
+        let addr = phys_to_virt(self.phys).to_usize() + offset;
+        unsafe { core::ptr::write_unaligned(addr as *mut T, value) }
+    }
+
+    // 起動コードをコピーし、ページの物理アドレスに依存する値を書き込む
+    fn install(phys: PhysAddr) -> Self {
+        let trampoline = Trampoline { phys };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                &raw const ap_trampoline_start,
+                phys_to_virt(phys).to_ptr_mut(),
+                Self::offset(&raw const ap_trampoline_end),
+            );
+        }
+        let base = phys.to_usize() as u32;
+        trampoline.write(
+            Self::offset(&raw const ap_trampoline_protected_jump),
+            base + Self::offset(&raw const ap_trampoline_protected) as u32,
+        );
+        trampoline.write(
+            Self::offset(&raw const ap_trampoline_long_jump),
+            base + Self::offset(&raw const ap_trampoline_long) as u32,
+        );
+        // GDTRのベース (limitの後ろ)
+        trampoline.write(
+            Self::offset(&raw const ap_trampoline_gdtr) + 2,
+            base + Self::offset(&raw const ap_trampoline_gdt) as u32,
+        );
+
+        // CR3は32bitモードで設定するため、PML4は4GiB未満に置かれている必要がある
+        // 下位12bitはPCIDのため取り除く
+        let cr3 = x86::read_cr3() & !0xFFF;
+        assert!(cr3 < 1 << 32, "PML4 must be below 4 GiB to start APs");
+        trampoline.write(Self::offset(&raw const ap_trampoline_cr3), cr3 as u64);
+        let entry: extern "C" fn(usize) -> ! = ap_main;
+        trampoline.write(
+            Self::offset(&raw const ap_trampoline_entry),
+            entry as usize as u64,
+        );
+        // ページングを有効にした直後はこのページ上で実行しているため、恒等マップしておく
+        paging::identity_map(phys, MSize::new(4096)).expect("Failed to map the AP trampoline");
+        trampoline
+    }
+
+    fn prepare(&self, stack_top: usize, cpu: usize) {
+        self.write(
+            Self::offset(&raw const ap_trampoline_stack),
+            stack_top as u64,
+        );
+        self.write(Self::offset(&raw const ap_trampoline_arg), cpu as u64);
+    }
+
+    fn page_number(&self) -> u8 {
+        (self.phys.to_usize() >> 12) as u8
+    }
+
+    // 全APがap_mainに到達した後は不要なので、恒等マップを外す
+    // グローバルなマッピングのためCR3の切り替えでは消えず、全CPUのTLBから無効化する
+    fn remove(self) {
+        let virt = VirtAddr::new(self.phys.to_usize());
+        paging::unmap(virt, MSize::new(4096)).expect("Failed to unmap the AP trampoline");
+        ipi::tlb_shootdown(virt.to_usize(), 1);
+    }
+}
+
+// CPU番号 (BSPが0) ごとのLocal APIC ID
+static CPU_APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+// 現在のCPUの番号
+pub fn cpu_id() -> usize {
+    percpu::cpu_id()
+}
+
+pub fn apic_id(cpu: usize) -> u32 {
+    CPU_APIC_IDS[cpu].load(Ordering::Relaxed)
+}
+
+extern "C" fn ap_main(cpu: usize) -> ! {
+    let _gdt = gdt::init_gdt();
+    // セグメントレジスタの再読み込みでGS_BASEが消えるため、GDTの後に設定する
+    percpu::init(cpu);
+    pcid::init(false);
+    paging::init_pat();
+    idt::load();
+    apic::local().enable();
+    timer::init_timer();
+    task::init_ap();
+    info!("CPU {} (APIC ID {}) started", cpu, apic::local().id());
+    AP_STARTED.store(true, Ordering::Release);
+
+    x86::enable_interrupts();
+    timer::start_scheduler_tick();
+    loop {
+        x86::halt();
+    }
+}
+
+// INIT-SIPI-SIPIでAPを起動し、ap_mainに到達するまで待つ
+fn start_ap(trampoline: &Trampoline, apic_id: u32) -> bool {
+    let cpu = CPU_COUNT.load(Ordering::Relaxed);
+    CPU_APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+    CPU_COUNT.store(cpu + 1, Ordering::Release);
+
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let stack_top = (stack.as_ptr() as usize + AP_STACK_SIZE) & !0xF;
+    trampoline.prepare(stack_top, cpu);
+    AP_STARTED.store(false, Ordering::Release);
+
+    let apic = apic::local();
+    let dest = IpiDest::Apic(apic_id);
+    apic.send_ipi(dest, IpiKind::Init);
+    // x2APICモードではINITのデアサートはサポートされていない
+    if apic.mode() == ApicMode::XApic {
+        apic.send_ipi(dest, IpiKind::InitDeassert);
+    }
+    pit::busy_wait(Duration::from_millis(10));
+    for _ in 0..2 {
+        apic.send_ipi(dest, IpiKind::Startup(trampoline.page_number()));
+        pit::busy_wait(Duration::from_micros(200));
+        if AP_STARTED.load(Ordering::Acquire) {
+            break;
+        }
+    }
+    for _ in 0..AP_STARTUP_TIMEOUT_MS {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        pit::busy_wait(Duration::from_millis(1));
+    }
+
+    warn!("CPU with APIC ID {} did not start", apic_id);
+    CPU_COUNT.store(cpu, Ordering::Release);
+    false
+}
+
+// MADTに記載されたAPを順に起動する
+pub fn init() {
+    let bsp_id = apic::local().id();
+    CPU_APIC_IDS[0].store(bsp_id, Ordering::Relaxed);
+
+    let boot_info = bootinfo::get();
+    let (Some(madt), true) = (acpi::madt(), boot_info.ap_trampoline != 0) else {
+        warn!("MADT or AP trampoline is not available, running on the BSP only");
+        return;
+    };
+    let trampoline = Trampoline::install(PhysAddr::new(boot_info.ap_trampoline as usize));
+    for apic_id in madt.usable_apic_ids() {
+        if apic_id == bsp_id {
+            continue;
+        }
+        if cpu_count() >= MAX_CPUS {
+            warn!("Too many CPUs, ignoring APIC ID {}", apic_id);
+            continue;
+        }
+        start_ap(&trampoline, apic_id);
+    }
+    trampoline.remove();
+    info!("{} CPU(s) online", cpu_count());
+}
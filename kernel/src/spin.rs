@@ -1,8 +1,11 @@
 // Reference: 「詳解Rustアトミック操作とロック」(オライリー・ジャパン ISBN978-4-8144-0051-5)
 use core::arch::asm;
 use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::x86;
 
 pub struct SpinLock<T> {
     locked: AtomicBool,
@@ -17,12 +20,34 @@ impl<T> SpinLock<T> {
         }
     }
 
+    // ロックを取得する前に割り込みを禁止し、ガードの解放時に元の状態に戻す
+    // (ネストしたロックの内側の解放で割り込みが有効にならないようにする)
     pub fn lock(&self) -> SpinGuard<T> {
+        let interrupts_enabled = x86::interrupts_enabled();
+        unsafe { asm!("cli") }
         while self.locked.swap(true, Ordering::Acquire) {
             core::hint::spin_loop();
         }
-        unsafe { asm!("cli") } // TODO: fix ロックの取得-`cli`までの間に割り込まれる可能性があり不十分
-        SpinGuard { lock: self }
+        SpinGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    // 既にロックされていれば待たずにNoneを返す (パニック時の出力など、待つと戻れない場所で使う)
+    pub fn try_lock(&self) -> Option<SpinGuard<'_, T>> {
+        let interrupts_enabled = x86::interrupts_enabled();
+        unsafe { asm!("cli") }
+        if self.locked.swap(true, Ordering::Acquire) {
+            if interrupts_enabled {
+                unsafe { asm!("sti") }
+            }
+            return None;
+        }
+        Some(SpinGuard {
+            lock: self,
+            interrupts_enabled,
+        })
     }
 }
 
@@ -30,6 +55,7 @@ unsafe impl<T> Sync for SpinLock<T> where T: Send {}
 
 pub struct SpinGuard<'a, T: 'a> {
     lock: &'a SpinLock<T>,
+    interrupts_enabled: bool,
 }
 
 impl<T> Deref for SpinGuard<'_, T> {
@@ -49,9 +75,60 @@ impl<T> DerefMut for SpinGuard<'_, T> {
 impl<'a, T> Drop for SpinGuard<'a, T> {
     fn drop(&mut self) {
         self.lock.locked.store(false, Ordering::Release);
-        unsafe { asm!("sti") }
+        if self.interrupts_enabled {
+            unsafe { asm!("sti") }
+        }
     }
 }
 
 unsafe impl<T> Send for SpinGuard<'_, T> where T: Send {}
 unsafe impl<T> Sync for SpinGuard<'_, T> where T: Send {}
+
+const ONCE_INCOMPLETE: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_COMPLETE: u8 = 2;
+
+// 一度だけ初期化される値 (ハードウェアの検出結果など) を保持する
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(ONCE_INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        match self.state.compare_exchange(
+            ONCE_INCOMPLETE,
+            ONCE_RUNNING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                unsafe { (*self.value.get()).write(f()) };
+                self.state.store(ONCE_COMPLETE, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != ONCE_COMPLETE {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == ONCE_COMPLETE {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl<T> Sync for Once<T> where T: Send + Sync {}
@@ -0,0 +1,153 @@
+use crate::{
+    clock::Instant,
+    memlayout::VirtAddr,
+    paging, random,
+    time::{self, SystemTime},
+};
+
+// int 0x80で呼び出す. 番号はrax、引数はrdi, rsi, rdxの順で、戻り値はraxに入る
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+// 番号とエラー番号はLinuxに合わせる
+pub const SYS_TIME: u64 = 201;
+pub const SYS_CLOCK_GETTIME: u64 = 228;
+pub const SYS_GETRANDOM: u64 = 318;
+
+const EAGAIN: i64 = 11;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOSYS: i64 = 38;
+
+// clock_gettimeの時計
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+
+// getrandomのフラグ (GRND_NONBLOCK, GRND_RANDOM, GRND_INSECURE)
+const GRND_MASK: u64 = 0b111;
+const GRND_INSECURE: u64 = 0b100;
+// 割り込みを禁止したまま処理するので、1回で返す量を制限する
+const GETRANDOM_MAX_LEN: usize = 4096;
+
+// ユーザ空間 (下位半分) の終わり
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+// 成功すれば0以上の値を、失敗すれば負のエラー番号を返す
+pub fn dispatch(number: u64, args: [u64; 3], from_user: bool) -> i64 {
+    match number {
+        SYS_TIME => sys_time(args[0], from_user),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(args[0], args[1], from_user),
+        SYS_GETRANDOM => sys_getrandom(args[0], args[1], args[2], from_user),
+        _ => -ENOSYS,
+    }
+}
+
+// 呼び出し元が書き込めるバッファか. ユーザモードからは下位半分のみ許す
+// 書き込みでページフォールトを起こさないよう、各ページがマップされているかページテーブルで確かめる
+fn user_buffer<'a>(addr: u64, len: usize, from_user: bool) -> Option<&'a mut [u8]> {
+    let valid = addr != 0
+        && addr
+            .checked_add(len as u64)
+            .is_some_and(|end| !from_user || end <= USER_SPACE_END)
+        && paging::is_writable_range(VirtAddr::new(addr as usize), len, from_user);
+    valid.then(|| unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) })
+}
+
+// UNIXエポックからの秒数を返す. tlocが0でなければそこにも書き込む
+fn sys_time(tloc: u64, from_user: bool) -> i64 {
+    let now = time::unix_timestamp() as i64;
+    if tloc != 0 {
+        let Some(buf) = user_buffer(tloc, size_of::<i64>(), from_user) else {
+            return -EFAULT;
+        };
+        buf.copy_from_slice(&now.to_le_bytes());
+    }
+    now
+}
+
+// struct timespec { tv_sec: i64, tv_nsec: i64 } に書き込む
+fn sys_clock_gettime(clock: u64, tp: u64, from_user: bool) -> i64 {
+    let elapsed = match clock {
+        CLOCK_REALTIME => SystemTime::now().since_epoch(),
+        CLOCK_MONOTONIC => Instant::now().since_boot(),
+        _ => return -EINVAL,
+    };
+    let Some(buf) = user_buffer(tp, 2 * size_of::<i64>(), from_user) else {
+        return -EFAULT;
+    };
+    buf[..8].copy_from_slice(&(elapsed.as_secs() as i64).to_le_bytes());
+    buf[8..].copy_from_slice(&(elapsed.subsec_nanos() as i64).to_le_bytes());
+    0
+}
+
+fn sys_getrandom(buf: u64, len: u64, flags: u64, from_user: bool) -> i64 {
+    if flags & !GRND_MASK != 0 {
+        return -EINVAL;
+    }
+    // 割り込みを禁止したまま待つことはできないので、初期化前はGRND_NONBLOCKと同じく失敗する
+    if flags & GRND_INSECURE == 0 && !random::is_seeded() {
+        return -EAGAIN;
+    }
+    let len = (len as usize).min(GETRANDOM_MAX_LEN);
+    let Some(buf) = user_buffer(buf, len, from_user) else {
+        return -EFAULT;
+    };
+    random::fill_bytes(buf);
+    len as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::arch::asm;
+
+    fn syscall3(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+        let ret: i64;
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") number as i64 => ret,
+                in("rdi") arg0,
+                in("rsi") arg1,
+                in("rdx") arg2,
+            );
+        }
+        ret
+    }
+
+    #[test_case]
+    fn getrandom_through_interrupt() {
+        let mut buf = [0u8; 64];
+        let ret = syscall3(SYS_GETRANDOM, buf.as_mut_ptr() as u64, buf.len() as u64, 0);
+        assert_eq!(ret, 64);
+        assert!(buf.iter().any(|&byte| byte != 0));
+
+        assert_eq!(syscall3(SYS_GETRANDOM, 0, 16, 0), -EFAULT);
+        // 下位半分はマップしていない
+        assert_eq!(syscall3(SYS_GETRANDOM, 0x7000_0000_0000, 16, 0), -EFAULT);
+        assert_eq!(
+            syscall3(SYS_GETRANDOM, buf.as_mut_ptr() as u64, 16, 0x100),
+            -EINVAL
+        );
+        assert_eq!(syscall3(0xFFFF, 0, 0, 0), -ENOSYS);
+    }
+
+    #[test_case]
+    fn clock_gettime_and_time() {
+        let mut timespec = [0i64; 2];
+        let ptr = timespec.as_mut_ptr() as u64;
+        assert_eq!(syscall3(SYS_CLOCK_GETTIME, CLOCK_REALTIME, ptr, 0), 0);
+        let now = time::unix_timestamp() as i64;
+        assert!((timespec[0] - now).abs() <= 1);
+        assert!((0..1_000_000_000).contains(&timespec[1]));
+
+        let mut seconds = 0i64;
+        let ret = syscall3(SYS_TIME, &mut seconds as *mut i64 as u64, 0, 0);
+        assert_eq!(ret, seconds);
+        assert!(seconds >= timespec[0]);
+
+        assert_eq!(syscall3(SYS_CLOCK_GETTIME, CLOCK_MONOTONIC, ptr, 0), 0);
+        assert!(timespec[0] < now);
+        assert_eq!(syscall3(SYS_CLOCK_GETTIME, 99, ptr, 0), -EINVAL);
+        assert_eq!(syscall3(SYS_CLOCK_GETTIME, CLOCK_REALTIME, 0, 0), -EFAULT);
+    }
+}
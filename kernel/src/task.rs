@@ -1,23 +1,32 @@
 use crate::{
-    info,
+    info, ipi,
     memlayout::{VirtAddr, virt_to_phys},
     paging::PageTable,
+    pcid::{self, Pcid},
+    percpu, smp,
     spin::{SpinGuard, SpinLock},
-    x86,
+    timer, x86,
 };
 
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
 use core::{
     arch::naked_asm,
     cell::RefCell,
     ops::AddAssign,
     pin::Pin,
     sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
     u32,
 };
 
 const KERNEL_STACK_SIZE: usize = 4096 * 4;
 
+const DEFAULT_QUANTUM: Duration = Duration::from_millis(10);
+
+// タスクが連続して実行できるタイマ割り込みの回数
+static QUANTUM_TICKS: AtomicUsize =
+    AtomicUsize::new(timer::duration_to_ticks(DEFAULT_QUANTUM) as usize);
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PId(u32);
 
@@ -57,14 +66,18 @@ struct TaskContext {
 }
 
 impl TaskContext {
+    // 最初の切り替えでtask_entryに戻り、そこからr12のfuncを呼び出す
+    // 切り替えを完了するまでは割り込みを禁止しておく
     fn setup_initial_call(&mut self, kstack: &KStack, func: fn()) {
+        let entry: unsafe extern "sysv64" fn() -> ! = task_entry;
         let mut stack_top = kstack.as_ref().get_ref().0.as_ptr() as *mut u64;
         unsafe {
-            stack_top = stack_top.add(KERNEL_STACK_SIZE);
-            stack_top = push_stack(stack_top, func as u64);
+            stack_top = stack_top.add(KERNEL_STACK_SIZE / size_of::<u64>());
+            stack_top = push_stack(stack_top, entry as usize as u64);
         }
         self.rsp = stack_top as u64;
-        self.rflags = 0x202;
+        self.r12 = func as usize as u64;
+        self.rflags = 0x2;
     }
 }
 
@@ -87,10 +100,24 @@ pub struct Task {
     running: bool,
     context: TaskContext,
     page_table: RefCell<Option<Pin<Box<PageTable>>>>, // 果たしてこれでいいのか...?
+    pcid: Option<Pcid>,
     kernel_stack: Option<KStack>,
 }
 
 impl Task {
+    pub fn pid(&self) -> PId {
+        self.pid
+    }
+
+    pub fn is_runnable(&self) -> bool {
+        self.state == TaskState::Runnable
+    }
+
+    // いずれかのCPUで実行中
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
     fn new() -> Self {
         static PID: AtomicU32 = AtomicU32::new(0);
         Task {
@@ -99,6 +126,7 @@ impl Task {
             running: false,
             context: TaskContext::default(),
             page_table: RefCell::new(None),
+            pcid: None,
             kernel_stack: None,
         }
     }
@@ -136,45 +164,102 @@ unsafe extern "sysv64" fn switch_inner(current: *mut TaskContext, next: *mut Tas
     )
 }
 
-pub struct CpuContextBlock {
-    pub ticks: AtomicUsize,
-    pub current_task: SpinLock<Option<Arc<SpinLock<Task>>>>,
+#[unsafe(naked)]
+unsafe extern "sysv64" fn task_entry() -> ! {
+    naked_asm!(
+        "call {finish_switch}",
+        "sti",
+        "call r12",
+        "ud2",
+        finish_switch = sym finish_switch,
+    )
 }
 
-impl CpuContextBlock {
-    pub const fn new() -> Self {
-        Self {
-            ticks: AtomicUsize::new(0),
-            current_task: SpinLock::new(None),
-        }
-    }
+type TaskRef = Arc<SpinLock<Task>>;
+
+percpu! {
+    static CURRENT_TASK: SpinLock<Option<TaskRef>> = SpinLock::new(None);
+    // 切り替え前に実行していたタスク. コンテキストの保存が完了してから実行キューに戻す
+    static PREV_TASK: SpinLock<Option<TaskRef>> = SpinLock::new(None);
+    // APの起動時のコンテキスト. 実行キューには入れない
+    static IDLE_TASK: SpinLock<Option<TaskRef>> = SpinLock::new(None);
+    // 現在のタスクが連続して実行しているティック数
+    static TICKS: AtomicUsize = AtomicUsize::new(0);
+    static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+    // 実行可能で、どのCPUでも実行されていないタスク
+    static RUN_QUEUE: SpinLock<VecDeque<TaskRef>> = SpinLock::new(VecDeque::new());
 }
 
-static CPU_CONTEXT_BLOCK: CpuContextBlock = CpuContextBlock::new();
+// Taskは無限ループして終了することはないものとして扱う
+static TASKS: SpinLock<Vec<TaskRef>> = SpinLock::new(Vec::new());
 
-pub fn context() -> &'static CpuContextBlock {
-    &CPU_CONTEXT_BLOCK
+pub fn current_task() -> TaskRef {
+    CURRENT_TASK.with(|current| current.lock().as_ref().unwrap().clone())
 }
 
-// Taskは無限ループして終了することはないものとして扱う
-static TASKS: SpinLock<Vec<Arc<SpinLock<Task>>>> = SpinLock::new(Vec::new());
+// タイマ割り込みから呼ばれる
+pub fn tick() {
+    TICKS.get().fetch_add(1, Ordering::Relaxed);
+}
 
-pub fn current_task() -> Arc<SpinLock<Task>> {
-    let context = context();
+pub fn ticks() -> usize {
+    TICKS.with(|ticks| ticks.load(Ordering::Relaxed))
+}
 
-    context.current_task.lock().as_ref().unwrap().clone()
+// 割り込みからの復帰時にタスクを切り替えさせる
+pub fn request_reschedule() {
+    TICKS.get().store(quantum_ticks(), Ordering::Relaxed);
 }
 
-pub fn tick() {
-    let context = context();
-    context.ticks.fetch_add(1, Ordering::Relaxed);
+// preempt_enableが同じ回数呼ばれるまで、タイマ割り込みによるタスクの切り替えを行わない
+pub fn preempt_disable() {
+    PREEMPT_COUNT.with(|count| count.fetch_add(1, Ordering::Relaxed));
+}
+
+pub fn preempt_enable() {
+    let prev = PREEMPT_COUNT.with(|count| count.fetch_sub(1, Ordering::Relaxed));
+    assert!(prev > 0, "preempt_enable called without preempt_disable");
+}
+
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT.with(|count| count.load(Ordering::Relaxed)) == 0
+}
+
+#[allow(dead_code)]
+pub fn runnable_count() -> usize {
+    tasks()
+        .iter()
+        .filter(|task| task.lock().state == TaskState::Runnable)
+        .count()
+}
+
+// 実行を待っているタスクの数 (全CPUの実行キューの合計)
+pub fn waiting_count() -> usize {
+    (0..smp::cpu_count())
+        .map(|cpu| RUN_QUEUE.get_for(cpu).lock().len())
+        .sum()
+}
+
+pub fn quantum_ticks() -> usize {
+    QUANTUM_TICKS.load(Ordering::Relaxed)
+}
+
+#[allow(dead_code)]
+pub fn quantum() -> Duration {
+    timer::ticks_to_duration(quantum_ticks() as u64)
 }
 
-pub fn tasks() -> SpinGuard<'static, Vec<Arc<SpinLock<Task>>>> {
+#[allow(dead_code)]
+pub fn set_quantum(quantum: Duration) {
+    let ticks = timer::duration_to_ticks(quantum).max(1);
+    QUANTUM_TICKS.store(ticks as usize, Ordering::Relaxed);
+}
+
+pub fn tasks() -> SpinGuard<'static, Vec<TaskRef>> {
     TASKS.lock()
 }
 
-pub fn init(page_table: Pin<Box<PageTable>>) {
+fn new_current_task(page_table: Pin<Box<PageTable>>) -> TaskRef {
     let mut task = Task::new();
     task.state = TaskState::Runnable;
     task.running = true;
@@ -182,87 +267,113 @@ pub fn init(page_table: Pin<Box<PageTable>>) {
 
     let task_lock = Arc::new(SpinLock::new(task));
     TASKS.lock().push(task_lock.clone());
+    CURRENT_TASK.with(|current| *current.lock() = Some(task_lock.clone()));
+    task_lock
+}
 
-    let context = context();
-    *context.current_task.lock() = Some(Arc::clone(&task_lock));
+// BSPで実行中のコンテキスト (kernel_main) を最初のタスクにする
+pub fn init(page_table: Pin<Box<PageTable>>) {
+    new_current_task(page_table);
 }
 
+// APの起動時に呼び出し、そのCPUのidleタスクを登録する
+// ページテーブルはBSPの最初のタスクのものを複製する
+pub fn init_ap() {
+    let page_table = {
+        let tasks = tasks();
+        let first = tasks[0].lock();
+        PageTable::duplicate_kernel(first.page_table.borrow().as_ref().unwrap())
+    };
+    let idle = new_current_task(page_table);
+    IDLE_TASK.with(|idle_task| *idle_task.lock() = Some(idle));
+}
+
+// idleタスクを実行しているCPU
+fn is_idle(cpu: usize) -> bool {
+    let idle = IDLE_TASK.get_for(cpu).lock();
+    let current = CURRENT_TASK.get_for(cpu).lock();
+    match (idle.as_ref(), current.as_ref()) {
+        (Some(idle), Some(current)) => Arc::ptr_eq(idle, current),
+        _ => false,
+    }
+}
+
+// 自分の実行キューが空のとき、他のCPUの実行キューからタスクを取ってくる
+fn steal_task() -> Option<TaskRef> {
+    let this = smp::cpu_id();
+    (0..smp::cpu_count())
+        .filter(|&cpu| cpu != this)
+        .find_map(|cpu| RUN_QUEUE.get_for(cpu).lock().pop_back())
+}
+
+// 実行キューの先頭のタスクに切り替える
+// 切り替え前のタスクは、切り替え先でコンテキストの保存が完了してから (finish_switch) 実行キューに戻す
 pub fn switch() {
     x86::disable_interrupts();
 
-    let percpu = context();
-    percpu.ticks.store(0, Ordering::Relaxed);
-
-    let (prev_task_lock, next_task_lock) = {
-        let tasks = tasks();
+    TICKS.get().store(0, Ordering::Relaxed);
 
-        let current_task_lock = current_task();
-        let current_index = tasks
-            .iter()
-            .position(|t| Arc::ptr_eq(t, &current_task_lock));
-
-        let next_task_lock = if tasks.is_empty() {
-            None
-        } else {
-            let len = tasks.len();
-            let mut i = current_index.map_or(0, |i| (i + 1) % len);
-            let start = i;
-            loop {
-                if let Some(task) = tasks.get(i) {
-                    let state = task.lock().state;
-                    if state == TaskState::Runnable {
-                        break Some(task.clone());
-                    }
-                }
-                i = (i + 1) % len;
-                if i == start {
-                    break None;
-                }
-            }
-        };
-
-        if let Some(next_task_lock) = next_task_lock {
-            (Some(current_task_lock), Some(next_task_lock))
-        } else {
-            (None, None)
-        }
+    let next = RUN_QUEUE.get().lock().pop_front();
+    let Some(next_task_lock) = next.or_else(steal_task) else {
+        x86::enable_interrupts();
+        return;
     };
+    let current_task_lock = current_task();
 
-    if let (Some(prev_task_lock), Some(next_task_lock)) = (prev_task_lock, next_task_lock) {
-        let mut prev_task_guard = prev_task_lock.lock();
+    let (current_ctx, next_ctx) = {
+        let mut prev_task_guard = current_task_lock.lock();
         let mut next_task_guard = next_task_lock.lock();
-
-        *percpu.current_task.lock() = Some(Arc::clone(&next_task_lock));
-
-        prev_task_guard.running = false;
         next_task_guard.running = true;
 
-        x86::write_cr3(virt_to_phys(VirtAddr::new(
+        let pml4 = virt_to_phys(VirtAddr::new(
             &*next_task_guard
                 .page_table
                 .borrow()
                 .as_ref()
                 .unwrap()
                 .as_ref() as *const PageTable as usize,
-        )));
-
-        let current_ctx = &mut prev_task_guard.context as *mut TaskContext;
-        let next_ctx = &mut next_task_guard.context as *mut TaskContext;
+        ));
+        pcid::switch_address_space(pml4, &mut next_task_guard.pcid);
+
+        // どちらのタスクも実行キューに入っていないため、ロックを解除しても他のCPUからは触られない
+        (
+            &mut prev_task_guard.context as *mut TaskContext,
+            &mut next_task_guard.context as *mut TaskContext,
+        )
+    };
 
-        // 強引にロックを解除してからコンテキストスイッチを行う
-        // TODO: FIX
-        drop(prev_task_guard);
-        drop(next_task_guard);
+    *CURRENT_TASK.get().lock() = Some(next_task_lock);
+    *PREV_TASK.get().lock() = Some(current_task_lock);
 
-        // Save the current task context
-        unsafe {
-            switch_inner(current_ctx, next_ctx);
-        }
+    // Save the current task context
+    unsafe {
+        switch_inner(current_ctx, next_ctx);
     }
+    // 別のCPUで再開している可能性があるため、切り替え前に取得した値は使わない
+    finish_switch();
 
     x86::enable_interrupts();
 }
 
+extern "sysv64" fn finish_switch() {
+    let Some(prev_task_lock) = PREV_TASK.get().lock().take() else {
+        return;
+    };
+    let runnable = {
+        let mut prev_task = prev_task_lock.lock();
+        prev_task.running = false;
+        prev_task.state == TaskState::Runnable
+    };
+    let is_idle = IDLE_TASK
+        .get()
+        .lock()
+        .as_ref()
+        .is_some_and(|idle| Arc::ptr_eq(idle, &prev_task_lock));
+    if runnable && !is_idle {
+        RUN_QUEUE.get().lock().push_back(prev_task_lock);
+    }
+}
+
 pub fn spawn(func: fn()) {
     let kstack = Pin::from(Box::new(AlignedStack([0u8; KERNEL_STACK_SIZE])));
     // spawn関数は、idleタスク実行中に呼び出されるため、current_task()はidleタスクを指している
@@ -284,4 +395,11 @@ pub fn spawn(func: fn()) {
             task.context.rsp
         );
     }
+    RUN_QUEUE.with(|queue| queue.lock().push_back(task_lock));
+    timer::start_scheduler_tick();
+    // idle中のCPUがあれば起こして、新しいタスクを取りに行かせる
+    let this = smp::cpu_id();
+    if let Some(cpu) = (0..smp::cpu_count()).find(|&cpu| cpu != this && is_idle(cpu)) {
+        ipi::send_reschedule(cpu);
+    }
 }
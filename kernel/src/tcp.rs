@@ -0,0 +1,1122 @@
+use crate::{
+    clock::Instant,
+    net::{self, Ipv4Addr, Ipv4Header, PROTOCOL_TCP},
+    random,
+    spin::SpinLock,
+    timer, warn, x86,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec,
+    vec::Vec,
+};
+use core::time::Duration;
+
+// フラグ
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+const HEADER_LEN: usize = 20;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+// MTU 1500からIPv4とTCPのヘッダを除いたもの
+const LOCAL_MSS: usize = 1460;
+// MSSオプションがない場合の既定値
+const DEFAULT_MSS: usize = 536;
+
+// ウィンドウスケールは使わないので、受信バッファは16ビットに収める
+const RECV_BUFFER_SIZE: usize = 32 * 1024;
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+const BACKLOG: usize = 16;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const MAX_RETRIES: u32 = 8;
+// 2MSL. カーネル内なので短くしている
+const TIME_WAIT: Duration = Duration::from_secs(4);
+
+// シーケンス番号の比較 (2^32で一周する)
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+// RFC 793の4µsごとに増えるクロックに乱数を足し、初期シーケンス番号を推測されないようにする
+fn initial_sequence() -> u32 {
+    ((Instant::now().as_nanos() / 4000) as u32).wrapping_add(random::next_u64() as u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+// (ローカルのポート, 相手のアドレス, 相手のポート)
+type Key = (u16, Ipv4Addr, u16);
+
+struct Segment<'a> {
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    data: &'a [u8],
+}
+
+impl Segment<'_> {
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    // SYNとFINもシーケンス番号を1つ消費する
+    fn len(&self) -> u32 {
+        self.data.len() as u32 + self.has(SYN) as u32 + self.has(FIN) as u32
+    }
+}
+
+fn parse_mss(options: &[u8]) -> Option<u16> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPTION_END => break,
+            OPTION_NOP => i += 1,
+            kind => {
+                let len = *options.get(i + 1)? as usize;
+                if len < 2 || i + len > options.len() {
+                    return None;
+                }
+                if kind == OPTION_MSS && len == 4 {
+                    return Some(net::read_u16(options, i + 2));
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+fn send_segment(key: Key, segment: &Segment) -> Result<(), &'static str> {
+    let (local_port, remote, remote_port) = key;
+    let header_len = if segment.mss.is_some() {
+        HEADER_LEN + 4
+    } else {
+        HEADER_LEN
+    };
+    let len = header_len + segment.data.len();
+    let mut packet = vec![0; len];
+    net::write_u16(&mut packet, 0, local_port);
+    net::write_u16(&mut packet, 2, remote_port);
+    net::write_u32(&mut packet, 4, segment.seq);
+    net::write_u32(&mut packet, 8, segment.ack);
+    packet[12] = ((header_len / 4) as u8) << 4;
+    packet[13] = segment.flags;
+    net::write_u16(&mut packet, 14, segment.window);
+    if let Some(mss) = segment.mss {
+        packet[20] = OPTION_MSS;
+        packet[21] = 4;
+        net::write_u16(&mut packet, 22, mss);
+    }
+    packet[header_len..].copy_from_slice(segment.data);
+    let src = net::config().ok_or("No network interface")?.address;
+    let sum = net::checksum(
+        &packet,
+        net::pseudo_header_sum(src, remote, PROTOCOL_TCP, len),
+    );
+    net::write_u16(&mut packet, 16, sum);
+    net::send_ipv4(remote, PROTOCOL_TCP, &packet)
+}
+
+// 受け取ったセグメントへのRST
+fn send_reset(key: Key, segment: &Segment) {
+    let reply = if segment.has(ACK) {
+        Segment {
+            seq: segment.ack,
+            ack: 0,
+            flags: RST,
+            window: 0,
+            mss: None,
+            data: &[],
+        }
+    } else {
+        Segment {
+            seq: 0,
+            ack: segment.seq.wrapping_add(segment.len()),
+            flags: RST | ACK,
+            window: 0,
+            mss: None,
+            data: &[],
+        }
+    };
+    let _ = send_segment(key, &reply);
+}
+
+// 接続ごとの状態 (TCB)
+struct Connection {
+    key: Key,
+    state: State,
+
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    // 送信した中で最大のシーケンス番号 (再送でsnd_nxtを戻すため)
+    snd_max: u32,
+    snd_wnd: u32,
+    rcv_nxt: u32,
+    // 最後に広告した受信ウィンドウ
+    rcv_wnd: u32,
+
+    // snd_unaから始まる未確認と未送信のデータ
+    send_buffer: VecDeque<u8>,
+    recv_buffer: VecDeque<u8>,
+
+    syn_acked: bool,
+    // shutdownが呼ばれ、送信データの後にFINを送る
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    fin_received: bool,
+    ack_needed: bool,
+
+    // 輻輳制御 (Reno)
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    dup_acks: u32,
+
+    // RTTの推定 (RFC 6298)
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    rtt_sample: Option<(u32, Instant)>,
+    retries: u32,
+
+    // 再送とTIME_WAITに使うタイマ. 期限を延ばすだけならタイマを登録し直さない
+    deadline: Option<Instant>,
+    timer_armed: bool,
+
+    error: Option<&'static str>,
+    // listenしているポートで受けた接続で、まだacceptされていない
+    listener: Option<u16>,
+    // TcpStreamが破棄された
+    orphaned: bool,
+}
+
+impl Connection {
+    fn new(key: Key, state: State) -> Self {
+        let iss = initial_sequence();
+        Connection {
+            key,
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_max: iss.wrapping_add(1),
+            snd_wnd: 0,
+            rcv_nxt: 0,
+            rcv_wnd: RECV_BUFFER_SIZE as u32,
+            send_buffer: VecDeque::new(),
+            recv_buffer: VecDeque::new(),
+            syn_acked: false,
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            fin_received: false,
+            ack_needed: false,
+            mss: DEFAULT_MSS,
+            cwnd: 0,
+            ssthresh: u16::MAX as usize,
+            dup_acks: 0,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            rtt_sample: None,
+            retries: 0,
+            deadline: None,
+            timer_armed: false,
+            error: None,
+            listener: None,
+            orphaned: false,
+        }
+    }
+
+    fn recv_window(&self) -> u32 {
+        (RECV_BUFFER_SIZE - self.recv_buffer.len()) as u32
+    }
+
+    fn in_flight(&self) -> usize {
+        self.snd_nxt.wrapping_sub(self.snd_una) as usize
+    }
+
+    fn send(&mut self, seq: u32, flags: u8, data: &[u8]) {
+        self.rcv_wnd = self.recv_window();
+        let synchronized = self.state != State::SynSent;
+        let segment = Segment {
+            seq,
+            ack: if synchronized { self.rcv_nxt } else { 0 },
+            flags: if synchronized { flags | ACK } else { flags },
+            window: self.rcv_wnd as u16,
+            mss: (flags & SYN != 0).then_some(LOCAL_MSS as u16),
+            data,
+        };
+        let _ = send_segment(self.key, &segment);
+        let end = seq.wrapping_add(segment.len());
+        if seq_lt(self.snd_max, end) {
+            self.snd_max = end;
+        }
+        self.ack_needed = false;
+    }
+
+    fn send_syn(&mut self) {
+        self.send(self.iss, SYN, &[]);
+    }
+
+    fn set_timer(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+        if !self.timer_armed {
+            self.timer_armed = true;
+            let key = self.key;
+            timer::add_timer(deadline, move || on_timer(key));
+        }
+    }
+
+    fn restart_retransmit_timer(&mut self) {
+        let deadline = Instant::now() + self.rto;
+        self.set_timer(deadline);
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.abs_diff(sample);
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap() + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // ウィンドウとcwndが許す分だけ未送信のデータとFINを送る
+    fn output(&mut self) {
+        if self.syn_acked && !self.fin_acked {
+            let mut sent = false;
+            while !self.fin_sent {
+                let offset = self.in_flight();
+                let unsent = self.send_buffer.len().saturating_sub(offset);
+                if unsent > 0 {
+                    let window = (self.snd_wnd as usize).min(self.cwnd);
+                    let usable = window.saturating_sub(offset);
+                    if usable == 0 {
+                        break;
+                    }
+                    let len = unsent.min(usable).min(self.mss);
+                    let data: Vec<u8> = self
+                        .send_buffer
+                        .range(offset..offset + len)
+                        .copied()
+                        .collect();
+                    let flags = if offset + len == self.send_buffer.len() {
+                        PSH
+                    } else {
+                        0
+                    };
+                    let seq = self.snd_nxt;
+                    // 新しいデータでRTTを測る (再送したものは使わない)
+                    if self.rtt_sample.is_none() && seq == self.snd_max {
+                        self.rtt_sample = Some((seq.wrapping_add(len as u32), Instant::now()));
+                    }
+                    self.send(seq, flags, &data);
+                    self.snd_nxt = seq.wrapping_add(len as u32);
+                    sent = true;
+                } else if self.fin_queued {
+                    let seq = self.snd_nxt;
+                    self.send(seq, FIN, &[]);
+                    self.snd_nxt = seq.wrapping_add(1);
+                    self.fin_sent = true;
+                    sent = true;
+                } else {
+                    break;
+                }
+            }
+            // 送信中のデータがあるか、ウィンドウが0で送れないデータがあれば
+            // タイマで再送やウィンドウの確認を行う
+            let waiting = self.send_buffer.len() > self.in_flight();
+            if (sent || self.in_flight() > 0 || waiting) && self.deadline.is_none() {
+                self.restart_retransmit_timer();
+            }
+        }
+        if self.ack_needed {
+            let seq = self.snd_nxt;
+            self.send(seq, 0, &[]);
+        }
+    }
+
+    // snd_unaから1セグメント分を再送する
+    fn retransmit_first(&mut self) {
+        self.rtt_sample = None;
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(),
+            _ => {
+                let len = self.send_buffer.len().min(self.mss);
+                if len > 0 {
+                    let data: Vec<u8> = self.send_buffer.range(..len).copied().collect();
+                    let seq = self.snd_una;
+                    self.send(seq, 0, &data);
+                } else if self.fin_sent {
+                    let seq = self.snd_una;
+                    self.send(seq, FIN, &[]);
+                }
+            }
+        }
+    }
+
+    fn on_timeout(&mut self) {
+        match self.state {
+            State::TimeWait | State::FinWait2 | State::Closed => {
+                self.state = State::Closed;
+                return;
+            }
+            _ => {}
+        }
+        if self.syn_acked && self.snd_wnd == 0 && !self.send_buffer.is_empty() {
+            // ゼロウィンドウの確認. 先頭の1バイトをウィンドウの外に送る
+            let seq = self.snd_una;
+            let byte = [self.send_buffer[0]];
+            self.send(seq, 0, &byte);
+            if self.in_flight() == 0 {
+                self.snd_nxt = seq.wrapping_add(1);
+            }
+            self.rto = (self.rto * 2).min(MAX_RTO);
+            self.restart_retransmit_timer();
+            return;
+        }
+        if self.syn_acked && self.snd_una == self.snd_max {
+            return;
+        }
+
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.abort("Connection timed out");
+            return;
+        }
+        self.ssthresh = (self.in_flight() / 2).max(2 * self.mss);
+        self.cwnd = self.mss;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.dup_acks = 0;
+        if self.syn_acked {
+            // 未確認のデータを全て送り直す (go-back-N)
+            self.snd_nxt = self.snd_una;
+            self.fin_sent = false;
+            self.rtt_sample = None;
+            self.output();
+        } else {
+            self.retransmit_first();
+        }
+        self.restart_retransmit_timer();
+    }
+
+    fn abort(&mut self, error: &'static str) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.send_buffer.clear();
+        self.deadline = None;
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.set_timer(Instant::now() + TIME_WAIT);
+    }
+
+    // SYN_SENTでのセグメントの処理
+    fn process_syn_sent(&mut self, segment: &Segment) {
+        if segment.has(ACK) && segment.ack != self.iss.wrapping_add(1) {
+            if !segment.has(RST) {
+                send_reset(self.key, segment);
+            }
+            return;
+        }
+        if segment.has(RST) {
+            if segment.has(ACK) {
+                self.abort("Connection refused");
+            }
+            return;
+        }
+        if !segment.has(SYN) {
+            return;
+        }
+        self.rcv_nxt = segment.seq.wrapping_add(1);
+        self.snd_wnd = segment.window as u32;
+        if let Some(mss) = segment.mss {
+            self.mss = (mss as usize).clamp(64, LOCAL_MSS);
+        }
+        self.cwnd = initial_cwnd(self.mss);
+        if segment.has(ACK) {
+            if self.retries == 0 {
+                self.update_rtt(
+                    self.rtt_sample
+                        .map_or(Duration::ZERO, |(_, at)| at.elapsed()),
+                );
+            }
+            self.snd_una = segment.ack;
+            self.syn_acked = true;
+            self.state = State::Established;
+            self.deadline = None;
+            self.retries = 0;
+            self.rtt_sample = None;
+            self.ack_needed = true;
+        } else {
+            // 同時オープン
+            self.state = State::SynReceived;
+            self.send_syn();
+        }
+    }
+
+    // 同期後の状態でのセグメントの処理. acceptできるようになったらtrueを返す
+    fn process(&mut self, segment: &Segment) -> bool {
+        if self.state == State::SynSent {
+            self.process_syn_sent(segment);
+            self.output();
+            return false;
+        }
+        let window = self.rcv_wnd.max(1);
+        let offset = segment.seq.wrapping_sub(self.rcv_nxt);
+
+        if segment.has(RST) {
+            if offset < window {
+                if self.state == State::SynReceived && self.listener.is_some() {
+                    self.state = State::Closed;
+                } else {
+                    self.abort("Connection reset");
+                }
+            }
+            return false;
+        }
+        if segment.has(SYN) {
+            // SYN-ACKが失われた場合の再送されたSYN
+            if self.state == State::SynReceived && segment.seq.wrapping_add(1) == self.rcv_nxt {
+                self.send_syn();
+            } else {
+                self.ack_needed = true;
+                self.output();
+            }
+            return false;
+        }
+        if !segment.has(ACK) {
+            return false;
+        }
+
+        let mut accepted = false;
+        if self.state == State::SynReceived {
+            if seq_lt(self.snd_una, segment.ack) && seq_le(segment.ack, self.snd_max) {
+                self.state = State::Established;
+                self.snd_wnd = segment.window as u32;
+                self.cwnd = initial_cwnd(self.mss);
+                accepted = self.listener.is_some();
+            } else {
+                send_reset(self.key, segment);
+                return false;
+            }
+        }
+
+        if seq_lt(self.snd_max, segment.ack) {
+            // まだ送っていないデータへのACK
+            self.ack_needed = true;
+            self.output();
+            return accepted;
+        }
+        if seq_lt(self.snd_una, segment.ack) {
+            self.process_ack(segment.ack);
+        } else if segment.ack == self.snd_una
+            && segment.data.is_empty()
+            && !segment.has(FIN)
+            && segment.window as u32 == self.snd_wnd
+            && self.snd_una != self.snd_max
+        {
+            self.dup_acks += 1;
+            if self.dup_acks == 3 {
+                // 高速再送
+                self.ssthresh = (self.in_flight() / 2).max(2 * self.mss);
+                self.cwnd = self.ssthresh;
+                self.retransmit_first();
+            }
+        }
+        if seq_le(self.snd_una, segment.ack) {
+            self.snd_wnd = segment.window as u32;
+        }
+
+        self.process_data(segment);
+        self.output();
+        accepted
+    }
+
+    fn process_ack(&mut self, ack: u32) {
+        let mut acked = ack.wrapping_sub(self.snd_una) as usize;
+        if !self.syn_acked {
+            self.syn_acked = true;
+            acked -= 1;
+        }
+        let data = acked.min(self.send_buffer.len());
+        self.send_buffer.drain(..data);
+        if acked > data {
+            self.fin_acked = true;
+            self.fin_sent = true;
+        }
+        self.snd_una = ack;
+        if seq_lt(self.snd_nxt, ack) {
+            self.snd_nxt = ack;
+        }
+
+        if let Some((seq, sent_at)) = self.rtt_sample
+            && seq_le(seq, ack)
+        {
+            self.update_rtt(sent_at.elapsed());
+            self.rtt_sample = None;
+        }
+        if self.cwnd < self.ssthresh {
+            self.cwnd += data.min(self.mss);
+        } else {
+            self.cwnd += (self.mss * self.mss / self.cwnd).max(1);
+        }
+        self.dup_acks = 0;
+        self.retries = 0;
+        self.deadline = None;
+        if self.snd_una != self.snd_max {
+            self.restart_retransmit_timer();
+        }
+
+        if self.fin_acked {
+            match self.state {
+                State::FinWait1 => {
+                    self.state = State::FinWait2;
+                    if self.orphaned {
+                        self.set_timer(Instant::now() + TIME_WAIT);
+                    }
+                }
+                State::Closing => self.enter_time_wait(),
+                State::LastAck => self.state = State::Closed,
+                _ => {}
+            }
+        }
+    }
+
+    fn process_data(&mut self, segment: &Segment) {
+        let mut data = segment.data;
+        let mut fin = segment.has(FIN);
+        let skip = self.rcv_nxt.wrapping_sub(segment.seq);
+        if (skip as i32) > 0 {
+            // 受信済みの部分を取り除く
+            self.ack_needed = true;
+            if skip as usize <= data.len() {
+                data = &data[skip as usize..];
+            } else {
+                data = &[];
+                fin = false;
+            }
+        } else if skip != 0 {
+            // 順序が入れ替わったセグメントは捨てて、期待する番号をACKで知らせる
+            if segment.len() > 0 {
+                self.ack_needed = true;
+            }
+            return;
+        }
+
+        let receiving = matches!(
+            self.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        );
+        if !data.is_empty() {
+            self.ack_needed = true;
+            if !receiving {
+                return;
+            }
+            let len = data.len().min(self.recv_window() as usize);
+            self.recv_buffer.extend(&data[..len]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+            if len < data.len() {
+                fin = false;
+            }
+        }
+        if fin && receiving {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.ack_needed = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 if self.fin_acked => self.enter_time_wait(),
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.enter_time_wait(),
+                _ => {}
+            }
+        }
+    }
+
+    // 送信側を閉じる. 未送信のデータを送った後にFINを送る
+    fn shutdown(&mut self) {
+        match self.state {
+            State::SynSent => self.state = State::Closed,
+            State::SynReceived | State::Established => self.state = State::FinWait1,
+            State::CloseWait => self.state = State::LastAck,
+            _ => return,
+        }
+        self.fin_queued = true;
+        self.output();
+    }
+}
+
+// RFC 3390
+fn initial_cwnd(mss: usize) -> usize {
+    (4 * mss).min((2 * mss).max(4380))
+}
+
+struct Listener {
+    // acceptを待っている確立済みの接続
+    queue: VecDeque<Key>,
+}
+
+struct Tcp {
+    connections: BTreeMap<Key, Connection>,
+    listeners: BTreeMap<u16, Listener>,
+}
+
+impl Tcp {
+    fn port_in_use(&self, port: u16) -> bool {
+        self.listeners.contains_key(&port)
+            || self
+                .connections
+                .range((port, Ipv4Addr::UNSPECIFIED, 0)..=(port, Ipv4Addr::BROADCAST, u16::MAX))
+                .next()
+                .is_some()
+    }
+
+    // 閉じていて、もう誰も使わない接続を取り除く
+    fn reap(&mut self, key: Key) {
+        let closed = self
+            .connections
+            .get(&key)
+            .is_some_and(|c| c.state == State::Closed && (c.orphaned || c.listener.is_some()));
+        if !closed {
+            return;
+        }
+        let connection = self.connections.remove(&key).unwrap();
+        if let Some(port) = connection.listener
+            && let Some(listener) = self.listeners.get_mut(&port)
+        {
+            listener.queue.retain(|&k| k != key);
+        }
+    }
+}
+
+static TCP: SpinLock<Tcp> = SpinLock::new(Tcp {
+    connections: BTreeMap::new(),
+    listeners: BTreeMap::new(),
+});
+
+// 期限を過ぎたタイマの接続. 再送は割り込みコンテキストでは重いため、poll_timersで行う
+static EXPIRED: SpinLock<Vec<Key>> = SpinLock::new(Vec::new());
+
+fn on_timer(key: Key) {
+    EXPIRED.lock().push(key);
+}
+
+// 期限を過ぎたタイマを処理する. net::pollからタスクのコンテキストで呼ばれる
+pub fn poll_timers() -> bool {
+    let expired = core::mem::take(&mut *EXPIRED.lock());
+    if expired.is_empty() {
+        return false;
+    }
+    let mut tcp = TCP.lock();
+    for key in expired {
+        let Some(connection) = tcp.connections.get_mut(&key) else {
+            continue;
+        };
+        connection.timer_armed = false;
+        match connection.deadline {
+            None => {}
+            Some(deadline) if Instant::now() < deadline => connection.set_timer(deadline),
+            Some(_) => {
+                connection.deadline = None;
+                connection.on_timeout();
+            }
+        }
+        tcp.reap(key);
+    }
+    true
+}
+
+// IPv4から受け取ったTCPセグメントを処理する
+pub fn handle_segment(header: &Ipv4Header, packet: &[u8]) {
+    if packet.len() < HEADER_LEN
+        || net::checksum(
+            packet,
+            net::pseudo_header_sum(header.src, header.dst, PROTOCOL_TCP, packet.len()),
+        ) != 0
+    {
+        return;
+    }
+    let header_len = (packet[12] >> 4) as usize * 4;
+    if header_len < HEADER_LEN || header_len > packet.len() {
+        return;
+    }
+    let segment = Segment {
+        seq: net::read_u32(packet, 4),
+        ack: net::read_u32(packet, 8),
+        flags: packet[13],
+        window: net::read_u16(packet, 14),
+        mss: parse_mss(&packet[HEADER_LEN..header_len]),
+        data: &packet[header_len..],
+    };
+    let key = (
+        net::read_u16(packet, 2),
+        header.src,
+        net::read_u16(packet, 0),
+    );
+
+    let mut tcp = TCP.lock();
+    if let Some(connection) = tcp.connections.get_mut(&key) {
+        if connection.process(&segment)
+            && let Some(listener) = tcp.listeners.get_mut(&key.0)
+        {
+            listener.queue.push_back(key);
+        }
+        tcp.reap(key);
+        return;
+    }
+
+    if segment.has(RST) {
+        return;
+    }
+    let pending = tcp
+        .connections
+        .values()
+        .filter(|c| c.listener == Some(key.0))
+        .count();
+    match tcp.listeners.get(&key.0) {
+        Some(_) if segment.has(SYN) && !segment.has(ACK) && pending < BACKLOG => {
+            let mut connection = Connection::new(key, State::SynReceived);
+            connection.listener = Some(key.0);
+            connection.rcv_nxt = segment.seq.wrapping_add(1);
+            connection.snd_wnd = segment.window as u32;
+            if let Some(mss) = segment.mss {
+                connection.mss = (mss as usize).clamp(64, LOCAL_MSS);
+            }
+            connection.send_syn();
+            connection.restart_retransmit_timer();
+            tcp.connections.insert(key, connection);
+        }
+        // バックログが一杯なら相手の再送を待つ
+        Some(_) if segment.has(SYN) && !segment.has(ACK) => {}
+        _ => send_reset(key, &segment),
+    }
+}
+
+pub struct TcpStream {
+    key: Key,
+}
+
+impl TcpStream {
+    // 接続が確立するか失敗するまで待つ
+    #[allow(dead_code)]
+    pub fn connect(addr: Ipv4Addr, port: u16) -> Result<TcpStream, &'static str> {
+        let key = {
+            let mut tcp = TCP.lock();
+            let local_port =
+                net::ephemeral_port(|port| tcp.port_in_use(port)).ok_or("No free TCP port")?;
+            let key = (local_port, addr, port);
+            let mut connection = Connection::new(key, State::SynSent);
+            connection.rtt_sample = Some((connection.iss, Instant::now()));
+            connection.send_syn();
+            connection.restart_retransmit_timer();
+            tcp.connections.insert(key, connection);
+            key
+        };
+        let stream = TcpStream { key };
+        net::wait_for(|| {
+            let state = stream.with(|c| c.state);
+            (state != State::SynSent && state != State::SynReceived).then_some(())
+        });
+        match stream.with(|c| (c.state, c.error)) {
+            (State::Closed, error) => Err(error.unwrap_or("Connection failed")),
+            _ => Ok(stream),
+        }
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Connection) -> T) -> T {
+        let mut tcp = TCP.lock();
+        let connection = tcp
+            .connections
+            .get_mut(&self.key)
+            .expect("TCP connection is missing");
+        f(connection)
+    }
+
+    #[allow(dead_code)]
+    pub fn peer_addr(&self) -> (Ipv4Addr, u16) {
+        (self.key.1, self.key.2)
+    }
+
+    #[allow(dead_code)]
+    pub fn local_port(&self) -> u16 {
+        self.key.0
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> State {
+        self.with(|c| c.state)
+    }
+
+    // 1バイト以上読めるまで待つ. 相手が送信側を閉じていれば0を返す
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        net::wait_for(|| {
+            self.with(|c| {
+                if !c.recv_buffer.is_empty() {
+                    let len = buf.len().min(c.recv_buffer.len());
+                    for (dst, src) in buf.iter_mut().zip(c.recv_buffer.drain(..len)) {
+                        *dst = src;
+                    }
+                    // ウィンドウが十分に開いたら相手に知らせる
+                    if c.recv_window()
+                        >= c.rcv_wnd + (c.mss as u32).min(RECV_BUFFER_SIZE as u32 / 2)
+                    {
+                        c.ack_needed = true;
+                        c.output();
+                    }
+                    Some(Ok(len))
+                } else if let Some(error) = c.error {
+                    Some(Err(error))
+                } else if c.fin_received || c.state == State::Closed {
+                    Some(Ok(0))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn read_exact(&self, mut buf: &mut [u8]) -> Result<(), &'static str> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => return Err("Connection closed"),
+                len => buf = &mut buf[len..],
+            }
+        }
+        Ok(())
+    }
+
+    // 送信バッファに空きができるまで待ち、入った分のバイト数を返す
+    pub fn write(&self, data: &[u8]) -> Result<usize, &'static str> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        net::wait_for(|| {
+            self.with(|c| {
+                if let Some(error) = c.error {
+                    return Some(Err(error));
+                }
+                if c.fin_queued || !matches!(c.state, State::Established | State::CloseWait) {
+                    return Some(Err("Connection is not writable"));
+                }
+                let len = data.len().min(SEND_BUFFER_SIZE - c.send_buffer.len());
+                if len == 0 {
+                    return None;
+                }
+                c.send_buffer.extend(&data[..len]);
+                c.output();
+                Some(Ok(len))
+            })
+        })
+    }
+
+    pub fn write_all(&self, mut data: &[u8]) -> Result<(), &'static str> {
+        while !data.is_empty() {
+            let len = self.write(data)?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    // 送信側を閉じる (相手はreadで0を受け取る)
+    #[allow(dead_code)]
+    pub fn shutdown(&self) {
+        self.with(|c| c.shutdown());
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut tcp = TCP.lock();
+        if let Some(connection) = tcp.connections.get_mut(&self.key) {
+            connection.orphaned = true;
+            connection.shutdown();
+            if connection.state == State::FinWait2 {
+                connection.set_timer(Instant::now() + TIME_WAIT);
+            }
+        }
+        tcp.reap(self.key);
+    }
+}
+
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, &'static str> {
+        let mut tcp = TCP.lock();
+        if tcp.port_in_use(port) {
+            return Err("TCP port is already in use");
+        }
+        tcp.listeners.insert(
+            port,
+            Listener {
+                queue: VecDeque::new(),
+            },
+        );
+        Ok(TcpListener { port })
+    }
+
+    // 確立済みの接続があれば取り出す
+    pub fn try_accept(&self) -> Option<TcpStream> {
+        let mut tcp = TCP.lock();
+        let key = tcp.listeners.get_mut(&self.port)?.queue.pop_front()?;
+        let connection = tcp.connections.get_mut(&key)?;
+        connection.listener = None;
+        Some(TcpStream { key })
+    }
+
+    #[allow(dead_code)]
+    pub fn accept(&self) -> TcpStream {
+        net::wait_for(|| self.try_accept())
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut tcp = TCP.lock();
+        tcp.listeners.remove(&self.port);
+        // acceptされていない接続はリセットする
+        let port = self.port;
+        tcp.connections.retain(|_, c| {
+            if c.listener != Some(port) {
+                return true;
+            }
+            let seq = c.snd_nxt;
+            c.send(seq, RST, &[]);
+            false
+        });
+    }
+}
+
+// TCPのechoサーバ. 1度に1つの接続を扱う
+const ECHO_PORT: u16 = 7;
+
+pub fn echo_task() {
+    let listener = match TcpListener::bind(ECHO_PORT) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("tcp: echo server is not available: {}", e);
+            loop {
+                x86::halt();
+            }
+        }
+    };
+    loop {
+        let Some(stream) = listener.try_accept() else {
+            x86::halt();
+            continue;
+        };
+        let mut buf = [0; 1024];
+        while let Ok(len @ 1..) = stream.read(&mut buf) {
+            if stream.write_all(&buf[..len]).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn sequence_numbers_wrap_around() {
+        assert!(seq_lt(0xFFFF_FFF0, 0x10));
+        assert!(!seq_lt(0x10, 0xFFFF_FFF0));
+        assert!(seq_le(5, 5));
+        assert_eq!(
+            parse_mss(&[OPTION_NOP, OPTION_MSS, 4, 0x05, 0xB4]),
+            Some(1460)
+        );
+        assert_eq!(parse_mss(&[OPTION_END, OPTION_MSS, 4, 0x05, 0xB4]), None);
+    }
+
+    #[test_case]
+    fn loopback_transfer_and_close() {
+        if net::config().is_none() {
+            return;
+        }
+        let config = net::wait_configured(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            TcpStream::connect(config.address, 9).err(),
+            Some("Connection refused")
+        );
+
+        let listener = TcpListener::bind(8007).unwrap();
+        let client = TcpStream::connect(config.address, 8007).unwrap();
+        let server = listener.accept();
+        assert_eq!(server.peer_addr(), (config.address, client.local_port()));
+
+        // 送受信を繰り返す
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        let mut buf = vec![0; data.len()];
+        for _ in 0..16 {
+            client.write_all(&data).unwrap();
+            server.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data);
+        }
+
+        // 受信バッファより大きいデータを読まずに送る. 送信バッファには収まるのでwrite_allは戻る
+        // 受信ウィンドウが0になっても、読み進めればウィンドウが開いて残りが届く
+        let large: Vec<u8> = (0..RECV_BUFFER_SIZE + 8192)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        assert!(large.len() <= SEND_BUFFER_SIZE);
+        client.write_all(&large).unwrap();
+        let mut large_buf = vec![0; large.len()];
+        server.read_exact(&mut large_buf).unwrap();
+        assert_eq!(large_buf, large);
+
+        client.shutdown();
+        assert_eq!(server.read(&mut buf), Ok(0));
+        server.write_all(b"bye").unwrap();
+        drop(server);
+        client.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(&buf[..3], b"bye");
+        assert_eq!(client.read(&mut buf), Ok(0));
+        assert_eq!(client.state(), State::TimeWait);
+    }
+}
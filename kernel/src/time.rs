@@ -0,0 +1,96 @@
+use crate::{clock::Instant, info, rtc, spin::Once};
+use core::{ops::Add, time::Duration};
+
+// 起動時のRTCの時刻 (UNIXエポックからの経過時間) と、その時点の単調時刻
+struct BootTime {
+    unix: Duration,
+    instant: Instant,
+}
+
+static BOOT_TIME: Once<BootTime> = Once::new();
+
+// 壁時計の時刻. RTCは起動時に一度だけ読み、以降は単調時刻を足して求める
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SystemTime(Duration);
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::ZERO);
+
+#[allow(dead_code)]
+impl SystemTime {
+    pub fn now() -> Self {
+        match BOOT_TIME.get() {
+            Some(boot) => SystemTime(boot.unix + boot.instant.elapsed()),
+            None => UNIX_EPOCH,
+        }
+    }
+
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    pub fn unix_timestamp(&self) -> u64 {
+        self.0.as_secs()
+    }
+
+    // UNIXエポックからの経過時間
+    pub fn since_epoch(&self) -> Duration {
+        self.0
+    }
+
+    pub fn to_datetime(self) -> rtc::DateTime {
+        rtc::DateTime::from_unix_timestamp(self.unix_timestamp())
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+    fn add(self, rhs: Duration) -> Self::Output {
+        SystemTime(self.0 + rhs)
+    }
+}
+
+pub fn init() {
+    let boot = BOOT_TIME.call_once(|| {
+        let datetime = rtc::read();
+        BootTime {
+            unix: Duration::from_secs(datetime.to_unix_timestamp()),
+            instant: Instant::now(),
+        }
+    });
+    info!(
+        "Wall clock: {} (unix {})",
+        rtc::DateTime::from_unix_timestamp(boot.unix.as_secs()),
+        boot.unix.as_secs()
+    );
+}
+
+// UNIXエポックからの経過秒数
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now().unix_timestamp()
+}
+
+// initの前はfalse (SystemTime::nowはUNIX_EPOCHを返す)
+pub fn is_initialized() -> bool {
+    BOOT_TIME.get().is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn system_time_advances_from_rtc() {
+        assert!(is_initialized());
+        let start = SystemTime::now();
+        // RTCの時刻はこのコードを書いた時点より後のはず
+        assert!(start.unix_timestamp() >= 1_760_000_000);
+        let datetime = start.to_datetime();
+        assert_eq!(datetime.to_unix_timestamp(), start.unix_timestamp());
+
+        let later = start + Duration::from_secs(90);
+        assert_eq!(later.duration_since(start), Some(Duration::from_secs(90)));
+        assert_eq!(start.duration_since(later), None);
+        assert!(SystemTime::now() >= start);
+        assert_eq!(UNIX_EPOCH.since_epoch(), Duration::ZERO);
+    }
+}
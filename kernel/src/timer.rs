@@ -1,67 +1,414 @@
-use crate::spin::SpinLock;
+use crate::{
+    apic::{self, LocalApic},
+    clock::{self, ClockSource, Instant},
+    hpet::{self, EventMode, EventRoute},
+    info, percpu, smp,
+    spin::SpinLock,
+    task, warn, x86,
+};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+// スケジューラのティックの周期
+pub const TICK_HZ: u64 = 1000;
+
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const TIMER_MODE_ONESHOT: u32 = 0b00 << 17;
+const TIMER_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+const CPUID_FEAT_ECX_TSC_DEADLINE: u32 = 1 << 24;
+// Always Running APIC Timer. なければ深いCステートでAPICタイマが止まる
+const CPUID_POWER_EAX_ARAT: u32 = 1 << 2;
+
+// どちらのタイマを使うか. ビルド時の環境変数KERNEL_TIMERにapicかhpetを指定すると固定できる
+const TIMER_PREFERENCE: Option<&str> = option_env!("KERNEL_TIMER");
+
+// BSPで動いたスケジューラのティックの回数
+static BSP_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    TscDeadline,
+    // APICタイマが使えない場合に、CPU番号と同じ番号のHPETのコンパレータからFSBで割り込ませる
+    Hpet,
+}
 
 pub struct LocalApicTimer {
-    base: usize,
-    pub count: u32,
+    // 分周後のタイマクロック周波数
+    frequency_hz: u64,
+    // HPETのコンパレータを使えないCPUでのモード
+    mode: TimerMode,
+    // APICタイマが使えないため、CPUごとにHPETのコンパレータを使う
+    prefer_hpet: bool,
+}
+
+percpu! {
+    // このCPUがHPETのコンパレータで割り込ませている
+    static USES_HPET: AtomicBool = AtomicBool::new(false);
 }
 
 impl LocalApicTimer {
-    pub const BASE: usize = 0xFEE00000;
-    const TIMER: usize = 0x320;
-    const TIMER_DIV: usize = 0x3E0;
-    const TIMER_INIT_COUNT: usize = 0x380;
-    const END_OF_INTERRUPT: usize = 0xB0;
+    pub const VECTOR: u8 = 0x2a;
 
     pub const fn new() -> Self {
         LocalApicTimer {
-            base: Self::BASE,
-            count: 0,
+            frequency_hz: 0,
+            mode: TimerMode::OneShot,
+            prefer_hpet: false,
+        }
+    }
+
+    // HPETかPITで一定時間待つ間にAPICタイマがいくつ減ったかを数えて周波数を求める
+    fn calibrate(&mut self) {
+        let apic = apic::local();
+        apic.write(LocalApic::TIMER_DIV, TIMER_DIVIDE_BY_16);
+        apic.mask_lvt(LocalApic::LVT_TIMER);
+        apic.write(LocalApic::TIMER_INIT_COUNT, u32::MAX);
+        clock::calibration_wait(CALIBRATION_PERIOD);
+        let elapsed = u32::MAX - apic.read(LocalApic::TIMER_CURRENT_COUNT);
+        apic.write(LocalApic::TIMER_INIT_COUNT, 0);
+
+        self.frequency_hz = elapsed as u64 * 1_000_000 / CALIBRATION_PERIOD.as_micros() as u64;
+    }
+
+    // TSC-deadlineモードはTSCを単調時刻として使っている場合のみ利用する
+    fn select_mode(&self) -> TimerMode {
+        let supported = x86::cpuid(1, 0).ecx & CPUID_FEAT_ECX_TSC_DEADLINE != 0;
+        match clock::source() {
+            Some(ClockSource::Tsc { .. }) if supported => TimerMode::TscDeadline,
+            _ => TimerMode::OneShot,
+        }
+    }
+
+    // 較正に失敗した、ARATがない、TSC-deadlineモードを使えない場合はAPICタイマを信頼しない
+    fn usable(&self) -> bool {
+        let arat = x86::cpuid(0, 0).eax >= 6 && x86::cpuid(6, 0).eax & CPUID_POWER_EAX_ARAT != 0;
+        self.frequency_hz != 0 && arat && self.mode == TimerMode::TscDeadline
+    }
+
+    pub fn init(&mut self) {
+        if self.frequency_hz == 0 {
+            self.calibrate();
+            self.mode = self.select_mode();
+            self.prefer_hpet = prefer_hpet(
+                TIMER_PREFERENCE,
+                self.usable(),
+                self.frequency_hz != 0,
+                hpet::get().is_some(),
+            );
+            info!(
+                "APIC timer calibrated: {} Hz (divide by 16), mode {:?}",
+                self.frequency_hz,
+                if self.prefer_hpet {
+                    TimerMode::Hpet
+                } else {
+                    self.mode
+                }
+            );
         }
+        let apic = apic::local();
+        apic.write(LocalApic::TIMER_DIV, TIMER_DIVIDE_BY_16);
+        if self.prefer_hpet {
+            let cpu = smp::cpu_id();
+            let hpet = hpet::get().unwrap();
+            if cpu < hpet.comparator_count() && hpet.supports_fsb(cpu) {
+                USES_HPET.get().store(true, Ordering::Relaxed);
+                apic.mask_lvt(LocalApic::LVT_TIMER);
+                return;
+            }
+            warn!(
+                "CPU {}: no HPET comparator with FSB delivery, falling back to {:?}",
+                cpu, self.mode
+            );
+        }
+        let mode = match self.mode {
+            TimerMode::OneShot => TIMER_MODE_ONESHOT,
+            TimerMode::TscDeadline => TIMER_MODE_TSC_DEADLINE,
+            TimerMode::Hpet => unreachable!(),
+        };
+        // call interrupt handler 0x2a(42)
+        apic.write(LocalApic::LVT_TIMER, mode | Self::VECTOR as u32);
     }
 
-    fn register(&self, offset: usize) -> *mut u32 {
-        (self.base + offset) as *mut u32
+    // 次の期限で一度だけ割り込みが発生するようにする
+    fn mode(&self) -> TimerMode {
+        if USES_HPET.get().load(Ordering::Relaxed) {
+            TimerMode::Hpet
+        } else {
+            self.mode
+        }
     }
 
-    pub fn init(&self) {
-        unsafe {
-            core::ptr::write_volatile(self.register(Self::TIMER_DIV), 0b100);
-            core::ptr::write_volatile(self.register(Self::TIMER_INIT_COUNT), 0x1000000);
-            // periodic interrupt
-            // call interrupt handler 0x2a(42)
-            core::ptr::write_volatile(self.register(Self::TIMER), (0b010 << 16) | 0x2a);
+    fn arm(&self, deadline: Instant) {
+        match self.mode() {
+            TimerMode::OneShot => {
+                let nanos = deadline.duration_since(Instant::now()).as_nanos();
+                // 期限を過ぎていてもすぐに割り込みが起きるように最低1にする
+                // 長すぎる場合は途中で一度割り込み、その時点で再設定する
+                let count =
+                    (nanos * self.frequency_hz as u128 / 1_000_000_000).clamp(1, u32::MAX as u128);
+                apic::local().write(LocalApic::TIMER_INIT_COUNT, count as u32);
+            }
+            TimerMode::TscDeadline => {
+                let tsc = clock::instant_to_tsc(deadline).unwrap_or(0).max(1);
+                x86::write_msr(IA32_TSC_DEADLINE, tsc);
+            }
+            TimerMode::Hpet => {
+                let route = EventRoute::Fsb {
+                    apic_id: apic::local().id(),
+                    vector: Self::VECTOR,
+                };
+                let delay = deadline.duration_since(Instant::now());
+                hpet::get()
+                    .unwrap()
+                    .start_event(smp::cpu_id(), EventMode::OneShot, delay, route)
+                    .expect("Failed to arm the HPET comparator");
+            }
         }
     }
 
-    fn increment_count(&mut self) {
-        self.count += 1;
-        if self.count == 0 {
-            self.count = 1;
+    fn disarm(&self) {
+        match self.mode() {
+            TimerMode::OneShot => apic::local().write(LocalApic::TIMER_INIT_COUNT, 0),
+            TimerMode::TscDeadline => x86::write_msr(IA32_TSC_DEADLINE, 0),
+            TimerMode::Hpet => hpet::get().unwrap().stop_event(smp::cpu_id()),
         }
     }
 
-    fn notify_end_of_interrupt(&self) {
-        unsafe {
-            core::ptr::write_volatile(self.register(Self::END_OF_INTERRUPT), 0);
+    #[allow(dead_code)]
+    pub fn frequency_hz(&self) -> u64 {
+        self.frequency_hz
+    }
+}
+
+// APICタイマが使えなければHPETを選ぶ. 指定があればそれに従うが、使えないタイマは選ばない
+fn prefer_hpet(
+    preference: Option<&str>,
+    apic_usable: bool,
+    apic_calibrated: bool,
+    hpet_available: bool,
+) -> bool {
+    match preference {
+        Some("apic") if apic_calibrated => false,
+        Some("hpet") => hpet_available,
+        Some("apic") | None => !apic_usable && hpet_available,
+        Some(other) => {
+            warn!("Unknown KERNEL_TIMER {:?}, selecting automatically", other);
+            !apic_usable && hpet_available
         }
     }
 }
 
 static LOCAL_APIC_TIMER: SpinLock<LocalApicTimer> = SpinLock::new(LocalApicTimer::new());
 
+pub type TimerCallback = Box<dyn FnOnce() + Send>;
+
+// add_timerの戻り値. キャンセルに使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    cpu: usize,
+    deadline: Instant,
+    id: u64,
+}
+
+// 期限順に並んだタイマのキュー. 同じ期限のタイマは登録順に発火する
+struct TimerQueue {
+    timers: BTreeMap<(Instant, u64), TimerCallback>,
+    next_id: u64,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        TimerQueue {
+            timers: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timers.keys().next().map(|(deadline, _)| *deadline)
+    }
+
+    fn pop_expired(&mut self, now: Instant) -> Vec<TimerCallback> {
+        let pending = self.timers.split_off(&(now, u64::MAX));
+        let expired = core::mem::replace(&mut self.timers, pending);
+        expired.into_values().collect()
+    }
+}
+
+percpu! {
+    // タイマはそれを登録したCPUのAPICタイマで発火する
+    static TIMER_QUEUE: SpinLock<TimerQueue> = SpinLock::new(TimerQueue::new());
+    static SCHEDULER_TICK_RUNNING: AtomicBool = AtomicBool::new(false);
+}
+
+fn rearm(queue: &TimerQueue) {
+    let timer = LOCAL_APIC_TIMER.lock();
+    match queue.next_deadline() {
+        Some(deadline) => timer.arm(deadline),
+        None => timer.disarm(),
+    }
+}
+
+// deadlineを過ぎた後のタイマ割り込みでcallbackを呼び出す
+// callbackは割り込みコンテキストで実行されるため、短く終わらせる必要がある
+pub fn add_timer(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> TimerHandle {
+    TIMER_QUEUE.with(|queue| {
+        let cpu = smp::cpu_id();
+        let mut queue = queue.lock();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        let is_earliest = queue.next_deadline().is_none_or(|next| deadline < next);
+        queue.timers.insert((deadline, id), Box::new(callback));
+        if is_earliest {
+            rearm(&queue);
+        }
+        TimerHandle { cpu, deadline, id }
+    })
+}
+
+// まだ発火していなければキャンセルしてtrueを返す
+#[allow(dead_code)]
+pub fn cancel_timer(handle: TimerHandle) -> bool {
+    let mut queue = TIMER_QUEUE.get_for(handle.cpu).lock();
+    let removed = queue.timers.remove(&(handle.deadline, handle.id)).is_some();
+    // 他のCPUのAPICタイマは設定できないため、そのまま発火させる (空振りするだけ)
+    if removed && handle.cpu == smp::cpu_id() {
+        rearm(&queue);
+    }
+    removed
+}
+
+pub fn handle_interrupt() {
+    apic::end_of_interrupt();
+    let queue = TIMER_QUEUE.get();
+    let expired = queue.lock().pop_expired(Instant::now());
+    for callback in expired {
+        callback();
+    }
+    rearm(&queue.lock());
+}
+
+// タスクの切り替えが必要な間だけ周期的に動くCPUごとのティック
+// 実行を待っているタスクがなくなれば止まり、spawn時に再開される
+fn scheduler_tick() {
+    // 時刻のフォールバックに使うため、ティック数はBSPでのみ数える
+    let cpu = smp::cpu_id();
+    if cpu == 0 {
+        BSP_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    task::tick();
+    let keep_running =
+        task::waiting_count() > 0 || matches!(clock::source(), Some(ClockSource::Tick) | None);
+    if keep_running {
+        add_timer(Instant::now() + ticks_to_duration(1), scheduler_tick);
+    } else {
+        SCHEDULER_TICK_RUNNING.get().store(false, Ordering::Release);
+    }
+}
+
+pub fn start_scheduler_tick() {
+    SCHEDULER_TICK_RUNNING.with(|running| {
+        if !running.swap(true, Ordering::AcqRel) {
+            add_timer(Instant::now() + ticks_to_duration(1), scheduler_tick);
+        }
+    });
+}
+
+// 各CPUで呼び出す. 周波数の測定はBSPでの初回のみ行う
+// スケジューラのティックは最初のタスクのspawn時 (APでは起動時) に開始する
 pub fn init_timer() {
     LOCAL_APIC_TIMER.lock().init();
 }
 
+// ティックはタスクの切り替えが不要になると止まるため、普段は経過時間を表さない
+// clockのソースがTickの間だけは止まらずに動き続け、時刻として使える
+// 経過時間にはclock::Instantかuptimeを使う
+pub fn bsp_tick_count() -> u64 {
+    BSP_TICKS.load(Ordering::Relaxed)
+}
+
+pub const fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * (1_000_000_000 / TICK_HZ))
+}
+
+pub const fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() as u64).div_ceil(1_000_000_000 / TICK_HZ)
+}
+
+// 起動後の経過時間
 #[allow(dead_code)]
-pub fn get_count() -> u32 {
-    LOCAL_APIC_TIMER.lock().count
+pub fn uptime() -> Duration {
+    Instant::now().since_boot()
 }
 
-pub fn increment_count() {
-    LOCAL_APIC_TIMER.lock().increment_count();
+// 割り込みが有効である必要がある
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    // 期限に少なくとも一度割り込みが起きるようにしておく
+    add_timer(deadline, || {});
+    while Instant::now() < deadline {
+        x86::halt();
+    }
+}
+
+#[allow(dead_code)]
+pub fn sleep_ms(ms: u64) {
+    sleep(Duration::from_millis(ms));
 }
 
-pub fn notify_end_of_interrupt() {
-    LOCAL_APIC_TIMER.lock().notify_end_of_interrupt();
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::{sync::Arc, vec};
+
+    #[test_case]
+    fn tick_duration_roundtrip() {
+        assert_eq!(duration_to_ticks(ticks_to_duration(42)), 42);
+        assert_eq!(duration_to_ticks(Duration::from_micros(1)), 1);
+        assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+    }
+
+    #[test_case]
+    fn hpet_replaces_unusable_apic_timer() {
+        assert!(!prefer_hpet(None, true, true, true));
+        // ARATやTSC-deadlineがない、または較正に失敗した
+        assert!(prefer_hpet(None, false, true, true));
+        assert!(prefer_hpet(None, false, false, true));
+        assert!(!prefer_hpet(None, false, true, false));
+        // 指定があればそれに従う
+        assert!(prefer_hpet(Some("hpet"), true, true, true));
+        assert!(!prefer_hpet(Some("apic"), false, true, true));
+        assert!(prefer_hpet(Some("apic"), false, false, true));
+    }
+
+    #[test_case]
+    fn uptime_advances() {
+        let start = uptime();
+        sleep(Duration::from_millis(20));
+        assert!(uptime() - start >= Duration::from_millis(20));
+    }
+
+    #[test_case]
+    fn timers_fire_in_deadline_order() {
+        let fired = Arc::new(SpinLock::new(Vec::new()));
+        let now = Instant::now();
+        for (delay, value) in [(20, 2), (10, 1), (15, 3)] {
+            let fired = fired.clone();
+            let handle = add_timer(now + Duration::from_millis(delay), move || {
+                fired.lock().push(value)
+            });
+            if value == 3 {
+                assert!(cancel_timer(handle));
+            }
+        }
+        sleep(Duration::from_millis(30));
+        assert_eq!(*fired.lock(), vec![1, 2]);
+    }
 }
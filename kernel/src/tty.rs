@@ -0,0 +1,312 @@
+use crate::{
+    fbcon,
+    spin::{Once, SpinLock},
+    uart::{self, SerialPort},
+    warn, x86,
+};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const ESCAPE: u8 = 0x1B;
+const DELETE: u8 = 0x7F;
+
+// 編集中の行の最大長 (超えた分は捨てる)
+const MAX_LINE_LEN: usize = 1024;
+// 読み出されていない入力の最大量
+const MAX_INPUT_LEN: usize = 4096;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // 行単位で編集し、改行を受け取ってから読み出せるようにする
+    Cooked,
+    // 受け取ったバイトをそのまま渡す (Ctrl-Cも文字として渡す)
+    Raw,
+}
+
+// 行の編集では使わないエスケープシーケンス (矢印キーなど) を読み飛ばす状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    // ESCを受け取った
+    Started,
+    // ESC [ またはESC O の後、終端のバイトを待っている
+    Sequence,
+}
+
+struct LineDiscipline {
+    mode: Mode,
+    echo: bool,
+    escape: Escape,
+    // 編集中の行
+    line: Vec<u8>,
+    // 読み出せる入力
+    input: VecDeque<u8>,
+}
+
+impl LineDiscipline {
+    const fn new() -> Self {
+        LineDiscipline {
+            mode: Mode::Cooked,
+            echo: true,
+            escape: Escape::None,
+            line: Vec::new(),
+            input: VecDeque::new(),
+        }
+    }
+
+    // 1バイト処理し、エコーする内容をechoに加える. Ctrl-Cを受け取ればtrueを返す
+    fn receive(&mut self, byte: u8, echo: &mut Vec<u8>) -> bool {
+        if self.mode == Mode::Raw {
+            if self.input.len() < MAX_INPUT_LEN {
+                self.input.push_back(byte);
+                if self.echo {
+                    echo.push(byte);
+                }
+            }
+            return false;
+        }
+        if byte != CTRL_C && self.escape != Escape::None {
+            self.escape = match (self.escape, byte) {
+                (Escape::Started, b'[' | b'O') => Escape::Sequence,
+                // 終端のバイトまでが1つのシーケンス
+                (Escape::Sequence, 0x20..0x40) => Escape::Sequence,
+                _ => Escape::None,
+            };
+            return false;
+        }
+        match byte {
+            ESCAPE => self.escape = Escape::Started,
+            CTRL_C => {
+                self.escape = Escape::None;
+                self.line.clear();
+                self.input.clear();
+                if self.echo {
+                    echo.extend(b"^C\n");
+                }
+                return true;
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() && self.echo {
+                    echo.extend(b"\x08 \x08");
+                }
+            }
+            // 端末のEnterはCRを送ってくる
+            b'\r' | b'\n' => {
+                if self.input.len() + self.line.len() < MAX_INPUT_LEN {
+                    self.input.extend(self.line.drain(..));
+                    self.input.push_back(b'\n');
+                }
+                self.line.clear();
+                if self.echo {
+                    echo.push(b'\n');
+                }
+            }
+            b'\t' | 0x20..0x7F | 0x80.. if self.line.len() < MAX_LINE_LEN => {
+                self.line.push(byte);
+                if self.echo {
+                    echo.push(byte);
+                }
+            }
+            // その他の制御文字と、長すぎる行の続きは無視する
+            _ => {}
+        }
+        false
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        let end = self.input.iter().position(|&byte| byte == b'\n')?;
+        let line: Vec<u8> = self.input.drain(..=end).take(end).collect();
+        Some(String::from_utf8_lossy(&line).into_owned())
+    }
+}
+
+pub struct Tty {
+    port: &'static SerialPort,
+    ldisc: SpinLock<LineDiscipline>,
+    // Ctrl-Cを受け取ってから、まだ誰も確認していない
+    interrupted: AtomicBool,
+}
+
+impl Tty {
+    // ポートが受信したバイトを全て行規律に通す
+    fn receive(&self) {
+        let mut data = Vec::new();
+        while let Some(byte) = self.port.read_byte() {
+            data.push(byte);
+        }
+        self.input(&data);
+    }
+
+    // キーボードなど、シリアル以外からの入力も行規律に通す
+    pub fn input(&self, data: &[u8]) {
+        let mut echo = Vec::new();
+        let mut interrupted = false;
+        {
+            let mut ldisc = self.ldisc.lock();
+            for &byte in data {
+                interrupted |= ldisc.receive(byte, &mut echo);
+            }
+        }
+        if interrupted {
+            self.interrupted.store(true, Ordering::Release);
+        }
+        if !echo.is_empty() {
+            self.write(&echo);
+        }
+    }
+
+    // 改行はCRLFにしてシリアルに出力し、フレームバッファのコンソールにも表示する
+    pub fn write(&self, data: &[u8]) {
+        for (i, chunk) in data.split(|&byte| byte == b'\n').enumerate() {
+            if i > 0 {
+                self.port.write(b"\r\n");
+            }
+            self.port.write(chunk);
+        }
+        fbcon::write(data);
+    }
+
+    pub fn write_str(&self, s: &str) {
+        self.write(s.as_bytes());
+    }
+
+    // 読み出せる入力があればbufに読み出し、そのバイト数を返す
+    #[allow(dead_code)]
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut ldisc = self.ldisc.lock();
+        let len = if ldisc.mode == Mode::Raw {
+            ldisc.input.len()
+        } else {
+            // 改行まで入力されたものだけを読み出す
+            ldisc
+                .input
+                .iter()
+                .rposition(|&byte| byte == b'\n')
+                .map_or(0, |end| end + 1)
+        }
+        .min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(ldisc.input.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+
+    // 1行読み出す. Ctrl-Cで中断されたらErrを返す
+    pub fn read_line(&self) -> Result<String, &'static str> {
+        loop {
+            if self.take_interrupt() {
+                return Err("Interrupted");
+            }
+            if let Some(line) = self.ldisc.lock().read_line() {
+                return Ok(line);
+            }
+            x86::halt();
+        }
+    }
+
+    // Ctrl-Cを受け取っていればtrueを返し、その記録を消す
+    pub fn take_interrupt(&self) -> bool {
+        self.interrupted.swap(false, Ordering::AcqRel)
+    }
+
+    #[allow(dead_code)]
+    pub fn mode(&self) -> Mode {
+        self.ldisc.lock().mode
+    }
+
+    // モードを切り替えると、編集中の行は入力として渡す
+    #[allow(dead_code)]
+    pub fn set_mode(&self, mode: Mode) {
+        let mut ldisc = self.ldisc.lock();
+        let ldisc = &mut *ldisc;
+        ldisc.input.extend(ldisc.line.drain(..));
+        ldisc.mode = mode;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_echo(&self, echo: bool) {
+        self.ldisc.lock().echo = echo;
+    }
+}
+
+static CONSOLE: Once<Tty> = Once::new();
+
+// シリアル (COM1) とフレームバッファに出力するコンソールのTTY
+pub fn console() -> &'static Tty {
+    CONSOLE.get().expect("TTY is not initialized")
+}
+
+// ioapic::initの後に呼び出す. 割り込みが使えない場合は入力を受け付けない
+pub fn init() {
+    let port = uart::com1();
+    CONSOLE.call_once(|| Tty {
+        port,
+        ldisc: SpinLock::new(LineDiscipline::new()),
+        interrupted: AtomicBool::new(false),
+    });
+    port.set_receiver(|| console().receive());
+    if let Err(e) = port.enable_interrupts() {
+        warn!("tty: serial input is disabled: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed(ldisc: &mut LineDiscipline, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut echo = Vec::new();
+        let mut interrupted = false;
+        for &byte in input {
+            interrupted |= ldisc.receive(byte, &mut echo);
+        }
+        (echo, interrupted)
+    }
+
+    #[test_case]
+    fn cooked_mode_edits_lines() {
+        let mut ldisc = LineDiscipline::new();
+        let (echo, interrupted) = feed(&mut ldisc, b"lsx\x7fpci");
+        assert_eq!(echo, b"lsx\x08 \x08pci");
+        assert!(!interrupted);
+        assert_eq!(ldisc.read_line(), None);
+
+        feed(&mut ldisc, b"\rps\r");
+        assert_eq!(ldisc.read_line().as_deref(), Some("lspci"));
+        assert_eq!(ldisc.read_line().as_deref(), Some("ps"));
+
+        let (echo, interrupted) = feed(&mut ldisc, b"reboot\x03");
+        assert!(interrupted);
+        assert!(echo.ends_with(b"^C\n"));
+        feed(&mut ldisc, b"\r");
+        assert_eq!(ldisc.read_line().as_deref(), Some(""));
+    }
+
+    #[test_case]
+    fn cooked_mode_skips_escape_sequences() {
+        let mut ldisc = LineDiscipline::new();
+        // 上矢印、Delete、F1 (SS3)、Alt+x
+        let (echo, _) = feed(&mut ldisc, b"p\x1b[As\x1b[3~\x1bOP\x1bx\r");
+        assert_eq!(echo, b"ps\n");
+        assert_eq!(ldisc.read_line().as_deref(), Some("ps"));
+
+        // シーケンスの途中でもCtrl-Cは効く
+        let (_, interrupted) = feed(&mut ldisc, b"\x1b[1\x03[A\r");
+        assert!(interrupted);
+        assert_eq!(ldisc.read_line().as_deref(), Some("[A"));
+    }
+
+    #[test_case]
+    fn raw_mode_passes_bytes_through() {
+        let mut ldisc = LineDiscipline::new();
+        ldisc.mode = Mode::Raw;
+        ldisc.echo = false;
+        let (echo, interrupted) = feed(&mut ldisc, b"a\x03\x7f");
+        assert!(echo.is_empty());
+        assert!(!interrupted);
+        assert_eq!(ldisc.input, [b'a', CTRL_C, DELETE]);
+    }
+}
@@ -1,16 +1,49 @@
-use crate::x86;
+use crate::{
+    apic, idt, ioapic,
+    spin::{Once, SpinLock},
+    x86,
+};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 const IO_ADDR_COM1: u16 = 0x3F8;
+// COM1はISAのIRQ4
+const IRQ_COM1: u8 = 4;
 
 #[allow(dead_code)]
 const IO_BAUD_DIVISOR: u16 = 0x01;
 
+// レジスタ (ベースアドレスからのオフセット)
+const REG_DATA: u16 = 0;
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_INTERRUPT_ID: u16 = 2;
+const REG_LINE_STATUS: u16 = 5;
+const REG_MODEM_STATUS: u16 = 6;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0x0E;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RX_TIMEOUT: u8 = 0x0C;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+// initで有効にする送信FIFOの大きさ
+const FIFO_SIZE: usize = 16;
+const RX_BUFFER_SIZE: usize = 1024;
+const TX_BUFFER_SIZE: usize = 4096;
+
 pub struct Uart {
     base: u16,
 }
 
 impl Uart {
-    pub fn new(base: u16) -> Self {
+    pub const fn new(base: u16) -> Self {
         Uart { base }
     }
 
@@ -25,10 +58,10 @@ impl Uart {
     }
 
     pub fn write(&self, byte: char) {
-        while x86::read_io(self.base + 5) & 0x20 == 0 {
+        while x86::read_io(self.base + REG_LINE_STATUS) & LSR_TX_EMPTY == 0 {
             core::hint::spin_loop();
         }
-        x86::write_io(self.base, byte as u8);
+        x86::write_io(self.base + REG_DATA, byte as u8);
     }
 
     pub fn write_str(&self, s: &str) {
@@ -36,6 +69,12 @@ impl Uart {
             self.write(byte as char);
         }
     }
+
+    // 受信済みのバイトがあれば読み出す
+    pub fn try_read(&self) -> Option<u8> {
+        (x86::read_io(self.base + REG_LINE_STATUS) & LSR_DATA_READY != 0)
+            .then(|| x86::read_io(self.base + REG_DATA))
+    }
 }
 
 impl core::fmt::Write for Uart {
@@ -51,3 +90,204 @@ impl Default for Uart {
         Uart::new(IO_ADDR_COM1)
     }
 }
+
+struct RingBuffer<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        RingBuffer {
+            data: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    // 満杯なら何もせずfalseを返す
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.data[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+struct Buffers {
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+}
+
+// 割り込みで送受信するシリアルポート. 割り込みを有効にするまではポーリングで動く
+pub struct SerialPort {
+    uart: Uart,
+    irq: u8,
+    buffers: SpinLock<Buffers>,
+    interrupts_enabled: AtomicBool,
+    // 受信したときに割り込みハンドラから呼ぶ
+    receiver: Once<fn()>,
+}
+
+static COM1: SerialPort = SerialPort {
+    uart: Uart::new(IO_ADDR_COM1),
+    irq: IRQ_COM1,
+    buffers: SpinLock::new(Buffers {
+        rx: RingBuffer::new(),
+        tx: RingBuffer::new(),
+    }),
+    interrupts_enabled: AtomicBool::new(false),
+    receiver: Once::new(),
+};
+
+pub fn com1() -> &'static SerialPort {
+    &COM1
+}
+
+impl SerialPort {
+    // 受信と送信の割り込みを、I/O APIC経由でこのCPUに届ける
+    pub fn enable_interrupts(&'static self) -> Result<(), &'static str> {
+        let vector = idt::allocate_vector(Arc::new(move || self.handle_interrupt()))?;
+        if let Err(e) = ioapic::route_isa_irq(self.irq, vector, apic::local().id()) {
+            idt::free_vector(vector);
+            return Err(e);
+        }
+        self.interrupts_enabled.store(true, Ordering::Release);
+        x86::write_io(self.uart.base + REG_INTERRUPT_ENABLE, IER_RX_AVAILABLE);
+        Ok(())
+    }
+
+    pub fn set_receiver(&self, receiver: fn()) {
+        self.receiver.call_once(|| receiver);
+    }
+
+    pub fn read_byte(&self) -> Option<u8> {
+        if !self.interrupts_enabled.load(Ordering::Acquire) {
+            return self.uart.try_read();
+        }
+        self.buffers.lock().rx.pop()
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        if !self.interrupts_enabled.load(Ordering::Acquire) {
+            for &byte in data {
+                self.uart.write(byte as char);
+            }
+            return;
+        }
+        let mut buffers = self.buffers.lock();
+        for &byte in data {
+            // 満杯のときは古いものから直接送って空ける
+            while !buffers.tx.push(byte) {
+                if let Some(byte) = buffers.tx.pop() {
+                    self.uart.write(byte as char);
+                }
+            }
+        }
+        // 送信FIFOが空なら、有効にした時点で割り込みが起きる
+        x86::write_io(
+            self.uart.base + REG_INTERRUPT_ENABLE,
+            IER_RX_AVAILABLE | IER_TX_EMPTY,
+        );
+    }
+
+    // 割り込みを待たずに、リングバッファに残っている分をポーリングで送る
+    // パニック時など、割り込みが来ない場面で使う. ロックを取れなければ諦める
+    pub fn try_flush(&self) {
+        if let Some(mut buffers) = self.buffers.try_lock() {
+            while let Some(byte) = buffers.tx.pop() {
+                self.uart.write(byte as char);
+            }
+        }
+    }
+
+    // 送信FIFOにリングバッファから詰める. 送るものがなくなれば送信の割り込みを止める
+    fn fill_fifo(&self, buffers: &mut Buffers) {
+        for _ in 0..FIFO_SIZE {
+            let Some(byte) = buffers.tx.pop() else {
+                break;
+            };
+            x86::write_io(self.uart.base + REG_DATA, byte);
+        }
+        let ier = if buffers.tx.is_empty() {
+            IER_RX_AVAILABLE
+        } else {
+            IER_RX_AVAILABLE | IER_TX_EMPTY
+        };
+        x86::write_io(self.uart.base + REG_INTERRUPT_ENABLE, ier);
+    }
+
+    fn handle_interrupt(&self) {
+        let mut received = false;
+        {
+            let mut buffers = self.buffers.lock();
+            // エッジトリガなので、要因がなくなるまで処理する
+            loop {
+                let iir = x86::read_io(self.uart.base + REG_INTERRUPT_ID);
+                if iir & IIR_NO_INTERRUPT != 0 {
+                    break;
+                }
+                match iir & IIR_ID_MASK {
+                    IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => {
+                        // 溢れた分は捨てる
+                        while let Some(byte) = self.uart.try_read() {
+                            buffers.rx.push(byte);
+                            received = true;
+                        }
+                    }
+                    IIR_TX_EMPTY => self.fill_fifo(&mut buffers),
+                    IIR_LINE_STATUS => {
+                        x86::read_io(self.uart.base + REG_LINE_STATUS);
+                    }
+                    _ => {
+                        x86::read_io(self.uart.base + REG_MODEM_STATUS);
+                    }
+                }
+            }
+        }
+        // 受け取った側がエコーなどで書き込めるよう、ロックの外で呼ぶ
+        if received && let Some(receiver) = self.receiver.get() {
+            receiver();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn ring_buffer_wraps_around() {
+        let mut ring = RingBuffer::<4>::new();
+        for byte in 0..4 {
+            assert!(ring.push(byte));
+        }
+        assert!(!ring.push(4));
+        assert_eq!(ring.pop(), Some(0));
+        assert_eq!(ring.pop(), Some(1));
+        assert!(ring.push(4));
+        assert!(ring.push(5));
+        assert_eq!(
+            core::iter::from_fn(|| ring.pop()).collect::<alloc::vec::Vec<_>>(),
+            [2, 3, 4, 5]
+        );
+        assert!(ring.is_empty());
+    }
+}
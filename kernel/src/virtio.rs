@@ -0,0 +1,627 @@
+use crate::{
+    frame::{self, FRAME_SIZE},
+    idt::InterruptHandler,
+    memlayout::{self, Address, MSize, PhysAddr, VirtAddr},
+    msi::MsiX,
+    paging,
+    pci::{self, Bar, PciDevice, PciMatch},
+};
+use core::sync::atomic::{Ordering, fence};
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+// modernなデバイスのデバイスIDは0x1040 + デバイスタイプ
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+// transitionalなデバイスのデバイスID. デバイスタイプはサブシステムIDに入っている
+const TRANSITIONAL_DEVICE_ID_START: u16 = 0x1000;
+const TRANSITIONAL_DEVICE_ID_END: u16 = 0x103F;
+const REG_SUBSYSTEM_ID: u16 = 0x2E;
+
+// virtio PCI capability (vendor specific capability) のフィールド
+const CAP_CFG_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_LENGTH: u16 = 12;
+const CAP_NOTIFY_OFF_MULTIPLIER: u16 = 16;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// common configuration structureのレジスタ
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_CONFIG_MSIX_VECTOR: usize = 0x10;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+// MSI-Xのエントリを割り当てない
+const NO_VECTOR: u16 = 0xFFFF;
+
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// virtqueueの大きさの上限 (ディスクリプタテーブルが1ページに収まる)
+const MAX_QUEUE_SIZE: u16 = 256;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum DeviceType {
+    Net = 1,
+    Block = 2,
+    Console = 3,
+    Entropy = 4,
+    Input = 18,
+}
+
+impl DeviceType {
+    // このデバイスタイプに一致するPCIのID (modern, transitional)
+    pub const fn pci_matches(self) -> [PciMatch; 2] {
+        let modern = PciMatch::device(VIRTIO_VENDOR_ID, MODERN_DEVICE_ID_BASE + self as u16);
+        let transitional = match self {
+            DeviceType::Net => PciMatch::device(VIRTIO_VENDOR_ID, 0x1000),
+            DeviceType::Block => PciMatch::device(VIRTIO_VENDOR_ID, 0x1001),
+            DeviceType::Console => PciMatch::device(VIRTIO_VENDOR_ID, 0x1003),
+            DeviceType::Entropy => PciMatch::device(VIRTIO_VENDOR_ID, 0x1005),
+            // virtio-inputにはtransitionalなデバイスIDがない
+            DeviceType::Input => modern,
+        };
+        [modern, transitional]
+    }
+}
+
+// PCIデバイスのvirtioのデバイスタイプ番号
+#[allow(dead_code)]
+pub fn device_type_id(device: &PciDevice) -> Option<u16> {
+    if device.vendor_id != VIRTIO_VENDOR_ID {
+        return None;
+    }
+    match device.device_id {
+        id if id >= MODERN_DEVICE_ID_BASE => Some(id - MODERN_DEVICE_ID_BASE),
+        TRANSITIONAL_DEVICE_ID_START..=TRANSITIONAL_DEVICE_ID_END => {
+            Some(device.read_u16(REG_SUBSYSTEM_ID))
+        }
+        _ => None,
+    }
+}
+
+// capabilityが指すBAR内の領域をマップする
+fn map_capability(device: &PciDevice, cap: u16) -> Result<(VirtAddr, usize), &'static str> {
+    let bar = device.read_u8(cap + CAP_BAR) as usize;
+    let offset = device.read_u32(cap + CAP_OFFSET) as u64;
+    let length = device.read_u32(cap + CAP_LENGTH) as u64;
+    let Some(Bar::Memory { address, size, .. }) = device.bars.get(bar).copied().flatten() else {
+        return Err("virtio capability does not point to a memory BAR");
+    };
+    if address == 0 || length == 0 || offset + length > size {
+        return Err("virtio capability is outside of its BAR");
+    }
+    let virt = paging::map_io(
+        PhysAddr::new((address + offset) as usize),
+        MSize::new(length as usize),
+    )?;
+    Ok((virt, length as usize))
+}
+
+fn read_volatile<T>(addr: usize) -> T {
+    unsafe { core::ptr::read_volatile(addr as *const T) }
+}
+
+fn write_volatile<T>(addr: usize, value: T) {
+    unsafe { core::ptr::write_volatile(addr as *mut T, value) }
+}
+
+// virtio-pci (modern) のトランスポート
+pub struct VirtioDevice {
+    pci: &'static PciDevice,
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_off_multiplier: u32,
+    isr: VirtAddr,
+    device_config: Option<(VirtAddr, usize)>,
+    msix: Option<MsiX>,
+    // MSI-Xのテーブルで次に使うエントリ
+    next_msix_entry: usize,
+}
+
+impl VirtioDevice {
+    pub fn new(pci: &'static PciDevice) -> Result<Self, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_config = None;
+        // 同じ種類のcapabilityが複数あれば最初のものを使う
+        for cap in pci.capabilities_with_id(pci::CAP_ID_VENDOR) {
+            let cap = cap.offset;
+            match pci.read_u8(cap + CAP_CFG_TYPE) {
+                CFG_TYPE_COMMON if common.is_none() => {
+                    common = Some(map_capability(pci, cap)?.0);
+                }
+                CFG_TYPE_NOTIFY if notify.is_none() => {
+                    let multiplier = pci.read_u32(cap + CAP_NOTIFY_OFF_MULTIPLIER);
+                    notify = Some((map_capability(pci, cap)?.0, multiplier));
+                }
+                CFG_TYPE_ISR if isr.is_none() => {
+                    isr = Some(map_capability(pci, cap)?.0);
+                }
+                CFG_TYPE_DEVICE if device_config.is_none() => {
+                    device_config = Some(map_capability(pci, cap)?);
+                }
+                _ => {}
+            }
+        }
+        let (notify, notify_off_multiplier) =
+            notify.ok_or("virtio notify capability is not found")?;
+        pci.enable();
+        Ok(VirtioDevice {
+            pci,
+            common: common.ok_or("virtio common capability is not found (legacy only device?)")?,
+            notify,
+            notify_off_multiplier,
+            isr: isr.ok_or("virtio ISR capability is not found")?,
+            device_config,
+            // MSI-Xがなければ割り込みは使えず、ポーリングになる
+            msix: MsiX::new(pci).ok(),
+            next_msix_entry: 0,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn pci(&self) -> &'static PciDevice {
+        self.pci
+    }
+
+    fn common_read<T>(&self, offset: usize) -> T {
+        read_volatile(self.common.to_usize() + offset)
+    }
+
+    fn common_write<T>(&self, offset: usize, value: T) {
+        write_volatile(self.common.to_usize() + offset, value)
+    }
+
+    fn status(&self) -> u8 {
+        self.common_read(COMMON_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.common_write(COMMON_DEVICE_STATUS, status);
+    }
+
+    fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    pub fn reset(&self) {
+        self.set_status(0);
+        // 0が読めるまでリセットは完了していない
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.common_write(COMMON_DEVICE_FEATURE_SELECT, 0u32);
+        let low: u32 = self.common_read(COMMON_DEVICE_FEATURE);
+        self.common_write(COMMON_DEVICE_FEATURE_SELECT, 1u32);
+        let high: u32 = self.common_read(COMMON_DEVICE_FEATURE);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.common_write(COMMON_DRIVER_FEATURE_SELECT, 0u32);
+        self.common_write(COMMON_DRIVER_FEATURE, features as u32);
+        self.common_write(COMMON_DRIVER_FEATURE_SELECT, 1u32);
+        self.common_write(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+    }
+
+    // デバイスをリセットし、featuresのうちデバイスが対応するものを有効にする
+    // VERSION_1は常に要求する. 有効にした機能を返す
+    pub fn negotiate_features(&self, features: u64) -> Result<u64, &'static str> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+        let negotiated = self.device_features() & (features | F_VERSION_1);
+        if negotiated & F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err("virtio device does not support VERSION_1");
+        }
+        self.set_driver_features(negotiated);
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err("virtio device rejected the features");
+        }
+        Ok(negotiated)
+    }
+
+    pub fn num_queues(&self) -> u16 {
+        self.common_read(COMMON_NUM_QUEUES)
+    }
+
+    // MSI-Xのエントリを1つ割り当て、handlerを呼ぶようにする. エントリ番号とベクタを返す
+    fn allocate_msix_entry(
+        &mut self,
+        cpu: usize,
+        handler: InterruptHandler,
+    ) -> Result<(u16, u8), &'static str> {
+        let msix = self
+            .msix
+            .as_ref()
+            .ok_or("virtio device does not support MSI-X")?;
+        let entry = self.next_msix_entry;
+        if entry >= msix.table_size() {
+            return Err("No free MSI-X table entry");
+        }
+        let vector = msix.set_vector(entry, cpu, handler)?;
+        self.next_msix_entry += 1;
+        Ok((entry as u16, vector))
+    }
+
+    // デバイスに設定できなかったエントリを戻す. 最後に割り当てたものなら再利用する
+    fn free_msix_entry(&mut self, entry: u16, vector: u8) {
+        if let Some(msix) = &self.msix {
+            msix.clear_vector(entry as usize, vector);
+        }
+        if entry as usize + 1 == self.next_msix_entry {
+            self.next_msix_entry -= 1;
+        }
+    }
+
+    // キューindexを作成して有効にする. handlerを渡すと、使用済みのバッファが返った時に
+    // MSI-Xでcpuに割り込み、handlerが呼ばれる
+    pub fn setup_queue(
+        &mut self,
+        index: u16,
+        interrupt: Option<(usize, InterruptHandler)>,
+    ) -> Result<VirtQueue, &'static str> {
+        if index >= self.num_queues() {
+            return Err("No such virtqueue");
+        }
+        self.common_write(COMMON_QUEUE_SELECT, index);
+        let max_size: u16 = self.common_read(COMMON_QUEUE_SIZE);
+        if max_size == 0 {
+            return Err("virtqueue is not available");
+        }
+        let size = max_size.min(MAX_QUEUE_SIZE);
+        let notify_off: u16 = self.common_read(COMMON_QUEUE_NOTIFY_OFF);
+        let notify = VirtAddr::new(
+            self.notify.to_usize() + notify_off as usize * self.notify_off_multiplier as usize,
+        );
+        // 失敗して返る時は、queueのDropでフレームが解放される
+        let queue = VirtQueue::new(index, size, notify)?;
+
+        let allocated = match interrupt {
+            Some((cpu, handler)) => Some(self.allocate_msix_entry(cpu, handler)?),
+            None => None,
+        };
+        let vector = allocated.map_or(NO_VECTOR, |(entry, _)| entry);
+        self.common_write(COMMON_QUEUE_SELECT, index);
+        self.common_write(COMMON_QUEUE_SIZE, size);
+        self.common_write(COMMON_QUEUE_MSIX_VECTOR, vector);
+        if self.common_read::<u16>(COMMON_QUEUE_MSIX_VECTOR) != vector {
+            self.common_write(COMMON_QUEUE_MSIX_VECTOR, NO_VECTOR);
+            if let Some((entry, vector)) = allocated {
+                self.free_msix_entry(entry, vector);
+            }
+            return Err("virtio device could not allocate the MSI-X vector");
+        }
+        self.common_write(COMMON_QUEUE_DESC, queue.desc_phys.to_usize() as u64);
+        self.common_write(COMMON_QUEUE_DRIVER, queue.avail_phys.to_usize() as u64);
+        self.common_write(COMMON_QUEUE_DEVICE, queue.used_phys.to_usize() as u64);
+        self.common_write(COMMON_QUEUE_ENABLE, 1u16);
+        Ok(queue)
+    }
+
+    // デバイス設定の変更をMSI-Xで受け取る
+    #[allow(dead_code)]
+    pub fn set_config_interrupt(
+        &mut self,
+        cpu: usize,
+        handler: InterruptHandler,
+    ) -> Result<(), &'static str> {
+        let (entry, vector) = self.allocate_msix_entry(cpu, handler)?;
+        self.common_write(COMMON_CONFIG_MSIX_VECTOR, entry);
+        if self.common_read::<u16>(COMMON_CONFIG_MSIX_VECTOR) != entry {
+            self.common_write(COMMON_CONFIG_MSIX_VECTOR, NO_VECTOR);
+            self.free_msix_entry(entry, vector);
+            return Err("virtio device could not allocate the MSI-X vector");
+        }
+        Ok(())
+    }
+
+    // キューの設定が終わったら呼び出す
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    // ISRの状態を読み出してクリアする (MSI-Xを使わない場合のみ意味がある)
+    #[allow(dead_code)]
+    pub fn isr_status(&self) -> u8 {
+        read_volatile(self.isr.to_usize())
+    }
+
+    fn device_config_address(&self, offset: usize, size: usize) -> usize {
+        let (config, length) = self
+            .device_config
+            .expect("virtio device has no device configuration");
+        assert!(offset + size <= length, "virtio device config out of range");
+        config.to_usize() + offset
+    }
+
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        read_volatile(self.device_config_address(offset, size_of::<T>()))
+    }
+
+    #[allow(dead_code)]
+    pub fn write_config<T: Copy>(&self, offset: usize, value: T) {
+        write_volatile(self.device_config_address(offset, size_of::<T>()), value)
+    }
+}
+
+// ディスクリプタ
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+// used ringの要素
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+// キューに渡すバッファ. writableならデバイスが書き込む
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub writable: bool,
+}
+
+impl Buffer {
+    pub fn readable(addr: PhysAddr, len: usize) -> Self {
+        Buffer {
+            addr,
+            len: len as u32,
+            writable: false,
+        }
+    }
+
+    pub fn writable(addr: PhysAddr, len: usize) -> Self {
+        Buffer {
+            addr,
+            len: len as u32,
+            writable: true,
+        }
+    }
+}
+
+// split virtqueue. ディスクリプタテーブル、available ring、used ringはそれぞれ物理フレームに置く
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    notify: VirtAddr,
+    desc_phys: PhysAddr,
+    avail_phys: PhysAddr,
+    used_phys: PhysAddr,
+    // 空きディスクリプタのリストの先頭と数
+    free_head: u16,
+    num_free: u16,
+    // 次にavail ringに書く位置と、次に読むused ringの位置
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+// available ring: flags, idx, ring[size], used_event
+const AVAIL_IDX: usize = 2;
+const AVAIL_RING: usize = 4;
+// used ring: flags, idx, ring[size], avail_event
+const USED_IDX: usize = 2;
+const USED_RING: usize = 4;
+
+impl VirtQueue {
+    fn new(index: u16, size: u16, notify: VirtAddr) -> Result<Self, &'static str> {
+        let desc_bytes = size_of::<Descriptor>() * size as usize;
+        let avail_bytes = AVAIL_RING + 2 * size as usize + 2;
+        let used_bytes = USED_RING + size_of::<UsedElem>() * size as usize + 2;
+        // 途中で失敗したら、それまでに確保した分を解放する
+        let sizes = [desc_bytes, avail_bytes, used_bytes];
+        let mut phys = [PhysAddr::new(0); 3];
+        for (i, bytes) in sizes.iter().enumerate() {
+            match frame::allocate_contiguous(bytes.div_ceil(FRAME_SIZE)) {
+                Some(allocated) => phys[i] = allocated,
+                None => {
+                    for (&allocated, bytes) in phys[..i].iter().zip(sizes) {
+                        frame::free(allocated, bytes.div_ceil(FRAME_SIZE));
+                    }
+                    return Err("Out of physical frames");
+                }
+            }
+        }
+        let [desc_phys, avail_phys, used_phys] = phys;
+        let mut queue = VirtQueue {
+            index,
+            size,
+            notify,
+            desc_phys,
+            avail_phys,
+            used_phys,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        // 全てのディスクリプタを空きリストにつなぐ
+        for i in 0..size {
+            queue.desc_mut(i).next = (i + 1) % size;
+        }
+        Ok(queue)
+    }
+
+    fn desc_mut(&mut self, i: u16) -> &mut Descriptor {
+        let base = memlayout::phys_to_virt(self.desc_phys).to_usize() as *mut Descriptor;
+        unsafe { &mut *base.add(i as usize) }
+    }
+
+    fn avail(&self, offset: usize) -> usize {
+        memlayout::phys_to_virt(self.avail_phys).to_usize() + offset
+    }
+
+    fn used(&self, offset: usize) -> usize {
+        memlayout::phys_to_virt(self.used_phys).to_usize() + offset
+    }
+
+    #[allow(dead_code)]
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    #[allow(dead_code)]
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    // バッファのチェーンをavailable ringに追加する. 先頭のディスクリプタ番号を返し、
+    // pop_usedが同じ番号を返したら完了している. notifyを呼ぶまでデバイスは気づかない
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, &'static str> {
+        if buffers.is_empty() {
+            return Err("No buffers to add");
+        }
+        if buffers.len() > self.num_free as usize {
+            return Err("virtqueue is full");
+        }
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = self.free_head;
+            let desc = self.desc_mut(index);
+            desc.addr = buffer.addr.to_usize() as u64;
+            desc.len = buffer.len;
+            desc.flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+            }
+            self.free_head = desc.next;
+        }
+        self.num_free -= buffers.len() as u16;
+
+        let slot = AVAIL_RING + 2 * (self.avail_idx % self.size) as usize;
+        write_volatile(self.avail(slot), head);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        // ディスクリプタとringの書き込みがidxの更新より先に見えるようにする
+        fence(Ordering::SeqCst);
+        write_volatile(self.avail(AVAIL_IDX), self.avail_idx);
+        Ok(head)
+    }
+
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        write_volatile(self.notify.to_usize(), self.index);
+    }
+
+    pub fn has_used(&self) -> bool {
+        let used_idx: u16 = read_volatile(self.used(USED_IDX));
+        used_idx != self.last_used_idx
+    }
+
+    // デバイスが使い終わったチェーンを1つ取り出す. (先頭のディスクリプタ番号, 書き込まれたバイト数)
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = USED_RING + size_of::<UsedElem>() * (self.last_used_idx % self.size) as usize;
+        let elem: UsedElem = read_volatile(self.used(slot));
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        // チェーンのディスクリプタを空きリストに戻す
+        let head = elem.id as u16;
+        let mut index = head;
+        loop {
+            self.num_free += 1;
+            let desc = *self.desc_mut(index);
+            if desc.flags & DESC_F_NEXT == 0 {
+                self.desc_mut(index).next = self.free_head;
+                break;
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+        Some((head, elem.len))
+    }
+}
+
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        let pages = |bytes: usize| bytes.div_ceil(FRAME_SIZE);
+        let size = self.size as usize;
+        frame::free(self.desc_phys, pages(size_of::<Descriptor>() * size));
+        frame::free(self.avail_phys, pages(AVAIL_RING + 2 * size + 2));
+        frame::free(
+            self.used_phys,
+            pages(USED_RING + size_of::<UsedElem>() * size + 2),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+
+    // デバイスの代わりにused ringへ書き込んで、ディスクリプタが再利用されることを確かめる
+    #[test_case]
+    fn used_chains_are_recycled() {
+        let doorbell = Box::leak(Box::new(0u32));
+        let notify = VirtAddr::new(doorbell as *mut u32 as usize);
+        let mut queue = VirtQueue::new(0, 4, notify).unwrap();
+        let buffer = Buffer::writable(PhysAddr::new(0x1000), 16);
+
+        let head = queue.add(&[buffer, buffer, buffer]).unwrap();
+        assert_eq!(queue.num_free(), 1);
+        assert!(queue.add(&[buffer, buffer]).is_err());
+        let avail_idx: u16 = read_volatile(queue.avail(AVAIL_IDX));
+        assert_eq!(avail_idx, 1);
+
+        write_volatile(
+            queue.used(USED_RING),
+            UsedElem {
+                id: head as u32,
+                len: 16,
+            },
+        );
+        write_volatile(queue.used(USED_IDX), 1u16);
+        assert_eq!(queue.pop_used(), Some((head, 16)));
+        assert_eq!(queue.pop_used(), None);
+        assert_eq!(queue.num_free(), 4);
+        assert!(queue.add(&[buffer, buffer, buffer, buffer]).is_ok());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::{
+    block::{self, BlockDevice, BlockOp, Completion, SECTOR_SIZE},
+    frame::{self, FRAME_SIZE},
+    info,
+    memlayout::{self, Address, PhysAddr},
+    pci::{self, PciDevice, PciDriver, PciMatch},
+    smp,
+    spin::SpinLock,
+    virtio::{Buffer, DeviceType, VirtQueue, VirtioDevice},
+    warn,
+};
+use alloc::{collections::BTreeMap, format, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// 機能ビット
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// デバイス設定
+const CONFIG_CAPACITY: usize = 0x00;
+
+// リクエストの種類
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+// リクエストヘッダ. 同じフレームの末尾にステータスを置く
+#[repr(C)]
+struct RequestHeader {
+    typ: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+const STATUS_OFFSET: usize = FRAME_SIZE - 1;
+
+// デバイスに渡したリクエスト
+struct PendingRequest {
+    op: BlockOp,
+    header: PhysAddr,
+    data: Option<(PhysAddr, usize)>,
+    completion: Completion,
+}
+
+impl PendingRequest {
+    fn status(&self) -> u8 {
+        let status = memlayout::phys_to_virt(self.header).to_usize() + STATUS_OFFSET;
+        unsafe { core::ptr::read_volatile(status as *const u8) }
+    }
+
+    // 結果をCompletionに渡し、フレームを解放する
+    fn finish(self) {
+        let result = match self.status() {
+            S_OK => Ok(match (self.op, self.data) {
+                (BlockOp::Read, Some((data, len))) => {
+                    let virt = memlayout::phys_to_virt(data).to_usize() as *const u8;
+                    unsafe { core::slice::from_raw_parts(virt, len) }.to_vec()
+                }
+                _ => Vec::new(),
+            }),
+            S_UNSUPP => Err("Request is not supported by the device"),
+            _ => Err("I/O error"),
+        };
+        frame::free(self.header, 1);
+        if let Some((data, len)) = self.data {
+            frame::free(data, len.div_ceil(FRAME_SIZE));
+        }
+        self.completion.complete(result);
+    }
+}
+
+struct RequestQueue {
+    queue: VirtQueue,
+    pending: BTreeMap<u16, PendingRequest>,
+}
+
+pub struct VirtioBlk {
+    // 割り込みハンドラと共有するため、キューの設定が終わってから入れる
+    requests: Arc<SpinLock<Option<RequestQueue>>>,
+    capacity: u64,
+    read_only: bool,
+    flush: bool,
+    _device: VirtioDevice,
+}
+
+// 使用済みのリクエストを全て完了させる
+fn complete_requests(requests: &SpinLock<Option<RequestQueue>>) {
+    let mut finished = Vec::new();
+    {
+        let mut guard = requests.lock();
+        let Some(requests) = guard.as_mut() else {
+            return;
+        };
+        while let Some((head, _)) = requests.queue.pop_used() {
+            finished.extend(requests.pending.remove(&head));
+        }
+    }
+    for request in finished {
+        request.finish();
+    }
+}
+
+impl VirtioBlk {
+    pub fn new(pci: &'static PciDevice) -> Result<Self, &'static str> {
+        let mut device = VirtioDevice::new(pci)?;
+        let features = device.negotiate_features(F_RO | F_FLUSH)?;
+        let requests = Arc::new(SpinLock::new(None));
+        let handler_requests = requests.clone();
+        let handler = Arc::new(move || complete_requests(&handler_requests));
+        // MSI-Xが使えなければ、完了をポーリングで待つ
+        let queue = match device.setup_queue(0, Some((smp::cpu_id(), handler))) {
+            Ok(queue) => queue,
+            Err(e) => {
+                warn!("virtio-blk: {}, falling back to polling", e);
+                device.setup_queue(0, None)?
+            }
+        };
+        *requests.lock() = Some(RequestQueue {
+            queue,
+            pending: BTreeMap::new(),
+        });
+        device.driver_ok();
+        Ok(VirtioBlk {
+            requests,
+            capacity: device.read_config(CONFIG_CAPACITY),
+            read_only: features & F_RO != 0,
+            flush: features & F_FLUSH != 0,
+            _device: device,
+        })
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn submit(&self, op: BlockOp, sector: u64, data: Vec<u8>) -> Result<Completion, &'static str> {
+        let completion = Completion::new();
+        if op == BlockOp::Flush && !self.flush {
+            // 書き込みキャッシュを持たないデバイスでは何もしなくてよい
+            completion.complete(Ok(Vec::new()));
+            return Ok(completion);
+        }
+
+        let header = frame::allocate().ok_or("Out of physical frames")?;
+        let typ = match op {
+            BlockOp::Read => T_IN,
+            BlockOp::Write => T_OUT,
+            BlockOp::Flush => T_FLUSH,
+        };
+        let header_ptr = memlayout::phys_to_virt(header).to_usize() as *mut RequestHeader;
+        unsafe {
+            header_ptr.write(RequestHeader {
+                typ,
+                reserved: 0,
+                sector,
+            })
+        };
+
+        let data = if data.is_empty() {
+            None
+        } else {
+            let Some(phys) = frame::allocate_contiguous(data.len().div_ceil(FRAME_SIZE)) else {
+                frame::free(header, 1);
+                return Err("Out of physical frames");
+            };
+            if op == BlockOp::Write {
+                let virt = memlayout::phys_to_virt(phys).to_usize() as *mut u8;
+                unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), virt, data.len()) };
+            }
+            Some((phys, data.len()))
+        };
+
+        let mut buffers = Vec::with_capacity(3);
+        buffers.push(Buffer::readable(header, size_of::<RequestHeader>()));
+        if let Some((phys, len)) = data {
+            buffers.push(Buffer {
+                addr: phys,
+                len: len as u32,
+                writable: op == BlockOp::Read,
+            });
+        }
+        buffers.push(Buffer::writable(
+            PhysAddr::new(header.to_usize() + STATUS_OFFSET),
+            1,
+        ));
+
+        let request = PendingRequest {
+            op,
+            header,
+            data,
+            completion: completion.clone(),
+        };
+        let mut guard = self.requests.lock();
+        let requests = guard.as_mut().expect("virtio-blk queue is not set up");
+        let head = match requests.queue.add(&buffers) {
+            Ok(head) => head,
+            Err(e) => {
+                drop(guard);
+                frame::free(request.header, 1);
+                if let Some((phys, len)) = request.data {
+                    frame::free(phys, len.div_ceil(FRAME_SIZE));
+                }
+                return Err(e);
+            }
+        };
+        requests.pending.insert(head, request);
+        requests.queue.notify();
+        Ok(completion)
+    }
+
+    fn poll(&self) {
+        complete_requests(&self.requests);
+    }
+}
+
+const MATCHES: [PciMatch; 2] = DeviceType::Block.pci_matches();
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &MATCHES,
+    probe,
+};
+
+static DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(pci: &'static PciDevice) -> Result<(), &'static str> {
+    let device = VirtioBlk::new(pci)?;
+    // vda, vdb, ...
+    let index = DEVICE_COUNT.fetch_add(1, Ordering::Relaxed);
+    let name = format!("vd{}", (b'a' + index as u8) as char);
+    info!(
+        "{}: {} sectors ({} MiB){}",
+        name,
+        device.capacity,
+        device.capacity * SECTOR_SIZE as u64 / (1024 * 1024),
+        if device.read_only { ", read-only" } else { "" }
+    );
+    block::register(name, Arc::new(device));
+    Ok(())
+}
+
+// pci::initより前に呼び出す
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // QEMU_OPTSのvirtio-blk (disk.img) に書いて読み戻す. 最後のセクタを使う
+    #[test_case]
+    fn read_back_written_sectors() {
+        let Some(disk) = block::get("vda") else {
+            return;
+        };
+        if disk.read_only() || disk.capacity() < 2 {
+            return;
+        }
+        let sector = disk.capacity() - 2;
+        let mut original = [0u8; SECTOR_SIZE * 2];
+        disk.read(sector, &mut original).unwrap();
+
+        let pattern: Vec<u8> = (0..SECTOR_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        disk.write(sector, &pattern).unwrap();
+        disk.flush().unwrap();
+        let mut buf = [0u8; SECTOR_SIZE * 2];
+        disk.read(sector, &mut buf).unwrap();
+        assert_eq!(&buf[..], &pattern[..]);
+
+        disk.write(sector, &original).unwrap();
+    }
+
+    #[test_case]
+    fn requests_complete_asynchronously() {
+        let Some(disk) = block::get("vda") else {
+            return;
+        };
+        let completions: Vec<Completion> = (0..4)
+            .map(|sector| {
+                disk.submit(BlockOp::Read, sector, alloc::vec![0; SECTOR_SIZE])
+                    .unwrap()
+            })
+            .collect();
+        for completion in completions {
+            assert_eq!(disk.wait(&completion).unwrap().len(), SECTOR_SIZE);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use crate::{
+    clock::Instant,
+    frame::{self, FRAME_SIZE},
+    info,
+    memlayout::{self, Address, PhysAddr},
+    pci::{self, PciDevice, PciDriver, PciMatch},
+    smp,
+    spin::{Once, SpinLock},
+    virtio::{Buffer, DeviceType, VirtQueue, VirtioDevice},
+    warn,
+};
+use alloc::{collections::BTreeMap, collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+// 機能ビット
+const F_MULTIPORT: u64 = 1 << 1;
+
+// デバイス設定
+const CONFIG_MAX_NR_PORTS: usize = 0x04;
+
+// コントロールメッセージ
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const DEVICE_REMOVE: u16 = 2;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+const CONTROL_HEADER_LEN: usize = 8;
+
+// 使うポートの数の上限. ポートごとに2つのキューを作る
+const MAX_PORTS: u32 = 8;
+// 受信キューごとに渡しておくバッファの数
+const RX_BUFFERS: usize = 16;
+// 起動時にホストからポートの通知を待つ時間
+const PORT_DISCOVERY_TIMEOUT: Duration = Duration::from_millis(100);
+
+// ポートnの受信キュー. ポート0の後の2つはコントロール用のキュー
+fn rx_queue_index(port: u32) -> u16 {
+    match port {
+        0 => 0,
+        n => (2 * n + 2) as u16,
+    }
+}
+
+const CONTROL_RX_QUEUE: u16 = 2;
+const CONTROL_TX_QUEUE: u16 = 3;
+
+// 1ページずつのバッファを渡すキュー
+struct BufferQueue {
+    queue: VirtQueue,
+    buffers: BTreeMap<u16, PhysAddr>,
+}
+
+impl BufferQueue {
+    fn new(queue: VirtQueue) -> Self {
+        BufferQueue {
+            queue,
+            buffers: BTreeMap::new(),
+        }
+    }
+
+    fn post_rx_buffers(&mut self) {
+        while self.buffers.len() < RX_BUFFERS && self.queue.num_free() > 0 {
+            let Some(phys) = frame::allocate() else {
+                break;
+            };
+            match self.queue.add(&[Buffer::writable(phys, FRAME_SIZE)]) {
+                Ok(head) => {
+                    self.buffers.insert(head, phys);
+                }
+                Err(_) => {
+                    frame::free(phys, 1);
+                    break;
+                }
+            }
+        }
+        self.queue.notify();
+    }
+
+    // 受信したデータを1つ取り出し、バッファを補充する
+    fn pop_received(&mut self) -> Option<Vec<u8>> {
+        let (head, len) = self.queue.pop_used()?;
+        let phys = self.buffers.remove(&head)?;
+        let virt = memlayout::phys_to_virt(phys).to_usize() as *const u8;
+        let len = (len as usize).min(FRAME_SIZE);
+        let data = unsafe { core::slice::from_raw_parts(virt, len) }.to_vec();
+        frame::free(phys, 1);
+        self.post_rx_buffers();
+        Some(data)
+    }
+
+    // dataをページ単位に分けて送る. キューが一杯ならデバイスが処理するのを待つ
+    fn send(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        for chunk in data.chunks(FRAME_SIZE) {
+            self.reclaim();
+            while self.queue.num_free() == 0 {
+                core::hint::spin_loop();
+                self.reclaim();
+            }
+            self.add_readable(chunk)?;
+        }
+        self.queue.notify();
+        Ok(())
+    }
+
+    // 1ページに収まるdataを送る. キューが一杯なら待たずに失敗する
+    fn try_send(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        self.reclaim();
+        if self.queue.num_free() == 0 {
+            return Err("Queue is full");
+        }
+        self.add_readable(data)?;
+        self.queue.notify();
+        Ok(())
+    }
+
+    fn add_readable(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        let phys = frame::allocate().ok_or("Out of physical frames")?;
+        let virt = memlayout::phys_to_virt(phys).to_usize() as *mut u8;
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), virt, data.len()) };
+        match self.queue.add(&[Buffer::readable(phys, data.len())]) {
+            Ok(head) => {
+                self.buffers.insert(head, phys);
+                Ok(())
+            }
+            Err(e) => {
+                frame::free(phys, 1);
+                Err(e)
+            }
+        }
+    }
+
+    // 送信が終わったフレームを解放する
+    fn reclaim(&mut self) {
+        while let Some((head, _)) = self.queue.pop_used() {
+            if let Some(phys) = self.buffers.remove(&head) {
+                frame::free(phys, 1);
+            }
+        }
+    }
+}
+
+// ホストのchardevとつながった名前付きのポート
+pub struct Port {
+    id: u32,
+    name: SpinLock<Option<String>>,
+    rx: SpinLock<BufferQueue>,
+    tx: SpinLock<BufferQueue>,
+    input: SpinLock<VecDeque<u8>>,
+    // デバイスから追加の通知を受けた
+    added: AtomicBool,
+    // ホスト側が開いている
+    host_connected: AtomicBool,
+}
+
+impl Port {
+    #[allow(dead_code)]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.name.lock().clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.host_connected.load(Ordering::Acquire)
+    }
+
+    // ホスト側が開いていなければ、データはデバイスに捨てられる
+    pub fn write(&self, data: &[u8]) -> Result<(), &'static str> {
+        self.tx.lock().send(data)
+    }
+
+    // 受信済みのデータを読む. なければ0を返す
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut input = self.input.lock();
+        {
+            let mut rx = self.rx.lock();
+            while let Some(data) = rx.pop_received() {
+                input.extend(data);
+            }
+        }
+        let len = buf.len().min(input.len());
+        for (dst, src) in buf.iter_mut().zip(input.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+}
+
+struct Console {
+    // MULTIPORTを使わない場合はない
+    control_rx: Option<SpinLock<BufferQueue>>,
+    control_tx: Option<SpinLock<BufferQueue>>,
+    // 送信キューが一杯で送れていないコントロールメッセージ
+    pending_control: SpinLock<VecDeque<[u8; CONTROL_HEADER_LEN]>>,
+    ports: Vec<Port>,
+    _device: VirtioDevice,
+}
+
+static CONSOLE: Once<Console> = Once::new();
+
+impl Console {
+    // 割り込みハンドラからも呼ばれるため、送信キューが空くのを待たない
+    // 送れなかったメッセージはflush_controlで送り直す
+    fn send_control(&self, id: u32, event: u16, value: u16) {
+        let mut message = [0u8; CONTROL_HEADER_LEN];
+        message[0..4].copy_from_slice(&id.to_le_bytes());
+        message[4..6].copy_from_slice(&event.to_le_bytes());
+        message[6..8].copy_from_slice(&value.to_le_bytes());
+        self.pending_control.lock().push_back(message);
+        self.flush_control();
+    }
+
+    fn flush_control(&self) {
+        let Some(control_tx) = &self.control_tx else {
+            return;
+        };
+        let mut pending = self.pending_control.lock();
+        let mut control_tx = control_tx.lock();
+        while let Some(message) = pending.front() {
+            if control_tx.try_send(message).is_err() {
+                break;
+            }
+            pending.pop_front();
+        }
+    }
+
+    // デバイスからのコントロールメッセージを処理する
+    fn process_control(&self) {
+        let Some(control_rx) = &self.control_rx else {
+            return;
+        };
+        loop {
+            let Some(message) = control_rx.lock().pop_received() else {
+                break;
+            };
+            if message.len() < CONTROL_HEADER_LEN {
+                continue;
+            }
+            let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+            let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+            let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+            let Some(port) = self.ports.get(id as usize) else {
+                // キューを用意していないポートは使えない
+                if event == DEVICE_ADD {
+                    self.send_control(id, PORT_READY, 0);
+                }
+                continue;
+            };
+            match event {
+                DEVICE_ADD => {
+                    port.added.store(true, Ordering::Release);
+                    self.send_control(id, PORT_READY, 1);
+                }
+                DEVICE_REMOVE => {
+                    port.added.store(false, Ordering::Release);
+                    port.host_connected.store(false, Ordering::Release);
+                }
+                PORT_NAME => {
+                    let name = String::from_utf8_lossy(&message[CONTROL_HEADER_LEN..]);
+                    *port.name.lock() = Some(String::from(name.trim_end_matches('\0')));
+                    self.send_control(id, PORT_OPEN, 1);
+                }
+                CONSOLE_PORT => self.send_control(id, PORT_OPEN, 1),
+                PORT_OPEN => port.host_connected.store(value != 0, Ordering::Release),
+                _ => {}
+            }
+        }
+    }
+}
+
+// 名前がnameのポートを探す
+pub fn port(name: &str) -> Option<&'static Port> {
+    let console = CONSOLE.get()?;
+    console.process_control();
+    console.flush_control();
+    console.ports.iter().find(|port| {
+        port.added.load(Ordering::Acquire) && port.name.lock().as_deref() == Some(name)
+    })
+}
+
+// デバイスが追加したポート
+#[allow(dead_code)]
+pub fn ports() -> Vec<&'static Port> {
+    let Some(console) = CONSOLE.get() else {
+        return Vec::new();
+    };
+    console.process_control();
+    console.flush_control();
+    console
+        .ports
+        .iter()
+        .filter(|port| port.added.load(Ordering::Acquire))
+        .collect()
+}
+
+fn setup_port(device: &mut VirtioDevice, id: u32) -> Result<Port, &'static str> {
+    let mut rx = BufferQueue::new(device.setup_queue(rx_queue_index(id), None)?);
+    rx.post_rx_buffers();
+    let tx = BufferQueue::new(device.setup_queue(rx_queue_index(id) + 1, None)?);
+    Ok(Port {
+        id,
+        name: SpinLock::new(None),
+        rx: SpinLock::new(rx),
+        tx: SpinLock::new(tx),
+        input: SpinLock::new(VecDeque::new()),
+        added: AtomicBool::new(false),
+        host_connected: AtomicBool::new(false),
+    })
+}
+
+fn probe(pci: &'static PciDevice) -> Result<(), &'static str> {
+    if CONSOLE.get().is_some() {
+        return Err("Only one virtio-console device is supported");
+    }
+    let mut device = VirtioDevice::new(pci)?;
+    let features = device.negotiate_features(F_MULTIPORT)?;
+    if features & F_MULTIPORT == 0 {
+        // ポート0だけのコンソール
+        let port = setup_port(&mut device, 0)?;
+        *port.name.lock() = Some(String::from("console"));
+        port.added.store(true, Ordering::Release);
+        port.host_connected.store(true, Ordering::Release);
+        device.driver_ok();
+        let console = Console {
+            control_rx: None,
+            control_tx: None,
+            pending_control: SpinLock::new(VecDeque::new()),
+            ports: alloc::vec![port],
+            _device: device,
+        };
+        CONSOLE.call_once(|| console);
+        return Ok(());
+    }
+
+    let max_ports = device
+        .read_config::<u32>(CONFIG_MAX_NR_PORTS)
+        .min(MAX_PORTS);
+    // コントロールメッセージは割り込みで処理する. MSI-Xが使えなければポートを使う時に処理する
+    let handler = Arc::new(|| {
+        if let Some(console) = CONSOLE.get() {
+            console.process_control();
+        }
+    });
+    let control_rx = match device.setup_queue(CONTROL_RX_QUEUE, Some((smp::cpu_id(), handler))) {
+        Ok(queue) => queue,
+        Err(e) => {
+            warn!("virtio-console: {}, falling back to polling", e);
+            device.setup_queue(CONTROL_RX_QUEUE, None)?
+        }
+    };
+    let mut control_rx = BufferQueue::new(control_rx);
+    control_rx.post_rx_buffers();
+    let control_tx = BufferQueue::new(device.setup_queue(CONTROL_TX_QUEUE, None)?);
+    let all_ports = (0..max_ports)
+        .map(|id| setup_port(&mut device, id))
+        .collect::<Result<Vec<_>, _>>()?;
+    device.driver_ok();
+
+    let console = CONSOLE.call_once(|| Console {
+        control_rx: Some(SpinLock::new(control_rx)),
+        control_tx: Some(SpinLock::new(control_tx)),
+        pending_control: SpinLock::new(VecDeque::new()),
+        ports: all_ports,
+        _device: device,
+    });
+    console.send_control(0, DEVICE_READY, 1);
+    // ポートの追加と名前の通知を少し待つ
+    let deadline = Instant::now() + PORT_DISCOVERY_TIMEOUT;
+    while Instant::now() < deadline {
+        console.process_control();
+        console.flush_control();
+        core::hint::spin_loop();
+    }
+    for port in ports() {
+        info!(
+            "virtio-console: port {} \"{}\"{}",
+            port.id,
+            port.name().unwrap_or_default(),
+            if port.is_connected() {
+                ""
+            } else {
+                " (not connected)"
+            }
+        );
+    }
+    Ok(())
+}
+
+const MATCHES: [PciMatch; 2] = DeviceType::Console.pci_matches();
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-console",
+    matches: &MATCHES,
+    probe,
+};
+
+// pci::initより前に呼び出す
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn queue_indices_skip_control_queues() {
+        assert_eq!(rx_queue_index(0), 0);
+        assert_eq!(rx_queue_index(1), 4);
+        assert_eq!(rx_queue_index(2), 6);
+    }
+
+    // QEMU_OPTSのvirtserialportが名前で見つかる
+    #[test_case]
+    fn named_ports_are_discovered() {
+        if CONSOLE.get().is_none() {
+            return;
+        }
+        let shell = port("kernel.shell").expect("kernel.shell port is not found");
+        assert!(shell.write(b"hello\n").is_ok());
+        assert!(port("no.such.port").is_none());
+    }
+}
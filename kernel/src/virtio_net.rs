@@ -0,0 +1,173 @@
+use crate::{
+    frame::{self, FRAME_SIZE},
+    info,
+    memlayout::{self, Address, PhysAddr},
+    net::{self, MacAddress, NetDevice},
+    pci::{self, PciDevice, PciDriver, PciMatch},
+    smp,
+    spin::SpinLock,
+    virtio::{Buffer, DeviceType, VirtQueue, VirtioDevice},
+    warn,
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+
+// 機能ビット
+const F_MAC: u64 = 1 << 5;
+
+// デバイス設定
+const CONFIG_MAC: usize = 0x00;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+// 各パケットの先頭に付くヘッダ (VIRTIO_F_VERSION_1ではnum_buffersを含む)
+const HEADER_LEN: usize = 12;
+// ヘッダ + VLANタグ付きの最大のEthernetフレーム
+const MAX_FRAME_LEN: usize = 1518;
+
+// バッファの先頭の物理アドレスをディスクリプタの番号ごとに覚えておく
+struct Queue {
+    queue: VirtQueue,
+    buffers: BTreeMap<u16, PhysAddr>,
+}
+
+pub struct VirtioNet {
+    mac: MacAddress,
+    rx: SpinLock<Queue>,
+    tx: SpinLock<Queue>,
+    _device: VirtioDevice,
+}
+
+impl Queue {
+    // 受信用のフレームを1つデバイスに渡す
+    fn post_rx_buffer(&mut self) -> Result<(), &'static str> {
+        let phys = frame::allocate().ok_or("Out of physical frames")?;
+        match self.queue.add(&[Buffer::writable(phys, FRAME_SIZE)]) {
+            Ok(head) => {
+                self.buffers.insert(head, phys);
+                Ok(())
+            }
+            Err(e) => {
+                frame::free(phys, 1);
+                Err(e)
+            }
+        }
+    }
+
+    // 送信が終わったフレームを解放する
+    fn reclaim_tx_buffers(&mut self) {
+        while let Some((head, _)) = self.queue.pop_used() {
+            if let Some(phys) = self.buffers.remove(&head) {
+                frame::free(phys, 1);
+            }
+        }
+    }
+}
+
+impl VirtioNet {
+    pub fn new(pci: &'static PciDevice) -> Result<Self, &'static str> {
+        let mut device = VirtioDevice::new(pci)?;
+        let features = device.negotiate_features(F_MAC)?;
+        // 割り込みは受信処理のタスクを起こすためだけに使う
+        let rx_queue = match device.setup_queue(RX_QUEUE, Some((smp::cpu_id(), Arc::new(|| {})))) {
+            Ok(queue) => queue,
+            Err(e) => {
+                warn!("virtio-net: {}, falling back to polling", e);
+                device.setup_queue(RX_QUEUE, None)?
+            }
+        };
+        let tx_queue = device.setup_queue(TX_QUEUE, None)?;
+        let mut rx = Queue {
+            queue: rx_queue,
+            buffers: BTreeMap::new(),
+        };
+        while rx.queue.num_free() > 0 {
+            rx.post_rx_buffer()?;
+        }
+        let mac = if features & F_MAC != 0 {
+            MacAddress(device.read_config(CONFIG_MAC))
+        } else {
+            return Err("virtio-net device has no MAC address");
+        };
+        device.driver_ok();
+        rx.queue.notify();
+        Ok(VirtioNet {
+            mac,
+            rx: SpinLock::new(rx),
+            tx: SpinLock::new(Queue {
+                queue: tx_queue,
+                buffers: BTreeMap::new(),
+            }),
+            _device: device,
+        })
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), &'static str> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err("Ethernet frame is too large");
+        }
+        let mut tx = self.tx.lock();
+        tx.reclaim_tx_buffers();
+        let phys = frame::allocate().ok_or("Out of physical frames")?;
+        // ヘッダは0のまま (オフロードを使わない)
+        let virt = memlayout::phys_to_virt(phys).to_usize() as *mut u8;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), virt.add(HEADER_LEN), frame.len())
+        };
+        match tx
+            .queue
+            .add(&[Buffer::readable(phys, HEADER_LEN + frame.len())])
+        {
+            Ok(head) => {
+                tx.buffers.insert(head, phys);
+                tx.queue.notify();
+                Ok(())
+            }
+            Err(e) => {
+                frame::free(phys, 1);
+                Err(e)
+            }
+        }
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        let (head, len) = rx.queue.pop_used()?;
+        let phys = rx.buffers.remove(&head)?;
+        let len = (len as usize).clamp(HEADER_LEN, FRAME_SIZE);
+        let virt = memlayout::phys_to_virt(phys).to_usize() as *const u8;
+        let frame =
+            unsafe { core::slice::from_raw_parts(virt.add(HEADER_LEN), len - HEADER_LEN) }.to_vec();
+        frame::free(phys, 1);
+        if rx.post_rx_buffer().is_ok() {
+            rx.queue.notify();
+        }
+        Some(frame)
+    }
+}
+
+const MATCHES: [PciMatch; 2] = DeviceType::Net.pci_matches();
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-net",
+    matches: &MATCHES,
+    probe,
+};
+
+fn probe(pci: &'static PciDevice) -> Result<(), &'static str> {
+    let device = VirtioNet::new(pci)?;
+    info!("virtio-net: MAC address {}", device.mac);
+    net::register_device(Arc::new(device));
+    Ok(())
+}
+
+// pci::initより前に呼び出す
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
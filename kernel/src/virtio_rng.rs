@@ -0,0 +1,184 @@
+use crate::{
+    clock::Instant,
+    frame, info,
+    memlayout::{self, Address, PhysAddr},
+    pci::{self, PciDevice, PciDriver, PciMatch},
+    random, smp,
+    spin::{Once, SpinLock},
+    virtio::{Buffer, DeviceType, VirtQueue, VirtioDevice},
+    warn,
+};
+use alloc::sync::Arc;
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+const REQUEST_QUEUE: u16 = 0;
+// 1回の要求で受け取るバイト数
+const REQUEST_LEN: usize = 64;
+// 起動時に最初の乱数を待つ時間
+const SEED_TIMEOUT: Duration = Duration::from_millis(100);
+
+struct Request {
+    queue: VirtQueue,
+    pending: bool,
+}
+
+struct VirtioRng {
+    request: SpinLock<Request>,
+    buffer: PhysAddr,
+    received: AtomicUsize,
+    _device: VirtioDevice,
+}
+
+static RNG: Once<VirtioRng> = Once::new();
+
+impl VirtioRng {
+    // 受け取り中でなければ、デバイスに乱数を要求する
+    fn request(&self) {
+        let mut request = self.request.lock();
+        if request.pending {
+            return;
+        }
+        if request
+            .queue
+            .add(&[Buffer::writable(self.buffer, REQUEST_LEN)])
+            .is_ok()
+        {
+            request.pending = true;
+            request.queue.notify();
+        }
+    }
+
+    // 届いた乱数をプールに加える
+    fn collect(&self) {
+        let mut request = self.request.lock();
+        let Some((_, len)) = request.queue.pop_used() else {
+            return;
+        };
+        request.pending = false;
+        let len = (len as usize).min(REQUEST_LEN);
+        let virt = memlayout::phys_to_virt(self.buffer).to_usize() as *const u8;
+        let data = unsafe { core::slice::from_raw_parts(virt, len) };
+        random::add_entropy(data, len * 8);
+        self.received.fetch_add(len, Ordering::Relaxed);
+    }
+}
+
+// 割り込みが使えない場合も、ここで前回の要求の結果を回収する
+fn refill() {
+    if let Some(rng) = RNG.get() {
+        rng.collect();
+        rng.request();
+    }
+}
+
+const MATCHES: [PciMatch; 2] = DeviceType::Entropy.pci_matches();
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-rng",
+    matches: &MATCHES,
+    probe,
+};
+
+fn probe(pci: &'static PciDevice) -> Result<(), &'static str> {
+    if RNG.get().is_some() {
+        return Err("virtio-rng is already in use");
+    }
+    let mut device = VirtioDevice::new(pci)?;
+    device.negotiate_features(0)?;
+    let handler = Arc::new(|| {
+        if let Some(rng) = RNG.get() {
+            rng.collect();
+        }
+    });
+    let queue = match device.setup_queue(REQUEST_QUEUE, Some((smp::cpu_id(), handler))) {
+        Ok(queue) => queue,
+        Err(e) => {
+            warn!("virtio-rng: {}, falling back to polling", e);
+            device.setup_queue(REQUEST_QUEUE, None)?
+        }
+    };
+    let buffer = frame::allocate().ok_or("Out of physical frames")?;
+    device.driver_ok();
+    let rng = RNG.call_once(|| VirtioRng {
+        request: SpinLock::new(Request {
+            queue,
+            pending: false,
+        }),
+        buffer,
+        received: AtomicUsize::new(0),
+        _device: device,
+    });
+
+    // 最初の乱数は待って受け取り、以降はrandomから定期的に要求してもらう
+    rng.request();
+    let start = Instant::now();
+    while rng.received.load(Ordering::Relaxed) == 0 && start.elapsed() < SEED_TIMEOUT {
+        rng.collect();
+        core::hint::spin_loop();
+    }
+    random::register_source(refill);
+    info!(
+        "virtio-rng: seeded with {} bytes",
+        rng.received.load(Ordering::Relaxed)
+    );
+    Ok(())
+}
+
+// pci::initより前に呼び出す
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // QEMU_OPTSのvirtio-rngから、ドライバ経由で乱数が届くことを確かめる
+    #[test_case]
+    fn device_feeds_pool() {
+        let Some(rng) = RNG.get() else {
+            return;
+        };
+        let before = rng.received.load(Ordering::Relaxed);
+        refill();
+        let start = Instant::now();
+        while rng.received.load(Ordering::Relaxed) == before {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            rng.collect();
+            core::hint::spin_loop();
+        }
+    }
+
+    // QEMU_OPTSのvirtio-rngで、トランスポートとキューが実際に動くことを確かめる
+    // デバイスはドライバが使っているため、リセットせずにドライバのキューへ直接積む
+    #[test_case]
+    fn entropy_device_fills_buffer() {
+        let Some(rng) = RNG.get() else {
+            return;
+        };
+        let wait_used = |queue: &mut VirtQueue| loop {
+            if let Some(used) = queue.pop_used() {
+                break used;
+            }
+            core::hint::spin_loop();
+        };
+        let mut request = rng.request.lock();
+        // ドライバの要求が残っていれば、先に完了させておく
+        if request.pending {
+            wait_used(&mut request.queue);
+            request.pending = false;
+        }
+
+        let page = frame::allocate().unwrap();
+        let head = request.queue.add(&[Buffer::writable(page, 64)]).unwrap();
+        request.queue.notify();
+        let (used, len) = wait_used(&mut request.queue);
+        assert_eq!(used, head);
+        assert!(len > 0);
+        drop(request);
+        frame::free(page, 1);
+    }
+}
@@ -2,7 +2,7 @@ use alloc::vec;
 use alloc::{string::String, vec::Vec};
 
 use crate::print;
-use core::arch::asm;
+use core::{arch::asm, fmt};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
@@ -279,89 +279,108 @@ fn get_end_addr(insts: &[Instruction], pc: usize) -> usize {
     }
 }
 
-pub fn wasm_entry() {
+// ファイルシステムがないため、シェルから名前で実行できるプログラムはここに組み込んでおく
+// 命令列を直接組み立てたもので、wasmのバイナリは読み込まない
+pub const PROGRAMS: [(&str, Program); 1] = [("diamond", print_diamond)];
+
+pub type Program = fn(out: &mut dyn fmt::Write);
+
+fn print_diamond(out: &mut dyn fmt::Write) {
     let size = 15; // ダイヤの高さ（奇数）
-    loop {
-        let wasm_space = Store {
-            funcs: vec![FuncInst::Internal(InternalFuncInst {
-                func_type: FuncType {
-                    params: vec![ValueType::I32],  // n
-                    results: vec![ValueType::I32], // 空白数
-                },
-                code: Func {
-                    locals: vec![],
-                    body: vec![
-                        // if n < size/2: return size/2 - n
-                        Instruction::LocalGet(0), // n
-                        Instruction::Const(size as i32 / 2),
-                        Instruction::I32Lts,
-                        Instruction::If(Block {
-                            block_type: BlockType::Void,
-                        }),
-                        Instruction::Const(size as i32 / 2),
-                        Instruction::LocalGet(0),
-                        Instruction::I32Sub,
-                        Instruction::Return,
-                        Instruction::End,
-                        // else: return n - size/2
-                        Instruction::LocalGet(0),
-                        Instruction::Const(size as i32 / 2),
-                        Instruction::I32Sub,
-                        Instruction::Return,
-                    ],
-                },
-            })],
-        };
-        let wasm_star = Store {
-            funcs: vec![FuncInst::Internal(InternalFuncInst {
-                func_type: FuncType {
-                    params: vec![ValueType::I32],  // n
-                    results: vec![ValueType::I32], // 星数
-                },
-                code: Func {
-                    locals: vec![],
-                    body: vec![
-                        // if n < size/2: return 2*n + 1
-                        Instruction::LocalGet(0),
-                        Instruction::Const(size as i32 / 2),
-                        Instruction::I32Lts,
-                        Instruction::If(Block {
-                            block_type: BlockType::Void,
-                        }),
-                        Instruction::LocalGet(0),
-                        Instruction::Const(2),
-                        Instruction::I32Mul,
-                        Instruction::Const(1),
-                        Instruction::I32Add,
-                        Instruction::Return,
-                        Instruction::End,
-                        // else: return size - 2*(n - size/2)
-                        Instruction::Const(size as i32),
-                        Instruction::Const(2),
-                        Instruction::LocalGet(0),
-                        Instruction::Const(size as i32 / 2),
-                        Instruction::I32Sub,
-                        Instruction::I32Mul,
-                        Instruction::I32Sub,
-                        Instruction::Return,
-                    ],
-                },
-            })],
-        };
+    let wasm_space = Store {
+        funcs: vec![FuncInst::Internal(InternalFuncInst {
+            func_type: FuncType {
+                params: vec![ValueType::I32],  // n
+                results: vec![ValueType::I32], // 空白数
+            },
+            code: Func {
+                locals: vec![],
+                body: vec![
+                    // if n < size/2: return size/2 - n
+                    Instruction::LocalGet(0), // n
+                    Instruction::Const(size as i32 / 2),
+                    Instruction::I32Lts,
+                    Instruction::If(Block {
+                        block_type: BlockType::Void,
+                    }),
+                    Instruction::Const(size as i32 / 2),
+                    Instruction::LocalGet(0),
+                    Instruction::I32Sub,
+                    Instruction::Return,
+                    Instruction::End,
+                    // else: return n - size/2
+                    Instruction::LocalGet(0),
+                    Instruction::Const(size as i32 / 2),
+                    Instruction::I32Sub,
+                    Instruction::Return,
+                ],
+            },
+        })],
+    };
+    let wasm_star = Store {
+        funcs: vec![FuncInst::Internal(InternalFuncInst {
+            func_type: FuncType {
+                params: vec![ValueType::I32],  // n
+                results: vec![ValueType::I32], // 星数
+            },
+            code: Func {
+                locals: vec![],
+                body: vec![
+                    // if n < size/2: return 2*n + 1
+                    Instruction::LocalGet(0),
+                    Instruction::Const(size as i32 / 2),
+                    Instruction::I32Lts,
+                    Instruction::If(Block {
+                        block_type: BlockType::Void,
+                    }),
+                    Instruction::LocalGet(0),
+                    Instruction::Const(2),
+                    Instruction::I32Mul,
+                    Instruction::Const(1),
+                    Instruction::I32Add,
+                    Instruction::Return,
+                    Instruction::End,
+                    // else: return size - 2*(n - size/2)
+                    Instruction::Const(size as i32),
+                    Instruction::Const(2),
+                    Instruction::LocalGet(0),
+                    Instruction::Const(size as i32 / 2),
+                    Instruction::I32Sub,
+                    Instruction::I32Mul,
+                    Instruction::I32Sub,
+                    Instruction::Return,
+                ],
+            },
+        })],
+    };
+
+    let mut runtime_space = Runtime::new(wasm_space);
+    let mut runtime_star = Runtime::new(wasm_star);
+
+    let _ = writeln!(out);
+    for n in 0..size {
+        let space = runtime_space.call(0, vec![n]).unwrap_or(0);
+        let star = runtime_star.call(0, vec![n]).unwrap_or(0);
+        let space_str: String = core::iter::repeat(' ').take(space as usize).collect();
+        let star_str: String = core::iter::repeat('*').take(star as usize).collect();
+        let _ = writeln!(out, "{}{}", space_str, star_str);
+    }
+    let _ = writeln!(out);
+}
 
-        let mut runtime_space = Runtime::new(wasm_space);
-        let mut runtime_star = Runtime::new(wasm_star);
+// print!に書き込む
+struct Console;
 
-        print!("\n");
-        for n in 0..size {
-            let space = runtime_space.call(0, vec![n]).unwrap_or(0);
-            let star = runtime_star.call(0, vec![n]).unwrap_or(0);
-            let space_str: String = core::iter::repeat(' ').take(space as usize).collect();
-            let star_str: String = core::iter::repeat('*').take(star as usize).collect();
-            print!("{}{}\n", space_str, star_str);
-        }
-        print!("\n");
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
 
+pub fn wasm_entry() {
+    loop {
+        print_diamond(&mut Console);
         unsafe { asm!("hlt") }
     }
 }
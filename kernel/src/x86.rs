@@ -2,6 +2,12 @@ use core::arch::asm;
 
 use crate::memlayout::{Address, PhysAddr};
 
+const RFLAGS_INTERRUPT_ENABLE: usize = 1 << 9;
+
+pub const CR4_PGE: usize = 1 << 7;
+pub const CR4_PCIDE: usize = 1 << 17;
+const CR3_PCID_NOFLUSH: usize = 1 << 63;
+
 pub fn write_io(port: u16, value: u8) {
     unsafe {
         asm!(
@@ -24,6 +30,51 @@ pub fn read_io(port: u16) -> u8 {
     value
 }
 
+pub fn write_io_u16(port: u16, value: u16) {
+    unsafe {
+        asm!(
+            "out dx, ax",
+            in("dx") port,
+            in("ax") value,
+        );
+    }
+}
+
+#[allow(dead_code)]
+pub fn read_io_u16(port: u16) -> u16 {
+    let mut value: u16;
+    unsafe {
+        asm!(
+            "in ax, dx",
+            in("dx") port,
+            out("ax") value,
+        );
+    }
+    value
+}
+
+pub fn write_io_u32(port: u16, value: u32) {
+    unsafe {
+        asm!(
+            "out dx, eax",
+            in("dx") port,
+            in("eax") value,
+        );
+    }
+}
+
+pub fn read_io_u32(port: u16) -> u32 {
+    let mut value: u32;
+    unsafe {
+        asm!(
+            "in eax, dx",
+            in("dx") port,
+            out("eax") value,
+        );
+    }
+    value
+}
+
 #[allow(dead_code)]
 pub fn disable_interrupts() {
     unsafe {
@@ -37,6 +88,16 @@ pub fn enable_interrupts() {
     }
 }
 
+pub fn interrupts_enabled() -> bool {
+    read_rflags() & RFLAGS_INTERRUPT_ENABLE != 0
+}
+
+pub fn halt() {
+    unsafe {
+        asm!("hlt", options(nostack),);
+    }
+}
+
 unsafe fn write_cr3_inner(value: usize) {
     unsafe {
         asm!(
@@ -53,6 +114,140 @@ pub fn write_cr3(phys: PhysAddr) {
     }
 }
 
+// 指定した仮想アドレスを含むページのTLBエントリを無効化する
+pub fn invlpg(addr: usize) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags));
+    }
+}
+
+// PCIDを指定してCR3を設定する. noflushがtrueならそのPCIDのTLBエントリを残す
+// CR4.PCIDEが有効である必要がある
+pub fn write_cr3_pcid(phys: PhysAddr, pcid: u16, noflush: bool) {
+    let noflush = if noflush { CR3_PCID_NOFLUSH } else { 0 };
+    unsafe {
+        write_cr3_inner(phys.to_usize() | pcid as usize | noflush);
+    }
+}
+
+pub fn write_cr4(value: usize) {
+    unsafe {
+        asm!(
+            "mov cr4, {}",
+            in(reg) value,
+            options(nostack),
+        );
+    }
+}
+
+// このCPUのTLBを、グローバルなエントリと全てのPCIDのエントリを含めて無効化する
+// CR4.PGEを切り替えると全て無効化され、PGEが無効ならCR3の再設定で無効化される
+pub fn flush_tlb() {
+    let cr4 = read_cr4();
+    if cr4 & CR4_PGE != 0 {
+        write_cr4(cr4 & !CR4_PGE);
+        write_cr4(cr4);
+    } else {
+        unsafe {
+            write_cr3_inner(read_cr3());
+        }
+    }
+}
+
+pub fn read_msr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!(
+            "rdmsr",
+            in("ecx") msr,
+            out("eax") low,
+            out("edx") high,
+            options(nostack),
+        );
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+pub fn write_msr(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack),
+        );
+    }
+}
+
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!(
+            "rdtsc",
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack),
+        );
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    // rbxはLLVMが予約しているため退避してから使う
+    unsafe {
+        asm!(
+            "mov {tmp:r}, rbx",
+            "cpuid",
+            "xchg {tmp:r}, rbx",
+            tmp = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+            options(nostack),
+        );
+    }
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+// CFが立っていなければ乱数がまだ用意できていないので、何度か試す
+macro_rules! make_read_random {
+    ($fn_name:ident, $inst:literal, $retries:literal) => {
+        pub fn $fn_name() -> Option<u64> {
+            for _ in 0..$retries {
+                let (value, ok): (u64, u8);
+                unsafe {
+                    asm!(
+                        concat!($inst, " {value}"),
+                        "setc {ok}",
+                        value = out(reg) value,
+                        ok = out(reg_byte) ok,
+                        options(nomem, nostack),
+                    );
+                }
+                if ok != 0 {
+                    return Some(value);
+                }
+                core::hint::spin_loop();
+            }
+            None
+        }
+    };
+}
+
+make_read_random!(rdrand, "rdrand", 10);
+make_read_random!(rdseed, "rdseed", 100);
+
 macro_rules! make_read_reg {
 	($fn_name:ident, $reg:tt) => {
 		#[allow(dead_code)]
@@ -104,7 +299,6 @@ pub fn read_rip() -> usize {
     rip
 }
 
-#[allow(dead_code)]
 pub fn read_rflags() -> usize {
     let rflags: usize;
     unsafe {
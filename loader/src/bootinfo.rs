@@ -0,0 +1,32 @@
+use crate::memory::MemoryRegionArray;
+
+// Information handed over to the kernel.
+// The layout must be kept in sync with kernel/src/bootinfo.rs.
+#[repr(C)]
+pub struct BootInfo {
+    pub heap_base: u64,
+    pub heap_size: u64,
+    // Physical address of the ACPI RSDP (0 if not found)
+    pub rsdp: u64,
+    // Physical address of a page below 1 MiB reserved for the AP startup code (0 if unavailable)
+    pub ap_trampoline: u64,
+    pub framebuffer: FramebufferInfo,
+    pub memory_regions: MemoryRegionArray,
+}
+
+// Pixel formats of the framebuffer (only 32-bit pixels are handed over)
+pub const PIXEL_FORMAT_RGB: u32 = 0;
+pub const PIXEL_FORMAT_BGR: u32 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct FramebufferInfo {
+    // Physical address of the GOP framebuffer (0 if unavailable)
+    pub base: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    // Pixels per scan line (may be larger than width)
+    pub stride: u32,
+    pub pixel_format: u32,
+}
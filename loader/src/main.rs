@@ -1,23 +1,27 @@
 #![feature(uefi_std)]
 
+mod bootinfo;
 mod memory;
 mod paging;
 
 use core::arch::asm;
 use elf::{ElfBytes, endian::AnyEndian};
-use r_efi::{efi, system};
+use r_efi::{efi, protocols::graphics_output, system};
 use std::{
     ffi::OsStr,
     os::uefi::{self, ffi::OsStrExt},
 };
 
 use crate::{
+    bootinfo::{BootInfo, FramebufferInfo, PIXEL_FORMAT_BGR, PIXEL_FORMAT_RGB},
     memory::{MemoryRegion, MemoryRegionArray, PAGE_SIZE},
     paging::{MSize, PhysAddr, VirtAddr, KERNEL_DIRECT_START},
 };
 
 const KERNEL_STACK_SIZE: u64 = 0x4000;
 const KERNEL_HEAP_SIZE: u64 = 0x1000000;
+// The AP startup code runs in real mode, so it must be placed below 1 MiB
+const AP_TRAMPOLINE_MAX_ADDRESS: u64 = 0xFFFFF;
 
 fn open_root_dir() -> *mut efi::protocols::file::Protocol {
     let bt = uefi::env::boot_services().unwrap().as_ptr() as *const efi::BootServices;
@@ -208,6 +212,82 @@ fn allocate_memory(size: u64) -> u64 {
     addr
 }
 
+// Look up the ACPI RSDP in the UEFI configuration table, preferring ACPI 2.0+
+fn find_rsdp() -> u64 {
+    let st = uefi::env::system_table().as_ptr() as *const efi::SystemTable;
+    let tables = unsafe {
+        core::slice::from_raw_parts((*st).configuration_table, (*st).number_of_table_entries)
+    };
+    let find = |guid: efi::Guid| {
+        tables
+            .iter()
+            .find(|table| table.vendor_guid == guid)
+            .map(|table| table.vendor_table as u64)
+    };
+    find(system::ACPI_20_TABLE_GUID)
+        .or_else(|| find(system::ACPI_10_TABLE_GUID))
+        .unwrap_or(0)
+}
+
+// Record the GOP framebuffer so that the kernel can keep drawing after exiting boot services
+fn find_framebuffer() -> FramebufferInfo {
+    let bt = uefi::env::boot_services().unwrap().as_ptr() as *const efi::BootServices;
+
+    let mut gop: *mut graphics_output::Protocol = core::ptr::null_mut();
+    let status = unsafe {
+        #[allow(const_item_mutation)]
+        ((*bt).locate_protocol)(
+            &mut graphics_output::PROTOCOL_GUID as *mut efi::Guid,
+            core::ptr::null_mut(),
+            &mut gop as *mut *mut graphics_output::Protocol as *mut *mut core::ffi::c_void,
+        )
+    };
+    if status_to_result(status).is_err() {
+        println!("Graphics Output Protocol is not available");
+        return FramebufferInfo::default();
+    }
+
+    let mode = unsafe { &*(*gop).mode };
+    let info = unsafe { &*mode.info };
+    let pixel_format = match info.pixel_format {
+        graphics_output::PIXEL_RED_GREEN_BLUE_RESERVED_8_BIT_PER_COLOR => PIXEL_FORMAT_RGB,
+        graphics_output::PIXEL_BLUE_GREEN_RED_RESERVED_8_BIT_PER_COLOR => PIXEL_FORMAT_BGR,
+        format => {
+            println!("Unsupported GOP pixel format: {format}");
+            return FramebufferInfo::default();
+        }
+    };
+    FramebufferInfo {
+        base: mode.frame_buffer_base,
+        size: mode.frame_buffer_size as u64,
+        width: info.horizontal_resolution,
+        height: info.vertical_resolution,
+        stride: info.pixels_per_scan_line,
+        pixel_format,
+    }
+}
+
+fn allocate_ap_trampoline() -> u64 {
+    let bt = uefi::env::boot_services().unwrap().as_ptr() as *const efi::BootServices;
+    let mut addr: u64 = AP_TRAMPOLINE_MAX_ADDRESS;
+
+    let status = unsafe {
+        ((*bt).allocate_pages)(
+            system::ALLOCATE_MAX_ADDRESS,
+            efi::LOADER_DATA,
+            1,
+            &mut addr as *mut u64,
+        )
+    };
+    match status_to_result(status) {
+        Ok(()) => addr,
+        Err(_) => {
+            println!("Failed to allocate memory for the AP trampoline");
+            0
+        }
+    }
+}
+
 pub fn status_to_result(status: efi::Status) -> Result<(), efi::Status> {
     match status {
         efi::Status::SUCCESS => Ok(()),
@@ -222,13 +302,15 @@ fn main() {
 
     println!("Hello, world!");
 
-    let memory_map = memory::MemoryMap::new();
-    /*for desc in memory_map.iter() {
-        println!(
-            "Physical Start: {:#018x}, Number of Pages: {:#07x}, Type: {:?}",
-            desc.physical_start, desc.number_of_pages, desc.r#type
-        );
-    }*/
+    // Allocate before taking the memory map so that the page is reported as reserved
+    let ap_trampoline = allocate_ap_trampoline();
+    let rsdp = find_rsdp();
+    println!("RSDP: {rsdp:#x}, AP Trampoline: {ap_trampoline:#x}");
+    let framebuffer = find_framebuffer();
+    println!(
+        "Framebuffer: {:#x} {}x{} (stride {})",
+        framebuffer.base, framebuffer.width, framebuffer.height, framebuffer.stride
+    );
 
     println!("Image Base: {:#x}", get_image_base());
 
@@ -243,6 +325,16 @@ fn main() {
     let heap_base = allocate_memory(KERNEL_HEAP_SIZE) + (KERNEL_DIRECT_START as u64);
     let heap_size: u64 = KERNEL_HEAP_SIZE;
 
+    // Take the memory map after the kernel image, stack and heap are allocated
+    // so that the kernel does not see them as usable memory.
+    let memory_map = memory::MemoryMap::new();
+    /*for desc in memory_map.iter() {
+        println!(
+            "Physical Start: {:#018x}, Number of Pages: {:#07x}, Type: {:?}",
+            desc.physical_start, desc.number_of_pages, desc.r#type
+        );
+    }*/
+
 	// FIXME:
 	// While strictly speaking one should use the memory map obtained immediately before calling the kernel,
 	// this implementation avoids changing the memory map revision by using heap memory within the processing.
@@ -265,6 +357,15 @@ fn main() {
         memory_regions.push(region);
     }
 
+    let boot_info = BootInfo {
+        heap_base,
+        heap_size,
+        rsdp,
+        ap_trampoline,
+        framebuffer,
+        memory_regions,
+    };
+
     let memory_map = memory::MemoryMap::new();
 
     status_to_result(unsafe {
@@ -273,9 +374,9 @@ fn main() {
     .expect("Failed to exit boot services");
 
     unsafe {
-        let kernel_entry: extern "sysv64" fn(stack_base: u64, heap_base: u64, heap_size: u64, memory_region: &MemoryRegionArray) -> ! =
+        let kernel_entry: extern "sysv64" fn(stack_base: u64, boot_info: &BootInfo) -> ! =
             core::mem::transmute(kernel_entry);
-        kernel_entry(stack_base as u64, heap_base as u64, heap_size as u64, &memory_regions);
+        kernel_entry(stack_base, &boot_info);
     }
 
     #[allow(unreachable_code)]
@@ -2,12 +2,14 @@ use super::status_to_result;
 use r_efi::efi::{self, MemoryDescriptor};
 use std::os::uefi;
 
+#[repr(u8)]
 #[derive(Clone, Copy, Debug)]
 pub enum MemoryRegionType {
     Reserved,
     Usable,
 }
 
+#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct MemoryRegion {
     base: usize,
@@ -45,6 +47,7 @@ impl MemoryRegion {
 pub const PAGE_SIZE: usize = 4096;
 const MAX_MEMORY_REGION_LEN: usize = 128;
 
+#[repr(C)]
 pub struct MemoryRegionArray {
     regions: [MemoryRegion; MAX_MEMORY_REGION_LEN],
     count: usize,